@@ -1,6 +1,16 @@
 use std::path;
 use std::time;
 use crate::dir_scan;
+use crate::work_db;
+use crate::work_dcm;
+use crate::work_dimse;
+use crate::work_duplicates;
+use crate::work_export;
+use crate::work_thumbnail;
+use crate::work_transcode;
+use crate::work_validate;
+use crate::work_volume;
+use crate::work_web;
 pub use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
@@ -12,39 +22,583 @@ struct Cli {
 
 }
 
+// Параметры обхода директорий, общие для команд поиска.
+// Обычный комментарий: doc-комментарий встроенной (flatten) структуры заменил бы описание подкоманды
+#[derive(Debug, StructOpt)]
+struct ScanArgs {
+    /// Input the path to the directory to search for DICOM files in it (can be repeated)
+    #[structopt(short = "p", long = "path", name = "find_in", parse(from_os_str), required = true, min_values = 1)]
+    paths_to_dir_for_search: Vec<path::PathBuf>,
+
+    /// Only read files whose path matches one of these glob patterns (e.g. `**/*.dcm`)
+    #[structopt(long = "include", name = "include_glob", parse(try_from_str = parse_glob))]
+    include: Vec<String>,
+
+    /// Skip files and directories whose path matches one of these glob patterns (e.g. `**/tmp`)
+    #[structopt(long = "exclude", name = "exclude_glob", parse(try_from_str = parse_glob))]
+    exclude: Vec<String>,
+
+    /// Maximum depth of the directory walk (0 means only the given paths)
+    #[structopt(long = "max-depth")]
+    max_depth: Option<usize>,
+
+    /// Skip files smaller than this size (bytes, or with K/M/G suffix)
+    #[structopt(long = "min-size", parse(try_from_str = parse_size))]
+    min_size: Option<u64>,
+
+    /// Skip files larger than this size (bytes, or with K/M/G suffix)
+    #[structopt(long = "max-size", parse(try_from_str = parse_size))]
+    max_size: Option<u64>,
+
+    /// Follow symbolic links (link loops are detected and skipped)
+    #[structopt(long = "follow-links")]
+    follow_links: bool,
+
+    /// Also search in hidden directories (names starting with a dot)
+    #[structopt(long = "hidden")]
+    include_hidden: bool,
+
+    /// Also read DICOM files inside ZIP, TAR and TGZ archives (indexed as `archive.zip!/member`)
+    #[structopt(long = "archives")]
+    scan_archives: bool,
+
+    /// Index the files listed in a found DICOMDIR from its records, without reading them (find only)
+    #[structopt(long = "use-dicomdir")]
+    use_dicomdir: bool,
+
+    /// Split series into sub-volumes by these attributes: orientation, image-type, echo, temporal,
+    /// acquisition, dimensions (each sub-volume is indexed and saved as its own series)
+    #[structopt(long = "split-series", name = "criterion")]
+    split_by: Vec<work_dcm::SplitKey>,
+}
+
+impl ScanArgs {
+    fn to_options(&self) -> dir_scan::ScanOptions {
+        dir_scan::ScanOptions {
+            roots: self.paths_to_dir_for_search.clone(),
+            include: self.include.clone(),
+            exclude: self.exclude.clone(),
+            max_depth: self.max_depth,
+            min_size: self.min_size,
+            max_size: self.max_size,
+            follow_links: self.follow_links,
+            include_hidden: self.include_hidden,
+            scan_archives: self.scan_archives,
+            use_dicomdir: self.use_dicomdir,
+            split_by: self.split_by.clone(),
+        }
+    }
+}
+
+// Параметры выгрузки результата поиска (обычный комментарий, см. `ScanArgs`)
+#[derive(Debug, StructOpt)]
+struct ResultArgs {
+    /// Write the result (patients -> studies -> series -> files) to this file, `-` for stdout
+    #[structopt(short = "o", long = "output", parse(from_os_str), default_value = "result_dcm_finder.json")]
+    output: path::PathBuf,
+
+    /// Write the result as JSON Lines: one series per line with its study and patient
+    #[structopt(long = "json-lines")]
+    json_lines: bool,
+}
+
+impl ResultArgs {
+    fn to_options(&self) -> work_export::ResultOptions {
+        work_export::ResultOptions {
+            output: self.output.clone(),
+            json_lines: self.json_lines,
+        }
+    }
+}
+
+// Параметры миниатюр серий (обычный комментарий, см. `ScanArgs`)
+#[derive(Debug, StructOpt)]
+struct ThumbnailArgs {
+    /// Write a thumbnail of the middle instance of every series to this directory (`<study>/<series>.png` or `.jpg`)
+    /// and record its path in the index and the result
+    #[structopt(long = "thumbnails", name = "thumbnails_dir", parse(from_os_str))]
+    thumbnails: Option<path::PathBuf>,
+
+    /// Thumbnail image format: png or jpeg
+    #[structopt(long = "thumbnail-format", name = "image_format", default_value = "png")]
+    thumbnail_format: work_thumbnail::ThumbnailFormat,
+
+    /// Largest side of a thumbnail, pixels
+    #[structopt(long = "thumbnail-size", name = "pixels", default_value = "256")]
+    thumbnail_size: u32,
+}
+
+impl ThumbnailArgs {
+    fn to_options(&self) -> Option<work_thumbnail::ThumbnailOptions> {
+        self.thumbnails.as_ref().map(|save_in| work_thumbnail::ThumbnailOptions {
+            save_in: save_in.clone(),
+            format: self.thumbnail_format,
+            size: self.thumbnail_size,
+        })
+    }
+}
+
+/// Разбирает размер файла: число байт с необязательным суффиксом K, M или G (степени 1024)
+fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (number, multiplier) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 1024),
+        Some('M') => (&s[..s.len() - 1], 1024 * 1024),
+        Some('G') => (&s[..s.len() - 1], 1024 * 1024 * 1024),
+        _ => (s, 1),
+    };
+    number.trim().parse::<u64>().ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid size '{}'", s))
+}
+
+/// Проверяет glob-шаблон, чтобы ошибка в нем не приводила к обходу без фильтра
+fn parse_glob(s: &str) -> Result<String, String> {
+    globset::Glob::new(s)
+        .map(|_| s.to_string())
+        .map_err(|e| e.to_string())
+}
+
 #[derive(Debug, StructOpt)]
 enum Command {
     /// Search for DICOM files in directory
     Find {
-        /// Input the path to the directory to search for DICOM files in it
-        #[structopt(short = "p", long = "path", name = "find_in", parse(from_os_str))]
-        path_to_dir_for_search: path::PathBuf,
+        #[structopt(flatten)]
+        scan: ScanArgs,
+
+        /// Save the index to the SQLite database at this path (kept in memory if not set)
+        #[structopt(short = "d", long = "db", name = "db", parse(from_os_str))]
+        path_to_db: Option<path::PathBuf>,
+
+        /// Additional tags to index, as `level:tag` where level is patient, study, series or instance
+        /// and tag is a dictionary keyword (e.g. `series:Manufacturer`) or `(gggg,eeee)`
+        #[structopt(long = "index-tags", name = "level:tag")]
+        index_tags: Vec<work_dcm::IndexTag>,
+
+        #[structopt(flatten)]
+        thumbnails: ThumbnailArgs,
 
+        #[structopt(flatten)]
+        result: ResultArgs,
     },
     /// Depersonalize all found DICOM files in the directory and save them in the specified directory.
     Depersonalize {
-        /// Input the path to the directory to search for DICOM files in it
-        #[structopt(short = "p", long = "path", name = "find_in", parse(from_os_str))]
-        path_to_dir_for_search: path::PathBuf,
+        #[structopt(flatten)]
+        scan: ScanArgs,
 
         /// Input the path to the directory where the de-identified DICOM files will be saved
         #[structopt(short = "s", long = "save", name = "save_in", parse(from_os_str))]
         path_to_dir_for_save: path::PathBuf,
+
+        /// Save the files under DICOM/ with PS3.10 file IDs and write a DICOMDIR for the save directory
+        #[structopt(long = "dicomdir")]
+        dicomdir: bool,
+
+        /// Save the files in this transfer syntax: explicit-le, implicit-le, deflate, rle or jpeg-ls
+        /// (lossless); pixel data is decompressed and compressed again as needed
+        #[structopt(long = "transfer-syntax", name = "syntax")]
+        transfer_syntax: Option<work_transcode::OutputSyntax>,
+
+        /// Save the index to the SQLite database at this path (kept in memory if not set)
+        #[structopt(short = "d", long = "db", name = "db", parse(from_os_str))]
+        path_to_db: Option<path::PathBuf>,
+
+        /// Additional tags to index, as `level:tag` where level is patient, study, series or instance
+        /// and tag is a dictionary keyword (e.g. `series:Manufacturer`) or `(gggg,eeee)`
+        #[structopt(long = "index-tags", name = "level:tag")]
+        index_tags: Vec<work_dcm::IndexTag>,
+
+        #[structopt(flatten)]
+        thumbnails: ThumbnailArgs,
+
+        #[structopt(flatten)]
+        result: ResultArgs,
+    },
+    /// Receive DICOM instances over the network (C-ECHO and C-STORE) and add them to the index,
+    /// answer C-FIND and serve C-MOVE/C-GET from the index
+    Listen {
+        /// TCP port to listen on
+        #[structopt(long = "port", default_value = "11112")]
+        port: u16,
+
+        /// Address to listen on (e.g. 127.0.0.1 to accept only local connections)
+        #[structopt(long = "bind", name = "address", default_value = "0.0.0.0")]
+        bind: String,
+
+        /// AE title of this node (associations called with another AE title are rejected)
+        #[structopt(long = "aet", default_value = "DCMFINDER")]
+        aet: String,
+
+        /// Input the path to the directory where the received DICOM files will be saved
+        #[structopt(short = "s", long = "save", name = "save_in", parse(from_os_str))]
+        path_to_dir_for_save: path::PathBuf,
+
+        /// Path to the SQLite database the received instances are added to
+        #[structopt(short = "d", long = "db", name = "db", parse(from_os_str), default_value = "study.db")]
+        path_to_db: path::PathBuf,
+
+        /// De-identify the received instances before saving them
+        #[structopt(long = "depersonalize")]
+        depersonalize: bool,
+
+        /// Known C-MOVE destination as `AET@host:port` (can be repeated)
+        #[structopt(long = "move-destination", name = "destination")]
+        move_destinations: Vec<work_dimse::SendTarget>,
+
+        /// Largest accepted command or data set of a DIMSE message (bytes, or with K/M/G suffix);
+        /// the association is aborted when a message exceeds it
+        #[structopt(long = "max-message", name = "size", parse(try_from_str = parse_size), default_value = "1G")]
+        max_message: u64,
+    },
+    /// Send indexed DICOM instances (or all files of a directory) to a DICOM node with C-STORE
+    Send {
+        /// Destination as `AET@host:port`
+        #[structopt(long = "to", name = "AET@host:port")]
+        to: work_dimse::SendTarget,
+
+        /// AE title of this node
+        #[structopt(long = "aet", default_value = "DCMFINDER")]
+        aet: String,
+
+        /// Path to the SQLite database saved by `find` or `depersonalize` with `--db`
+        #[structopt(short = "d", long = "db", name = "db", parse(from_os_str), default_value = "study.db")]
+        path_to_db: path::PathBuf,
+
+        /// Send only the series matching these words (as in `search`)
+        #[structopt(short = "q", long = "query")]
+        query: Option<String>,
+
+        /// Send only these studies (Study Instance UID, can be repeated)
+        #[structopt(long = "study")]
+        study_uids: Vec<String>,
+
+        /// Send only these series (Series Instance UID, can be repeated)
+        #[structopt(long = "series")]
+        series_uids: Vec<String>,
+
+        /// Send all DICOM files in this directory (e.g. the output of `depersonalize`) instead of the index
+        #[structopt(long = "dir", parse(from_os_str), conflicts_with_all = &["query", "study-uids", "series-uids"])]
+        dir: Option<path::PathBuf>,
+
+        /// Number of associations used in parallel
+        #[structopt(short = "j", long = "jobs", default_value = "4")]
+        jobs: usize,
+
+        /// Number of retries for an instance that failed to be sent
+        #[structopt(long = "retries", default_value = "2")]
+        retries: usize,
+    },
+    /// Serve a saved index over DICOMweb: QIDO-RS search, WADO-RS retrieval of the indexed files
+    /// and STOW-RS upload
+    Serve {
+        /// HTTP port to listen on
+        #[structopt(long = "http", name = "port", default_value = "8080")]
+        port: u16,
+
+        /// Path to the SQLite database saved by `find`, `depersonalize` or `listen`
+        #[structopt(short = "d", long = "db", name = "db", parse(from_os_str), default_value = "study.db")]
+        path_to_db: path::PathBuf,
+
+        /// Number of threads handling requests
+        #[structopt(short = "j", long = "jobs", default_value = "4")]
+        jobs: usize,
+
+        /// Accept STOW-RS uploads: de-identify the instances and save them in this directory
+        #[structopt(short = "s", long = "save", name = "save_in", parse(from_os_str))]
+        path_to_dir_for_save: Option<path::PathBuf>,
+
+        /// De-identification of STOW-RS uploads: basic (as `depersonalize`) or off (save them as received)
+        #[structopt(long = "profile", default_value = "basic")]
+        profile: work_web::StowProfile,
+
+        /// Largest accepted STOW-RS request (bytes, or with K/M/G suffix); larger requests get 413
+        #[structopt(long = "max-upload", name = "size", parse(try_from_str = parse_size), default_value = "1G")]
+        max_upload: u64,
+    },
+    /// Export the index as a flat CSV or Parquet table, the search result as JSON or JSON Lines,
+    /// or the full header of indexed instances as DICOM JSON or Native DICOM Model XML (one file per instance)
+    Export {
+        /// Output format: dicom-json (PS3.18), dicom-xml (PS3.19), csv, parquet, json or jsonl
+        #[structopt(long = "format", default_value = "dicom-json")]
+        format: work_export::ExportFormat,
+
+        /// Table level for csv and parquet (one row per entity): patient, study, series or instance
+        #[structopt(long = "level", default_value = "series")]
+        level: work_dcm::Level,
+
+        /// File the table or result is written to (`-` for stdout with json and jsonl), or directory
+        /// the per-instance files are written to (as `<study>/<series>/<instance>.json|xml`)
+        #[structopt(short = "o", long = "output", parse(from_os_str))]
+        output: path::PathBuf,
+
+        /// Path to the SQLite database saved by `find` or `depersonalize` with `--db`
+        #[structopt(short = "d", long = "db", name = "db", parse(from_os_str), default_value = "study.db")]
+        path_to_db: path::PathBuf,
+
+        /// Export only the series matching these words (as in `search`)
+        #[structopt(short = "q", long = "query")]
+        query: Option<String>,
+
+        /// Export only these studies (Study Instance UID, can be repeated)
+        #[structopt(long = "study")]
+        study_uids: Vec<String>,
+
+        /// Export only these series (Series Instance UID, can be repeated)
+        #[structopt(long = "series")]
+        series_uids: Vec<String>,
+    },
+    /// Convert indexed series to volumes: slices stacked in spatial order with Rescale Slope/Intercept applied,
+    /// written as `<study>/<series>.<ext>` in each requested format with a JSON sidecar of de-identified series attributes
+    Convert {
+        /// Output formats: nifti, nrrd, raw (comma-separated or repeated; the volume is assembled once)
+        #[structopt(long = "to", name = "format", required = true, use_delimiter = true)]
+        to: Vec<work_volume::ConvertFormat>,
+
+        /// Directory the volumes are written to
+        #[structopt(short = "o", long = "output", parse(from_os_str))]
+        output: path::PathBuf,
+
+        /// Path to the SQLite database saved by `find` or `depersonalize` with `--db`
+        #[structopt(short = "d", long = "db", name = "db", parse(from_os_str), default_value = "study.db")]
+        path_to_db: path::PathBuf,
+
+        /// Convert only the series matching these words (as in `search`)
+        #[structopt(short = "q", long = "query")]
+        query: Option<String>,
+
+        /// Convert only these studies (Study Instance UID, can be repeated)
+        #[structopt(long = "study")]
+        study_uids: Vec<String>,
+
+        /// Convert only these series (Series Instance UID, can be repeated)
+        #[structopt(long = "series")]
+        series_uids: Vec<String>,
+    },
+    /// Validate indexed DICOM instances (or all files of a directory) against IOD module requirements
+    /// of common SOP classes, VR/VM rules and UID syntax
+    Validate {
+        /// Path to the SQLite database saved by `find` or `depersonalize` with `--db`
+        #[structopt(short = "d", long = "db", name = "db", parse(from_os_str), default_value = "study.db")]
+        path_to_db: path::PathBuf,
+
+        /// Validate only the series matching these words (as in `search`)
+        #[structopt(short = "q", long = "query")]
+        query: Option<String>,
+
+        /// Validate only these studies (Study Instance UID, can be repeated)
+        #[structopt(long = "study")]
+        study_uids: Vec<String>,
+
+        /// Validate only these series (Series Instance UID, can be repeated)
+        #[structopt(long = "series")]
+        series_uids: Vec<String>,
+
+        /// Validate all DICOM files in this directory instead of the index
+        #[structopt(long = "dir", parse(from_os_str), conflicts_with_all = &["query", "study-uids", "series-uids"])]
+        dir: Option<path::PathBuf>,
+
+        /// Also write the report (issues per file and summary counts) as JSON to this file
+        #[structopt(short = "o", long = "output", parse(from_os_str))]
+        output: Option<path::PathBuf>,
+    },
+    /// Report indexed instances stored more than once: groups of files with the same SOP Instance UID
+    /// or the same pixel content, with their paths and sizes
+    Duplicates {
+        /// Keys files are grouped by: uid (SOP Instance UID), content (hash of the decoded pixels and
+        /// their dimensions, independent of the rest of the header) or exact (both) (comma-separated or repeated)
+        #[structopt(long = "by", name = "key", default_value = "uid,content", use_delimiter = true)]
+        keys: Vec<work_duplicates::DuplicateKey>,
+
+        /// Path to the SQLite database saved by `find` or `depersonalize` with `--db`
+        #[structopt(short = "d", long = "db", name = "db", parse(from_os_str), default_value = "study.db")]
+        path_to_db: path::PathBuf,
+
+        /// Also write the duplicate groups as JSON to this file
+        #[structopt(short = "o", long = "output", parse(from_os_str))]
+        output: Option<path::PathBuf>,
+    },
+    /// Keep one copy of every duplicated instance and delete the others or replace them with hard links
+    /// (a dry run unless `--apply` is given)
+    Dedupe {
+        /// Key files are grouped by: uid, content or exact (see `duplicates`)
+        #[structopt(long = "by", name = "key", default_value = "exact")]
+        key: work_duplicates::DuplicateKey,
+
+        /// Copy to keep: oldest, newest (by modification time) or shortest-path
+        #[structopt(long = "keep", default_value = "oldest")]
+        keep: work_duplicates::Keep,
+
+        /// What to do with the other copies: delete, or link (replace with a hard link to the kept copy)
+        #[structopt(long = "action", default_value = "delete")]
+        action: work_duplicates::DedupeAction,
+
+        /// Change the files and the index; without it only the plan is printed
+        #[structopt(long = "apply")]
+        apply: bool,
+
+        /// Path to the SQLite database saved by `find` or `depersonalize` with `--db`
+        #[structopt(short = "d", long = "db", name = "db", parse(from_os_str), default_value = "study.db")]
+        path_to_db: path::PathBuf,
+    },
+    /// Full-text search over study and series descriptions in a saved index
+    Search {
+        /// Words to look for in Study/Series Description, Protocol Name and Body Part Examined
+        #[structopt(name = "query")]
+        query: String,
+
+        /// Path to the SQLite database saved by `find` or `depersonalize` with `--db`
+        #[structopt(short = "d", long = "db", name = "db", default_value = "study.db", parse(from_os_str))]
+        path_to_db: path::PathBuf,
+
+        /// Maximum number of series in the result
+        #[structopt(short = "l", long = "limit", default_value = "50")]
+        limit: usize,
     },
 }
 
+impl Command {
+    /// Команда пишет результат в stdout, поэтому сообщения о ходе работы выводятся в stderr
+    fn writes_to_stdout(&self) -> bool {
+        match self {
+            Command::Find { result, .. } | Command::Depersonalize { result, .. } => result.to_options().to_stdout(),
+            Command::Export { format, output, .. } => format.is_result() && output.as_os_str() == "-",
+            _ => false,
+        }
+    }
+}
+
 
 pub fn start_cli() {
     let args = Cli::from_args();
     let before = time::Instant::now();
     match &args.action {
-        Command::Find { path_to_dir_for_search } => {
-            dir_scan::scanning(&path_to_dir_for_search, true,None);
+        Command::Find { scan, thumbnails, result, path_to_db, index_tags } => {
+            dir_scan::scanning(&scan.to_options(), true,None, path_to_db.as_ref(), index_tags,
+                               thumbnails.to_options().as_ref(), &result.to_options());
+        }
+        Command::Depersonalize { scan, thumbnails, result, path_to_dir_for_save, dicomdir, transfer_syntax, path_to_db, index_tags } => {
+            let save = dir_scan::SaveOptions {
+                save_in: path_to_dir_for_save.clone(),
+                dicomdir: *dicomdir,
+                transfer_syntax: *transfer_syntax,
+                file_ids: Default::default(),
+            };
+            dir_scan::scanning(&scan.to_options(), false, Some(&save), path_to_db.as_ref(), index_tags,
+                               thumbnails.to_options().as_ref(), &result.to_options());
         }
-        Command::Depersonalize { path_to_dir_for_search, path_to_dir_for_save } => {
-            dir_scan::scanning(&path_to_dir_for_search, false, Some(&path_to_dir_for_save));
+        Command::Listen { port, bind, aet, path_to_dir_for_save, path_to_db, depersonalize, move_destinations,
+                          max_message } => {
+            let options = work_dimse::ListenOptions {
+                bind: bind.clone(),
+                port: *port,
+                aet: aet.clone(),
+                save_in: path_to_dir_for_save.clone(),
+                depersonalize: *depersonalize,
+                move_destinations: move_destinations.clone(),
+                max_message: *max_message,
+            };
+            work_dimse::listen(options, path_to_db);
+        }
+        Command::Send { to, aet, path_to_db, query, study_uids, series_uids, dir, jobs, retries } => {
+            let paths = match dir {
+                Some(dir) => dir_scan::find_dicom_files(dir),
+                None => work_db::select_paths(path_to_db, query.as_deref(), study_uids, series_uids)
+                    .unwrap_or_default(),
+            };
+            let options = work_dimse::SendOptions {
+                target: to.clone(),
+                calling_aet: aet.clone(),
+                jobs: *jobs,
+                retries: *retries,
+            };
+            work_dimse::send(&paths, &options);
+        }
+        Command::Serve { port, path_to_db, jobs, path_to_dir_for_save, profile, max_upload } => {
+            let options = work_web::ServeOptions {
+                port: *port,
+                db_path: path_to_db.clone(),
+                workers: *jobs,
+                save_in: path_to_dir_for_save.clone(),
+                profile: *profile,
+                max_upload: *max_upload,
+            };
+            work_web::serve(&options);
+        }
+        Command::Export { format, level, output, path_to_db, query, study_uids, series_uids } => {
+            if format.is_result() {
+                let options = work_export::ResultOptions {
+                    output: output.clone(),
+                    json_lines: *format == work_export::ExportFormat::JsonLines,
+                };
+                work_export::export_result(path_to_db, &options);
+            } else if !format.is_per_instance() {
+                work_export::export_table(path_to_db, *level, *format, output);
+            } else if let Some(paths) = work_db::select_paths(path_to_db, query.as_deref(), study_uids, series_uids) {
+                work_export::export_instances(&paths, *format, output);
+            }
+        }
+        Command::Convert { to, output, path_to_db, query, study_uids, series_uids } => {
+            if let Some(series) = work_db::select_series(path_to_db, query.as_deref(), study_uids, series_uids) {
+                work_volume::convert(&series, to, output);
+            }
+        }
+        Command::Validate { path_to_db, query, study_uids, series_uids, dir, output } => {
+            let paths = match dir {
+                Some(dir) => dir_scan::find_dicom_files(dir),
+                None => work_db::select_paths(path_to_db, query.as_deref(), study_uids, series_uids)
+                    .unwrap_or_default(),
+            };
+            work_validate::validate(&paths, output.as_deref());
+        }
+        Command::Duplicates { keys, path_to_db, output } => {
+            work_duplicates::report_duplicates(path_to_db, keys, output.as_deref());
+        }
+        Command::Dedupe { key, keep, action, apply, path_to_db } => {
+            let options = work_duplicates::DedupeOptions {
+                key: *key,
+                keep: *keep,
+                action: *action,
+                apply: *apply,
+            };
+            work_duplicates::dedupe(path_to_db, &options);
+        }
+        Command::Search { query, path_to_db, limit } => {
+            work_db::search(path_to_db, query, *limit);
         }
     };
-    println!("Elapsed time to complete: {:.2?}", before.elapsed());
+    if args.action.writes_to_stdout() {
+        eprintln!("Elapsed time to complete: {:.2?}", before.elapsed());
+    } else {
+        println!("Elapsed time to complete: {:.2?}", before.elapsed());
+    }
+
+}
+
 
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_are_parsed() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("2K"), Ok(2048));
+        assert_eq!(parse_size(" 3m "), Ok(3 * 1024 * 1024));
+        assert_eq!(parse_size("1G"), Ok(1024 * 1024 * 1024));
+        assert!(parse_size("").is_err());
+        assert!(parse_size("K").is_err());
+        assert!(parse_size("-1").is_err());
+        assert!(parse_size("1.5M").is_err());
+        assert!(parse_size("10T").is_err());
+        assert!(parse_size("99999999999G").is_err());
+    }
+
+    #[test]
+    fn invalid_glob_is_rejected() {
+        assert_eq!(parse_glob("**/*.dcm"), Ok("**/*.dcm".to_string()));
+        assert!(parse_glob("**/[a.dcm").is_err());
+        let args = |pattern: &str| Cli::from_iter_safe(["dcm_finder", "find", "-p", ".", "--exclude", pattern]);
+        assert!(args("**/tmp").is_ok());
+        assert!(args("**/{tmp").is_err());
+    }
+}
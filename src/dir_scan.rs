@@ -1,17 +1,74 @@
 extern crate indicatif;
 use std::fs;
+use std::io::Write;
 use std::path;
-use std::convert::TryInto;
-use std::sync::{Arc, Mutex};
+use std::collections::HashSet;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
 use walkdir::{DirEntry, WalkDir};
-use indicatif::ParallelProgressIterator;
-use rayon::iter::{ParallelIterator, IntoParallelRefIterator};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use serde::Serialize;
+use indicatif::{ProgressBar, ProgressStyle};
+use rayon::iter::{ParallelBridge, ParallelIterator};
 use crate::work_db;
 use crate::work_db::Dcm;
 use rand::Rng;
 
 use crate::work_dcm;
+use crate::work_archive;
+use crate::work_dicomdir;
+use crate::work_export;
+use crate::work_thumbnail;
+use crate::work_transcode;
+use dicom::object::DefaultDicomObject;
 
+/// Размер очередей между стадиями обработки (обход -> чтение -> индексация)
+const PIPELINE_BOUND: usize = 1024;
+/// Количество записей, добавляемых в индекс в одной транзакции
+const INDEX_BATCH: usize = 500;
+/// Пауза в приеме файлов по сети, после которой пересчитывается геометрия серий
+const GEOMETRY_IDLE: Duration = Duration::from_secs(2);
+
+
+/// Параметры обхода директорий. Сохраняются в метаданных сканирования (таблица `scans`)
+#[derive(Debug, Serialize)]
+pub struct ScanOptions {
+    /// Директории, в которых выполняется поиск
+    pub roots: Vec<path::PathBuf>,
+    /// Glob-шаблоны путей файлов, которые нужно читать (если пусто — все файлы)
+    pub include: Vec<String>,
+    /// Glob-шаблоны путей файлов и директорий, которые нужно пропустить
+    pub exclude: Vec<String>,
+    /// Максимальная глубина обхода относительно корня
+    pub max_depth: Option<usize>,
+    /// Минимальный размер файла в байтах
+    pub min_size: Option<u64>,
+    /// Максимальный размер файла в байтах
+    pub max_size: Option<u64>,
+    /// Переходить по символическим ссылкам (циклы обнаруживаются и пропускаются)
+    pub follow_links: bool,
+    /// Выполнять поиск в скрытых директориях
+    pub include_hidden: bool,
+    /// Читать DICOM файлы внутри архивов ZIP, TAR и TGZ
+    pub scan_archives: bool,
+    /// Индексировать файлы по найденным DICOMDIR, не читая сами файлы
+    pub use_dicomdir: bool,
+    /// Признаки, по которым серии делятся на подобъемы (если пусто — не делятся)
+    pub split_by: Vec<work_dcm::SplitKey>,
+}
+
+/// Параметры сохранения деперсонализированных копий файлов
+pub struct SaveOptions {
+    /// Директория, в которую сохраняются файлы
+    pub save_in: path::PathBuf,
+    /// Сохранять файлы с идентификаторами PS3.10 и записать DICOMDIR для выходного дерева
+    pub dicomdir: bool,
+    /// Синтаксис передачи сохраняемых файлов (если не задан — синтаксис исходного файла)
+    pub transfer_syntax: Option<work_transcode::OutputSyntax>,
+    /// Компоненты идентификаторов файлов, выданные при сохранении с DICOMDIR
+    pub file_ids: work_dicomdir::FileIds,
+}
 
 /// Проверяет, является ли директория скрытой
 fn is_hidden(entry: &DirEntry) -> bool {
@@ -21,54 +78,264 @@ fn is_hidden(entry: &DirEntry) -> bool {
         .unwrap_or(false)
 }
 
-/// Выполняет рекурсивный поиск всех файлов в директории
-/// Поиск не выполняется в скрытых директориях
-/// Возвращает вектор путей
-fn find_all_files(dir_path: &path::PathBuf) -> Vec<path::PathBuf> {
-    WalkDir::new(dir_path)
-        .into_iter()
-        .filter_entry(|e| !is_hidden(e))
-        .filter_map(|e| e.ok())
-        .map(|file| file.path().to_owned())
-        .collect()
+/// Собирает набор glob-шаблонов
+pub fn build_glob_set(patterns: &[String]) -> Result<GlobSet, globset::Error> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern)?);
+    }
+    builder.build()
+}
+
+/// Проверяет, подходит ли размер файла под ограничения
+fn is_size_allowed(entry: &DirEntry, options: &ScanOptions) -> bool {
+    if options.min_size.is_none() && options.max_size.is_none() {
+        return true;
+    }
+    match entry.metadata() {
+        Ok(metadata) => {
+            options.min_size.is_none_or(|min| metadata.len() >= min)
+                && options.max_size.is_none_or(|max| metadata.len() <= max)
+        }
+        Err(_) => false,
+    }
+}
+
+/// Идентификатор директории: устройство и inode, вне Unix - канонический путь
+#[cfg(unix)]
+type DirId = (u64, u64);
+#[cfg(not(unix))]
+type DirId = path::PathBuf;
+
+#[cfg(unix)]
+fn dir_id(entry: &DirEntry) -> Option<DirId> {
+    use std::os::unix::fs::MetadataExt;
+    entry.metadata().ok().map(|metadata| (metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn dir_id(entry: &DirEntry) -> Option<DirId> {
+    fs::canonicalize(entry.path()).ok()
+}
+
+/// Выполняет рекурсивный поиск всех файлов во всех корневых директориях
+/// с учетом фильтров из `options` и собранных из них шаблонов `include` и `exclude`.
+/// По умолчанию поиск не выполняется в скрытых директориях.
+/// Каждый найденный путь сразу передается в `on_file`, обход прекращается, если он вернул `false`.
+/// Возвращает количество найденных файлов
+fn find_all_files(options: &ScanOptions, include: &GlobSet, exclude: &GlobSet,
+                  mut on_file: impl FnMut(path::PathBuf) -> bool) -> usize {
+    // Одна и та же директория может быть найдена из нескольких корней или по символической ссылке:
+    // запоминаются только пройденные директории, поэтому расход памяти не зависит от числа файлов
+    let check_duplicates = options.follow_links || options.roots.len() > 1;
+    let mut seen_dirs: HashSet<DirId> = HashSet::new();
+    // Ссылка на файл пропускается, если он лежит в одном из корней и будет найден напрямую
+    let canonical_roots: Vec<path::PathBuf> = options.roots.iter()
+        .filter_map(|root| fs::canonicalize(root).ok())
+        .collect();
+    let mut linked_files: HashSet<path::PathBuf> = HashSet::new();
+    let mut count = 0;
+
+    for root in &options.roots {
+        let mut walker = WalkDir::new(root).follow_links(options.follow_links);
+        if let Some(max_depth) = options.max_depth {
+            walker = walker.max_depth(max_depth);
+        }
+        if options.use_dicomdir {
+            // DICOMDIR должен быть найден раньше файлов, на которые он ссылается
+            walker = walker.sort_by(|a, b| a.file_type().is_dir().cmp(&b.file_type().is_dir()));
+        }
+        let seen_dirs = &mut seen_dirs;
+        let entries = walker
+            .into_iter()
+            .filter_entry(|e| {
+                (e.depth() == 0 || options.include_hidden || !is_hidden(e))
+                    && !exclude.is_match(e.path())
+                    && !(check_duplicates && e.file_type().is_dir()
+                         && dir_id(e).is_some_and(|id| !seen_dirs.insert(id)))
+            })
+            .filter_map(|e| match e {
+                Ok(entry) => Some(entry),
+                Err(error) => {
+                    if let Some(ancestor) = error.loop_ancestor() {
+                        eprintln!("Skipping symbolic link loop to {}", ancestor.display());
+                    }
+                    None
+                }
+            })
+            .filter(|e| e.file_type().is_file())
+            .filter(|e| {
+                include.is_empty() || include.is_match(e.path())
+                    || (options.scan_archives && work_archive::is_archive(e.path()))
+            })
+            .filter(|e| is_size_allowed(e, options));
+        for entry in entries {
+            if entry.path_is_symlink() {
+                if let Ok(target) = fs::canonicalize(entry.path()) {
+                    if canonical_roots.iter().any(|root| target.starts_with(root))
+                        || !linked_files.insert(target) {
+                        continue;
+                    }
+                }
+            }
+            count += 1;
+            if !on_file(entry.into_path()) {
+                return count;
+            }
+        }
+    }
+    count
 }
 
-/// Выполняет рекурсивный поиск всех DICOM файлов в директории с
-/// параллельной итерацией при выполнении операции чтения
-/// Поиск не выполняется в скрытых директориях
-pub fn scanning(find_in: &path::PathBuf, only_find: bool, save_in: Option<&path::PathBuf>) {
-    let paths = find_all_files(find_in);
-    println!("Total files found: {}", paths.len());
+/// Возвращает пути всех DICOM файлов в директории (без индексации), кроме DICOMDIR
+pub fn find_dicom_files(root: &path::Path) -> Vec<String> {
+    let options = ScanOptions {
+        roots: vec![root.to_path_buf()],
+        include: Vec::new(),
+        exclude: Vec::new(),
+        max_depth: None,
+        min_size: None,
+        max_size: None,
+        follow_links: false,
+        include_hidden: false,
+        scan_archives: false,
+        use_dicomdir: false,
+        split_by: Vec::new(),
+    };
+    let mut paths = Vec::new();
+    find_all_files(&options, &GlobSet::empty(), &GlobSet::empty(), |path| {
+        if work_dcm::is_dicom_file(&path) && !work_dicomdir::is_dicomdir_name(&path) {
+            paths.push(path.to_str().unwrap_or_default().to_string());
+        }
+        true
+    });
+    paths.sort();
+    paths
+}
 
-    match work_db::Connection::create_dcm_tables(true) {
+/// Выполняет рекурсивный поиск всех DICOM файлов в директориях из `options`.
+/// Обработка выполняется конвейером: обход директорий -> проверка сигнатуры и чтение
+/// (параллельно) -> добавление в индекс. Стадии связаны очередями ограниченного размера,
+/// поэтому чтение начинается сразу, а расход памяти не зависит от количества файлов.
+/// Если указан `db_path`, индекс сохраняется в файл базы данных, иначе хранится в памяти.
+/// Теги из `index_tags` индексируются дополнительно к фиксированному набору атрибутов.
+/// При поиске с `use_dicomdir` файлы, перечисленные в найденном DICOMDIR, индексируются
+/// по его записям и не читаются.
+/// Если заданы `thumbnails`, после индексации создаются миниатюры серий.
+/// Результат выгружается согласно `result`.
+pub fn scanning(options: &ScanOptions, only_find: bool, save: Option<&SaveOptions>,
+                db_path: Option<&path::PathBuf>, index_tags: &[work_dcm::IndexTag],
+                thumbnails: Option<&work_thumbnail::ThumbnailOptions>, result: &work_export::ResultOptions) {
+    let (include, exclude) = match (build_glob_set(&options.include), build_glob_set(&options.exclude)) {
+        (Ok(include), Ok(exclude)) => (include, exclude),
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("Error in include/exclude pattern: {}", e);
+            return;
+        }
+    };
+    let conn = match db_path {
+        Some(db_path) => work_db::Connection::open_dcm_tables(db_path),
+        None => work_db::Connection::create_dcm_tables(true),
+    };
+    match conn {
         Ok(conn) => {
-            let contents = Arc::new(Mutex::new(conn));
-            paths.par_iter()
-                .progress_count(paths.len().try_into().unwrap_or_default())
-                .for_each(|path| {
-                    match work_dcm::read_dcm(&path.as_path()) {
-                        Ok(dcm_obj) => {
-                            let meta_dcm = work_dcm::MetaDcm::from(
-                                &dcm_obj,
-                                &path.as_path().to_str().unwrap_or_default()
-                            );
-                            add_dcm_in_contents(&contents, &meta_dcm);
-
-                            if !only_find && save_in.is_some() {
-                                let new_save_in = create_new_path(&meta_dcm, save_in.unwrap());
-                                let mut dcm_obj = dcm_obj;
-                                work_dcm::depersonalize_obj(&mut dcm_obj);
-                                work_dcm::save_dcm(&dcm_obj, &new_save_in).unwrap_or_else(|e| {
-                                    eprintln!("Error saving depersonalized dicom [path: {}]: \n {:?} ",
-                                              &path.as_path().to_str().unwrap_or_default(), e);
-                                });
-                            }
+            let options_json = serde_json::to_string(options).unwrap_or_default();
+            let scan_id = conn.insert_scan(&options_json).map_err(|e| {
+                eprintln!("Error insert scan metadata in db: {:?}", e);
+            }).ok();
+            let save = if only_find { None } else { save };
+            let use_dicomdir = options.use_dicomdir && save.is_none();
+            // Файлы архивов отбираются по тем же шаблонам, что и файлы директорий
+            let select_member = |path: &str| {
+                (include.is_empty() || include.is_match(path)) && !exclude.is_match(path)
+            };
+
+            let progress = ProgressBar::new(0);
+            progress.set_style(ProgressStyle::default_bar()
+                .template("[{elapsed_precise}] {bar:40} processed {pos} of {len} discovered ({per_sec})"));
+            let (path_tx, path_rx) = mpsc::sync_channel::<path::PathBuf>(PIPELINE_BOUND);
+            let (meta_tx, meta_rx) = mpsc::sync_channel::<work_dcm::MetaDcm>(PIPELINE_BOUND);
 
+            let (files_found, conn) = thread::scope(|scope| {
+                let progress_ref = &progress;
+                let (include, exclude) = (&include, &exclude);
+                let dicomdir_tx = meta_tx.clone();
+                let walker = scope.spawn(move || {
+                    let mut listed_in_dicomdir: HashSet<path::PathBuf> = HashSet::new();
+                    find_all_files(options, include, exclude, |path| {
+                        if use_dicomdir {
+                            if listed_in_dicomdir.remove(&path) {
+                                return true;
+                            }
+                            if work_dicomdir::is_dicomdir_name(&path) {
+                                match work_dicomdir::read_dicomdir(&path, index_tags) {
+                                    Ok(records) => {
+                                        progress_ref.inc_length(records.len() as u64);
+                                        progress_ref.inc(records.len() as u64);
+                                        for meta_dcm in records {
+                                            listed_in_dicomdir.insert(path::PathBuf::from(meta_dcm.get_path_ref()));
+                                            dicomdir_tx.send(meta_dcm).unwrap_or_default();
+                                        }
+                                        return true;
+                                    }
+                                    Err(e) => eprintln!("Error reading DICOMDIR [path: {}]: {:?}", path.display(), e),
+                                }
+                            }
                         }
-                        Err(_) => {}
-                    };
+                        progress_ref.inc_length(1);
+                        path_tx.send(path).is_ok()
+                    })
+                });
+                let indexer = scope.spawn(move || {
+                    index_dcm(&conn, meta_rx);
+                    conn
                 });
-            export_res_as_json(contents);
+                path_rx.into_iter()
+                    .par_bridge()
+                    .for_each_with(meta_tx, |meta_tx, path| {
+                        if options.scan_archives && work_archive::is_archive(&path) {
+                            read_archive(&path, &select_member, save, index_tags, &options.split_by, |meta_dcm| {
+                                meta_tx.send(meta_dcm).unwrap_or_default();
+                            });
+                        } else if let Some(meta_dcm) = read_and_save_dcm(&path, save, index_tags, &options.split_by) {
+                            meta_tx.send(meta_dcm).unwrap_or_default();
+                        }
+                        progress.inc(1);
+                    });
+                (walker.join().unwrap_or_default(), indexer.join())
+            });
+            progress.finish();
+            let mut status = result.status();
+            writeln!(status, "Total files found: {}", files_found).unwrap_or_default();
+            if let Some(save) = save.filter(|save| save.dicomdir) {
+                match work_dicomdir::write_dicomdir(&save.save_in) {
+                    Ok((added, skipped)) => {
+                        writeln!(status, "DICOMDIR written: {} files", added).unwrap_or_default();
+                        if skipped != 0 {
+                            writeln!(status, "Files not included in DICOMDIR (not PS3.10 file IDs): {}", skipped)
+                                .unwrap_or_default();
+                        }
+                    }
+                    Err(e) => eprintln!("Error writing DICOMDIR: {:?}", e),
+                }
+            }
+
+            match conn {
+                Ok(conn) => {
+                    if let Some(scan_id) = scan_id {
+                        conn.finish_scan(scan_id, files_found).unwrap_or_else(|e| {
+                            eprintln!("Error update scan metadata in db: {:?}", e);
+                        });
+                    }
+                    if let Some(thumbnails) = thumbnails {
+                        let (written, failed) = work_thumbnail::write_thumbnails(&conn, thumbnails);
+                        writeln!(status, "Thumbnails written: {} to {}, failed: {}",
+                                 written, thumbnails.save_in.display(), failed).unwrap_or_default();
+                    }
+                    work_export::write_result(&conn, result);
+                }
+                Err(_) => eprintln!("Error indexing found files"),
+            }
         }
         Err(error) => {
             eprintln!("Error create data base in memory: {:?}", error)
@@ -76,27 +343,138 @@ pub fn scanning(find_in: &path::PathBuf, only_find: bool, save_in: Option<&path:
     }
 }
 
-fn create_new_path(meta_dcm: &work_dcm::MetaDcm, save_in: &path::PathBuf) -> String {
-    let mut patient_id: &String = &"NoPatientID".to_string();
-    let mut study_uid: &String = &"NoStudyDateTime".to_string();
-    let mut series_uid: &String = &"NoSeriesUid".to_string();
-    if meta_dcm.get_patient_ref().patient_id.len() != 0 {
-        patient_id = &meta_dcm.get_patient_ref().patient_id
+/// Стадия чтения: проверяет сигнатуру файла и читает его.
+/// Если задан `save`, сохраняет деперсонализированную копию файла.
+/// Возвращает метаданные для индексации или `None`, если файл не является DICOM
+/// или является каталогом носителя (DICOMDIR)
+fn read_and_save_dcm(path: &path::Path, save: Option<&SaveOptions>, index_tags: &[work_dcm::IndexTag],
+                     split_by: &[work_dcm::SplitKey]) -> Option<work_dcm::MetaDcm> {
+    if !work_dcm::is_dicom_file(path) {
+        return None;
+    }
+    let dcm_obj = work_dcm::read_dcm(path).ok()?;
+    if work_dicomdir::is_dicomdir_obj(&dcm_obj) {
+        return None;
+    }
+    Some(describe_and_save_dcm(dcm_obj, path.to_str().unwrap_or_default(), save, index_tags, split_by))
+}
+
+/// Стадия чтения для архива: читает DICOM файлы архива в память, не распаковывая его на диск.
+/// Файлы индексируются под виртуальным путем `архив!/путь/в/архиве`; читаются только файлы,
+/// виртуальный путь которых прошел `select`
+fn read_archive(archive: &path::Path, select: &dyn Fn(&str) -> bool, save: Option<&SaveOptions>,
+                index_tags: &[work_dcm::IndexTag], split_by: &[work_dcm::SplitKey],
+                mut on_dcm: impl FnMut(work_dcm::MetaDcm)) {
+    let selected = |member: &str| select(&work_archive::virtual_path(archive, member));
+    work_archive::for_each_member(archive, selected, |member, data| {
+        if !work_dcm::is_dicom_bytes(&data) {
+            return;
+        }
+        match work_dcm::read_dcm_from_bytes(&data) {
+            Ok(dcm_obj) if !work_dicomdir::is_dicomdir_obj(&dcm_obj) => {
+                let path = work_archive::virtual_path(archive, member);
+                on_dcm(describe_and_save_dcm(dcm_obj, &path, save, index_tags, split_by));
+            }
+            _ => {}
+        }
+    }).unwrap_or_else(|e| {
+        eprintln!("Error reading archive [path: {}]: {:?}", archive.display(), e);
+    });
+}
+
+/// Извлекает метаданные для индексации и, если задан `save`,
+/// сохраняет деперсонализированную копию объекта (в синтаксисе передачи `save.transfer_syntax`).
+/// Файл подобъема разделенной серии сохраняется с UID подобъема в папку подобъема
+fn describe_and_save_dcm(dcm_obj: DefaultDicomObject, path: &str, save: Option<&SaveOptions>,
+                         index_tags: &[work_dcm::IndexTag], split_by: &[work_dcm::SplitKey]) -> work_dcm::MetaDcm {
+    let mut meta_dcm = work_dcm::MetaDcm::from_with_tags(&dcm_obj, path, index_tags);
+    meta_dcm.split_series(&dcm_obj, split_by);
+    if let Some(save) = save {
+        let new_save_in = if save.dicomdir {
+            create_media_path(&meta_dcm, save)
+        } else {
+            create_new_path(&meta_dcm, &save.save_in)
+        };
+        let mut dcm_obj = dcm_obj;
+        work_dcm::depersonalize_obj(&mut dcm_obj);
+        if meta_dcm.get_series_ref().source_series_uid.is_some() {
+            work_dcm::replace_series_uid(&mut dcm_obj, &meta_dcm.get_series_ref().series_uid);
+        }
+        if let Some(syntax) = save.transfer_syntax {
+            if let Err(e) = work_transcode::transcode(&mut dcm_obj, syntax) {
+                eprintln!("Error transcoding dicom to {}, not saved [path: {}]: {}", syntax.uid(), path, e);
+                return meta_dcm;
+            }
+        }
+        work_dcm::save_dcm(&mut dcm_obj, &new_save_in, None).unwrap_or_else(|e| {
+            eprintln!("Error saving depersonalized dicom [path: {}]: \n {:?} ", path, e);
+        });
     }
-    if meta_dcm.get_study_ref().study_uid.len() != 0 {
-        study_uid = &meta_dcm.get_study_ref().study_uid;
+    meta_dcm
+}
 
+/// Стадия индексации: единственный владелец соединения с базой данных.
+/// Записи добавляются в транзакциях по `INDEX_BATCH` штук
+fn index_dcm(conn: &work_db::Connection, meta_rx: mpsc::Receiver<work_dcm::MetaDcm>) {
+    let mut in_batch = 0;
+    let mut series_uids: HashSet<String> = HashSet::new();
+    conn.execute_batch("BEGIN;").unwrap_or_else(|e| {
+        eprintln!("Error begin transaction: {:?}", e);
+    });
+    for meta_dcm in meta_rx {
+        conn.insert_dcm(&meta_dcm);
+        series_uids.insert(meta_dcm.get_series_ref().series_uid.clone());
+        in_batch += 1;
+        if in_batch == INDEX_BATCH {
+            conn.execute_batch("COMMIT; BEGIN;").unwrap_or_else(|e| {
+                eprintln!("Error commit transaction: {:?}", e);
+            });
+            in_batch = 0;
+        }
     }
-    if meta_dcm.get_series_ref().series_uid.len() != 0 {
-        series_uid = &meta_dcm.get_series_ref().series_uid
+    update_geometry(conn, series_uids);
+    conn.execute_batch("COMMIT;").unwrap_or_else(|e| {
+        eprintln!("Error commit transaction: {:?}", e);
+    });
+}
+
+/// Стадия индексации для приема файлов по сети (`listen`, STOW-RS): файлы добавляются
+/// по мере поступления, геометрия измененных серий пересчитывается, когда прием затихает
+pub fn index_received(conn: &work_db::Connection, meta_rx: mpsc::Receiver<work_dcm::MetaDcm>) {
+    let mut series_uids: HashSet<String> = HashSet::new();
+    loop {
+        match meta_rx.recv_timeout(GEOMETRY_IDLE) {
+            Ok(meta_dcm) => {
+                conn.insert_dcm(&meta_dcm);
+                series_uids.insert(meta_dcm.get_series_ref().series_uid.clone());
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => update_geometry(conn, std::mem::take(&mut series_uids)),
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        }
     }
+    update_geometry(conn, series_uids);
+}
+
+/// Пересчитывает сводку геометрии серий (см. `work_geometry`)
+fn update_geometry(conn: &work_db::Connection, series_uids: HashSet<String>) {
+    for series_uid in series_uids {
+        if let Err(e) = conn.update_series_geometry(&series_uid) {
+            eprintln!("Error analysing geometry of series {}: {:?}", series_uid, e);
+        }
+    }
+}
+
+pub fn create_new_path(meta_dcm: &work_dcm::MetaDcm, save_in: &path::PathBuf) -> String {
+    let patient_id = path_component(&meta_dcm.get_patient_ref().patient_id, "NoPatientID");
+    let study_uid = path_component(&meta_dcm.get_study_ref().study_uid, "NoStudyDateTime");
+    let series_uid = path_component(&meta_dcm.get_series_ref().series_uid, "NoSeriesUid");
     let folder_for_mane_is_err = save_in
         .join(path::Path::new("out_data_dcm_finder"));
 
     let new_path = save_in
-        .join(path::Path::new(patient_id.trim()))
-        .join(path::Path::new(study_uid.trim()))
-        .join(path::Path::new(series_uid.trim()));
+        .join(patient_id)
+        .join(study_uid)
+        .join(series_uid);
     let new_path = match std::fs::create_dir_all(&new_path) {
         Ok(_) => { new_path }
         Err(_) => {
@@ -115,6 +493,45 @@ fn create_new_path(meta_dcm: &work_dcm::MetaDcm, save_in: &path::PathBuf) -> Str
     new_path.join(path::Path::new(file_name)).to_str().unwrap().trim().to_string()
 }
 
+/// Путь для сохранения файла, пригодный для записи на носитель с DICOMDIR:
+/// `DICOM/<пациент>/<исследование>/<серия>/<файл>`, где каждый компонент —
+/// идентификатор PS3.10 из 8 символов верхнего регистра
+fn create_media_path(meta_dcm: &work_dcm::MetaDcm, save: &SaveOptions) -> String {
+    let patient_dir = save.save_in.join(work_dicomdir::MEDIA_ROOT);
+    let patient_dir = patient_dir.join(save.file_ids.component(
+        &patient_dir, 'P', meta_dcm.get_patient_ref().patient_id.trim()));
+    let study_dir = patient_dir.join(save.file_ids.component(
+        &patient_dir, 'S', meta_dcm.get_study_ref().study_uid.trim()));
+    let series_dir = study_dir.join(save.file_ids.component(
+        &study_dir, 'R', meta_dcm.get_series_ref().series_uid.trim()));
+    std::fs::create_dir_all(&series_dir).unwrap_or_else(|e| {
+        eprintln!("Error creating directory [path: {}]: {:?}", series_dir.display(), e);
+    });
+    let mut rng = rand::thread_rng();
+    loop {
+        let file_path = series_dir.join(format!("I{:07X}", rng.gen::<u32>() & 0x0FFF_FFFF));
+        if !file_path.exists() {
+            return file_path.to_str().unwrap_or_default().to_string();
+        }
+    }
+}
+
+/// Имя каталога из значения атрибута, которое не выводит путь за пределы каталога сохранения:
+/// разделители, двоеточие (префикс диска), NUL и управляющие символы заменяются на `_`,
+/// а `.` и `..` — целиком. Пустое значение заменяется на `default`
+fn path_component(value: &str, default: &str) -> String {
+    let value = value.trim();
+    if value.is_empty() {
+        return default.to_string();
+    }
+    if value == "." || value == ".." {
+        return "_".repeat(value.len());
+    }
+    value.chars()
+        .map(|c| if matches!(c, '/' | '\\' | ':') || c.is_control() { '_' } else { c })
+        .collect()
+}
+
 /// Возвращает количество файлов в директории (без захода в подкаталоги)
 fn count_files_in_dir(path: &path::PathBuf) -> String {
     match  fs::read_dir(path) {
@@ -123,26 +540,279 @@ fn count_files_in_dir(path: &path::PathBuf) -> String {
     }
 }
 
-fn export_res_as_json(con: Arc<Mutex<work_db::Connection>>) {
-    match con.lock() {
-        Ok(c) => {
-            c.export_result().unwrap_or_else(|e| {
-                eprintln!("Error exporting result as json: {:?}", e);
-            });
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use dicom::core::{DataElement, PrimitiveValue, Tag, VR};
+    use dicom::object::{FileMetaTableBuilder, InMemDicomObject};
+
+    /// Содержимое файла КТ без Pixel Data: исследование 1.2.5, серия `series`, экземпляр `sop_instance_uid`
+    fn dicom_bytes(series: &str, sop_instance_uid: &str) -> Vec<u8> {
+        let mut obj = InMemDicomObject::new_empty();
+        obj.put(DataElement::new(Tag(0x0010, 0x0020), VR::LO, PrimitiveValue::from("P5")));
+        obj.put(DataElement::new(Tag(0x0020, 0x000D), VR::UI, PrimitiveValue::from("1.2.5")));
+        obj.put(DataElement::new(Tag(0x0020, 0x000E), VR::UI, PrimitiveValue::from(series)));
+        obj.put(DataElement::new(Tag(0x0008, 0x0016), VR::UI, PrimitiveValue::from("1.2.840.10008.5.1.4.1.1.2")));
+        obj.put(DataElement::new(Tag(0x0008, 0x0018), VR::UI, PrimitiveValue::from(sop_instance_uid)));
+        let obj = obj.with_meta(FileMetaTableBuilder::new()
+            .media_storage_sop_class_uid("1.2.840.10008.5.1.4.1.1.2")
+            .media_storage_sop_instance_uid(sop_instance_uid)
+            .transfer_syntax(work_dcm::EXPLICIT_VR_LITTLE_ENDIAN))
+            .unwrap();
+        let mut data = Vec::new();
+        obj.write_all(&mut data).unwrap();
+        data
+    }
+
+    fn test_dir(name: &str) -> path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("dcm_finder_test_{}_scan_{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn scan_options(root: &path::Path) -> ScanOptions {
+        ScanOptions {
+            roots: vec![root.to_path_buf()],
+            include: Vec::new(),
+            exclude: Vec::new(),
+            max_depth: None,
+            min_size: None,
+            max_size: None,
+            follow_links: false,
+            include_hidden: false,
+            scan_archives: false,
+            use_dicomdir: false,
+            split_by: Vec::new(),
         }
-        Err(e) => {
-            eprintln!("Error exporting result as json: {:?}", e);
+    }
+
+    /// Индексирует `options.roots` в базу `db_name` рядом с ними и возвращает пути экземпляров индекса
+    fn scan_paths(options: &ScanOptions, output: &path::Path, db_name: &str) -> Vec<String> {
+        let db_path = output.join(db_name);
+        let result = work_export::ResultOptions { output: output.join(format!("{}.json", db_name)), json_lines: false };
+        scanning(options, true, None, Some(&db_path), &[], None, &result);
+        let conn = work_db::Connection::open_dcm_tables(&db_path).unwrap();
+        conn.query_paths(work_dcm::Level::Instance, &[]).unwrap()
+    }
+
+    #[test]
+    fn archive_members_are_indexed_under_virtual_paths() {
+        let dir = test_dir("archives");
+        let data = dir.join("data");
+        fs::create_dir_all(&data).unwrap();
+        fs::write(data.join("plain.dcm"), dicom_bytes("1.2.5.1", "1.2.5.1.5")).unwrap();
+
+        let mut zip = zip::ZipWriter::new(fs::File::create(data.join("study.zip")).unwrap());
+        for (name, content) in [("IMG/1.dcm", dicom_bytes("1.2.5.1", "1.2.5.1.1")),
+                                ("IMG/2.dcm", dicom_bytes("1.2.5.1", "1.2.5.1.2")),
+                                ("notes.txt", b"not dicom".to_vec())] {
+            zip.start_file(name, zip::write::FileOptions::default()).unwrap();
+            zip.write_all(&content).unwrap();
         }
+        zip.finish().unwrap();
+
+        let gz = flate2::write::GzEncoder::new(fs::File::create(data.join("series.tar.gz")).unwrap(),
+                                               flate2::Compression::default());
+        let mut tar = tar::Builder::new(gz);
+        for (name, content) in [("a/3.dcm", dicom_bytes("1.2.5.2", "1.2.5.2.3")),
+                                ("skip/4.dcm", dicom_bytes("1.2.5.2", "1.2.5.2.4"))] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            tar.append_data(&mut header, name, content.as_slice()).unwrap();
+        }
+        tar.into_inner().unwrap().finish().unwrap();
+
+        let virtual_path = |archive: &str, member: &str| work_archive::virtual_path(&data.join(archive), member);
+        let mut options = scan_options(&data);
+        // Без --archives архивы не читаются
+        assert_eq!(scan_paths(&options, &dir, "plain.db"), [data.join("plain.dcm").display().to_string()]);
+
+        options.scan_archives = true;
+        let mut expected = vec![
+            data.join("plain.dcm").display().to_string(),
+            virtual_path("series.tar.gz", "a/3.dcm"),
+            virtual_path("series.tar.gz", "skip/4.dcm"),
+            virtual_path("study.zip", "IMG/1.dcm"),
+            virtual_path("study.zip", "IMG/2.dcm"),
+        ];
+        let mut paths = scan_paths(&options, &dir, "all.db");
+        paths.sort();
+        expected.sort();
+        assert_eq!(paths, expected);
+        let conn = work_db::Connection::open_dcm_tables(&dir.join("all.db")).unwrap();
+        let keys = [work_db::QueryKey { keyword: "SeriesInstanceUID".to_string(), value: "1.2.5.2".to_string() }];
+        assert_eq!(conn.query_paths(work_dcm::Level::Series, &keys).unwrap(),
+                   [virtual_path("series.tar.gz", "a/3.dcm"), virtual_path("series.tar.gz", "skip/4.dcm")]);
+        assert!(work_dcm::read_indexed_dcm(&virtual_path("study.zip", "IMG/2.dcm")).is_ok());
+
+        // Шаблоны проверяются на виртуальных путях файлов архивов
+        options.include = vec!["**/IMG/*.dcm".to_string(), "**/a/*".to_string()];
+        options.exclude = vec!["**/2.dcm".to_string()];
+        let mut paths = scan_paths(&options, &dir, "filtered.db");
+        paths.sort();
+        assert_eq!(paths, [virtual_path("series.tar.gz", "a/3.dcm"), virtual_path("study.zip", "IMG/1.dcm")]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Пути, найденные обходом `root` с фильтрами `options`, относительно `root`
+    fn found(options: &ScanOptions, root: &path::Path) -> Vec<String> {
+        let include = build_glob_set(&options.include).unwrap();
+        let exclude = build_glob_set(&options.exclude).unwrap();
+        let mut paths = Vec::new();
+        find_all_files(options, &include, &exclude, |path| {
+            paths.push(path.strip_prefix(root).unwrap().to_string_lossy().replace('\\', "/"));
+            true
+        });
+        paths.sort();
+        paths
     }
-}
 
-fn add_dcm_in_contents(con: &Arc<Mutex<work_db::Connection>>, meta_dcm: &work_dcm::MetaDcm) {
-    match con.lock() {
-        Ok(c) => {
-            c.insert_dcm(&meta_dcm);
+    #[test]
+    fn walk_filters_depth_size_hidden_and_globs() {
+        let dir = test_dir("filters");
+        for (name, size) in [("a.dcm", 10), ("big.dcm", 5000), ("notes.txt", 10), ("sub/b.dcm", 10),
+                             ("sub/tmp/c.dcm", 10), ("sub/deep/d.dcm", 10), (".hidden/e.dcm", 10)] {
+            let file = dir.join(name);
+            fs::create_dir_all(file.parent().unwrap()).unwrap();
+            fs::write(file, vec![0u8; size]).unwrap();
         }
-        Err(e) => {
-            eprintln!("Error insert meta dcm in db: {:?}", e);
+        let mut options = scan_options(&dir);
+        assert_eq!(found(&options, &dir),
+                   ["a.dcm", "big.dcm", "notes.txt", "sub/b.dcm", "sub/deep/d.dcm", "sub/tmp/c.dcm"]);
+
+        options.include_hidden = true;
+        assert!(found(&options, &dir).contains(&".hidden/e.dcm".to_string()));
+        options.include_hidden = false;
+
+        options.max_depth = Some(1);
+        assert_eq!(found(&options, &dir), ["a.dcm", "big.dcm", "notes.txt"]);
+        options.max_depth = Some(2);
+        assert_eq!(found(&options, &dir), ["a.dcm", "big.dcm", "notes.txt", "sub/b.dcm"]);
+        options.max_depth = None;
+
+        options.min_size = Some(100);
+        assert_eq!(found(&options, &dir), ["big.dcm"]);
+        options.min_size = None;
+        options.max_size = Some(100);
+        assert!(!found(&options, &dir).contains(&"big.dcm".to_string()));
+        options.max_size = None;
+
+        // Исключенная директория не обходится, включающие шаблоны отбирают файлы
+        options.include = vec!["**/*.dcm".to_string()];
+        options.exclude = vec!["**/tmp".to_string(), "**/big.*".to_string()];
+        assert_eq!(found(&options, &dir), ["a.dcm", "sub/b.dcm", "sub/deep/d.dcm"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn overlapping_roots_and_links_are_walked_once() {
+        let dir = test_dir("overlap");
+        for name in ["a.dcm", "sub/b.dcm", "sub/deep/c.dcm"] {
+            let file = dir.join(name);
+            fs::create_dir_all(file.parent().unwrap()).unwrap();
+            fs::write(file, b"data").unwrap();
         }
+        let outside = test_dir("overlap_outside");
+        fs::write(outside.join("d.dcm"), b"data").unwrap();
+        std::os::unix::fs::symlink(dir.join("sub"), dir.join("link_sub")).unwrap();
+        std::os::unix::fs::symlink(dir.join("a.dcm"), dir.join("link_a.dcm")).unwrap();
+        std::os::unix::fs::symlink(outside.join("d.dcm"), dir.join("link_d.dcm")).unwrap();
+        std::os::unix::fs::symlink(outside.join("d.dcm"), dir.join("sub/link_d.dcm")).unwrap();
+
+        // Без перехода по ссылкам ссылки пропускаются, вложенный корень не обходится повторно
+        let mut options = scan_options(&dir);
+        options.roots.push(dir.join("sub"));
+        assert_eq!(found(&options, &dir), ["a.dcm", "sub/b.dcm", "sub/deep/c.dcm"]);
+        options.roots.reverse();
+        assert_eq!(found(&options, &dir), ["a.dcm", "sub/b.dcm", "sub/deep/c.dcm"]);
+
+        // По ссылкам каждый файл находится один раз: директория и файлы внутри корней
+        // пропускаются, файл вне корней находится по первой ссылке
+        options.follow_links = true;
+        options.roots = vec![dir.clone()];
+        let paths = found(&options, &dir);
+        assert_eq!(paths.len(), 4);
+        assert!(paths.contains(&"a.dcm".to_string()) && paths.contains(&"sub/deep/c.dcm".to_string()));
+        assert_eq!(paths.iter().filter(|path| path.ends_with("link_d.dcm")).count(), 1);
+        assert_eq!(paths.iter().filter(|path| path.ends_with("b.dcm")).count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(&outside).unwrap();
+    }
+
+    #[test]
+    fn pipeline_indexes_dicom_files_across_batches() {
+        let dir = test_dir("pipeline");
+        let data = dir.join("data");
+        let count = INDEX_BATCH + 3;
+        let mut expected = Vec::new();
+        for i in 0..count {
+            let path = data.join(format!("s{}", i % 3)).join(format!("{}.dcm", i));
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, dicom_bytes(&format!("1.2.5.{}", i % 3), &format!("1.2.5.9.{}", i))).unwrap();
+            expected.push(path.display().to_string());
+        }
+        // Файлы без сигнатуры DICM в позиции 128 не индексируются
+        let mut wrong_offset = vec![0u8; 200];
+        wrong_offset[..4].copy_from_slice(b"DICM");
+        let not_dicom = [("short.dcm", b"DICM".to_vec()), ("empty.dcm", Vec::new()),
+                         ("wrong_offset.dcm", wrong_offset), ("text.txt", vec![b'x'; 300])];
+        for (name, content) in &not_dicom {
+            fs::write(data.join(name), content).unwrap();
+            assert!(!work_dcm::is_dicom_file(&data.join(name)));
+        }
+        assert!(work_dcm::is_dicom_file(path::Path::new(&expected[0])));
+        assert!(!work_dcm::is_dicom_file(&data.join("missing.dcm")));
+
+        let mut paths = scan_paths(&scan_options(&data), &dir, "pipeline.db");
+        paths.sort();
+        expected.sort();
+        assert_eq!(paths, expected);
+        let conn = work_db::Connection::open_dcm_tables(&dir.join("pipeline.db")).unwrap();
+        assert_eq!(conn.query_paths(work_dcm::Level::Series, &[]).unwrap().len(), count);
+        let keys = [work_db::QueryKey { keyword: "SeriesInstanceUID".to_string(), value: "1.2.5.1".to_string() }];
+        assert_eq!(conn.query_paths(work_dcm::Level::Series, &keys).unwrap().len(), (0..count).filter(|i| i % 3 == 1).count());
+        fs::remove_dir_all(&dir).unwrap();
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn rescan_with_split_series_moves_files_to_volumes() {
+        let dir = test_dir("split_rescan");
+        let data = dir.join("data");
+        fs::create_dir_all(&data).unwrap();
+        for echo in ["1", "2"] {
+            let mut obj = work_dcm::read_dcm_from_bytes(&dicom_bytes("1.2.5.7", &format!("1.2.5.7.{}", echo))).unwrap();
+            obj.put(DataElement::new(Tag(0x0018, 0x0086), VR::IS, PrimitiveValue::from(echo)));
+            obj.write_to_file(data.join(format!("{}.dcm", echo))).unwrap();
+        }
+        let mut options = scan_options(&data);
+        assert_eq!(scan_paths(&options, &dir, "split.db").len(), 2);
+        // Повторное сканирование той же базы с --split-series переносит файлы в подобъемы,
+        // исходная серия без файлов удаляется
+        options.split_by = vec![work_dcm::SplitKey::Echo];
+        assert_eq!(scan_paths(&options, &dir, "split.db").len(), 2);
+        let conn = work_db::Connection::open_dcm_tables(&dir.join("split.db")).unwrap();
+        let series = |sql: &str| -> Vec<String> {
+            let mut stmt = conn.prepare(sql).unwrap();
+            let rows = stmt.query_map([], |row| row.get(0)).unwrap();
+            rows.collect::<Result<Vec<String>, _>>().unwrap()
+        };
+        let volumes = series("SELECT series_uid FROM series WHERE source_series_uid = '1.2.5.7' ORDER BY series_uid;");
+        assert_eq!(volumes.len(), 2);
+        assert_eq!(series("SELECT series_uid FROM series ORDER BY series_uid;"), volumes);
+        assert_eq!(series("SELECT DISTINCT series_uid FROM paths ORDER BY series_uid;"), volumes);
+        assert!(series("SELECT series_uid FROM series_fts WHERE series_uid = '1.2.5.7';").is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_glob_is_an_error() {
+        assert!(build_glob_set(&["**/*.dcm".to_string()]).is_ok());
+        assert!(build_glob_set(&["**/[a.dcm".to_string()]).is_err());
+    }
+}
@@ -1,7 +1,33 @@
 mod cli;
 mod dir_scan;
+mod work_archive;
 mod work_dcm;
 mod work_db;
+mod work_dicomdir;
+mod work_dimse;
+mod work_duplicates;
+mod work_export;
+mod work_geometry;
+mod work_json;
+#[cfg(feature = "codecs")]
+mod work_j2k;
+#[cfg(feature = "codecs")]
+mod work_jpeg;
+#[cfg(feature = "codecs")]
+mod work_jpegls;
+mod work_nifti;
+mod work_nrrd;
+mod work_pixels;
+mod work_qr;
+mod work_raw;
+#[cfg(feature = "codecs")]
+mod work_rle;
+mod work_thumbnail;
+mod work_transcode;
+mod work_validate;
+mod work_volume;
+mod work_web;
+mod work_xml;
 
 use cli as dcm_finder_cli;
 
@@ -0,0 +1,119 @@
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path;
+use flate2::read::GzDecoder;
+use zip::ZipArchive;
+
+
+/// Разделитель пути к архиву и пути к файлу внутри архива: `bundle.zip!/IMG001`
+pub const MEMBER_SEPARATOR: &str = "!/";
+/// Наибольший объем памяти, выделяемый заранее под файл архива: размер из заголовка
+/// архива не проверен, буфер дорастает до фактического размера при чтении
+const MAX_PREALLOCATION: u64 = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ArchiveKind {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+/// Определяет тип архива по расширению файла
+fn archive_kind(path: &path::Path) -> Option<ArchiveKind> {
+    let name = path.file_name()?.to_str()?.to_lowercase();
+    if name.ends_with(".zip") {
+        Some(ArchiveKind::Zip)
+    } else if name.ends_with(".tar") {
+        Some(ArchiveKind::Tar)
+    } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        Some(ArchiveKind::TarGz)
+    } else {
+        None
+    }
+}
+
+/// Проверяет, является ли файл архивом ZIP, TAR или TGZ (по расширению)
+pub fn is_archive(path: &path::Path) -> bool {
+    archive_kind(path).is_some()
+}
+
+/// Возвращает виртуальный путь файла внутри архива, под которым он хранится в индексе
+pub fn virtual_path(archive: &path::Path, member: &str) -> String {
+    format!("{}{}{}", archive.display(), MEMBER_SEPARATOR, member)
+}
+
+/// Разделяет виртуальный путь на путь к архиву и путь файла внутри архива.
+/// Возвращает `None` для обычного пути
+pub fn split_virtual_path(path: &str) -> Option<(&path::Path, &str)> {
+    path.match_indices(MEMBER_SEPARATOR)
+        .map(|(index, _)| (path::Path::new(&path[..index]), &path[index + MEMBER_SEPARATOR.len()..]))
+        .find(|(archive, _)| is_archive(archive))
+}
+
+/// Читает в память один файл архива
+pub fn read_member(archive: &path::Path, member: &str) -> io::Result<Vec<u8>> {
+    if archive_kind(archive) == Some(ArchiveKind::Zip) {
+        let mut zip = ZipArchive::new(BufReader::new(File::open(archive)?))?;
+        let mut file = zip.by_name(member)?;
+        let mut data = Vec::with_capacity(preallocation(file.size()));
+        file.read_to_end(&mut data)?;
+        return Ok(data);
+    }
+    let mut found = None;
+    for_each_member(archive, |name| name == member, |_, data| {
+        if found.is_none() {
+            found = Some(data);
+        }
+    })?;
+    found.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} not found in archive", member)))
+}
+
+/// Перебирает файлы архива, передавая в `on_member` путь файла внутри архива и его содержимое.
+/// Читаются только файлы, путь которых прошел `select`.
+/// Файлы читаются в память по одному, архив на диск не распаковывается.
+pub fn for_each_member(archive: &path::Path, select: impl Fn(&str) -> bool,
+                       mut on_member: impl FnMut(&str, Vec<u8>)) -> io::Result<()> {
+    let kind = archive_kind(archive)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "unknown archive type"))?;
+    let file = BufReader::new(File::open(archive)?);
+    match kind {
+        ArchiveKind::Zip => {
+            let mut zip = ZipArchive::new(file)?;
+            for i in 0..zip.len() {
+                let mut member = zip.by_index(i)?;
+                if !member.is_file() || !select(member.name()) {
+                    continue;
+                }
+                let name = member.name().to_string();
+                let mut data = Vec::with_capacity(preallocation(member.size()));
+                member.read_to_end(&mut data)?;
+                on_member(&name, data);
+            }
+            Ok(())
+        }
+        ArchiveKind::Tar => for_each_tar_member(tar::Archive::new(file), select, on_member),
+        ArchiveKind::TarGz => for_each_tar_member(tar::Archive::new(GzDecoder::new(file)), select, on_member),
+    }
+}
+
+fn for_each_tar_member<R: Read>(mut archive: tar::Archive<R>, select: impl Fn(&str) -> bool,
+                                mut on_member: impl FnMut(&str, Vec<u8>)) -> io::Result<()> {
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let name = entry.path()?.to_string_lossy().to_string();
+        if !select(&name) {
+            continue;
+        }
+        let mut data = Vec::with_capacity(preallocation(entry.size()));
+        entry.read_to_end(&mut data)?;
+        on_member(&name, data);
+    }
+    Ok(())
+}
+
+fn preallocation(size: u64) -> usize {
+    size.min(MAX_PREALLOCATION) as usize
+}
@@ -1,57 +1,38 @@
 pub use rusqlite::{Connection, Result, Error};
 use rusqlite::NO_PARAMS;
+use rusqlite::OptionalExtension;
+use rusqlite::types::ValueRef;
 use crate::work_dcm;
-use std::collections::HashMap;
+use crate::work_duplicates;
+use crate::work_geometry;
+use std::collections::BTreeMap;
 use serde::{Deserialize, Serialize};
-use std::fs::File;
+use std::io;
 use std::io::Write;
+use std::path;
 
 
 
+/// Пациент в результате экспорта. Исследования пишутся потоково следом за полями пациента
 #[derive(Serialize, Deserialize,Debug)]
 pub struct Pa {
     pub patient_id: String,
     pub birth_date: String,
     pub sex: String,
     pub age: String,
-    pub studies: Vec<St>,
-}
-
-impl Pa {
-    pub fn count_studies(&self) -> usize {
-        self.studies.len()
-    }
-
-    pub fn count(&self) -> (usize, usize, usize) {
-        let len_studies = self.count_studies();
-        let mut len_series = 0;
-        let mut len_paths = 0;
-        for study in &self.studies {
-            let (tmp_len_series, tmp_len_paths) = study.count();
-            len_series = len_series + tmp_len_series;
-            len_paths = len_paths + tmp_len_paths;
-        };
-        (len_studies, len_series, len_paths)
-    }
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub extra: BTreeMap<String, String>,
 }
 
+/// Исследование в результате экспорта. Серии пишутся потоково следом за полями исследования
 #[derive(Serialize, Deserialize, Debug)]
 pub struct St {
     pub study_uid: String,
     pub study_date: String,
     pub study_time: String,
     pub description: String,
-    pub series: Vec<Se>,
-}
-
-impl St {
-    pub fn count_series(&self) -> usize {
-        self.series.len()
-    }
-
-    pub fn count(&self) -> (usize, usize){
-        (self.count_series(), self.series.iter().map(|series|{series.count_paths()}).sum())
-    }
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub extra: BTreeMap<String, String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -71,13 +52,78 @@ pub struct Se {
     pub exposuretime: String,
     pub rescaleintercept: String,
     pub description: String,
+    pub protocolname: String,
+    pub bodypartexamined: String,
+    /// Исходный Series Instance UID подобъема разделенной серии
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_series_uid: Option<String>,
+    /// Значения признаков, по которым выделен подобъем
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub volume_key: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub extra: BTreeMap<String, String>,
+    /// Сводка геометрии серии (см. `work_geometry::analyze`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub geometry: Option<work_geometry::SeriesGeometry>,
+    /// Путь к миниатюре среднего среза серии
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thumbnail: Option<String>,
     pub paths: Vec<String>,
+    /// Дополнительные теги уровня instance, ключ — путь к файлу
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub instances: BTreeMap<String, BTreeMap<String, String>>,
 }
 
-impl Se {
-    pub fn count_paths(&self) -> usize {
-        self.paths.len()
-    }
+/// Строка результата в формате JSON Lines: серия вместе с исследованием и пациентом
+#[derive(Serialize, Debug)]
+pub struct SeriesRecord<'a> {
+    pub patient: &'a Pa,
+    pub study: &'a St,
+    pub series: &'a Se,
+}
+
+/// Количество исследований, серий и файлов пациента, подсчитанное при экспорте
+#[derive(Debug, Default)]
+pub struct PatientCount {
+    pub patient_id: String,
+    pub studies: usize,
+    pub series: usize,
+    pub paths: usize,
+}
+
+/// Серия, выбранная для обработки, с путями ее файлов
+#[derive(Debug, Clone)]
+pub struct SelectedSeries {
+    pub study_uid: String,
+    pub series_uid: String,
+    pub paths: Vec<String>,
+}
+
+/// Метаданные одного сканирования: параметры обхода директорий и число найденных файлов
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ScanRecord {
+    pub scan_id: i64,
+    pub started_at: String,
+    pub options: serde_json::Value,
+    pub files_found: i64,
+}
+
+/// Серия, найденная полнотекстовым поиском, вместе с исследованием, к которому она относится
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SearchHit {
+    pub patient_id: String,
+    pub study_uid: String,
+    pub study_date: String,
+    pub study_description: String,
+    pub series_uid: String,
+    pub modality: String,
+    pub series_description: String,
+    pub protocolname: String,
+    pub bodypartexamined: String,
+    pub files: usize,
+    pub rank: f64,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub extra: BTreeMap<String, String>,
 }
 
 
@@ -85,29 +131,64 @@ impl Se {
 
 pub trait Dcm {
     fn create_dcm_tables(in_memory: bool) -> Result<Connection, Error>;
+    fn open_dcm_tables(db_path: &path::Path) -> Result<Connection, Error>;
+    fn init_dcm_tables(conn: Connection) -> Result<Connection, Error>;
     fn insert_dcm(&self, meta_dcm: &work_dcm::MetaDcm);
     fn insert_path(&self, path: &str) -> Result<(), Error>;
-    fn insert_path_with_uid(&self, path: &str, series_uid: &String) -> Result<(), Error>;
+    fn insert_path_with_uid(&self, path: &str, series_uid: &String,
+                            instance: &work_dcm::MetaInstance) -> Result<(), Error>;
+    fn insert_extra_tag(&self, entity_id: &str, extra: &work_dcm::MetaExtra) -> Result<(), Error>;
+    fn insert_scan(&self, options_json: &str) -> Result<i64, Error>;
+    fn finish_scan(&self, scan_id: i64, files_found: usize) -> Result<(), Error>;
+    fn get_scans(&self) -> Result<Vec<ScanRecord>, Error>;
 
     fn get_or_add_patient(&self, p: &work_dcm::MetaPatient) -> Result<String, Error>;
     fn get_or_add_study(&self, p: &work_dcm::MetaStudy, patient_id: &String) -> Result<String, Error>;
     fn get_or_add_series(&self, p: &work_dcm::MetaSeries, study_uid: &String) -> Result<String, Error>;
-    fn get_patients_as_struct(&self) -> Result<Vec<Pa>, Error>;
-    fn get_studies_as_struct(&self, patient_id: &String) -> Result<Vec<St>, Error>;
-    fn get_series_as_struct(&self, study_uid: &String) -> Result<Vec<Se>, Error>;
-    fn get_paths_as_vec(&self, series_uid: &String) -> Result<Vec<String>, Error>;
-    fn print_count(vec_patients: &Vec<Pa>);
-    fn export_result(&self) -> Result<()>;
+    fn get_extra_tags(&self, level: work_dcm::Level, entity_id: &str) -> Result<BTreeMap<String, String>, Error>;
+    fn get_instances_extra_tags(&self, series_uid: &str) -> Result<BTreeMap<String, BTreeMap<String, String>>, Error>;
+    fn update_series_geometry(&self, series_uid: &str) -> Result<work_geometry::SeriesGeometry, Error>;
+    fn get_series_geometry(&self, series_uid: &str) -> Result<Option<work_geometry::SeriesGeometry>, Error>;
+    fn get_middle_instance(&self, series_uid: &str) -> Result<Option<String>, Error>;
+    fn set_series_thumbnail(&self, series_uid: &str, thumbnail: &str) -> Result<(), Error>;
+    fn get_unhashed_paths(&self) -> Result<Vec<String>, Error>;
+    fn set_content_hash(&self, path: &str, content_hash: &str) -> Result<(), Error>;
+    fn get_duplicate_paths(&self, key: work_duplicates::DuplicateKey) -> Result<Vec<(String, String, String, String)>, Error>;
+    fn remove_path(&self, path: &str) -> Result<Option<String>, Error>;
+    fn remove_empty_series(&self, series_uid: &str) -> Result<bool, Error>;
+    fn search_series(&self, query: &str, limit: usize) -> Result<Vec<SearchHit>, Error>;
+    fn select_paths(&self, query: Option<&str>, study_uids: &[String], series_uids: &[String]) -> Result<Vec<String>, Error>;
+    fn select_series(&self, query: Option<&str>, study_uids: &[String], series_uids: &[String]) -> Result<Vec<SelectedSeries>, Error>;
+    fn query_entities(&self, level: work_dcm::Level, keys: &[QueryKey], limit: Option<usize>, offset: usize)
+        -> Result<Vec<BTreeMap<String, String>>, Error>;
+    fn query_paths(&self, level: work_dcm::Level, keys: &[QueryKey]) -> Result<Vec<String>, Error>;
+    fn table_columns(&self, level: work_dcm::Level) -> Result<Vec<TableColumn>, Error>;
+    fn for_each_table_row(&self, level: work_dcm::Level, columns: &[TableColumn],
+                          on_row: &mut dyn FnMut(TableRow) -> io::Result<()>) -> std::result::Result<(), Box<dyn std::error::Error>>;
+    fn print_count(counts: &[PatientCount], out: &mut dyn Write) -> io::Result<()>;
+    fn print_search_hits(hits: &[SearchHit]);
+    fn export_result(&self, writer: &mut dyn Write, json_lines: bool)
+        -> std::result::Result<Vec<PatientCount>, Box<dyn std::error::Error>>;
 }
 
 impl Dcm for Connection {
     /// Создает таблицы в sqlite
     fn create_dcm_tables(in_memory: bool) -> Result<Connection, Error> {
-        let conn = if in_memory {
-            Connection::open_in_memory()?
+        if in_memory {
+            Connection::init_dcm_tables(Connection::open_in_memory()?)
         } else {
-            Connection::open("study.db")?
-        };
+            Connection::open_dcm_tables(path::Path::new("study.db"))
+        }
+    }
+
+    /// Открывает (или создает) базу данных в файле и создает в ней таблицы
+    fn open_dcm_tables(db_path: &path::Path) -> Result<Connection, Error> {
+        let conn = Connection::open(db_path)?;
+        conn.execute_batch("PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL;")?;
+        Connection::init_dcm_tables(conn)
+    }
+
+    fn init_dcm_tables(conn: Connection) -> Result<Connection, Error> {
         // Для использования даты необходимо соблюдать формат YYYY-MM-DD HH:MM:SS.SSS
         conn.execute(
             "CREATE TABLE IF NOT EXISTS patients (
@@ -150,6 +231,11 @@ impl Dcm for Connection {
                 exposuretime TEXT DEFAULT NULL,
                 rescaleintercept TEXT DEFAULT NULL,
                 description TEXT DEFAULT NULL,
+                protocolname TEXT DEFAULT NULL,
+                bodypartexamined TEXT DEFAULT NULL,
+                source_series_uid TEXT DEFAULT NULL,
+                volume_key TEXT DEFAULT NULL,
+                thumbnail TEXT DEFAULT NULL,
 
                 study_uid TEXT NOT NULL,
                 FOREIGN KEY (study_uid)
@@ -162,6 +248,15 @@ impl Dcm for Connection {
         conn.execute(
             "CREATE TABLE IF NOT EXISTS paths (
                 path TEXT NOT NULL PRIMARY KEY,
+                sop_instance_uid TEXT DEFAULT NULL,
+                sop_class_uid TEXT DEFAULT NULL,
+                instance_number TEXT DEFAULT NULL,
+                image_position_patient TEXT DEFAULT NULL,
+                image_orientation_patient TEXT DEFAULT NULL,
+                pixel_spacing TEXT DEFAULT NULL,
+                image_rows TEXT DEFAULT NULL,
+                image_columns TEXT DEFAULT NULL,
+                content_hash TEXT DEFAULT NULL,
 
                 series_uid TEXT NOT NULL DEFAULT 'UIDNotSet',
                 FOREIGN KEY (series_uid)
@@ -171,6 +266,93 @@ impl Dcm for Connection {
         ",
             NO_PARAMS,
         )?;
+        // Индексы, созданные до появления подобъемов, миниатюр, атрибутов instance и хешей содержимого,
+        // дополняются пустыми столбцами
+        add_missing_columns(&conn, "series", &["source_series_uid", "volume_key", "thumbnail"])?;
+        add_missing_columns(&conn, "paths",
+                            &["sop_instance_uid", "sop_class_uid", "instance_number",
+                                "image_position_patient", "image_orientation_patient", "pixel_spacing",
+                                "image_rows", "image_columns", "content_hash"])?;
+        conn.execute_batch(
+            "CREATE INDEX IF NOT EXISTS paths_series_uid ON paths (series_uid);
+            CREATE INDEX IF NOT EXISTS paths_sop_instance_uid ON paths (sop_instance_uid);
+            CREATE INDEX IF NOT EXISTS paths_content_hash ON paths (content_hash);
+        ",
+        )?;
+        // Сводка геометрии серий, пересчитывается после добавления файлов серии.
+        // issues — проблемы через запятую
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS series_geometry (
+                series_uid TEXT NOT NULL PRIMARY KEY,
+                slices INTEGER NOT NULL,
+                slice_spacing REAL DEFAULT NULL,
+                voxel_size_x REAL DEFAULT NULL,
+                voxel_size_y REAL DEFAULT NULL,
+                voxel_size_z REAL DEFAULT NULL,
+                extent_x REAL DEFAULT NULL,
+                extent_y REAL DEFAULT NULL,
+                extent_z REAL DEFAULT NULL,
+                expected_slices INTEGER NOT NULL,
+                missing_slices INTEGER NOT NULL,
+                duplicate_positions INTEGER NOT NULL,
+                completeness REAL NOT NULL,
+                gantry_tilt REAL DEFAULT NULL,
+                issues TEXT NOT NULL DEFAULT '',
+                FOREIGN KEY (series_uid)
+                REFERENCES series (series_uid)
+                ON UPDATE CASCADE
+            );
+        ",
+            [],
+        )?;
+        // Метаданные сканирований, параметры обхода хранятся в виде JSON
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS scans (
+                scan_id INTEGER PRIMARY KEY AUTOINCREMENT,
+                started_at TEXT NOT NULL DEFAULT (datetime('now')),
+                options TEXT NOT NULL,
+                files_found INTEGER DEFAULT NULL
+            );
+        ",
+            [],
+        )?;
+        // Дополнительные теги, заданные пользователем (--index-tags), в виде EAV.
+        // entity_id — идентификатор сущности соответствующего уровня (для instance — путь к файлу)
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS extra_tags (
+                level TEXT NOT NULL,
+                entity_id TEXT NOT NULL,
+                tag TEXT NOT NULL,
+                keyword TEXT NOT NULL,
+                value TEXT DEFAULT NULL,
+                PRIMARY KEY (level, entity_id, tag)
+            );
+        ",
+            [],
+        )?;
+        // Полнотекстовый индекс по описательным полям исследования и серии.
+        // Заполняется триггером при добавлении новой серии, значения "Unknown" не индексируются.
+        conn.execute_batch(
+            "CREATE VIRTUAL TABLE IF NOT EXISTS series_fts USING fts5(
+                series_uid UNINDEXED,
+                study_description,
+                series_description,
+                protocol_name,
+                body_part_examined
+            );
+            CREATE TRIGGER IF NOT EXISTS series_fts_insert AFTER INSERT ON series
+            BEGIN
+                INSERT INTO series_fts (series_uid, study_description, series_description,
+                                        protocol_name, body_part_examined)
+                SELECT new.series_uid,
+                       NULLIF(study.description, 'Unknown'),
+                       NULLIF(new.description, 'Unknown'),
+                       NULLIF(new.protocolname, 'Unknown'),
+                       NULLIF(new.bodypartexamined, 'Unknown')
+                FROM study WHERE study.study_uid = new.study_uid;
+            END;
+        ",
+        )?;
 
         Ok(conn)
     }
@@ -182,14 +364,78 @@ impl Dcm for Connection {
         )?;
         Ok(())
     }
-    fn insert_path_with_uid(&self, path: &str, series_uid: &String) -> Result<(), Error> {
+    /// Добавляет файл в серию. Файл, уже бывший в индексе (например, при повторном сканировании
+    /// с `--split-series`), переносится в серию `series_uid`, а прежняя серия без файлов удаляется
+    fn insert_path_with_uid(&self, path: &str, series_uid: &String,
+                            instance: &work_dcm::MetaInstance) -> Result<(), Error> {
+        let previous_series: Option<String> = self.query_row(
+            "SELECT series_uid FROM paths WHERE path = (?1);", [path], |row| row.get(0)).optional()?.flatten();
+        self.execute(
+            "INSERT INTO `paths` (path, series_uid, sop_instance_uid, sop_class_uid, instance_number, \
+             image_position_patient, image_orientation_patient, pixel_spacing, image_rows, image_columns) \
+             VALUES(?1,?2,?3,?4,?5,?6,?7,?8,?9,?10) \
+             ON CONFLICT (path) DO UPDATE SET series_uid = excluded.series_uid, \
+             sop_instance_uid = excluded.sop_instance_uid, \
+             sop_class_uid = excluded.sop_class_uid, instance_number = excluded.instance_number, \
+             image_position_patient = excluded.image_position_patient, \
+             image_orientation_patient = excluded.image_orientation_patient, \
+             pixel_spacing = excluded.pixel_spacing, image_rows = excluded.image_rows, \
+             image_columns = excluded.image_columns, content_hash = NULL;",
+            [path, series_uid, &instance.sop_instance_uid, &instance.sop_class_uid, &instance.instance_number,
+                &instance.image_position_patient, &instance.image_orientation_patient, &instance.pixel_spacing,
+                &instance.rows, &instance.columns],
+        )?;
+        if let Some(previous_series) = previous_series.filter(|previous| previous != series_uid) {
+            self.remove_empty_series(&previous_series)?;
+        }
+        Ok(())
+    }
+
+    fn insert_extra_tag(&self, entity_id: &str, extra: &work_dcm::MetaExtra) -> Result<(), Error> {
+        self.execute(
+            "INSERT OR IGNORE INTO `extra_tags` (level, entity_id, tag, keyword, value) \
+             VALUES(?1,?2,?3,?4,?5);",
+            [extra.level.as_str(), entity_id, &extra.tag, &extra.keyword, &extra.value],
+        )?;
+        Ok(())
+    }
+
+    /// Сохраняет параметры сканирования, возвращает идентификатор сканирования
+    fn insert_scan(&self, options_json: &str) -> Result<i64, Error> {
+        self.execute(
+            "INSERT INTO `scans` (options) VALUES(?1);",
+            [options_json],
+        )?;
+        Ok(self.last_insert_rowid())
+    }
+
+    /// Сохраняет количество найденных файлов по окончании сканирования
+    fn finish_scan(&self, scan_id: i64, files_found: usize) -> Result<(), Error> {
         self.execute(
-            "INSERT OR IGNORE INTO `paths` (path, series_uid) VALUES(?1,?2);",
-            &[path, series_uid],
+            "UPDATE `scans` SET files_found = (?1) WHERE scan_id = (?2);",
+            [files_found as i64, scan_id],
         )?;
         Ok(())
     }
 
+    fn get_scans(&self) -> Result<Vec<ScanRecord>, Error> {
+        let mut stmt = self.prepare(
+            "SELECT scan_id, started_at, options, files_found FROM scans ORDER BY scan_id;")?;
+        let mut rows = stmt.query([])?;
+        let mut scans: Vec<ScanRecord> = Vec::new();
+        while let Some(row) = rows.next()? {
+            let options: String = row.get(2)?;
+            scans.push(
+                ScanRecord {
+                    scan_id: row.get(0)?,
+                    started_at: row.get(1)?,
+                    options: serde_json::from_str(&options).unwrap_or_default(),
+                    files_found: row.get::<_, Option<i64>>(3)?.unwrap_or_default(),
+                });
+        }
+        Ok(scans)
+    }
+
     fn insert_dcm(&self, meta_dcm: &work_dcm::MetaDcm) {
         if !match self.get_or_add_patient(&meta_dcm.get_patient_ref()) {
             Ok(patient_id) => {
@@ -197,7 +443,19 @@ impl Dcm for Connection {
                     Ok(study_uid) => {
                         match self.get_or_add_series(&meta_dcm.get_series_ref(), &study_uid) {
                             Ok(series_uid) => {
-                                if self.insert_path_with_uid(meta_dcm.get_path_ref(), &series_uid)
+                                for extra in meta_dcm.get_extra_ref() {
+                                    let entity_id = match extra.level {
+                                        work_dcm::Level::Patient => patient_id.as_str(),
+                                        work_dcm::Level::Study => study_uid.as_str(),
+                                        work_dcm::Level::Series => series_uid.as_str(),
+                                        work_dcm::Level::Instance => meta_dcm.get_path_ref(),
+                                    };
+                                    self.insert_extra_tag(entity_id, extra).unwrap_or_else(|e| {
+                                        eprintln!("Error insert extra tag {} in db: {:?}", extra.tag, e);
+                                    });
+                                }
+                                if self.insert_path_with_uid(meta_dcm.get_path_ref(), &series_uid,
+                                                             meta_dcm.get_instance_ref())
                                     .is_ok() {
                                     true
                                 } else {
@@ -218,12 +476,16 @@ impl Dcm for Connection {
 
     fn get_or_add_series(&self, p: &work_dcm::MetaSeries, study_uid: &String) -> Result<String, Error> {
         self.execute(
-            "INSERT OR IGNORE INTO `series` \
-             VALUES(?1,?2,?3,?4,?5,?6,?7,?8,?9,?10,?11,?12,?13,?14,?15,?16);",
-            &[&p.series_uid, &p.modality, &p.instancenumber, &p.imagepositionpatient,
+            "INSERT OR IGNORE INTO `series` (series_uid, modality, instancenumber, imagepositionpatient, \
+             imageorientationpatient, pixelspacing, numberofframes, xraytubecurrent, kvp, filtertype, \
+             rows, columns, exposuretime, rescaleintercept, description, protocolname, bodypartexamined, \
+             source_series_uid, volume_key, study_uid) \
+             VALUES(?1,?2,?3,?4,?5,?6,?7,?8,?9,?10,?11,?12,?13,?14,?15,?16,?17,?18,?19,?20);",
+            rusqlite::params![&p.series_uid, &p.modality, &p.instancenumber, &p.imagepositionpatient,
                 &p.imageorientationpatient, &p.pixelspacing, &p.numberofframes,
                 &p.xraytubecurrent, &p.kvp, &p.filtertype, &p.rows, &p.columns,
-                &p.exposuretime, &p.rescaleintercept, &p.description, &study_uid],
+                &p.exposuretime, &p.rescaleintercept, &p.description,
+                &p.protocolname, &p.bodypartexamined, &p.source_series_uid, &p.volume_key, &study_uid],
         )?;
         let result = self.query_row(
             "SELECT series_uid FROM series WHERE series_uid = (?1);",
@@ -261,116 +523,1297 @@ impl Dcm for Connection {
         Ok(result)
     }
 
-    fn get_studies_as_struct(&self, patient_id: &String) -> Result<Vec<St>, Error> {
-        let mut stmt = self.prepare("SELECT * FROM study WHERE patient_id = (?1);")?;
-        let mut rows = stmt.query(&[&patient_id])?;
-        let mut studies: Vec<St> = Vec::new();
+    /// Возвращает дополнительные теги сущности в виде `ключевое слово -> значение`
+    fn get_extra_tags(&self, level: work_dcm::Level, entity_id: &str) -> Result<BTreeMap<String, String>, Error> {
+        let mut stmt = self.prepare(
+            "SELECT keyword, value FROM extra_tags WHERE level = (?1) AND entity_id = (?2);")?;
+        let mut rows = stmt.query([level.as_str(), entity_id])?;
+        let mut extra: BTreeMap<String, String> = BTreeMap::new();
         while let Some(row) = rows.next()? {
-            let study_uid = row.get(0)?;
-            studies.push(
-                St {
-                    study_uid: row.get(0)?,
-                    study_date: row.get(1)?,
-                    study_time: row.get(2)?,
-                    description: row.get(3)?,
-                    series: self.get_series_as_struct(&study_uid)?,
-                });
+            extra.insert(row.get(0)?, row.get(1)?);
         }
-        Ok(studies)
+        Ok(extra)
     }
 
-    fn get_patients_as_struct(&self) -> Result<Vec<Pa>, Error> {
-        let mut stmt = self.prepare("SELECT * FROM patients")?;
-        let mut rows = stmt.query(NO_PARAMS)?;
-        let mut items: Vec<String> = Vec::new();
-        let mut patients: Vec<Pa> = Vec::new();
+    /// Возвращает дополнительные теги уровня instance для всех файлов серии
+    fn get_instances_extra_tags(&self, series_uid: &str) -> Result<BTreeMap<String, BTreeMap<String, String>>, Error> {
+        let mut stmt = self.prepare(
+            "SELECT extra_tags.entity_id, extra_tags.keyword, extra_tags.value FROM extra_tags
+             JOIN paths ON paths.path = extra_tags.entity_id
+             WHERE extra_tags.level = 'instance' AND paths.series_uid = (?1);")?;
+        let mut rows = stmt.query([series_uid])?;
+        let mut instances: BTreeMap<String, BTreeMap<String, String>> = BTreeMap::new();
         while let Some(row) = rows.next()? {
-            items.push(row.get(0)?);
-            let patient_id = row.get(0)?;
-            patients.push(
-                Pa {
-                    patient_id: row.get(0)?,
-                    birth_date: row.get(1)?,
-                    sex: row.get(2)?,
-                    age: row.get(3)?,
-                    studies: self.get_studies_as_struct(&patient_id)?,
-                }
-            );
+            instances.entry(row.get(0)?).or_default().insert(row.get(1)?, row.get(2)?);
         }
-        Ok(patients)
+        Ok(instances)
+    }
+
+    /// Анализирует геометрию серии по атрибутам ее файлов в индексе и сохраняет сводку
+    fn update_series_geometry(&self, series_uid: &str) -> Result<work_geometry::SeriesGeometry, Error> {
+        let mut stmt = self.prepare(
+            "SELECT image_position_patient, image_orientation_patient, pixel_spacing, image_rows, image_columns
+             FROM paths WHERE series_uid = (?1);")?;
+        let slices = stmt.query_map([series_uid], |row| {
+            let text = |i: usize| -> Result<String, Error> { Ok(row.get::<_, Option<String>>(i)?.unwrap_or_default()) };
+            Ok(work_geometry::SliceGeometry::parse(&text(0)?, &text(1)?, &text(2)?, &text(3)?, &text(4)?))
+        })?.collect::<Result<Vec<work_geometry::SliceGeometry>, Error>>()?;
+        let geometry = work_geometry::analyze(&slices);
+        let voxel_size = geometry.voxel_size.map(|size| size.map(Some)).unwrap_or_default();
+        let extent = geometry.extent.map(|extent| extent.map(Some)).unwrap_or_default();
+        self.execute(
+            "INSERT OR REPLACE INTO series_geometry (series_uid, slices, slice_spacing,
+                voxel_size_x, voxel_size_y, voxel_size_z, extent_x, extent_y, extent_z, expected_slices,
+                missing_slices, duplicate_positions, completeness, gantry_tilt, issues)
+             VALUES(?1,?2,?3,?4,?5,?6,?7,?8,?9,?10,?11,?12,?13,?14,?15);",
+            rusqlite::params![series_uid, geometry.slices as i64, geometry.slice_spacing,
+                voxel_size[0], voxel_size[1], voxel_size[2], extent[0], extent[1], extent[2],
+                geometry.expected_slices as i64, geometry.missing_slices as i64,
+                geometry.duplicate_positions as i64, geometry.completeness, geometry.gantry_tilt,
+                geometry.issues.join(",")],
+        )?;
+        Ok(geometry)
     }
 
-    fn get_series_as_struct(&self, study_uid: &String) -> Result<Vec<Se>, Error> {
-        let mut stmt = self.prepare("SELECT * FROM series WHERE study_uid = (?1);")?;
-        let mut rows = stmt.query(&[&study_uid])?;
-        let mut series: Vec<Se> = Vec::new();
+    /// Возвращает сохраненную сводку геометрии серии
+    fn get_series_geometry(&self, series_uid: &str) -> Result<Option<work_geometry::SeriesGeometry>, Error> {
+        let mut stmt = self.prepare(
+            "SELECT slices, slice_spacing, voxel_size_x, voxel_size_y, voxel_size_z,
+                    extent_x, extent_y, extent_z, expected_slices, missing_slices, duplicate_positions,
+                    completeness, gantry_tilt, issues
+             FROM series_geometry WHERE series_uid = (?1);")?;
+        let mut rows = stmt.query([series_uid])?;
+        let row = match rows.next()? {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        let triple = |first: usize| -> Result<Option<[f64; 3]>, Error> {
+            Ok(match (row.get(first)?, row.get(first + 1)?, row.get(first + 2)?) {
+                (Some(x), Some(y), Some(z)) => Some([x, y, z]),
+                _ => None,
+            })
+        };
+        let issues: String = row.get(13)?;
+        Ok(Some(work_geometry::SeriesGeometry {
+            slices: row.get::<_, i64>(0)? as usize,
+            slice_spacing: row.get(1)?,
+            voxel_size: triple(2)?,
+            extent: triple(5)?,
+            expected_slices: row.get::<_, i64>(8)? as usize,
+            missing_slices: row.get::<_, i64>(9)? as usize,
+            duplicate_positions: row.get::<_, i64>(10)? as usize,
+            completeness: row.get(11)?,
+            gantry_tilt: row.get(12)?,
+            issues: issues.split(',').filter(|issue| !issue.is_empty()).map(|issue| issue.to_string()).collect(),
+        }))
+    }
+
+    /// Возвращает путь среднего экземпляра серии. Экземпляры упорядочиваются по положению
+    /// вдоль нормали к плоскости среза, если оно известно у всех, иначе по Instance Number и пути
+    fn get_middle_instance(&self, series_uid: &str) -> Result<Option<String>, Error> {
+        let mut stmt = self.prepare(
+            "SELECT path, instance_number, image_position_patient, image_orientation_patient
+             FROM paths WHERE series_uid = (?1) ORDER BY path;")?;
+        let mut instances = stmt.query_map([series_uid], |row| {
+            let text = |i: usize| -> Result<String, Error> { Ok(row.get::<_, Option<String>>(i)?.unwrap_or_default()) };
+            let orientation: Option<[f64; 6]> = work_geometry::parse_numbers(&text(3)?);
+            let position: Option<[f64; 3]> = work_geometry::parse_numbers(&text(2)?);
+            let location = position.zip(orientation)
+                .map(|(position, orientation)| work_geometry::dot(&position, &work_geometry::normal(&orientation)));
+            Ok((location, text(1)?.trim().parse::<f64>().ok(), text(0)?))
+        })?.collect::<Result<Vec<(Option<f64>, Option<f64>, String)>, Error>>()?;
+        if instances.iter().all(|(location, _, _)| location.is_some()) {
+            instances.sort_by(|a, b| a.0.unwrap_or_default().total_cmp(&b.0.unwrap_or_default()));
+        } else {
+            instances.sort_by(|a, b| a.1.unwrap_or(f64::MAX).total_cmp(&b.1.unwrap_or(f64::MAX)));
+        }
+        let middle = instances.len() / 2;
+        Ok(instances.into_iter().nth(middle).map(|(_, _, path)| path))
+    }
+
+    /// Сохраняет путь к миниатюре серии
+    fn set_series_thumbnail(&self, series_uid: &str, thumbnail: &str) -> Result<(), Error> {
+        self.execute("UPDATE series SET thumbnail = (?2) WHERE series_uid = (?1);", [series_uid, thumbnail])?;
+        Ok(())
+    }
+
+    /// Файлы, для которых хеш содержимого еще не вычислен (или сброшен при повторной индексации)
+    fn get_unhashed_paths(&self) -> Result<Vec<String>, Error> {
+        let mut stmt = self.prepare("SELECT path FROM paths WHERE content_hash IS NULL ORDER BY path;")?;
+        let paths = stmt.query_map([], |row| row.get(0))?.collect::<Result<Vec<String>, Error>>()?;
+        Ok(paths)
+    }
+
+    /// Сохраняет хеш содержимого файла; пустая строка — у файла нет Pixel Data
+    fn set_content_hash(&self, path: &str, content_hash: &str) -> Result<(), Error> {
+        self.execute("UPDATE paths SET content_hash = (?2) WHERE path = (?1);", [path, content_hash])?;
+        Ok(())
+    }
+
+    /// Ключ, путь, экземпляр (`study/series/SOP Instance UID`) и хеш содержимого файлов, ключ которых встречается
+    /// в индексе больше одного раза, упорядоченные по ключу и пути. Файлы без ключа не учитываются
+    fn get_duplicate_paths(&self, key: work_duplicates::DuplicateKey) -> Result<Vec<(String, String, String, String)>, Error> {
+        let expression = match key {
+            work_duplicates::DuplicateKey::Uid => "NULLIF(NULLIF(sop_instance_uid, ''), 'Unknown')",
+            work_duplicates::DuplicateKey::Content => "NULLIF(content_hash, '')",
+            work_duplicates::DuplicateKey::Exact =>
+                "NULLIF(NULLIF(sop_instance_uid, ''), 'Unknown') || '/' || NULLIF(content_hash, '')",
+        };
+        let mut stmt = self.prepare(&format!(
+            "SELECT key, path, instance, ifnull(content_hash, '') FROM (
+                 SELECT {0} AS key, path, content_hash,
+                        ifnull(series.study_uid, '') || '/' || paths.series_uid || '/' || ifnull(sop_instance_uid, '') AS instance
+                 FROM paths LEFT JOIN series ON series.series_uid = paths.series_uid) AS keys
+             WHERE key IN (SELECT {0} FROM paths GROUP BY 1 HAVING count(*) > 1)
+             ORDER BY key, path;", expression))?;
+        let files = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))?
+            .collect::<Result<Vec<(String, String, String, String)>, Error>>()?;
+        Ok(files)
+    }
+
+    /// Удаляет файл из индекса вместе с его дополнительными тегами. Серия, исследование и пациент,
+    /// у которых не осталось файлов, удаляются следом. Возвращает серию файла, если в ней остались файлы
+    fn remove_path(&self, path: &str) -> Result<Option<String>, Error> {
+        let series_uid: Option<String> = self.query_row(
+            "SELECT series_uid FROM paths WHERE path = (?1);", [path], |row| row.get(0)).optional()?;
+        self.execute("DELETE FROM extra_tags WHERE level = 'instance' AND entity_id = (?1);", [path])?;
+        self.execute("DELETE FROM paths WHERE path = (?1);", [path])?;
+        match series_uid {
+            Some(series_uid) if !self.remove_empty_series(&series_uid)? => Ok(Some(series_uid)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Удаляет серию, если у нее не осталось файлов, а следом исследование и пациента без серий.
+    /// Возвращает, была ли серия удалена
+    fn remove_empty_series(&self, series_uid: &str) -> Result<bool, Error> {
+        let exists = |sql: &str, id: &str| -> Result<bool, Error> { self.query_row(sql, [id], |row| row.get(0)) };
+        if exists("SELECT EXISTS (SELECT 1 FROM paths WHERE series_uid = (?1));", series_uid)? {
+            return Ok(false);
+        }
+        let study_uid: Option<String> = self.query_row(
+            "SELECT study_uid FROM series WHERE series_uid = (?1);", [series_uid], |row| row.get(0)).optional()?;
+        self.execute("DELETE FROM series_geometry WHERE series_uid = (?1);", [series_uid])?;
+        self.execute("DELETE FROM series_fts WHERE series_uid = (?1);", [series_uid])?;
+        self.execute("DELETE FROM extra_tags WHERE level = 'series' AND entity_id = (?1);", [series_uid])?;
+        self.execute("DELETE FROM series WHERE series_uid = (?1);", [series_uid])?;
+        let study_uid = match study_uid {
+            Some(study_uid) if !exists("SELECT EXISTS (SELECT 1 FROM series WHERE study_uid = (?1));", &study_uid)? => study_uid,
+            _ => return Ok(true),
+        };
+        let patient_id: Option<String> = self.query_row(
+            "SELECT patient_id FROM study WHERE study_uid = (?1);", [&study_uid], |row| row.get(0)).optional()?;
+        self.execute("DELETE FROM extra_tags WHERE level = 'study' AND entity_id = (?1);", [&study_uid])?;
+        self.execute("DELETE FROM study WHERE study_uid = (?1);", [&study_uid])?;
+        if let Some(patient_id) = patient_id {
+            if !exists("SELECT EXISTS (SELECT 1 FROM study WHERE patient_id = (?1));", &patient_id)? {
+                self.execute("DELETE FROM extra_tags WHERE level = 'patient' AND entity_id = (?1);", [&patient_id])?;
+                self.execute("DELETE FROM patients WHERE patient_id = (?1);", [&patient_id])?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Выполняет полнотекстовый поиск серий, результаты упорядочены по релевантности (bm25)
+    fn search_series(&self, query: &str, limit: usize) -> Result<Vec<SearchHit>, Error> {
+        let fts_query = to_fts_query(query);
+        if fts_query.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut stmt = self.prepare(
+            "SELECT study.patient_id, study.study_uid, study.study_date, study.description,
+                    series.series_uid, series.modality, series.description,
+                    series.protocolname, series.bodypartexamined,
+                    (SELECT count(*) FROM paths WHERE paths.series_uid = series.series_uid),
+                    bm25(series_fts) AS rank
+             FROM series_fts
+             JOIN series ON series.series_uid = series_fts.series_uid
+             JOIN study ON study.study_uid = series.study_uid
+             WHERE series_fts MATCH (?1)
+             ORDER BY rank
+             LIMIT (?2);",
+        )?;
+        let mut rows = stmt.query(rusqlite::params![fts_query, limit as i64])?;
+        let mut hits: Vec<SearchHit> = Vec::new();
         while let Some(row) = rows.next()? {
-            let series_uid = row.get(0)?;
-            series.push(
-                Se {
-                    series_uid: row.get(0)?,
-                    modality: row.get(1)?,
-                    instancenumber: row.get(2)?,
-                    imagepositionpatient: row.get(3)?,
-                    imageorientationpatient: row.get(4)?,
-                    pixelspacing: row.get(5)?,
-                    numberofframes: row.get(6)?,
-                    xraytubecurrent: row.get(7)?,
-                    kvp: row.get(8)?,
-                    filtertype: row.get(9)?,
-                    rows: row.get(10)?,
-                    columns: row.get(11)?,
-                    exposuretime: row.get(12)?,
-                    rescaleintercept: row.get(13)?,
-                    description: row.get(14)?,
-                    paths: self.get_paths_as_vec(&series_uid)?,
+            let files: i64 = row.get(9)?;
+            let study_uid: String = row.get(1)?;
+            let series_uid: String = row.get(4)?;
+            let mut extra = self.get_extra_tags(work_dcm::Level::Study, &study_uid)?;
+            extra.extend(self.get_extra_tags(work_dcm::Level::Series, &series_uid)?);
+            hits.push(
+                SearchHit {
+                    patient_id: row.get(0)?,
+                    study_uid,
+                    study_date: row.get(2)?,
+                    study_description: row.get(3)?,
+                    series_uid,
+                    modality: row.get(5)?,
+                    series_description: row.get(6)?,
+                    protocolname: row.get(7)?,
+                    bodypartexamined: row.get(8)?,
+                    files: files as usize,
+                    rank: row.get(10)?,
+                    extra,
                 });
         }
-        Ok(series)
+        Ok(hits)
+    }
+
+    /// Возвращает пути файлов серий, подходящих под все заданные условия:
+    /// полнотекстовый запрос (как в `search`), исследования и серии.
+    /// Без условий возвращает все файлы индекса
+    fn select_paths(&self, query: Option<&str>, study_uids: &[String], series_uids: &[String]) -> Result<Vec<String>, Error> {
+        let (filter, values) = series_filter(query, study_uids, series_uids);
+        let mut stmt = self.prepare(&format!(
+            "SELECT paths.path FROM paths
+             JOIN series ON series.series_uid = paths.series_uid
+             {}
+             ORDER BY series.study_uid, paths.series_uid, paths.path;", filter))?;
+        let paths = stmt.query_map(rusqlite::params_from_iter(values.iter()), |row| row.get(0))?
+            .collect::<Result<Vec<String>, Error>>()?;
+        Ok(paths)
     }
 
-    fn get_paths_as_vec(&self, series_uid: &String) -> Result<Vec<String>, Error> {
-        let mut stmt = self.prepare("SELECT * FROM paths WHERE series_uid = (?1);")?;
-        let mut rows = stmt.query(&[&series_uid])?;
-        let mut paths: Vec<String> = Vec::new();
+    /// Возвращает серии, выбранные теми же условиями, что и в `select_paths`, с путями их файлов
+    fn select_series(&self, query: Option<&str>, study_uids: &[String], series_uids: &[String]) -> Result<Vec<SelectedSeries>, Error> {
+        let (filter, values) = series_filter(query, study_uids, series_uids);
+        let mut stmt = self.prepare(&format!(
+            "SELECT series.study_uid, paths.series_uid, paths.path FROM paths
+             JOIN series ON series.series_uid = paths.series_uid
+             {}
+             ORDER BY series.study_uid, paths.series_uid, paths.path;", filter))?;
+        let mut rows = stmt.query(rusqlite::params_from_iter(values.iter()))?;
+        let mut selected: Vec<SelectedSeries> = Vec::new();
         while let Some(row) = rows.next()? {
-            paths.push(row.get(0)?);
+            let (study_uid, series_uid, path): (String, String, String) = (row.get(0)?, row.get(1)?, row.get(2)?);
+            match selected.last_mut() {
+                Some(series) if series.series_uid == series_uid => series.paths.push(path),
+                _ => selected.push(SelectedSeries { study_uid, series_uid, paths: vec![path] }),
+            }
         }
+        Ok(selected)
+    }
+
+    /// Выполняет запрос C-FIND уровня `level`: возвращает для каждой найденной сущности
+    /// значения запрошенных атрибутов (и уникального ключа уровня) по ключевым словам.
+    /// Атрибуты, которых нет в индексе, не участвуют в отборе и не возвращаются.
+    /// `limit` и `offset` ограничивают выборку в порядке уникального ключа (QIDO-RS)
+    fn query_entities(&self, level: work_dcm::Level, keys: &[QueryKey], limit: Option<usize>, offset: usize)
+        -> Result<Vec<BTreeMap<String, String>>, Error> {
+        let mut values: Vec<String> = Vec::new();
+        let filter = query_filter(level, keys, &mut values);
+        let unique_key = query_unique_key(level);
+        let mut columns: Vec<(&str, &str)> = QUERY_ATTRIBUTES.iter()
+            .filter(|(keyword, attribute_level, _)| *attribute_level as usize <= level as usize
+                && keys.iter().any(|key| key.keyword == *keyword))
+            .map(|(keyword, _, expression)| (*keyword, *expression))
+            .collect();
+        let unique_expression = QUERY_ATTRIBUTES.iter()
+            .find(|(keyword, _, _)| *keyword == unique_key)
+            .map(|(_, _, expression)| *expression)
+            .unwrap_or_default();
+        if !columns.iter().any(|(keyword, _)| *keyword == unique_key) {
+            columns.push((unique_key, unique_expression));
+        }
+        let select: Vec<&str> = columns.iter().map(|(_, expression)| *expression).collect();
+        let mut stmt = self.prepare(&format!(
+            "SELECT {} {} {} ORDER BY {} LIMIT {} OFFSET {};", select.join(", "), query_from(level), filter,
+            unique_expression, limit.map_or(-1, |limit| limit.min(i64::MAX as usize) as i64), offset))?;
+        let entities = stmt.query_map(rusqlite::params_from_iter(values.iter()), |row| {
+            let mut entity = BTreeMap::new();
+            for (i, (keyword, _)) in columns.iter().enumerate() {
+                let value = match row.get_ref(i)? {
+                    ValueRef::Text(text) => String::from_utf8_lossy(text).trim_end().to_string(),
+                    ValueRef::Integer(number) => number.to_string(),
+                    _ => String::new(),
+                };
+                entity.insert(keyword.to_string(), if value == "Unknown" { String::new() } else { value });
+            }
+            Ok(entity)
+        })?.collect::<Result<Vec<BTreeMap<String, String>>, Error>>()?;
+        Ok(entities)
+    }
+
+    /// Возвращает пути файлов всех экземпляров сущностей, подходящих под запрос C-MOVE/C-GET уровня `level`
+    fn query_paths(&self, level: work_dcm::Level, keys: &[QueryKey]) -> Result<Vec<String>, Error> {
+        let mut values: Vec<String> = Vec::new();
+        let filter = query_filter(level, keys, &mut values);
+        let mut stmt = self.prepare(&format!(
+            "SELECT paths.path {} {} ORDER BY series.study_uid, paths.series_uid, paths.path;",
+            query_from(work_dcm::Level::Instance), filter))?;
+        let paths = stmt.query_map(rusqlite::params_from_iter(values.iter()), |row| row.get(0))?
+            .collect::<Result<Vec<String>, Error>>()?;
         Ok(paths)
     }
 
-    fn print_count(vec_patients: &Vec<Pa>){
-        println!("Among them, patients were found: {}", &vec_patients.len());
-        for (i, patient) in vec_patients.iter().enumerate() {
-            let tmp_tuple = patient.count();
-            println!("\t{}. {:>15}--->\t\tStudies:\t{},\tSeries:\t{},\tFiles:\t{}",
-                     i+1, patient.patient_id, tmp_tuple.0, tmp_tuple.1, tmp_tuple.2)
+    /// Столбцы плоской таблицы уровня `level` (одна строка на сущность): столбцы таблиц индекса
+    /// этого и вышестоящих уровней (без внешних ключей), вычисляемые атрибуты (количества,
+    /// ModalitiesInStudy) и дополнительные теги (--index-tags) этих уровней.
+    /// Имя столбца таблицы индекса, которое встречается на нескольких уровнях, получает префикс уровня
+    fn table_columns(&self, level: work_dcm::Level) -> Result<Vec<TableColumn>, Error> {
+        let levels: Vec<work_dcm::Level> = TABLE_LEVELS.iter()
+            .map(|(table_level, _, _, _)| *table_level)
+            .filter(|table_level| *table_level as usize <= level as usize)
+            .collect();
+        let mut table_columns: Vec<(work_dcm::Level, &str, String)> = Vec::new();
+        for (table_level, table, _, foreign_key) in TABLE_LEVELS.iter() {
+            let mut stmt = self.prepare(&format!("SELECT name FROM pragma_table_info('{}');", table))?;
+            let names = stmt.query_map([], |row| row.get(0))?
+                .collect::<Result<Vec<String>, Error>>()?;
+            table_columns.extend(names.into_iter()
+                .filter(|name| Some(name.as_str()) != *foreign_key)
+                .map(|name| (*table_level, *table, name)));
+        }
+        let mut columns: Vec<TableColumn> = Vec::new();
+        for (table_level, table, name) in &table_columns {
+            if !levels.contains(table_level) {
+                continue;
+            }
+            let ambiguous = table_columns.iter().filter(|(_, _, other)| other == name).count() > 1;
+            columns.push(TableColumn {
+                name: if ambiguous { format!("{}_{}", table_level.as_str(), name) } else { name.clone() },
+                expression: format!("NULLIF({}.{}, 'Unknown')", table, name),
+                kind: ColumnKind::Text,
+            });
+        }
+        for (keyword, attribute_level, expression) in QUERY_ATTRIBUTES {
+            if expression.starts_with('(') && levels.contains(attribute_level) {
+                columns.push(TableColumn {
+                    name: to_snake_case(keyword),
+                    expression: expression.to_string(),
+                    kind: if keyword.starts_with("NumberOf") { ColumnKind::Integer } else { ColumnKind::Text },
+                });
+            }
+        }
+        if levels.contains(&work_dcm::Level::Series) {
+            let mut stmt = self.prepare("SELECT name, type FROM pragma_table_info('series_geometry');")?;
+            let geometry_columns = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?
+                .collect::<Result<Vec<(String, String)>, Error>>()?;
+            for (name, column_type) in geometry_columns.into_iter().filter(|(name, _)| name != "series_uid") {
+                columns.push(TableColumn {
+                    expression: format!(
+                        "(SELECT NULLIF({0}, '') FROM series_geometry WHERE series_geometry.series_uid = series.series_uid)",
+                        name),
+                    name: if name == "issues" { "geometry_issues".to_string() } else { name },
+                    kind: match column_type.as_str() {
+                        "INTEGER" => ColumnKind::Integer,
+                        "REAL" => ColumnKind::Real,
+                        _ => ColumnKind::Text,
+                    },
+                });
+            }
         }
+        let mut stmt = self.prepare("SELECT DISTINCT level, keyword FROM extra_tags ORDER BY level, keyword;")?;
+        let mut extra_tags = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?
+            .collect::<Result<Vec<(String, String)>, Error>>()?;
+        extra_tags.sort_by_key(|(extra_level, _)| TABLE_LEVELS.iter()
+            .position(|(table_level, _, _, _)| table_level.as_str() == extra_level));
+        for (extra_level, keyword) in extra_tags {
+            let entity_id = match TABLE_LEVELS.iter()
+                .find(|(table_level, _, _, _)| table_level.as_str() == extra_level && levels.contains(table_level)) {
+                Some((_, _, entity_id, _)) => entity_id,
+                None => continue,
+            };
+            let name = if columns.iter().any(|column| column.name == keyword) {
+                format!("{}_{}", extra_level, keyword)
+            } else {
+                keyword.clone()
+            };
+            columns.push(TableColumn {
+                name,
+                expression: format!(
+                    "(SELECT value FROM extra_tags WHERE level = '{}' AND entity_id = {} AND keyword = '{}')",
+                    extra_level, entity_id, keyword.replace('\'', "''")),
+                kind: ColumnKind::Text,
+            });
+        }
+        Ok(columns)
     }
 
-    fn export_result(&self) -> Result<()> {
-        let mut dict = HashMap::new();
-        dict.insert("result", self.get_patients_as_struct()?);
-        match dict.get(&"result") {
-            Some(vec_patients) => {
-                Connection::print_count(vec_patients);
-            },
-            _ => {println!("No patients found")}
-        };
-        let j = serde_json::to_string(&dict).unwrap_or_default();
-        match File::create("result_dcm_finder.json") {
-            Ok(mut file) => {
-                match file.write_all(j.as_bytes()){
-                    Ok(_) => {}
-                    Err(e) => {
-                        eprintln!("Error write result in file (as json): {:?}", e);
-                    }
+    /// Проходит курсором по строкам плоской таблицы уровня `level` (см. `table_columns`),
+    /// не загружая таблицу в память. Строки упорядочены по идентификаторам сущностей
+    fn for_each_table_row(&self, level: work_dcm::Level, columns: &[TableColumn],
+                          on_row: &mut dyn FnMut(TableRow) -> io::Result<()>) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let select: Vec<&str> = columns.iter().map(|column| column.expression.as_str()).collect();
+        let order: Vec<&str> = TABLE_LEVELS.iter()
+            .filter(|(table_level, _, _, _)| *table_level as usize <= level as usize)
+            .map(|(_, _, entity_id, _)| *entity_id)
+            .collect();
+        let mut stmt = self.prepare(&format!("SELECT {} {} ORDER BY {};",
+                                             select.join(", "), query_from(level), order.join(", ")))?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let mut values: TableRow = Vec::with_capacity(columns.len());
+            for i in 0..columns.len() {
+                values.push(match row.get_ref(i)? {
+                    ValueRef::Text(text) => Some(String::from_utf8_lossy(text).trim_end().to_string()),
+                    ValueRef::Integer(number) => Some(number.to_string()),
+                    ValueRef::Real(number) => Some(number.to_string()),
+                    _ => None,
+                });
+            }
+            on_row(values)?;
+        }
+        Ok(())
+    }
+
+    fn print_count(counts: &[PatientCount], out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "Among them, patients were found: {}", counts.len())?;
+        for (i, count) in counts.iter().enumerate() {
+            writeln!(out, "\t{}. {:>15}--->\t\tStudies:\t{},\tSeries:\t{},\tFiles:\t{}",
+                     i+1, count.patient_id, count.studies, count.series, count.paths)?;
+        }
+        Ok(())
+    }
+
+    /// Выводит найденные серии, сгруппированные по исследованиям.
+    /// Исследования упорядочены по наиболее релевантной из их серий.
+    fn print_search_hits(hits: &[SearchHit]) {
+        let mut studies: Vec<(&str, Vec<&SearchHit>)> = Vec::new();
+        for hit in hits {
+            match studies.iter_mut().find(|(study_uid, _)| *study_uid == hit.study_uid) {
+                Some((_, series)) => series.push(hit),
+                None => studies.push((&hit.study_uid, vec![hit])),
+            }
+        }
+        println!("Studies found: {}, series found: {}", studies.len(), hits.len());
+        for (i, (study_uid, series)) in studies.iter().enumerate() {
+            let first = series[0];
+            let files: usize = series.iter().map(|hit| hit.files).sum();
+            println!("\t{}. {} {:>10} \"{}\"\tPatient: {},\tSeries: {},\tFiles: {}",
+                     i + 1, study_uid, first.study_date.trim(), first.study_description.trim(),
+                     first.patient_id.trim(), series.len(), files);
+            for hit in series {
+                println!("\t\t[{:>2}] {} \"{}\" ({})\tFiles: {}",
+                         hit.modality.trim(), hit.series_uid, hit.series_description.trim(),
+                         hit.protocolname.trim(), hit.files);
+                for (keyword, value) in &hit.extra {
+                    println!("\t\t\t{}: {}", keyword, value);
                 }
             }
-            Err(error) => {
-                eprintln!("Error create result file (as json): {:?}", error)
+        }
+    }
+
+    /// Выгружает результат (пациенты -> исследования -> серии -> пути файлов, затем сканирования)
+    /// в `writer`. Индекс читается одним курсором, в памяти хранится только текущая серия.
+    /// В режиме `json_lines` пишется по строке `SeriesRecord` на серию, без сканирований.
+    fn export_result(&self, writer: &mut dyn Write, json_lines: bool)
+        -> std::result::Result<Vec<PatientCount>, Box<dyn std::error::Error>> {
+        let mut stmt = self.prepare(
+            "SELECT patients.patient_id, patients.birth_date, patients.sex, patients.age,
+                    study.study_uid, study.study_date, study.study_time, study.description,
+                    series.series_uid, series.modality, series.instancenumber, series.imagepositionpatient,
+                    series.imageorientationpatient, series.pixelspacing, series.numberofframes,
+                    series.xraytubecurrent, series.kvp, series.filtertype, series.rows, series.columns,
+                    series.exposuretime, series.rescaleintercept, series.description,
+                    series.protocolname, series.bodypartexamined, paths.path,
+                    series.source_series_uid, series.volume_key, series.thumbnail
+             FROM patients
+             LEFT JOIN study ON study.patient_id = patients.patient_id
+             LEFT JOIN series ON series.study_uid = study.study_uid
+             LEFT JOIN paths ON paths.series_uid = series.series_uid
+             ORDER BY patients.patient_id, study.study_uid, series.series_uid, paths.path;")?;
+        let mut rows = stmt.query([])?;
+        let mut result = ResultWriter::new(writer, json_lines)?;
+        while let Some(row) = rows.next()? {
+            let text = |i: usize| -> Result<String, Error> {
+                Ok(row.get::<_, Option<String>>(i)?.unwrap_or_default())
+            };
+            let patient_id = text(0)?;
+            if result.patient.as_ref().is_none_or(|patient| patient.patient_id != patient_id) {
+                result.open_patient(Pa {
+                    extra: self.get_extra_tags(work_dcm::Level::Patient, &patient_id)?,
+                    patient_id,
+                    birth_date: text(1)?,
+                    sex: text(2)?,
+                    age: text(3)?,
+                })?;
+            }
+            let study_uid: Option<String> = row.get(4)?;
+            let study_uid = match study_uid {
+                Some(study_uid) => study_uid,
+                None => continue,
+            };
+            if result.study.as_ref().is_none_or(|study| study.study_uid != study_uid) {
+                result.open_study(St {
+                    extra: self.get_extra_tags(work_dcm::Level::Study, &study_uid)?,
+                    study_uid,
+                    study_date: text(5)?,
+                    study_time: text(6)?,
+                    description: text(7)?,
+                })?;
+            }
+            let series_uid: Option<String> = row.get(8)?;
+            let series_uid = match series_uid {
+                Some(series_uid) => series_uid,
+                None => continue,
+            };
+            if result.series.as_ref().is_none_or(|series| series.series_uid != series_uid) {
+                result.open_series(Se {
+                    extra: self.get_extra_tags(work_dcm::Level::Series, &series_uid)?,
+                    instances: self.get_instances_extra_tags(&series_uid)?,
+                    geometry: self.get_series_geometry(&series_uid)?,
+                    series_uid,
+                    modality: text(9)?,
+                    instancenumber: text(10)?,
+                    imagepositionpatient: text(11)?,
+                    imageorientationpatient: text(12)?,
+                    pixelspacing: text(13)?,
+                    numberofframes: text(14)?,
+                    xraytubecurrent: text(15)?,
+                    kvp: text(16)?,
+                    filtertype: text(17)?,
+                    rows: text(18)?,
+                    columns: text(19)?,
+                    exposuretime: text(20)?,
+                    rescaleintercept: text(21)?,
+                    description: text(22)?,
+                    protocolname: text(23)?,
+                    bodypartexamined: text(24)?,
+                    source_series_uid: row.get(26)?,
+                    volume_key: row.get(27)?,
+                    thumbnail: row.get(28)?,
+                    paths: Vec::new(),
+                })?;
+            }
+            if let Some(path) = row.get::<_, Option<String>>(25)? {
+                result.add_path(path);
+            }
+        }
+        let scans = if json_lines { Vec::new() } else { self.get_scans()? };
+        result.finish(&scans)
+    }
+}
+
+
+/// Потоковая запись результата экспорта. Поля пациента и исследования записываются
+/// при их открытии, серия — целиком при закрытии
+struct ResultWriter<'w> {
+    writer: &'w mut dyn Write,
+    json_lines: bool,
+    patient: Option<Pa>,
+    study: Option<St>,
+    series: Option<Se>,
+    first_study: bool,
+    first_series: bool,
+    counts: Vec<PatientCount>,
+}
+
+impl<'w> ResultWriter<'w> {
+    fn new(writer: &'w mut dyn Write, json_lines: bool) -> io::Result<Self> {
+        if !json_lines {
+            writer.write_all(b"{\"result\":[")?;
+        }
+        Ok(ResultWriter {
+            writer, json_lines, patient: None, study: None, series: None,
+            first_study: true, first_series: true, counts: Vec::new(),
+        })
+    }
+
+    /// Записывает объект без закрывающей скобки, чтобы дописать к нему вложенный массив `key`
+    fn write_open(&mut self, value: &impl Serialize, key: &str) -> serde_json::Result<()> {
+        let json = serde_json::to_string(value)?;
+        let fields = json.strip_suffix('}').unwrap_or(&json);
+        write!(self.writer, "{},\"{}\":[", fields, key).map_err(serde_json::Error::io)
+    }
+
+    fn open_patient(&mut self, patient: Pa) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        self.close_study()?;
+        if !self.json_lines {
+            if !self.counts.is_empty() {
+                self.writer.write_all(b"]},")?;
+            }
+            self.write_open(&patient, "studies")?;
+        }
+        self.counts.push(PatientCount { patient_id: patient.patient_id.clone(), ..Default::default() });
+        self.patient = Some(patient);
+        self.first_study = true;
+        Ok(())
+    }
+
+    fn open_study(&mut self, study: St) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        self.close_study()?;
+        if !self.json_lines {
+            if !self.first_study {
+                self.writer.write_all(b",")?;
             }
+            self.write_open(&study, "series")?;
+        }
+        if let Some(count) = self.counts.last_mut() {
+            count.studies += 1;
+        }
+        self.study = Some(study);
+        self.first_study = false;
+        self.first_series = true;
+        Ok(())
+    }
+
+    fn open_series(&mut self, series: Se) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        self.close_series()?;
+        if let Some(count) = self.counts.last_mut() {
+            count.series += 1;
         }
+        self.series = Some(series);
         Ok(())
     }
+
+    fn add_path(&mut self, path: String) {
+        if let Some(series) = self.series.as_mut() {
+            series.paths.push(path);
+            if let Some(count) = self.counts.last_mut() {
+                count.paths += 1;
+            }
+        }
+    }
+
+    fn close_series(&mut self) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        let series = match self.series.take() {
+            Some(series) => series,
+            None => return Ok(()),
+        };
+        if self.json_lines {
+            if let (Some(patient), Some(study)) = (self.patient.as_ref(), self.study.as_ref()) {
+                serde_json::to_writer(&mut *self.writer, &SeriesRecord { patient, study, series: &series })?;
+                self.writer.write_all(b"\n")?;
+            }
+        } else {
+            if !self.first_series {
+                self.writer.write_all(b",")?;
+            }
+            serde_json::to_writer(&mut *self.writer, &series)?;
+            self.first_series = false;
+        }
+        Ok(())
+    }
+
+    fn close_study(&mut self) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        self.close_series()?;
+        if self.study.take().is_some() && !self.json_lines {
+            self.writer.write_all(b"]}")?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self, scans: &[ScanRecord]) -> std::result::Result<Vec<PatientCount>, Box<dyn std::error::Error>> {
+        self.close_study()?;
+        if !self.json_lines {
+            if !self.counts.is_empty() {
+                self.writer.write_all(b"]}")?;
+            }
+            self.writer.write_all(b"],\"scans\":")?;
+            serde_json::to_writer(&mut *self.writer, scans)?;
+            self.writer.write_all(b"}")?;
+        }
+        self.writer.flush()?;
+        Ok(self.counts)
+    }
+}
+
+
+/// Открывает сохраненный индекс и выводит результаты полнотекстового поиска
+pub fn search(db_path: &path::Path, query: &str, limit: usize) {
+    if !db_path.is_file() {
+        eprintln!("Index database not found: {}", db_path.display());
+        return;
+    }
+    match Connection::open_dcm_tables(db_path) {
+        Ok(conn) => {
+            match conn.search_series(query, limit) {
+                Ok(hits) => Connection::print_search_hits(&hits),
+                Err(e) => eprintln!("Error searching in index: {:?}", e),
+            }
+        }
+        Err(e) => {
+            eprintln!("Error open index database: {:?}", e)
+        }
+    }
+}
+
+/// Открывает сохраненный индекс и возвращает пути файлов, выбранных условиями (см. `select_paths`)
+pub fn select_paths(db_path: &path::Path, query: Option<&str>, study_uids: &[String],
+                    series_uids: &[String]) -> Option<Vec<String>> {
+    if !db_path.is_file() {
+        eprintln!("Index database not found: {}", db_path.display());
+        return None;
+    }
+    match Connection::open_dcm_tables(db_path) {
+        Ok(conn) => conn.select_paths(query, study_uids, series_uids)
+            .map_err(|e| eprintln!("Error selecting files in index: {:?}", e))
+            .ok(),
+        Err(e) => {
+            eprintln!("Error open index database: {:?}", e);
+            None
+        }
+    }
+}
+
+/// Открывает сохраненный индекс и возвращает серии, выбранные условиями (см. `select_paths`)
+pub fn select_series(db_path: &path::Path, query: Option<&str>, study_uids: &[String],
+                     series_uids: &[String]) -> Option<Vec<SelectedSeries>> {
+    if !db_path.is_file() {
+        eprintln!("Index database not found: {}", db_path.display());
+        return None;
+    }
+    match Connection::open_dcm_tables(db_path) {
+        Ok(conn) => conn.select_series(query, study_uids, series_uids)
+            .map_err(|e| eprintln!("Error selecting series in index: {:?}", e))
+            .ok(),
+        Err(e) => {
+            eprintln!("Error open index database: {:?}", e);
+            None
+        }
+    }
+}
+
+/// Условие WHERE по словам поиска и UID исследований и серий с параметрами для него
+fn series_filter(query: Option<&str>, study_uids: &[String], series_uids: &[String]) -> (String, Vec<String>) {
+    let mut conditions: Vec<String> = Vec::new();
+    let mut values: Vec<String> = Vec::new();
+    if let Some(fts_query) = query.map(to_fts_query) {
+        // Запрос без слов ничего не находит, как и в `search_series` (пустой MATCH - ошибка FTS5)
+        if fts_query.is_empty() {
+            conditions.push("0".to_string());
+        } else {
+            values.push(fts_query);
+            conditions.push(format!(
+                "series.series_uid IN (SELECT series_uid FROM series_fts WHERE series_fts MATCH ?{})",
+                values.len()));
+        }
+    }
+    for (column, uids) in [("series.study_uid", study_uids), ("series.series_uid", series_uids)] {
+        if uids.is_empty() {
+            continue;
+        }
+        let placeholders: Vec<String> = uids.iter()
+            .map(|uid| {
+                values.push(uid.clone());
+                format!("?{}", values.len())
+            })
+            .collect();
+        conditions.push(format!("{} IN ({})", column, placeholders.join(", ")));
+    }
+    let filter = if conditions.is_empty() {
+        String::new()
+    } else {
+        format!("WHERE {}", conditions.join(" AND "))
+    };
+    (filter, values)
+}
+
+/// Добавляет в таблицу столбцы TEXT, которых в ней еще нет
+fn add_missing_columns(conn: &Connection, table: &str, columns: &[&str]) -> Result<(), Error> {
+    let mut stmt = conn.prepare(&format!("SELECT name FROM pragma_table_info('{}');", table))?;
+    let existing = stmt.query_map([], |row| row.get(0))?
+        .collect::<Result<Vec<String>, Error>>()?;
+    for column in columns.iter().filter(|column| !existing.iter().any(|name| name == *column)) {
+        conn.execute(&format!("ALTER TABLE `{}` ADD COLUMN {} TEXT DEFAULT NULL;", table, column), [])?;
+    }
+    Ok(())
+}
+
+/// Тип значений столбца плоской таблицы
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColumnKind {
+    Text,
+    Integer,
+    Real,
+}
+
+/// Столбец плоской таблицы для выгрузки: имя, выражение SQL и тип значения
+#[derive(Debug, Clone)]
+pub struct TableColumn {
+    pub name: String,
+    pub expression: String,
+    pub kind: ColumnKind,
+}
+
+/// Строка плоской таблицы: значения в порядке столбцов (`None` — значение отсутствует)
+pub type TableRow = Vec<Option<String>>;
+
+/// Таблицы индекса по уровням: уровень, таблица, идентификатор сущности и внешний ключ
+/// на вышестоящий уровень (в плоской таблице не нужен)
+const TABLE_LEVELS: &[(work_dcm::Level, &str, &str, Option<&str>)] = &[
+    (work_dcm::Level::Patient, "patients", "patients.patient_id", None),
+    (work_dcm::Level::Study, "study", "study.study_uid", Some("patient_id")),
+    (work_dcm::Level::Series, "series", "series.series_uid", Some("study_uid")),
+    (work_dcm::Level::Instance, "paths", "paths.path", Some("series_uid")),
+];
+
+/// `NumberOfStudyRelatedSeries` -> `number_of_study_related_series`
+fn to_snake_case(keyword: &str) -> String {
+    let mut name = String::with_capacity(keyword.len() + 8);
+    for (i, c) in keyword.chars().enumerate() {
+        if c.is_ascii_uppercase() && i != 0 {
+            name.push('_');
+        }
+        name.push(c.to_ascii_lowercase());
+    }
+    name
+}
+
+/// Ключ запроса C-FIND/C-MOVE: ключевое слово атрибута и значение для сопоставления
+/// (пустое значение — атрибут только возвращается)
+#[derive(Debug, Clone)]
+pub struct QueryKey {
+    pub keyword: String,
+    pub value: String,
+}
+
+/// Атрибуты, доступные для запросов к индексу: ключевое слово, уровень и выражение SQL.
+/// Количества и ModalitiesInStudy вычисляются по подчиненным записям
+const QUERY_ATTRIBUTES: &[(&str, work_dcm::Level, &str)] = &[
+    ("PatientID", work_dcm::Level::Patient, "patients.patient_id"),
+    ("PatientBirthDate", work_dcm::Level::Patient, "patients.birth_date"),
+    ("PatientSex", work_dcm::Level::Patient, "patients.sex"),
+    ("PatientAge", work_dcm::Level::Patient, "patients.age"),
+    ("NumberOfPatientRelatedStudies", work_dcm::Level::Patient,
+     "(SELECT count(*) FROM study AS s WHERE s.patient_id = patients.patient_id)"),
+    ("NumberOfPatientRelatedSeries", work_dcm::Level::Patient,
+     "(SELECT count(*) FROM series AS se JOIN study AS s ON s.study_uid = se.study_uid \
+       WHERE s.patient_id = patients.patient_id)"),
+    ("NumberOfPatientRelatedInstances", work_dcm::Level::Patient,
+     "(SELECT count(*) FROM paths AS p JOIN series AS se ON se.series_uid = p.series_uid \
+       JOIN study AS s ON s.study_uid = se.study_uid WHERE s.patient_id = patients.patient_id)"),
+    ("StudyInstanceUID", work_dcm::Level::Study, "study.study_uid"),
+    ("StudyDate", work_dcm::Level::Study, "study.study_date"),
+    ("StudyTime", work_dcm::Level::Study, "study.study_time"),
+    ("StudyDescription", work_dcm::Level::Study, "study.description"),
+    ("ModalitiesInStudy", work_dcm::Level::Study,
+     "(SELECT replace(group_concat(DISTINCT rtrim(se.modality)), ',', '\\') FROM series AS se \
+       WHERE se.study_uid = study.study_uid)"),
+    ("NumberOfStudyRelatedSeries", work_dcm::Level::Study,
+     "(SELECT count(*) FROM series AS se WHERE se.study_uid = study.study_uid)"),
+    ("NumberOfStudyRelatedInstances", work_dcm::Level::Study,
+     "(SELECT count(*) FROM paths AS p JOIN series AS se ON se.series_uid = p.series_uid \
+       WHERE se.study_uid = study.study_uid)"),
+    ("SeriesInstanceUID", work_dcm::Level::Series, "series.series_uid"),
+    ("Modality", work_dcm::Level::Series, "series.modality"),
+    ("SeriesDescription", work_dcm::Level::Series, "series.description"),
+    ("ProtocolName", work_dcm::Level::Series, "series.protocolname"),
+    ("BodyPartExamined", work_dcm::Level::Series, "series.bodypartexamined"),
+    ("NumberOfSeriesRelatedInstances", work_dcm::Level::Series,
+     "(SELECT count(*) FROM paths AS p WHERE p.series_uid = series.series_uid)"),
+    ("SOPInstanceUID", work_dcm::Level::Instance, "paths.sop_instance_uid"),
+    ("SOPClassUID", work_dcm::Level::Instance, "paths.sop_class_uid"),
+    ("InstanceNumber", work_dcm::Level::Instance, "paths.instance_number"),
+];
+
+/// Атрибуты идентификатора запроса, которые управляют запросом и не сопоставляются
+const QUERY_CONTROL_KEYWORDS: &[&str] = &["SpecificCharacterSet", "QueryRetrieveLevel", "RetrieveAETitle",
+    "TimezoneOffsetFromUTC"];
+
+/// Ключевые слова атрибутов индекса, доступных на уровне `level` (вместе с вышестоящими уровнями)
+pub fn query_keywords(level: work_dcm::Level) -> Vec<&'static str> {
+    QUERY_ATTRIBUTES.iter()
+        .filter(|(_, attribute_level, _)| *attribute_level as usize <= level as usize)
+        .map(|(keyword, _, _)| *keyword)
+        .collect()
+}
+
+/// Уникальный ключ уровня запроса
+fn query_unique_key(level: work_dcm::Level) -> &'static str {
+    match level {
+        work_dcm::Level::Patient => "PatientID",
+        work_dcm::Level::Study => "StudyInstanceUID",
+        work_dcm::Level::Series => "SeriesInstanceUID",
+        work_dcm::Level::Instance => "SOPInstanceUID",
+    }
+}
+
+/// Таблицы запроса уровня `level` вместе с таблицами вышестоящих уровней
+fn query_from(level: work_dcm::Level) -> &'static str {
+    match level {
+        work_dcm::Level::Patient => "FROM patients",
+        work_dcm::Level::Study => "FROM study JOIN patients ON patients.patient_id = study.patient_id",
+        work_dcm::Level::Series => "FROM series JOIN study ON study.study_uid = series.study_uid \
+                                    JOIN patients ON patients.patient_id = study.patient_id",
+        work_dcm::Level::Instance => "FROM paths JOIN series ON series.series_uid = paths.series_uid \
+                                      JOIN study ON study.study_uid = series.study_uid \
+                                      JOIN patients ON patients.patient_id = study.patient_id",
+    }
+}
+
+/// Строит условие WHERE по ключам запроса уровня `level` и ключам вышестоящих уровней.
+/// Сопоставление по правилам C-FIND: список UID через `\`, шаблон с `*` и `?`,
+/// диапазон дат и времени `начало-конец`, иначе точное совпадение.
+/// Непустой ключ, по которому индекс не может отобрать (атрибут не индексируется, вычисляется
+/// или относится к нижестоящему уровню), не совпадает ни с чем: иначе запрос вернул бы все сущности
+fn query_filter(level: work_dcm::Level, keys: &[QueryKey], values: &mut Vec<String>) -> String {
+    let mut conditions: Vec<String> = Vec::new();
+    for key in keys {
+        let value = key.value.trim_end_matches(['\0', ' ']);
+        if value.is_empty() || value == "*" || QUERY_CONTROL_KEYWORDS.contains(&key.keyword.as_str()) {
+            continue;
+        }
+        let expression = match QUERY_ATTRIBUTES.iter()
+            .find(|(keyword, attribute_level, expression)| *keyword == key.keyword
+                && *attribute_level as usize <= level as usize && !expression.starts_with('(')) {
+            Some((_, _, expression)) => format!("rtrim({})", expression),
+            None if key.keyword == "ModalitiesInStudy" && level as usize >= work_dcm::Level::Study as usize => {
+                let placeholders = push_values(values, value.split('\\'));
+                conditions.push(format!(
+                    "EXISTS (SELECT 1 FROM series AS se WHERE se.study_uid = study.study_uid \
+                     AND rtrim(se.modality) IN ({}))", placeholders));
+                continue;
+            }
+            None => {
+                conditions.push("0".to_string());
+                continue;
+            }
+        };
+        let is_range = matches!(key.keyword.as_str(), "StudyDate" | "StudyTime" | "PatientBirthDate")
+            && value.contains('-');
+        if is_range {
+            let (from, to) = value.split_once('-').unwrap_or((value, value));
+            if !from.is_empty() {
+                values.push(from.trim().to_string());
+                conditions.push(format!("{} >= ?{}", expression, values.len()));
+            }
+            if !to.is_empty() {
+                // Верхняя граница включает и значения с долями секунды (`130000.5` для `-130000`)
+                values.push(format!("{}~", to.trim()));
+                conditions.push(format!("{} <= ?{}", expression, values.len()));
+            }
+        } else if value.contains('\\') {
+            let placeholders = push_values(values, value.split('\\'));
+            conditions.push(format!("{} IN ({})", expression, placeholders));
+        } else if value.contains(['*', '?']) {
+            values.push(value.to_string());
+            conditions.push(format!("{} GLOB ?{}", expression, values.len()));
+        } else {
+            values.push(value.to_string());
+            conditions.push(format!("{} = ?{}", expression, values.len()));
+        }
+    }
+    if conditions.is_empty() {
+        String::new()
+    } else {
+        format!("WHERE {}", conditions.join(" AND "))
+    }
+}
+
+/// Добавляет значения в параметры запроса и возвращает их заполнители через запятую
+fn push_values<'a>(values: &mut Vec<String>, items: impl Iterator<Item = &'a str>) -> String {
+    items.map(|item| {
+        values.push(item.trim().to_string());
+        format!("?{}", values.len())
+    })
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// Преобразует пользовательский запрос в запрос FTS5:
+/// каждое слово становится фразой с поиском по префиксу, все слова должны присутствовать.
+/// Например `l-spine t2` -> `"l-spine"* "t2"*`
+fn to_fts_query(query: &str) -> String {
+    query.split_whitespace()
+        .map(|term| format!("\"{}\"*", term.replace('"', "\"\"")))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dicom::core::{DataElement, PrimitiveValue};
+    use dicom::object::InMemDicomObject;
+    use crate::work_json;
+
+    /// Набор данных из пар (ключевое слово, значение); VR берется из словаря
+    fn dataset(attributes: &[(&str, &str)]) -> InMemDicomObject {
+        let mut obj = InMemDicomObject::new_empty();
+        for (keyword, value) in attributes {
+            let tag = work_dcm::tag_by_keyword(keyword).unwrap();
+            obj.put(DataElement::new(tag, work_json::vr_by_tag(tag), PrimitiveValue::from(*value)));
+        }
+        obj
+    }
+
+    /// Индекс в памяти: два пациента, три исследования, по серии в каждом и по два экземпляра в серии
+    fn index() -> Connection {
+        let conn = Connection::create_dcm_tables(true).unwrap();
+        let studies = [
+            ("P1", "1.2.1", "20150301", "101500", "Head CT", "CT"),
+            ("P1", "1.2.2", "20160720", "083000", "Thorax CT", "CT"),
+            ("P2", "1.2.3", "20160801", "140000", "Knee MR", "MR"),
+        ];
+        for (patient_id, study_uid, study_date, study_time, description, modality) in studies {
+            for instance in 1..=2 {
+                let series_uid = format!("{}.1", study_uid);
+                let sop_instance_uid = format!("{}.{}", series_uid, instance);
+                let obj = dataset(&[
+                    ("PatientID", patient_id), ("PatientSex", "F"),
+                    ("StudyInstanceUID", study_uid), ("StudyDate", study_date), ("StudyTime", study_time),
+                    ("StudyDescription", description),
+                    ("SeriesInstanceUID", &series_uid), ("Modality", modality),
+                    ("SOPInstanceUID", &sop_instance_uid), ("SOPClassUID", "1.2.840.10008.5.1.4.1.1.2"),
+                    ("InstanceNumber", &instance.to_string()),
+                ]);
+                conn.insert_dcm(&work_dcm::MetaDcm::from_with_tags(&obj, &format!("/data/{}.dcm", sop_instance_uid), &[]));
+            }
+        }
+        conn
+    }
+
+    fn keys(pairs: &[(&str, &str)]) -> Vec<QueryKey> {
+        pairs.iter().map(|(keyword, value)| QueryKey { keyword: keyword.to_string(), value: value.to_string() }).collect()
+    }
+
+    fn study_uids(conn: &Connection, pairs: &[(&str, &str)]) -> Vec<String> {
+        conn.query_entities(work_dcm::Level::Study, &keys(pairs), None, 0).unwrap().into_iter()
+            .map(|entity| entity["StudyInstanceUID"].clone())
+            .collect()
+    }
+
+    #[test]
+    fn query_matches_single_values_lists_and_wildcards() {
+        let conn = index();
+        assert_eq!(study_uids(&conn, &[]), ["1.2.1", "1.2.2", "1.2.3"]);
+        assert_eq!(study_uids(&conn, &[("PatientID", "P1")]), ["1.2.1", "1.2.2"]);
+        assert_eq!(study_uids(&conn, &[("StudyInstanceUID", "1.2.1\\1.2.3")]), ["1.2.1", "1.2.3"]);
+        assert_eq!(study_uids(&conn, &[("StudyDescription", "*CT")]), ["1.2.1", "1.2.2"]);
+        assert_eq!(study_uids(&conn, &[("StudyDescription", "?nee*")]), ["1.2.3"]);
+        assert_eq!(study_uids(&conn, &[("PatientID", "*"), ("StudyDescription", "")]), ["1.2.1", "1.2.2", "1.2.3"]);
+        assert_eq!(study_uids(&conn, &[("ModalitiesInStudy", "MR\\US")]), ["1.2.3"]);
+
+        let entities = conn.query_entities(work_dcm::Level::Study,
+                                           &keys(&[("PatientID", "P2"), ("NumberOfStudyRelatedInstances", "")]),
+                                           None, 0).unwrap();
+        assert_eq!(entities[0]["NumberOfStudyRelatedInstances"], "2");
+        assert_eq!(entities[0]["PatientID"], "P2");
+    }
+
+    #[test]
+    fn query_matches_date_and_time_ranges() {
+        let conn = index();
+        assert_eq!(study_uids(&conn, &[("StudyDate", "20160101-")]), ["1.2.2", "1.2.3"]);
+        assert_eq!(study_uids(&conn, &[("StudyDate", "-20160720")]), ["1.2.1", "1.2.2"]);
+        assert_eq!(study_uids(&conn, &[("StudyDate", "20160701-20160731")]), ["1.2.2"]);
+        assert_eq!(study_uids(&conn, &[("StudyTime", "-101500")]), ["1.2.1", "1.2.2"]);
+    }
+
+    #[test]
+    fn query_pages_follow_unique_key_order() {
+        let conn = index();
+        let page = |limit, offset| -> Vec<String> {
+            conn.query_entities(work_dcm::Level::Study, &[], limit, offset).unwrap().into_iter()
+                .map(|entity| entity["StudyInstanceUID"].clone())
+                .collect()
+        };
+        assert_eq!(page(Some(2), 0), ["1.2.1", "1.2.2"]);
+        assert_eq!(page(Some(2), 2), ["1.2.3"]);
+        assert_eq!(page(None, 1), ["1.2.2", "1.2.3"]);
+        assert!(page(Some(0), 0).is_empty());
+    }
+
+    #[test]
+    fn unsupported_keys_match_nothing() {
+        let conn = index();
+        // Атрибуты, которых нет в индексе, вычисляемые и нижестоящего уровня
+        assert!(study_uids(&conn, &[("PatientName", "DOE")]).is_empty());
+        assert!(study_uids(&conn, &[("AccessionNumber", "A*")]).is_empty());
+        assert!(study_uids(&conn, &[("NumberOfStudyRelatedSeries", "1")]).is_empty());
+        assert!(study_uids(&conn, &[("Modality", "CT")]).is_empty());
+        assert!(conn.query_paths(work_dcm::Level::Study, &keys(&[("PatientName", "DOE")])).unwrap().is_empty());
+        // Без значения такие атрибуты только возвращаются, управляющие атрибуты не сопоставляются
+        assert_eq!(study_uids(&conn, &[("PatientName", ""), ("AccessionNumber", "*")]).len(), 3);
+        assert_eq!(study_uids(&conn, &[("QueryRetrieveLevel", "STUDY"), ("SpecificCharacterSet", "ISO_IR 192"),
+                                       ("PatientID", "P2")]), ["1.2.3"]);
+    }
+
+    #[test]
+    fn query_paths_returns_files_of_matching_entities() {
+        let conn = index();
+        assert_eq!(conn.query_paths(work_dcm::Level::Series, &keys(&[("SeriesInstanceUID", "1.2.2.1")])).unwrap(),
+                   ["/data/1.2.2.1.1.dcm", "/data/1.2.2.1.2.dcm"]);
+        assert_eq!(conn.query_paths(work_dcm::Level::Instance,
+                                    &keys(&[("StudyInstanceUID", "1.2.3"), ("SOPInstanceUID", "1.2.3.1.2")])).unwrap(),
+                   ["/data/1.2.3.1.2.dcm"]);
+        assert_eq!(conn.query_paths(work_dcm::Level::Patient, &keys(&[("PatientID", "P1")])).unwrap().len(), 4);
+        assert_eq!(conn.query_paths(work_dcm::Level::Study, &keys(&[("StudyDate", "2016*")])).unwrap().len(), 4);
+    }
+
+    /// Индекс в памяти с сериями, описания которых содержат дефисы, кавычки и кириллицу
+    fn search_index() -> Connection {
+        let conn = Connection::create_dcm_tables(true).unwrap();
+        let series = [
+            ("1.3.1", "L-SPINE", "Sag T2 TSE", "t2_tse_sag", "LSPINE"),
+            ("1.3.2", "L-SPINE", "Ax T1 3\"", "t1_tse_tra", "LSPINE"),
+            ("1.3.3", "Голова КТ", "Голова 5мм", "Head_Routine", "HEAD"),
+        ];
+        for (study_uid, study_description, series_description, protocol_name, body_part) in series {
+            let series_uid = format!("{}.1", study_uid);
+            let sop_instance_uid = format!("{}.1", series_uid);
+            let obj = dataset(&[
+                ("PatientID", "P3"), ("StudyInstanceUID", study_uid), ("StudyDescription", study_description),
+                ("SeriesInstanceUID", &series_uid), ("Modality", "MR"), ("SeriesDescription", series_description),
+                ("ProtocolName", protocol_name), ("BodyPartExamined", body_part),
+                ("SOPInstanceUID", &sop_instance_uid), ("SOPClassUID", "1.2.840.10008.5.1.4.1.1.4"),
+            ]);
+            conn.insert_dcm(&work_dcm::MetaDcm::from_with_tags(&obj, &format!("/data/{}.dcm", sop_instance_uid), &[]));
+        }
+        conn
+    }
+
+    fn found_series(conn: &Connection, query: &str) -> Vec<String> {
+        let mut uids: Vec<String> = conn.search_series(query, 10).unwrap().into_iter()
+            .map(|hit| hit.series_uid)
+            .collect();
+        uids.sort();
+        uids
+    }
+
+    #[test]
+    fn fts_query_quotes_every_term() {
+        assert_eq!(to_fts_query("l-spine t2"), "\"l-spine\"* \"t2\"*");
+        assert_eq!(to_fts_query("  3\"  "), "\"3\"\"\"*");
+        assert_eq!(to_fts_query("t2* -sag"), "\"t2*\"* \"-sag\"*");
+        assert_eq!(to_fts_query("Голова"), "\"Голова\"*");
+        assert_eq!(to_fts_query(""), "");
+        assert_eq!(to_fts_query(" \t "), "");
+    }
+
+    #[test]
+    fn search_handles_fts_syntax_characters() {
+        let conn = search_index();
+        // Каждое слово ищется по префиксу, все слова должны присутствовать
+        assert_eq!(found_series(&conn, "l-spine"), ["1.3.1.1", "1.3.2.1"]);
+        assert_eq!(found_series(&conn, "l-spine t2"), ["1.3.1.1"]);
+        assert_eq!(found_series(&conn, "lsp"), ["1.3.1.1", "1.3.2.1"]);
+        // Операторы FTS5 в запросе пользователя не интерпретируются
+        assert_eq!(found_series(&conn, "t2*"), ["1.3.1.1"]);
+        assert_eq!(found_series(&conn, "-sag"), ["1.3.1.1"]);
+        assert_eq!(found_series(&conn, "spine AND"), Vec::<String>::new());
+        assert_eq!(found_series(&conn, "(spine OR"), Vec::<String>::new());
+        assert_eq!(found_series(&conn, "(spine)"), ["1.3.1.1", "1.3.2.1"]);
+        assert_eq!(found_series(&conn, "3\""), ["1.3.2.1"]);
+        assert_eq!(found_series(&conn, "\""), Vec::<String>::new());
+        // Кириллица ищется без учета регистра
+        assert_eq!(found_series(&conn, "голова"), ["1.3.3.1"]);
+        assert_eq!(found_series(&conn, "ГОЛ 5мм"), ["1.3.3.1"]);
+        // Пустой запрос ничего не находит, но и не выбирает все серии
+        assert!(found_series(&conn, "").is_empty());
+        assert!(found_series(&conn, "   ").is_empty());
+        assert_eq!(conn.select_paths(Some("head"), &[], &[]).unwrap(), ["/data/1.3.3.1.1.dcm"]);
+        assert!(conn.select_paths(Some(" "), &[], &[]).unwrap().is_empty());
+        assert_eq!(conn.select_series(Some("t1"), &[], &[]).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn extra_tags_round_trip_through_the_index() {
+        let conn = Connection::create_dcm_tables(true).unwrap();
+        let index_tags: Vec<work_dcm::IndexTag> = ["patient:PatientWeight", "study:InstitutionName",
+                                                   "series:Manufacturer", "instance:SliceThickness"]
+            .iter().map(|spec| spec.parse().unwrap()).collect();
+        for (instance, thickness) in [(1, "1.25"), (2, "2.5")] {
+            let obj = dataset(&[
+                ("PatientID", "P7"), ("PatientWeight", "70"),
+                ("StudyInstanceUID", "1.7"), ("InstitutionName", "Клиника 'Север'"),
+                ("SeriesInstanceUID", "1.7.1"), ("Modality", "CT"), ("Manufacturer", "ACME"),
+                ("SOPInstanceUID", &format!("1.7.1.{}", instance)), ("SliceThickness", thickness),
+            ]);
+            conn.insert_dcm(&work_dcm::MetaDcm::from_with_tags(&obj, &format!("/data/{}.dcm", instance), &index_tags));
+        }
+        let extra = |level, entity_id| conn.get_extra_tags(level, entity_id).unwrap();
+        assert_eq!(extra(work_dcm::Level::Patient, "P7"), BTreeMap::from([("PatientWeight".to_string(), "70".to_string())]));
+        assert_eq!(extra(work_dcm::Level::Study, "1.7")["InstitutionName"], "Клиника 'Север'");
+        assert_eq!(extra(work_dcm::Level::Series, "1.7.1")["Manufacturer"], "ACME");
+        assert!(extra(work_dcm::Level::Series, "1.7").is_empty());
+        let instances = conn.get_instances_extra_tags("1.7.1").unwrap();
+        assert_eq!(instances.len(), 2);
+        assert_eq!(instances["/data/1.dcm"]["SliceThickness"], "1.25");
+        assert_eq!(instances["/data/2.dcm"]["SliceThickness"], "2.5");
+
+        // Столбцы плоской таблицы уровня серии: теги пациента, исследования и серии, без тегов файлов
+        let columns = conn.table_columns(work_dcm::Level::Series).unwrap();
+        let mut row_values = Vec::new();
+        conn.for_each_table_row(work_dcm::Level::Series, &columns, &mut |row| {
+            row_values = row;
+            Ok(())
+        }).unwrap();
+        let value = |name: &str| columns.iter().position(|column| column.name == name)
+            .and_then(|index| row_values[index].clone());
+        assert_eq!(value("PatientWeight").as_deref(), Some("70"));
+        assert_eq!(value("InstitutionName").as_deref(), Some("Клиника 'Север'"));
+        assert_eq!(value("Manufacturer").as_deref(), Some("ACME"));
+        assert!(columns.iter().all(|column| column.name != "SliceThickness"));
+
+        // Удаление файла удаляет его теги, удаление последнего файла - теги всех уровней
+        conn.remove_path("/data/1.dcm").unwrap();
+        assert_eq!(conn.get_instances_extra_tags("1.7.1").unwrap().len(), 1);
+        conn.remove_path("/data/2.dcm").unwrap();
+        assert!(extra(work_dcm::Level::Series, "1.7.1").is_empty());
+        assert!(extra(work_dcm::Level::Patient, "P7").is_empty());
+        assert_eq!(count_rows(&conn, "extra_tags"), 0);
+    }
+
+    fn count_rows(conn: &Connection, table: &str) -> i64 {
+        conn.query_row(&format!("SELECT count(*) FROM {};", table), [], |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn result_is_written_as_a_tree_or_as_series_lines() {
+        let conn = index();
+        let mut output = Vec::new();
+        let counts = conn.export_result(&mut output, false).unwrap();
+        assert_eq!(counts.iter().map(|count| (count.patient_id.as_str(), count.studies, count.series, count.paths))
+                       .collect::<Vec<_>>(), [("P1", 2, 2, 4), ("P2", 1, 1, 2)]);
+        let tree: serde_json::Value = serde_json::from_slice(&output).unwrap();
+        assert_eq!(tree["scans"], serde_json::json!([]));
+        let patients = tree["result"].as_array().unwrap();
+        assert_eq!(patients.len(), 2);
+        assert_eq!(patients[0]["patient_id"], "P1");
+        assert_eq!(patients[0]["studies"][1]["study_uid"], "1.2.2");
+        assert_eq!(patients[0]["studies"][1]["series"][0]["paths"], serde_json::json!(["/data/1.2.2.1.1.dcm", "/data/1.2.2.1.2.dcm"]));
+        assert_eq!(patients[1]["studies"][0]["series"][0]["modality"], "MR");
+
+        // JSON Lines: строка на серию с ее исследованием и пациентом
+        let mut output = Vec::new();
+        conn.export_result(&mut output, true).unwrap();
+        let lines: Vec<serde_json::Value> = String::from_utf8(output).unwrap().lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines.iter().map(|line| line["series"]["series_uid"].as_str().unwrap()).collect::<Vec<_>>(),
+                   ["1.2.1.1", "1.2.2.1", "1.2.3.1"]);
+        assert_eq!((lines[2]["patient"]["patient_id"].as_str(), lines[2]["study"]["description"].as_str()),
+                   (Some("P2"), Some("Knee MR")));
+        assert!(lines[0]["study"].get("series").is_none());
+        assert_eq!(lines[0]["series"]["paths"].as_array().unwrap().len(), 2);
+    }
+
+    /// Поток, который принимает `limit` байт, а затем возвращает ошибку
+    struct LimitedWriter {
+        written: Vec<u8>,
+        limit: usize,
+    }
+
+    impl Write for LimitedWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.written.len() + buf.len() > self.limit {
+                return Err(io::Error::other("disk full"));
+            }
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn series_lines_are_streamed() {
+        let conn = index();
+        let mut full = Vec::new();
+        conn.export_result(&mut full, true).unwrap();
+        let first_line = full.iter().position(|byte| *byte == b'\n').unwrap() + 1;
+        // Первая серия записана до того, как прочитаны следующие: ошибка записи второй строки
+        // прерывает экспорт, а первая строка уже в потоке
+        let mut writer = LimitedWriter { written: Vec::new(), limit: first_line + 10 };
+        assert!(conn.export_result(&mut writer, true).is_err());
+        assert_eq!(writer.written[..first_line], full[..first_line]);
+    }
 }
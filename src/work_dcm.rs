@@ -3,17 +3,169 @@ use dicom::core::dicom_value;
 use dicom::core::value as dcm_core_value;
 use dicom::object::mem::{InMemElement};
 use dicom::object::open_file as dcm_core_open_file;
+use dicom::object::from_reader as dcm_core_from_reader;
 
-use dicom::object::{DefaultDicomObject, Result};
+use dicom::object::{DefaultDicomObject, FileMetaTable, FileMetaTableBuilder, InMemDicomObject, Result};
+use dicom::object::StandardDataDictionary;
+use dicom::core::dictionary::{DataDictionary, DictionaryEntry};
+use dicom::transfer_syntax::TransferSyntaxRegistry;
+use dicom::encoding::TransferSyntaxIndex;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
 use std::path;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
 use std::collections::HashMap;
+use std::str::FromStr;
+use serde::Serialize;
+use crate::work_archive;
 
 
-pub struct MetaDcm<'a> {
+pub struct MetaDcm {
     patient: MetaPatient,
     study: MetaStudy,
     series: MetaSeries,
-    path: &'a str,
+    instance: MetaInstance,
+    extra: Vec<MetaExtra>,
+    path: String,
+}
+
+/// Уровень иерархии DICOM, к которому относится атрибут
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Patient,
+    Study,
+    Series,
+    Instance,
+}
+
+impl Level {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Level::Patient => "patient",
+            Level::Study => "study",
+            Level::Series => "series",
+            Level::Instance => "instance",
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "patient" => Ok(Level::Patient),
+            "study" => Ok(Level::Study),
+            "series" => Ok(Level::Series),
+            "instance" => Ok(Level::Instance),
+            _ => Err(format!("unknown level '{}', expected patient, study, series or instance", s)),
+        }
+    }
+}
+
+/// Дополнительный тег для индексации, задается в виде `уровень:тег`,
+/// где тег — ключевое слово словаря DICOM (`Manufacturer`) или `(gggg,eeee)`
+#[derive(Debug, Clone)]
+pub struct IndexTag {
+    pub level: Level,
+    pub tag: Tag,
+    pub keyword: String,
+}
+
+impl FromStr for IndexTag {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (level, tag) = s.split_once(':')
+            .ok_or_else(|| format!("expected 'level:tag', got '{}'", s))?;
+        let level = level.parse::<Level>()?;
+        let tag = tag.trim();
+        let tag = match Tag::from_str(tag) {
+            Ok(tag) => tag,
+            Err(_) => tag_by_keyword(tag)
+                .ok_or_else(|| format!("unknown tag keyword '{}'", tag))?,
+        };
+        let keyword = keyword_by_tag(tag)
+            .map(|keyword| keyword.to_string())
+            .unwrap_or_else(|| tag.to_string());
+        Ok(IndexTag { level, tag, keyword })
+    }
+}
+
+/// Признак, по которому серия делится на подобъемы (`--split-series`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SplitKey {
+    /// Image Orientation (Patient), косинусы округляются до сотых
+    Orientation,
+    ImageType,
+    /// Echo Numbers
+    Echo,
+    /// Temporal Position Identifier
+    Temporal,
+    AcquisitionNumber,
+    /// Rows и Columns
+    Dimensions,
+}
+
+impl SplitKey {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SplitKey::Orientation => "orientation",
+            SplitKey::ImageType => "image-type",
+            SplitKey::Echo => "echo",
+            SplitKey::Temporal => "temporal",
+            SplitKey::AcquisitionNumber => "acquisition",
+            SplitKey::Dimensions => "dimensions",
+        }
+    }
+
+    /// Значение признака в наборе данных (пустое, если атрибута нет)
+    fn value(&self, obj: &InMemDicomObject) -> String {
+        let value = |keyword: &str| obj.element_by_name(keyword).ok()
+            .and_then(|element| element.value().to_str().ok().map(|value| value.trim().to_string()))
+            .unwrap_or_default();
+        match self {
+            SplitKey::Orientation => value("ImageOrientationPatient").split('\\')
+                .map(|cosine| cosine.trim().parse::<f64>()
+                    .map(|cosine| format!("{:.2}", cosine + 0.0).replace("-0.00", "0.00"))
+                    .unwrap_or_default())
+                .collect::<Vec<String>>()
+                .join("\\"),
+            SplitKey::ImageType => value("ImageType"),
+            SplitKey::Echo => value("EchoNumbers"),
+            SplitKey::Temporal => value("TemporalPositionIdentifier"),
+            SplitKey::AcquisitionNumber => value("AcquisitionNumber"),
+            SplitKey::Dimensions => format!("{}x{}", value("Rows"), value("Columns")),
+        }
+    }
+}
+
+impl FromStr for SplitKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "orientation" => Ok(SplitKey::Orientation),
+            "image-type" => Ok(SplitKey::ImageType),
+            "echo" => Ok(SplitKey::Echo),
+            "temporal" => Ok(SplitKey::Temporal),
+            "acquisition" => Ok(SplitKey::AcquisitionNumber),
+            "dimensions" => Ok(SplitKey::Dimensions),
+            _ => Err(format!("unknown split criterion '{}', expected orientation, image-type, echo, \
+                              temporal, acquisition or dimensions", s)),
+        }
+    }
+}
+
+/// Значение дополнительного тега, извлеченное из файла
+pub struct MetaExtra {
+    pub level: Level,
+    pub tag: String,
+    pub keyword: String,
+    pub value: String,
 }
 
 #[derive(Debug)]
@@ -47,58 +199,142 @@ pub struct MetaSeries {
     pub exposuretime: String,
     pub rescaleintercept: String,
     pub description: String,
+    pub protocolname: String,
+    pub bodypartexamined: String,
+    /// Для подобъема разделенной серии: исходный Series Instance UID (`series_uid` — логический)
+    pub source_series_uid: Option<String>,
+    /// Для подобъема разделенной серии: значения признаков разделения
+    pub volume_key: Option<String>,
 }
 
-impl MetaDcm<'_> {
-    pub fn from<'a>(obj: &'a DefaultDicomObject, path: &'a str) -> MetaDcm<'a> {
+/// Атрибуты уровня instance, по которым выполняются запросы C-FIND уровня IMAGE
+/// и анализируется геометрия серии
+pub struct MetaInstance {
+    pub sop_instance_uid: String,
+    pub sop_class_uid: String,
+    pub instance_number: String,
+    pub image_position_patient: String,
+    pub image_orientation_patient: String,
+    pub pixel_spacing: String,
+    pub rows: String,
+    pub columns: String,
+}
+
+impl MetaDcm {
+    pub fn from(obj: &DefaultDicomObject, path: &str) -> MetaDcm {
+        MetaDcm::from_with_tags(obj, path, &[])
+    }
+
+    /// Помимо фиксированного набора атрибутов извлекает дополнительные теги `index_tags`.
+    /// Отсутствующие в файле теги пропускаются.
+    /// Принимает как прочитанный файл, так и набор данных без File Meta (например, записи DICOMDIR).
+    pub fn from_with_tags(obj: &InMemDicomObject, path: &str,
+                          index_tags: &[IndexTag]) -> MetaDcm {
         MetaDcm {
             patient: MetaPatient {
-                patient_id: get_value_for_tag(obj, Tag(0x0010, 0x0020)),
-                birth_date: get_value_for_tag(obj, Tag(0x0010, 0x0030)),
-                sex: get_value_for_tag(obj, Tag(0x0010, 0x0040)),
-                age: get_value_for_tag(obj, Tag(0x0010, 0x1010)),
+                patient_id: get_value_for_keyword(obj, "PatientID"),
+                birth_date: get_value_for_keyword(obj, "PatientBirthDate"),
+                sex: get_value_for_keyword(obj, "PatientSex"),
+                age: get_value_for_keyword(obj, "PatientAge"),
             },
             study: MetaStudy {
-                study_uid: get_value_for_tag(obj, Tag(0x0020, 0x000D)),
-                study_date: get_value_for_tag(obj, Tag(0x0008, 0x0020)),
-                study_time: get_value_for_tag(obj, Tag(0x0008, 0x0030)),
-                description: get_value_for_tag(obj, Tag(0x0008, 0x1030)),
+                study_uid: get_value_for_keyword(obj, "StudyInstanceUID"),
+                study_date: get_value_for_keyword(obj, "StudyDate"),
+                study_time: get_value_for_keyword(obj, "StudyTime"),
+                description: get_value_for_keyword(obj, "StudyDescription"),
             },
             series: MetaSeries {
-                series_uid: get_value_for_tag(obj, Tag(0x0020, 0x000E)),
-                modality: get_value_for_tag(obj, Tag(0x0008, 0x0060)),
-                instancenumber: get_value_for_tag(obj, Tag(0x0020, 0x0013)),
-                imagepositionpatient: get_value_for_tag(obj, Tag(0x0020, 0x0032)),
-                imageorientationpatient: get_value_for_tag(obj, Tag(0x0020, 0x0037)),
-                pixelspacing: get_value_for_tag(obj, Tag(0x0028, 0x0030)),
-                numberofframes: get_value_for_tag(obj, Tag(0x0028, 0x0008)),
-                xraytubecurrent: get_value_for_tag(obj, Tag(0x0018, 0x1151)),
-                kvp: get_value_for_tag(obj, Tag(0x0018, 0x0060)),
-                filtertype: get_value_for_tag(obj, Tag(0x0018, 0x1160)),
-                rows: get_value_for_tag(obj, Tag(0x0028, 0x0010)),
-                columns: get_value_for_tag(obj, Tag(0x0028, 0x0011)),
-                exposuretime: get_value_for_tag(obj, Tag(0x0018, 0x1150)),
-                rescaleintercept: get_value_for_tag(obj, Tag(0x0028, 0x1052)),
-                description: get_value_for_tag(obj, Tag(0x0080, 0x103E)),
+                series_uid: get_value_for_keyword(obj, "SeriesInstanceUID"),
+                modality: get_value_for_keyword(obj, "Modality"),
+                instancenumber: get_value_for_keyword(obj, "InstanceNumber"),
+                imagepositionpatient: get_value_for_keyword(obj, "ImagePositionPatient"),
+                imageorientationpatient: get_value_for_keyword(obj, "ImageOrientationPatient"),
+                pixelspacing: get_value_for_keyword(obj, "PixelSpacing"),
+                numberofframes: get_value_for_keyword(obj, "NumberOfFrames"),
+                xraytubecurrent: get_value_for_keyword(obj, "XRayTubeCurrent"),
+                kvp: get_value_for_keyword(obj, "KVP"),
+                filtertype: get_value_for_keyword(obj, "FilterType"),
+                rows: get_value_for_keyword(obj, "Rows"),
+                columns: get_value_for_keyword(obj, "Columns"),
+                exposuretime: get_value_for_keyword(obj, "ExposureTime"),
+                rescaleintercept: get_value_for_keyword(obj, "RescaleIntercept"),
+                description: get_value_for_keyword(obj, "SeriesDescription"),
+                protocolname: get_value_for_keyword(obj, "ProtocolName"),
+                bodypartexamined: get_value_for_keyword(obj, "BodyPartExamined"),
+                source_series_uid: None,
+                volume_key: None,
             },
-            path: path,
+            instance: MetaInstance {
+                sop_instance_uid: get_value_for_keyword(obj, "SOPInstanceUID"),
+                sop_class_uid: get_value_for_keyword(obj, "SOPClassUID"),
+                instance_number: get_value_for_keyword(obj, "InstanceNumber"),
+                image_position_patient: get_value_for_keyword(obj, "ImagePositionPatient"),
+                image_orientation_patient: get_value_for_keyword(obj, "ImageOrientationPatient"),
+                pixel_spacing: get_value_for_keyword(obj, "PixelSpacing"),
+                rows: get_value_for_keyword(obj, "Rows"),
+                columns: get_value_for_keyword(obj, "Columns"),
+            },
+            extra: index_tags.iter()
+                .filter_map(|index_tag| {
+                    let value = obj.element(index_tag.tag).ok()?.value().to_str().ok()?;
+                    Some(MetaExtra {
+                        level: index_tag.level,
+                        tag: index_tag.tag.to_string(),
+                        keyword: index_tag.keyword.clone(),
+                        value: value.trim().to_string(),
+                    })
+                })
+                .collect(),
+            path: path.to_string(),
         }
     }
+    /// Относит файл к подобъему серии по признакам `split_by`: подобъем индексируется как
+    /// логическая серия с UID, однозначно полученным из исходного UID и значений признаков
+    pub fn split_series(&mut self, obj: &InMemDicomObject, split_by: &[SplitKey]) {
+        if split_by.is_empty() {
+            return;
+        }
+        let volume_key = split_by.iter()
+            .map(|key| format!("{}={}", key.as_str(), key.value(obj)))
+            .collect::<Vec<String>>()
+            .join(";");
+        let source_series_uid = std::mem::take(&mut self.series.series_uid);
+        self.series.series_uid = derive_uid(&[&source_series_uid, &volume_key]);
+        self.series.source_series_uid = Some(source_series_uid);
+        self.series.volume_key = Some(volume_key);
+    }
+
     pub fn get_patient_ref(&self) -> &MetaPatient { &self.patient }
     pub fn get_study_ref(&self) -> &MetaStudy { &self.study }
     pub fn get_series_ref(&self) -> &MetaSeries { &self.series }
+    pub fn get_instance_ref(&self) -> &MetaInstance { &self.instance }
+    pub fn get_extra_ref(&self) -> &[MetaExtra] { &self.extra }
     pub fn get_path_ref(&self) -> &str { &self.path }
 }
 
-fn get_value_for_tag(obj: &DefaultDicomObject, tag: Tag) -> String {
-    match obj.element(tag) {
+/// Возвращает значение атрибута по его ключевому слову в словаре DICOM (например `SeriesDescription`).
+/// Если атрибут отсутствует или не может быть представлен строкой (последовательность), возвращает "Unknown".
+fn get_value_for_keyword(obj: &InMemDicomObject, keyword: &str) -> String {
+    match obj.element_by_name(keyword) {
         Ok(el) => {
-            String::from(el.value().to_str().unwrap())
+            el.value().to_str()
+                .map(|value| value.to_string())
+                .unwrap_or_else(|_| String::from("Unknown"))
         }
         Err(_) => { String::from("Unknown") }
     }
 }
 
+/// Возвращает тег по ключевому слову словаря DICOM
+pub fn tag_by_keyword(keyword: &str) -> Option<Tag> {
+    StandardDataDictionary.by_name(keyword).map(|entry| entry.tag())
+}
+
+/// Возвращает ключевое слово словаря DICOM для тега
+pub fn keyword_by_tag(tag: Tag) -> Option<&'static str> {
+    StandardDataDictionary.by_tag(tag).map(|entry| entry.alias())
+}
+
 pub fn depersonalize_obj(obj: &mut DefaultDicomObject) {
     let mut tags_for_depersonalization: HashMap<Tag, &str> = HashMap::new();
     // Patient's Name Attribute
@@ -113,8 +349,8 @@ pub fn depersonalize_obj(obj: &mut DefaultDicomObject) {
     tags_for_depersonalization.insert(Tag(0x0010, 0x0034), "19000101");
     // Patient Comments Attribute
     tags_for_depersonalization.insert(Tag(0x0010, 0x4000), "Unknown Comments");
-    // Person's Phone
-    tags_for_depersonalization.insert(Tag(0x0040, 0x1102), "Unknown Phone");
+    // Person's Telephone Numbers Attribute
+    tags_for_depersonalization.insert(Tag(0x0040, 0x1103), "Unknown Phone");
     // Institution Address Attribute
     tags_for_depersonalization.insert(Tag(0x0008, 0x0081), "Unknown Address");
     // Institution Name Attribute
@@ -151,14 +387,539 @@ fn replace_element_in_dcm_obj(obj: &mut DefaultDicomObject, tag: Tag, values: &s
     };
 }
 
-pub fn save_dcm(obj: &DefaultDicomObject, save_in: &String) ->Result<()> {
-    obj.write_to_file(path::Path::new(save_in.as_str()))?;
+/// Implicit VR Little Endian — синтаксис передачи по умолчанию (и наборов команд DIMSE)
+pub const IMPLICIT_VR_LITTLE_ENDIAN: &str = "1.2.840.10008.1.2";
+/// Explicit VR Little Endian
+pub const EXPLICIT_VR_LITTLE_ENDIAN: &str = "1.2.840.10008.1.2.1";
+/// Explicit VR Big Endian (устаревший, но несжатый)
+pub const EXPLICIT_VR_BIG_ENDIAN: &str = "1.2.840.10008.1.2.2";
+/// Deflated Explicit VR Little Endian: набор данных в Explicit VR LE, сжатый deflate без заголовка zlib
+pub const DEFLATED_EXPLICIT_VR_LITTLE_ENDIAN: &str = "1.2.840.10008.1.2.1.99";
+
+/// Implementation Class UID, которым dcm_finder подписывает записываемые файлы
+pub const IMPLEMENTATION_CLASS_UID: &str = "2.25.160935014457262237958829486514298437071";
+/// Implementation Version Name dcm_finder (SH, не длиннее 16 символов, поэтому без номера версии)
+pub const IMPLEMENTATION_VERSION_NAME: &str = "DCM_FINDER";
+
+/// Генерирует новый UID в корне 2.25 (UUID, записанный десятичным числом)
+pub fn generate_uid() -> String {
+    format!("2.25.{}", rand::random::<u128>() >> 6)
+}
+
+/// UID в корне 2.25, однозначно определяемый строками `parts` (FNV-1a, 128 бит)
+pub fn derive_uid(parts: &[&str]) -> String {
+    let mut hash: u128 = 0x6c62272e07bb014262b821756295c58d;
+    for byte in parts.join("\0").bytes() {
+        hash ^= byte as u128;
+        hash = hash.wrapping_mul(0x0000000001000000000000000000013B);
+    }
+    format!("2.25.{}", hash >> 6)
+}
+
+/// Заменяет Series Instance UID набора данных (например, на UID подобъема разделенной серии)
+pub fn replace_series_uid(obj: &mut DefaultDicomObject, series_uid: &str) {
+    replace_element_in_dcm_obj(obj, Tag(0x0020, 0x000E), series_uid);
+}
+
+/// Проверка синтаксиса UID (PS3.5 9.1): не длиннее 64 символов, числовые компоненты через точку
+/// без ведущих нулей
+pub fn is_valid_uid(uid: &str) -> bool {
+    !uid.is_empty() && uid.len() <= 64 && uid.split('.').all(|component| {
+        !component.is_empty() && component.bytes().all(|b| b.is_ascii_digit())
+            && (component == "0" || !component.starts_with('0'))
+    })
+}
+
+/// UID из набора данных без завершающих пробелов и нулевых байт
+fn dataset_uid(obj: &DefaultDicomObject, tag: Tag) -> Option<String> {
+    let value = obj.element(tag).ok()?.to_str().ok()?;
+    let value = value.trim_end_matches(|c: char| c.is_whitespace() || c == '\0');
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+/// Проверяет File Meta Information: Media Storage SOP Class/Instance UID совпадают
+/// с SOP Class/Instance UID набора данных. Синтаксис UID не проверяется (см. `file_meta_uid_problems`)
+pub fn check_file_meta(obj: &DefaultDicomObject) -> std::result::Result<(), String> {
+    let meta = obj.meta();
+    let pairs = [
+        ("Media Storage SOP Class UID", meta.media_storage_sop_class_uid(), "SOP Class UID", Tag(0x0008, 0x0016)),
+        ("Media Storage SOP Instance UID", meta.media_storage_sop_instance_uid(), "SOP Instance UID", Tag(0x0008, 0x0018)),
+    ];
+    for (meta_name, meta_uid, name, tag) in pairs {
+        let uid = dataset_uid(obj, tag).ok_or_else(|| format!("no {} in the data set", name))?;
+        if meta_uid != uid {
+            return Err(format!("{} {} does not match {} {}", meta_name, meta_uid, name, uid));
+        }
+    }
+    Ok(())
+}
+
+/// UID группы File Meta Information с неверным синтаксисом (например, с ведущими нулями
+/// или длиннее 64 символов). Такие UID встречаются в реальных архивах, поэтому файл с ними
+/// все равно записывается, а проблема только сообщается
+pub fn file_meta_uid_problems(obj: &DefaultDicomObject) -> Vec<(Tag, String)> {
+    let meta = obj.meta();
+    let uids = [
+        (Tag(0x0002, 0x0002), "Media Storage SOP Class UID", meta.media_storage_sop_class_uid()),
+        (Tag(0x0002, 0x0003), "Media Storage SOP Instance UID", meta.media_storage_sop_instance_uid()),
+        (Tag(0x0002, 0x0010), "Transfer Syntax UID", meta.transfer_syntax()),
+        (Tag(0x0002, 0x0012), "Implementation Class UID", meta.implementation_class_uid()),
+    ];
+    uids.into_iter()
+        .map(|(tag, name, uid)| (tag, name, uid.trim_end_matches('\0')))
+        .filter(|(_, _, uid)| !is_valid_uid(uid))
+        .map(|(tag, name, uid)| (tag, format!("{} '{}' is not a valid UID", name, uid)))
+        .collect()
+}
+
+/// Собирает File Meta Information заново по набору данных: SOP Class/Instance UID из набора,
+/// синтаксис передачи прежний, реализация — dcm_finder. Source AE Title прежнего файла
+/// описывает того, кто его записал, поэтому остается только переданный `source_aet`
+pub fn rebuild_file_meta(obj: &mut DefaultDicomObject, source_aet: Option<&str>) -> std::result::Result<(), String> {
+    let sop_class_uid = dataset_uid(obj, Tag(0x0008, 0x0016)).ok_or("no SOP Class UID in the data set")?;
+    let sop_instance_uid = dataset_uid(obj, Tag(0x0008, 0x0018)).ok_or("no SOP Instance UID in the data set")?;
+    let mut builder = FileMetaTableBuilder::new()
+        .media_storage_sop_class_uid(sop_class_uid)
+        .media_storage_sop_instance_uid(sop_instance_uid)
+        .transfer_syntax(obj.meta().transfer_syntax())
+        .implementation_class_uid(IMPLEMENTATION_CLASS_UID)
+        .implementation_version_name(IMPLEMENTATION_VERSION_NAME);
+    if let Some(source_aet) = source_aet {
+        builder = builder.source_application_entity_title(source_aet);
+    }
+    *obj.meta_mut() = builder.build().map_err(|e| e.to_string())?;
+    check_file_meta(obj)
+}
+
+/// Сохраняет объект в синтаксисе передачи из его File Meta Information. Перед записью группа
+/// File Meta Information собирается заново (см. `rebuild_file_meta`); если она не согласуется
+/// с набором данных, файл не записывается. UID с неверным синтаксисом только выводятся как предупреждение.
+/// Deflated Explicit VR Little Endian dicom-rs не пишет, такой файл собирается здесь
+pub fn save_dcm(obj: &mut DefaultDicomObject, save_in: &String, source_aet: Option<&str>)
+                -> std::result::Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    rebuild_file_meta(obj, source_aet)?;
+    for (_, problem) in file_meta_uid_problems(obj) {
+        eprintln!("Warning: {}: {}", save_in, problem);
+    }
+    let path = path::Path::new(save_in.as_str());
+    if obj.meta().transfer_syntax() != DEFLATED_EXPLICIT_VR_LITTLE_ENDIAN {
+        obj.write_to_file(path)?;
+        return Ok(());
+    }
+    let mut to = BufWriter::new(File::create(path)?);
+    to.write_all(&[0u8; 128])?;
+    to.write_all(b"DICM")?;
+    obj.meta().write(&mut to)?;
+    let ts = TransferSyntaxRegistry.get(EXPLICIT_VR_LITTLE_ENDIAN).ok_or("Explicit VR Little Endian is not registered")?;
+    let mut deflate = DeflateEncoder::new(to, Compression::default());
+    obj.write_dataset_with_ts(&mut deflate, ts)?;
+    deflate.finish()?.flush()?;
     Ok(())
 }
 
+/// Быстрая проверка сигнатуры DICOM файла: 128 байт преамбулы и префикс "DICM".
+/// Позволяет не разбирать файлы, которые заведомо не являются DICOM
+pub fn is_dicom_file(path: &path::Path) -> bool {
+    let mut header = [0u8; 132];
+    match File::open(path) {
+        Ok(mut file) => {
+            file.read_exact(&mut header).is_ok() && is_dicom_bytes(&header)
+        }
+        Err(_) => false,
+    }
+}
+
+/// Проверка сигнатуры DICOM для содержимого файла, прочитанного в память
+pub fn is_dicom_bytes(data: &[u8]) -> bool {
+    data.len() >= 132 && &data[128..132] == b"DICM"
+}
+
+/// Читает DICOM объект из содержимого файла (вместе с преамбулой), прочитанного в память
+pub fn read_dcm_from_bytes(data: &[u8]) -> Result<DefaultDicomObject> {
+    let data = &data[128.min(data.len())..];
+    dcm_core_from_reader(data).or_else(|error| read_deflated(data).unwrap_or(Err(error)))
+}
+
 pub fn read_dcm(path: &path::Path) -> Result<DefaultDicomObject> {
-    dcm_core_open_file(path)
+    dcm_core_open_file(path).or_else(|error| {
+        let deflated = File::open(path).ok().and_then(|file| {
+            let mut reader = BufReader::new(file);
+            reader.read_exact(&mut [0u8; 128]).ok()?;
+            read_deflated(reader)
+        });
+        deflated.unwrap_or(Err(error))
+    })
 }
 
+/// Читает файл в Deflated Explicit VR Little Endian, который dicom-rs не открывает.
+/// `source` начинается с префикса "DICM"; `None`, если файл записан в другом синтаксисе
+fn read_deflated<R: Read>(mut source: R) -> Option<Result<DefaultDicomObject>> {
+    let meta = FileMetaTable::from_reader(&mut source).ok()?;
+    if meta.transfer_syntax() != DEFLATED_EXPLICIT_VR_LITTLE_ENDIAN {
+        return None;
+    }
+    let ts = TransferSyntaxRegistry.get(EXPLICIT_VR_LITTLE_ENDIAN)?;
+    Some(InMemDicomObject::read_dataset_with_ts(DeflateDecoder::new(source), ts)
+        .map(|obj| obj.with_exact_meta(meta)))
+}
+
+/// Читает файл по пути, под которым он хранится в индексе:
+/// обычный путь или виртуальный путь файла внутри архива (`архив!/путь/в/архиве`)
+pub fn read_indexed_dcm(path: &str) -> std::result::Result<DefaultDicomObject, Box<dyn std::error::Error + Send + Sync>> {
+    match work_archive::split_virtual_path(path) {
+        Some((archive, member)) => {
+            let data = work_archive::read_member(archive, member)?;
+            if !is_dicom_bytes(&data) {
+                return Err(format!("not a DICOM file: {}", path).into());
+            }
+            Ok(read_dcm_from_bytes(&data)?)
+        }
+        None => Ok(read_dcm(path::Path::new(path))?),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dicom::core::{DataElement, PrimitiveValue, VR};
+    use dicom::object::{FileMetaTableBuilder, InMemDicomObject};
+    use crate::work_db;
+    use crate::work_db::Dcm;
+
+    /// Атрибуты, которые извлекает MetaDcm, с тегами из стандарта (PS3.6)
+    /// и уникальным значением для каждого
+    const ATTRIBUTES: &[(Tag, VR, &str)] = &[
+        (Tag(0x0010, 0x0020), VR::LO, "PID-0001"),
+        (Tag(0x0010, 0x0030), VR::DA, "19800102"),
+        (Tag(0x0010, 0x0040), VR::CS, "O"),
+        (Tag(0x0010, 0x1010), VR::AS, "042Y"),
+        (Tag(0x0020, 0x000D), VR::UI, "1.2.3.100"),
+        (Tag(0x0008, 0x0020), VR::DA, "20200304"),
+        (Tag(0x0008, 0x0030), VR::TM, "101112"),
+        (Tag(0x0008, 0x1030), VR::LO, "study description"),
+        (Tag(0x0020, 0x000E), VR::UI, "1.2.3.100.1"),
+        (Tag(0x0008, 0x0060), VR::CS, "CT"),
+        (Tag(0x0020, 0x0013), VR::IS, "7"),
+        (Tag(0x0020, 0x0032), VR::DS, "-1\\-2\\-3"),
+        (Tag(0x0020, 0x0037), VR::DS, "1\\0\\0\\0\\1\\0"),
+        (Tag(0x0028, 0x0030), VR::DS, "0.5\\0.6"),
+        (Tag(0x0028, 0x0008), VR::IS, "3"),
+        (Tag(0x0018, 0x1151), VR::IS, "250"),
+        (Tag(0x0018, 0x0060), VR::DS, "120"),
+        (Tag(0x0018, 0x1160), VR::SH, "BODY"),
+        (Tag(0x0018, 0x1150), VR::IS, "1000"),
+        (Tag(0x0028, 0x1052), VR::DS, "-1024"),
+        (Tag(0x0008, 0x103E), VR::LO, "series description"),
+        (Tag(0x0018, 0x1030), VR::LO, "protocol name"),
+        (Tag(0x0018, 0x0015), VR::CS, "CHEST"),
+    ];
+
+    fn synthetic_obj(obj: InMemDicomObject) -> DefaultDicomObject {
+        obj.with_meta(FileMetaTableBuilder::new()
+            .media_storage_sop_class_uid("1.2.840.10008.5.1.4.1.1.2")
+            .media_storage_sop_instance_uid("1.2.3.100.1.1")
+            .transfer_syntax("1.2.840.10008.1.2.1"))
+            .unwrap()
+    }
+
+    fn full_obj() -> DefaultDicomObject {
+        let mut obj = InMemDicomObject::new_empty();
+        for &(tag, vr, value) in ATTRIBUTES {
+            obj.put(DataElement::new(tag, vr, PrimitiveValue::from(value)));
+        }
+        obj.put(DataElement::new(Tag(0x0028, 0x0010), VR::US, PrimitiveValue::from(512_u16)));
+        obj.put(DataElement::new(Tag(0x0028, 0x0011), VR::US, PrimitiveValue::from(256_u16)));
+        synthetic_obj(obj)
+    }
+
+    #[test]
+    fn meta_dcm_fields_are_taken_from_their_tags() {
+        let obj = full_obj();
+        let meta = MetaDcm::from(&obj, "/data/IMG001");
+        let patient = meta.get_patient_ref();
+        assert_eq!(patient.patient_id, "PID-0001");
+        assert_eq!(patient.birth_date, "19800102");
+        assert_eq!(patient.sex, "O");
+        assert_eq!(patient.age, "042Y");
+        let study = meta.get_study_ref();
+        assert_eq!(study.study_uid, "1.2.3.100");
+        assert_eq!(study.study_date, "20200304");
+        assert_eq!(study.study_time, "101112");
+        assert_eq!(study.description, "study description");
+        let series = meta.get_series_ref();
+        assert_eq!(series.series_uid, "1.2.3.100.1");
+        assert_eq!(series.modality, "CT");
+        assert_eq!(series.instancenumber, "7");
+        assert_eq!(series.imagepositionpatient, "-1\\-2\\-3");
+        assert_eq!(series.imageorientationpatient, "1\\0\\0\\0\\1\\0");
+        assert_eq!(series.pixelspacing, "0.5\\0.6");
+        assert_eq!(series.numberofframes, "3");
+        assert_eq!(series.xraytubecurrent, "250");
+        assert_eq!(series.kvp, "120");
+        assert_eq!(series.filtertype, "BODY");
+        assert_eq!(series.rows, "512");
+        assert_eq!(series.columns, "256");
+        assert_eq!(series.exposuretime, "1000");
+        assert_eq!(series.rescaleintercept, "-1024");
+        assert_eq!(series.description, "series description");
+        assert_eq!(series.protocolname, "protocol name");
+        assert_eq!(series.bodypartexamined, "CHEST");
+        assert_eq!(meta.get_path_ref(), "/data/IMG001");
+    }
+
+    #[test]
+    fn missing_attributes_are_unknown() {
+        let obj = synthetic_obj(InMemDicomObject::new_empty());
+        let meta = MetaDcm::from(&obj, "");
+        assert_eq!(meta.get_patient_ref().patient_id, "Unknown");
+        assert_eq!(meta.get_study_ref().description, "Unknown");
+        assert_eq!(meta.get_series_ref().description, "Unknown");
+        assert_eq!(meta.get_series_ref().rows, "Unknown");
+    }
+
+    #[test]
+    fn sequence_attribute_is_unknown() {
+        let mut obj = InMemDicomObject::new_empty();
+        let items: dcm_core_value::Value<InMemDicomObject, Vec<u8>> = dcm_core_value::Value::Sequence {
+            items: smallvec::smallvec![InMemDicomObject::new_empty()],
+            size: dicom::core::Length::UNDEFINED,
+        };
+        obj.put(InMemElement::new(Tag(0x0008, 0x1030), VR::SQ, items));
+        let obj = synthetic_obj(obj);
+        let meta = MetaDcm::from(&obj, "");
+        assert_eq!(meta.get_study_ref().description, "Unknown");
+    }
+
+    #[test]
+    fn keyword_lookup() {
+        assert_eq!(tag_by_keyword("SeriesDescription"), Some(Tag(0x0008, 0x103E)));
+        assert_eq!(tag_by_keyword("NotAKeyword"), None);
+        assert_eq!(keyword_by_tag(Tag(0x0018, 0x0015)), Some("BodyPartExamined"));
+    }
+
+    #[test]
+    fn index_tag_from_keyword_or_hex() {
+        let index_tag: IndexTag = "series:Manufacturer".parse().unwrap();
+        assert_eq!(index_tag.level, Level::Series);
+        assert_eq!(index_tag.tag, Tag(0x0008, 0x0070));
+        assert_eq!(index_tag.keyword, "Manufacturer");
+
+        let index_tag: IndexTag = "instance:(0018,0050)".parse().unwrap();
+        assert_eq!(index_tag.level, Level::Instance);
+        assert_eq!(index_tag.tag, Tag(0x0018, 0x0050));
+        assert_eq!(index_tag.keyword, "SliceThickness");
+
+        assert!("series".parse::<IndexTag>().is_err());
+        assert!("volume:Manufacturer".parse::<IndexTag>().is_err());
+        assert!("series:NotAKeyword".parse::<IndexTag>().is_err());
+    }
 
+    #[test]
+    fn index_tag_spec_variants() {
+        // Уровень без учета регистра, пробелы вокруг частей допускаются
+        let index_tag: IndexTag = " Study : (0008,0070) ".parse().unwrap();
+        assert_eq!((index_tag.level, index_tag.tag, index_tag.keyword.as_str()),
+                   (Level::Study, Tag(0x0008, 0x0070), "Manufacturer"));
+        let index_tag: IndexTag = "PATIENT:PatientWeight".parse().unwrap();
+        assert_eq!((index_tag.level, index_tag.tag), (Level::Patient, Tag(0x0010, 0x1030)));
+        // Тег без ключевого слова в словаре (частный) хранится под своим номером
+        let index_tag: IndexTag = "series:(0009,1001)".parse().unwrap();
+        assert_eq!((index_tag.tag, index_tag.keyword.as_str()), (Tag(0x0009, 0x1001), "(0009,1001)"));
 
+        assert!("series:".parse::<IndexTag>().is_err());
+        assert!(":Manufacturer".parse::<IndexTag>().is_err());
+        assert!("series:(0009,10G1)".parse::<IndexTag>().is_err());
+        assert!("series:manufacturer".parse::<IndexTag>().is_err());
+    }
+
+    #[test]
+    fn extra_tags_are_extracted() {
+        let obj = full_obj();
+        let index_tags: Vec<IndexTag> = vec![
+            "series:BodyPartExamined".parse().unwrap(),
+            "study:Manufacturer".parse().unwrap(),
+        ];
+        let meta = MetaDcm::from_with_tags(&obj, "", &index_tags);
+        let extra = meta.get_extra_ref();
+        assert_eq!(extra.len(), 1);
+        assert_eq!(extra[0].level, Level::Series);
+        assert_eq!(extra[0].tag, "(0018,0015)");
+        assert_eq!(extra[0].keyword, "BodyPartExamined");
+        assert_eq!(extra[0].value, "CHEST");
+    }
+
+    /// Экземпляр многообъемной серии 1.2.3.100.1, прочитанный из закодированного файла
+    fn volume_obj(echo: &str, orientation: &str, image_type: &str, rows: u16) -> DefaultDicomObject {
+        let mut obj = full_obj();
+        obj.put(DataElement::new(Tag(0x0018, 0x0086), VR::IS, PrimitiveValue::from(echo)));
+        obj.put(DataElement::new(Tag(0x0020, 0x0037), VR::DS, PrimitiveValue::from(orientation)));
+        obj.put(DataElement::new(Tag(0x0008, 0x0008), VR::CS, PrimitiveValue::from(image_type)));
+        obj.put(DataElement::new(Tag(0x0028, 0x0010), VR::US, PrimitiveValue::from(rows)));
+        let mut data = Vec::new();
+        obj.write_all(&mut data).unwrap();
+        read_dcm_from_bytes(&data).unwrap()
+    }
+
+    fn split(obj: &DefaultDicomObject, split_by: &[SplitKey]) -> MetaDcm {
+        split_file(obj, split_by, "")
+    }
+
+    fn split_file(obj: &DefaultDicomObject, split_by: &[SplitKey], path: &str) -> MetaDcm {
+        let mut meta = MetaDcm::from(obj, path);
+        meta.split_series(obj, split_by);
+        meta
+    }
+
+    #[test]
+    fn series_is_split_into_volumes() {
+        let keys: Vec<SplitKey> = ["echo", " Orientation"].iter().map(|key| key.parse().unwrap()).collect();
+        assert_eq!(keys, [SplitKey::Echo, SplitKey::Orientation]);
+        assert!("volume".parse::<SplitKey>().is_err());
+
+        let axial = "1\\0\\0\\0\\1\\0";
+        let first = split_file(&volume_obj("1", axial, "ORIGINAL\\PRIMARY", 512), &keys, "first.dcm");
+        let series = first.get_series_ref();
+        assert_eq!(series.source_series_uid.as_deref(), Some("1.2.3.100.1"));
+        assert_eq!(series.volume_key.as_deref(), Some("echo=1;orientation=1.00\\0.00\\0.00\\0.00\\1.00\\0.00"));
+        assert!(series.series_uid.starts_with("2.25.") && is_valid_uid(&series.series_uid));
+
+        // UID подобъема определяется исходным UID и значениями признаков: шум в косинусах
+        // и знак нуля не создают новый подобъем, другое эхо — создает
+        let same = split_file(&volume_obj("1", "0.99999\\-0.0001\\0\\0\\1\\0", "DERIVED", 256), &keys, "same.dcm");
+        assert_eq!(same.get_series_ref().series_uid, series.series_uid);
+        let second = split_file(&volume_obj("2", axial, "ORIGINAL\\PRIMARY", 512), &keys, "second.dcm");
+        assert_ne!(second.get_series_ref().series_uid, series.series_uid);
+        assert_eq!(second.get_series_ref().source_series_uid.as_deref(), Some("1.2.3.100.1"));
+
+        // Тип изображения и размер кадра
+        let by_type = [SplitKey::ImageType, SplitKey::Dimensions];
+        let original = split(&volume_obj("1", axial, "ORIGINAL\\PRIMARY", 512), &by_type);
+        assert_eq!(original.get_series_ref().volume_key.as_deref(), Some("image-type=ORIGINAL\\PRIMARY;dimensions=512x256"));
+        let derived = split(&volume_obj("1", axial, "DERIVED\\SECONDARY", 512), &by_type);
+        assert_ne!(derived.get_series_ref().series_uid, original.get_series_ref().series_uid);
+        let smaller = split(&volume_obj("1", axial, "ORIGINAL\\PRIMARY", 256), &by_type);
+        assert_ne!(smaller.get_series_ref().series_uid, original.get_series_ref().series_uid);
+        // Отсутствующий атрибут дает пустое значение признака
+        assert_eq!(split(&full_obj(), &[SplitKey::Temporal]).get_series_ref().volume_key.as_deref(), Some("temporal="));
+
+        // Подобъемы индексируются как отдельные серии со ссылкой на исходную
+        let conn = work_db::Connection::create_dcm_tables(true).unwrap();
+        for meta in [&first, &same, &second] {
+            conn.insert_dcm(meta);
+        }
+        let volumes: Vec<(String, String)> = conn.prepare("SELECT source_series_uid, volume_key FROM series ORDER BY volume_key;")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .map(|row| row.unwrap())
+            .collect();
+        assert_eq!(volumes.len(), 2);
+        assert!(volumes.iter().all(|(source, _)| source == "1.2.3.100.1"));
+        assert!(volumes[1].1.starts_with("echo=2;"));
+
+        // Без признаков серия не делится
+        let whole = split(&volume_obj("2", axial, "ORIGINAL", 512), &[]);
+        assert_eq!(whole.get_series_ref().series_uid, "1.2.3.100.1");
+        assert!(whole.get_series_ref().source_series_uid.is_none() && whole.get_series_ref().volume_key.is_none());
+    }
+
+    #[test]
+    fn uid_syntax_is_checked() {
+        assert!(is_valid_uid("1.2.840.10008.1.2.1"));
+        assert!(is_valid_uid(IMPLEMENTATION_CLASS_UID));
+        assert!(is_valid_uid("1.0.3"));
+        assert!(!is_valid_uid("1.02.3"));
+        assert!(!is_valid_uid("1..3"));
+        assert!(!is_valid_uid("1.2.a"));
+        assert!(!is_valid_uid(&format!("1.{}", "2".repeat(63))));
+    }
+
+    /// Объект с SOP Class/Instance UID, совпадающими с его File Meta Information
+    fn sop_obj() -> DefaultDicomObject {
+        let mut obj = full_obj();
+        obj.put(DataElement::new(Tag(0x0008, 0x0016), VR::UI, PrimitiveValue::from("1.2.840.10008.5.1.4.1.1.2")));
+        obj.put(DataElement::new(Tag(0x0008, 0x0018), VR::UI, PrimitiveValue::from("1.2.3.100.1.1")));
+        obj
+    }
+
+    /// Группа File Meta Information, собранная вручную с заданными SOP Class и SOP Instance UID
+    fn hand_built_meta(sop_class_uid: &str, sop_instance_uid: &str) -> FileMetaTable {
+        FileMetaTableBuilder::new()
+            .media_storage_sop_class_uid(sop_class_uid)
+            .media_storage_sop_instance_uid(sop_instance_uid)
+            .transfer_syntax(EXPLICIT_VR_LITTLE_ENDIAN)
+            .implementation_class_uid("1.2.3.4")
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn file_meta_is_checked_against_the_data_set() {
+        let mut obj = sop_obj();
+        *obj.meta_mut() = hand_built_meta("1.2.840.10008.5.1.4.1.1.2", "1.2.3.100.1.1");
+        assert_eq!(check_file_meta(&obj), Ok(()));
+        // Экземпляр получил новый UID, а группа 0002 осталась от исходного файла
+        *obj.meta_mut() = hand_built_meta("1.2.840.10008.5.1.4.1.1.2", "1.2.3.100.1.2");
+        assert_eq!(check_file_meta(&obj).unwrap_err(),
+                   "Media Storage SOP Instance UID 1.2.3.100.1.2 does not match SOP Instance UID 1.2.3.100.1.1");
+        *obj.meta_mut() = hand_built_meta("1.2.840.10008.5.1.4.1.1.4", "1.2.3.100.1.1");
+        assert_eq!(check_file_meta(&obj).unwrap_err(),
+                   "Media Storage SOP Class UID 1.2.840.10008.5.1.4.1.1.4 does not match SOP Class UID 1.2.840.10008.5.1.4.1.1.2");
+        // UID в группе 0002 дополняются до четной длины нулевым байтом, это не расхождение
+        *obj.meta_mut() = hand_built_meta("1.2.840.10008.5.1.4.1.1.2\0", "1.2.3.100.1.1\0");
+        assert_eq!(check_file_meta(&obj), Ok(()));
+        obj.remove_element(Tag(0x0008, 0x0018));
+        assert_eq!(check_file_meta(&obj).unwrap_err(), "no SOP Instance UID in the data set");
+    }
+
+    #[test]
+    fn file_meta_is_rebuilt_from_the_data_set() {
+        let mut obj = sop_obj();
+        obj.meta_mut().source_application_entity_title = Some("HOSPITAL_PACS".to_string());
+        obj.meta_mut().implementation_class_uid = "1.2.3.4".to_string();
+        // Экземпляр получил новый UID, а группа 0002 осталась от исходного файла
+        obj.put(DataElement::new(Tag(0x0008, 0x0018), VR::UI, PrimitiveValue::from("1.2.3.100.1.2")));
+
+        rebuild_file_meta(&mut obj, None).unwrap();
+        let meta = obj.meta();
+        assert_eq!(meta.media_storage_sop_instance_uid(), "1.2.3.100.1.2");
+        assert_eq!(meta.media_storage_sop_class_uid(), "1.2.840.10008.5.1.4.1.1.2");
+        assert_eq!(meta.transfer_syntax(), EXPLICIT_VR_LITTLE_ENDIAN);
+        assert_eq!(meta.implementation_class_uid(), IMPLEMENTATION_CLASS_UID);
+        assert_eq!(meta.implementation_version_name.as_deref(), Some(IMPLEMENTATION_VERSION_NAME));
+        assert!(IMPLEMENTATION_VERSION_NAME.len() <= 16);
+        assert_eq!(meta.source_application_entity_title, None);
+        let mut written = Vec::new();
+        meta.write(&mut written).unwrap();
+        assert_eq!(u32::from_le_bytes([written[8], written[9], written[10], written[11]]) as usize, written.len() - 12);
+
+        rebuild_file_meta(&mut obj, Some("MODALITY")).unwrap();
+        assert_eq!(obj.meta().source_application_entity_title.as_deref(), Some("MODALITY"));
+    }
+
+    #[test]
+    fn file_meta_needs_sop_uids() {
+        let mut obj = sop_obj();
+        obj.remove_element(Tag(0x0008, 0x0016));
+        assert!(rebuild_file_meta(&mut obj, None).is_err());
+    }
+
+    #[test]
+    fn invalid_uid_syntax_is_only_reported() {
+        // Ведущий ноль в компоненте и UID длиннее 64 символов встречаются в реальных архивах
+        for uid in ["1.2.840.0123.1", &format!("1.2.{}", "9".repeat(70))] {
+            let mut obj = sop_obj();
+            obj.put(DataElement::new(Tag(0x0008, 0x0018), VR::UI, PrimitiveValue::from(uid)));
+            rebuild_file_meta(&mut obj, None).unwrap();
+            assert_eq!(obj.meta().media_storage_sop_instance_uid(), uid);
+            let problems = file_meta_uid_problems(&obj);
+            assert_eq!(problems.len(), 1);
+            assert_eq!(problems[0].0, Tag(0x0002, 0x0003));
+            assert!(problems[0].1.contains("not a valid UID"));
+        }
+        let mut obj = sop_obj();
+        rebuild_file_meta(&mut obj, None).unwrap();
+        assert!(file_meta_uid_problems(&obj).is_empty());
+    }
+}
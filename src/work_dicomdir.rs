@@ -0,0 +1,743 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path;
+use std::sync::Mutex;
+use dicom::core::{Tag, VR};
+use dicom::object::mem::InMemElement;
+use dicom::object::{DefaultDicomObject, InMemDicomObject, OpenFileOptions};
+use walkdir::WalkDir;
+
+use crate::work_dcm;
+
+
+/// Media Storage SOP Class UID файла DICOMDIR (Media Storage Directory Storage)
+pub const MEDIA_STORAGE_DIRECTORY_SOP_CLASS: &str = "1.2.840.10008.1.3.10";
+/// Имя директории внутри выходного дерева, в которой сохраняются файлы для записи на носитель
+pub const MEDIA_ROOT: &str = "DICOM";
+
+const FIRST_ROOT_RECORD_OFFSET: Tag = Tag(0x0004, 0x1200);
+const DIRECTORY_RECORD_SEQUENCE: Tag = Tag(0x0004, 0x1220);
+const NEXT_RECORD_OFFSET: Tag = Tag(0x0004, 0x1400);
+const RECORD_IN_USE_FLAG: Tag = Tag(0x0004, 0x1410);
+const LOWER_LEVEL_RECORD_OFFSET: Tag = Tag(0x0004, 0x1420);
+const DIRECTORY_RECORD_TYPE: Tag = Tag(0x0004, 0x1430);
+const REFERENCED_FILE_ID: Tag = Tag(0x0004, 0x1500);
+const REFERENCED_SOP_CLASS_UID_IN_FILE: Tag = Tag(0x0004, 0x1510);
+const REFERENCED_SOP_INSTANCE_UID_IN_FILE: Tag = Tag(0x0004, 0x1511);
+const PIXEL_DATA: Tag = Tag(0x7FE0, 0x0010);
+
+/// Атрибуты записей каждого уровня: тег, VR и признак необязательности (тип 1C/3).
+/// Обязательные атрибуты, отсутствующие в файле, записываются пустыми.
+const PATIENT_ATTRIBUTES: &[(Tag, &str, bool)] = &[
+    (Tag(0x0008, 0x0005), "CS", true),
+    (Tag(0x0010, 0x0010), "PN", false),
+    (Tag(0x0010, 0x0020), "LO", false),
+];
+const STUDY_ATTRIBUTES: &[(Tag, &str, bool)] = &[
+    (Tag(0x0008, 0x0005), "CS", true),
+    (Tag(0x0008, 0x0020), "DA", false),
+    (Tag(0x0008, 0x0030), "TM", false),
+    (Tag(0x0008, 0x0050), "SH", false),
+    (Tag(0x0008, 0x1030), "LO", false),
+    (Tag(0x0020, 0x000D), "UI", false),
+    (Tag(0x0020, 0x0010), "SH", false),
+];
+const SERIES_ATTRIBUTES: &[(Tag, &str, bool)] = &[
+    (Tag(0x0008, 0x0005), "CS", true),
+    (Tag(0x0008, 0x0060), "CS", false),
+    (Tag(0x0020, 0x000E), "UI", false),
+    (Tag(0x0020, 0x0011), "IS", false),
+];
+const INSTANCE_ATTRIBUTES: &[(Tag, &str, bool)] = &[
+    (Tag(0x0008, 0x0005), "CS", true),
+    (Tag(0x0020, 0x0013), "IS", false),
+];
+
+
+/// Проверяет, называется ли файл DICOMDIR
+pub fn is_dicomdir_name(path: &path::Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| name.eq_ignore_ascii_case("DICOMDIR"))
+        .unwrap_or(false)
+}
+
+/// Проверяет, является ли объект каталогом носителя (DICOMDIR), а не изображением
+pub fn is_dicomdir_obj(obj: &DefaultDicomObject) -> bool {
+    trim_uid(&obj.meta().media_storage_sop_class_uid) == MEDIA_STORAGE_DIRECTORY_SOP_CLASS
+}
+
+fn trim_uid(uid: &str) -> &str {
+    uid.trim_end_matches(['\0', ' '])
+}
+
+/// Читает иерархию пациент/исследование/серия/экземпляр из DICOMDIR без чтения самих файлов.
+/// Атрибуты экземпляра собираются из его записи и записей вышестоящих уровней,
+/// атрибуты, которых нет в записях, получают значение "Unknown".
+/// Записи обходятся по ссылкам 0004,1400/1420 начиная с 0004,1200, неиспользуемые записи
+/// (0004,1410 = 0) пропускаются вместе с дочерними. Если ссылки не указывают на записи
+/// или образуют цикл, записи обходятся в порядке следования в Directory Record Sequence.
+pub fn read_dicomdir(path: &path::Path,
+                     index_tags: &[work_dcm::IndexTag]) -> dicom::object::Result<Vec<work_dcm::MetaDcm>> {
+    let obj = work_dcm::read_dcm(path)?;
+    let mut result = Vec::new();
+    if !is_dicomdir_obj(&obj) {
+        return Ok(result);
+    }
+    let records = match obj.element(DIRECTORY_RECORD_SEQUENCE).ok().and_then(|e| e.value().items()) {
+        Some(records) => records,
+        None => return Ok(result),
+    };
+    let order = if trim_uid(obj.meta().transfer_syntax()) == work_dcm::EXPLICIT_VR_LITTLE_ENDIAN {
+        fs::read(path).ok()
+            .and_then(|data| record_offsets(&data))
+            .filter(|offsets| offsets.len() == records.len())
+            .and_then(|offsets| record_order(records, &offsets, offset_value(&obj, FIRST_ROOT_RECORD_OFFSET)))
+    } else {
+        None
+    };
+    let order = order.unwrap_or_else(|| (0..records.len()).collect());
+    let base = path.parent().unwrap_or_else(|| path::Path::new(""));
+    let empty = InMemDicomObject::new_empty();
+    let (mut patient, mut study, mut series) = (&empty, &empty, &empty);
+    for record in order.into_iter().map(|index| &records[index]) {
+        let record_type = record.element(DIRECTORY_RECORD_TYPE).ok()
+            .and_then(|e| e.value().to_str().ok())
+            .map(|value| value.trim().to_uppercase())
+            .unwrap_or_default();
+        match record_type.as_str() {
+            "PATIENT" => {
+                patient = record;
+                study = &empty;
+                series = &empty;
+            }
+            "STUDY" => {
+                study = record;
+                series = &empty;
+            }
+            "SERIES" => series = record,
+            _ => {
+                let file_id = match record.element(REFERENCED_FILE_ID).ok()
+                    .and_then(|e| e.value().to_multi_str().ok()) {
+                    Some(file_id) => file_id,
+                    None => continue,
+                };
+                let mut merged = InMemDicomObject::new_empty();
+                for level in [patient, study, series, record] {
+                    for element in level {
+                        merged.put(element.clone());
+                    }
+                }
+                // UID экземпляра хранятся в записи как Referenced SOP ... UID in File
+                for (referenced, tag) in [(REFERENCED_SOP_CLASS_UID_IN_FILE, Tag(0x0008, 0x0016)),
+                                          (REFERENCED_SOP_INSTANCE_UID_IN_FILE, Tag(0x0008, 0x0018))] {
+                    if let Ok(element) = record.element(referenced) {
+                        merged.put(InMemElement::new(tag, VR::UI, element.value().clone()));
+                    }
+                }
+                let file_path = resolve_file_id(base, &file_id);
+                result.push(work_dcm::MetaDcm::from_with_tags(
+                    &merged,
+                    file_path.to_str().unwrap_or_default(),
+                    index_tags,
+                ));
+            }
+        }
+    }
+    Ok(result)
+}
+
+fn offset_value(obj: &InMemDicomObject, tag: Tag) -> u32 {
+    obj.element(tag).ok().and_then(|e| e.to_int::<u32>().ok()).unwrap_or(0)
+}
+
+/// Порядок обхода записей по ссылкам: запись, затем ее дочерние записи, затем следующая запись уровня.
+/// `offsets` — смещения записей от начала файла. `None`, если ссылка не указывает на запись,
+/// запись встречается дважды или первая запись не задана при непустом каталоге
+fn record_order(records: &[InMemDicomObject], offsets: &[u32], first: u32) -> Option<Vec<usize>> {
+    if first == 0 {
+        return if records.is_empty() { Some(Vec::new()) } else { None };
+    }
+    let index: HashMap<u32, usize> = offsets.iter().enumerate().map(|(i, &offset)| (offset, i)).collect();
+    let mut visited = vec![false; records.len()];
+    let mut order = Vec::new();
+    let mut stack = vec![first];
+    while let Some(offset) = stack.pop() {
+        if offset == 0 {
+            continue;
+        }
+        let i = *index.get(&offset)?;
+        if std::mem::replace(&mut visited[i], true) {
+            return None;
+        }
+        let record = &records[i];
+        stack.push(offset_value(record, NEXT_RECORD_OFFSET));
+        let in_use = record.element(RECORD_IN_USE_FLAG).ok()
+            .and_then(|e| e.to_int::<u16>().ok())
+            .map(|flag| flag != 0)
+            .unwrap_or(true);
+        if in_use {
+            order.push(i);
+            stack.push(offset_value(record, LOWER_LEVEL_RECORD_OFFSET));
+        }
+    }
+    Some(order)
+}
+
+/// Смещения элементов Directory Record Sequence от первого байта файла DICOMDIR
+/// в Explicit VR Little Endian; `None`, если файл не удалось разобрать
+fn record_offsets(data: &[u8]) -> Option<Vec<u32>> {
+    let mut pos = 132;
+    loop {
+        let (tag, length, header) = element_header(data, pos)?;
+        if tag != DIRECTORY_RECORD_SEQUENCE {
+            pos = skip_element(data, pos)?;
+            continue;
+        }
+        let end = if length == u32::MAX { data.len() } else { pos + header + length as usize };
+        let mut offsets = Vec::new();
+        let mut pos = pos + header;
+        while pos < end.min(data.len()) {
+            match element_header(data, pos)?.0 {
+                Tag(0xFFFE, 0xE000) => {
+                    offsets.push(u32::try_from(pos).ok()?);
+                    pos = skip_element(data, pos)?;
+                }
+                Tag(0xFFFE, 0xE0DD) => break,
+                _ => return None,
+            }
+        }
+        return Some(offsets);
+    }
+}
+
+/// Тег, длина значения и длина заголовка элемента (или элемента последовательности),
+/// начинающегося с позиции `pos`
+fn element_header(data: &[u8], pos: usize) -> Option<(Tag, u32, usize)> {
+    let u16_at = |at: usize| data.get(at..at + 2).map(|b| u16::from_le_bytes([b[0], b[1]]));
+    let u32_at = |at: usize| data.get(at..at + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+    let tag = Tag(u16_at(pos)?, u16_at(pos + 2)?);
+    if tag.group() == 0xFFFE {
+        return Some((tag, u32_at(pos + 4)?, 8));
+    }
+    match data.get(pos + 4..pos + 6)? {
+        b"OB" | b"OD" | b"OF" | b"OL" | b"OV" | b"OW" | b"SQ" | b"SV" | b"UC" | b"UN" | b"UR" | b"UT" | b"UV" =>
+            Some((tag, u32_at(pos + 8)?, 12)),
+        _ => Some((tag, u16_at(pos + 6)? as u32, 8)),
+    }
+}
+
+/// Позиция, следующая за элементом, начинающимся с `pos`.
+/// Значения неопределенной длины пропускаются до разделителя
+fn skip_element(data: &[u8], pos: usize) -> Option<usize> {
+    let (_, length, header) = element_header(data, pos)?;
+    if length != u32::MAX {
+        let end = pos.checked_add(header)?.checked_add(length as usize)?;
+        return if end <= data.len() { Some(end) } else { None };
+    }
+    let mut pos = pos + header;
+    loop {
+        match element_header(data, pos)?.0 {
+            Tag(0xFFFE, 0xE00D) | Tag(0xFFFE, 0xE0DD) => return Some(pos + 8),
+            _ => pos = skip_element(data, pos)?,
+        }
+    }
+}
+
+/// Преобразует Referenced File ID в путь относительно директории DICOMDIR.
+/// Носители часто монтируются с именами в нижнем регистре, поэтому при отсутствии
+/// точного совпадения компонент ищется без учета регистра
+fn resolve_file_id(base: &path::Path, file_id: &[String]) -> path::PathBuf {
+    let mut path = base.to_path_buf();
+    for component in file_id.iter().map(|c| c.trim()) {
+        let exact = path.join(component);
+        path = if exact.exists() {
+            exact
+        } else {
+            fs::read_dir(&path).ok()
+                .and_then(|entries| entries
+                    .filter_map(|entry| entry.ok())
+                    .find(|entry| entry.file_name().to_str()
+                        .map(|name| name.eq_ignore_ascii_case(component))
+                        .unwrap_or(false)))
+                .map(|entry| entry.path())
+                .unwrap_or(exact)
+        };
+    }
+    path
+}
+
+/// Компонент идентификатора файла PS3.10 для значения `value`:
+/// префикс и 7 шестнадцатеричных цифр хэша (8 символов верхнего регистра)
+fn file_id_component(prefix: char, value: &str) -> String {
+    format!("{}{:07X}", prefix, fnv1a(value) & 0x0FFF_FFFF)
+}
+
+fn fnv1a(value: &str) -> u32 {
+    value.bytes().fold(0x811c_9dc5u32, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+/// Выданные компоненты идентификаторов файлов. Разным значениям внутри одной
+/// родительской директории выдаются разные компоненты: при совпадении хэшей
+/// к компоненту добавляется счетчик
+#[derive(Default)]
+pub struct FileIds {
+    assigned: Mutex<AssignedFileIds>,
+}
+
+#[derive(Default)]
+struct AssignedFileIds {
+    /// Компонент по директории и значению
+    by_value: HashMap<(path::PathBuf, String), String>,
+    /// Пути, компоненты которых уже выданы
+    taken: HashSet<path::PathBuf>,
+}
+
+impl FileIds {
+    /// Компонент для значения `value` в директории `parent` (см. [`file_id_component`]);
+    /// для повторного значения возвращается ранее выданный компонент
+    pub fn component(&self, parent: &path::Path, prefix: char, value: &str) -> String {
+        let mut assigned = self.assigned.lock().unwrap_or_else(|e| e.into_inner());
+        let AssignedFileIds { by_value, taken } = &mut *assigned;
+        let key = (parent.to_path_buf(), value.to_string());
+        if let Some(component) = by_value.get(&key) {
+            return component.clone();
+        }
+        let hash = fnv1a(value);
+        let component = (0u32..=0xFF)
+            .map(|n| match n {
+                0 => file_id_component(prefix, value),
+                // 8 символов: префикс, 4 цифры хэша, '_' и счетчик
+                n => format!("{}{:04X}_{:02X}", prefix, hash >> 16, n),
+            })
+            .find(|component| !taken.contains(&parent.join(component)))
+            .unwrap_or_else(|| file_id_component(prefix, value));
+        taken.insert(parent.join(&component));
+        by_value.insert(key, component.clone());
+        component
+    }
+}
+
+/// Проверяет, что компонент пути допустим в идентификаторе файла PS3.10:
+/// от 1 до 8 символов из A-Z, 0-9 и '_'
+fn is_valid_file_id_component(component: &str) -> bool {
+    !component.is_empty() && component.len() <= 8
+        && component.bytes().all(|b| b.is_ascii_uppercase() || b.is_ascii_digit() || b == b'_')
+}
+
+/// Запись каталога с дочерними записями
+struct Record {
+    kind: &'static str,
+    key: String,
+    elements: Vec<(Tag, &'static str, Vec<u8>)>,
+    children: Vec<Record>,
+}
+
+impl Record {
+    fn from_obj(kind: &'static str, key: String, obj: &InMemDicomObject,
+                attributes: &[(Tag, &'static str, bool)]) -> Record {
+        let mut elements = Vec::new();
+        for &(tag, vr, optional) in attributes {
+            let value = obj.element(tag).ok()
+                .and_then(|e| e.value().to_str().ok())
+                .map(|value| value.trim_end_matches(['\0', ' ']).to_string());
+            match value {
+                Some(value) => elements.push((tag, vr, pad(value.as_bytes(), vr))),
+                None if !optional => elements.push((tag, vr, Vec::new())),
+                None => {}
+            }
+        }
+        Record { kind, key, elements, children: Vec::new() }
+    }
+
+    /// Дочерняя запись с ключом `key`; создается с помощью `make`, если ее еще нет
+    fn child(&mut self, key: &str, make: impl FnOnce() -> Record) -> &mut Record {
+        match self.children.iter().position(|r| r.key == key) {
+            Some(index) => &mut self.children[index],
+            None => {
+                self.children.push(make());
+                self.children.last_mut().unwrap()
+            }
+        }
+    }
+
+    fn sort(&mut self) {
+        self.children.sort_by(|a, b| a.key.cmp(&b.key));
+        self.children.iter_mut().for_each(Record::sort);
+    }
+
+    /// Кодирует запись как элемент последовательности с заданными смещениями
+    fn encode(&self, next: u32, lower: u32) -> Vec<u8> {
+        let mut content = Vec::new();
+        encode_element(&mut content, Tag(0x0004, 0x1400), "UL", &next.to_le_bytes());
+        encode_element(&mut content, Tag(0x0004, 0x1410), "US", &0xFFFFu16.to_le_bytes());
+        encode_element(&mut content, Tag(0x0004, 0x1420), "UL", &lower.to_le_bytes());
+        encode_element(&mut content, DIRECTORY_RECORD_TYPE, "CS", &pad(self.kind.as_bytes(), "CS"));
+        let mut elements: Vec<_> = self.elements.iter().collect();
+        elements.sort_by_key(|(tag, _, _)| *tag);
+        for (tag, vr, value) in elements {
+            encode_element(&mut content, *tag, vr, value);
+        }
+        let mut item = Vec::with_capacity(content.len() + 8);
+        item.extend_from_slice(&0xFFFEu16.to_le_bytes());
+        item.extend_from_slice(&0xE000u16.to_le_bytes());
+        item.extend_from_slice(&(content.len() as u32).to_le_bytes());
+        item.extend_from_slice(&content);
+        item
+    }
+}
+
+/// Дополняет значение до четной длины (UI — нулевым байтом, остальные VR — пробелом)
+fn pad(value: &[u8], vr: &str) -> Vec<u8> {
+    let mut value = value.to_vec();
+    if value.len() % 2 == 1 {
+        value.push(if vr == "UI" { 0 } else { b' ' });
+    }
+    value
+}
+
+/// Кодирует элемент в Explicit VR Little Endian
+fn encode_element(out: &mut Vec<u8>, tag: Tag, vr: &str, value: &[u8]) {
+    out.extend_from_slice(&tag.group().to_le_bytes());
+    out.extend_from_slice(&tag.element().to_le_bytes());
+    out.extend_from_slice(vr.as_bytes());
+    if matches!(vr, "OB" | "OW" | "SQ" | "UN" | "UT") {
+        out.extend_from_slice(&[0, 0]);
+        out.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    } else {
+        out.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    }
+    out.extend_from_slice(value);
+}
+
+/// Тип записи каталога для экземпляра по его SOP Class UID
+fn instance_record_type(sop_class_uid: &str) -> &'static str {
+    if sop_class_uid.starts_with("1.2.840.10008.5.1.4.1.1.88.") {
+        "SR DOCUMENT"
+    } else if sop_class_uid.starts_with("1.2.840.10008.5.1.4.1.1.11.") {
+        "PRESENTATION"
+    } else {
+        "IMAGE"
+    }
+}
+
+/// Записывает в корень дерева `root` файл DICOMDIR, описывающий все DICOM файлы дерева.
+/// В каталог попадают только файлы, путь которых является допустимым идентификатором
+/// файла PS3.10 (не более 8 компонент по 8 символов A-Z, 0-9, '_').
+/// Возвращает количество файлов в каталоге и количество пропущенных файлов
+pub fn write_dicomdir(root: &path::Path) -> io::Result<(usize, usize)> {
+    let mut tree = Record { kind: "", key: String::new(), elements: Vec::new(), children: Vec::new() };
+    let (mut added, mut skipped) = (0, 0);
+
+    for entry in WalkDir::new(root).into_iter().filter_map(|e| e.ok()) {
+        if !entry.file_type().is_file() || entry.depth() == 1 && is_dicomdir_name(entry.path()) {
+            continue;
+        }
+        let file_id: Vec<String> = match entry.path().strip_prefix(root) {
+            Ok(relative) => relative.iter().map(|c| c.to_string_lossy().to_string()).collect(),
+            Err(_) => continue,
+        };
+        if !work_dcm::is_dicom_file(entry.path()) {
+            continue;
+        }
+        if file_id.len() > 8 || !file_id.iter().all(|c| is_valid_file_id_component(c)) {
+            skipped += 1;
+            continue;
+        }
+        // Deflated Explicit VR Little Endian dicom-rs не открывает, такие файлы читаются целиком
+        let obj = match OpenFileOptions::new().read_until(PIXEL_DATA).open_file(entry.path())
+            .or_else(|_| work_dcm::read_dcm(entry.path())) {
+            Ok(obj) => obj,
+            Err(_) => {
+                skipped += 1;
+                continue;
+            }
+        };
+        if is_dicomdir_obj(&obj) {
+            continue;
+        }
+        let value = |keyword: &str| obj.element_by_name(keyword).ok()
+            .and_then(|e| e.value().to_str().ok())
+            .map(|value| value.trim_end_matches(['\0', ' ']).to_string())
+            .unwrap_or_default();
+        let (patient_id, study_uid, series_uid) =
+            (value("PatientID"), value("StudyInstanceUID"), value("SeriesInstanceUID"));
+        let instance_number: i64 = value("InstanceNumber").trim().parse().unwrap_or(0);
+
+        let meta = obj.meta();
+        let sop_class_uid = trim_uid(&meta.media_storage_sop_class_uid).to_string();
+        let mut instance = Record::from_obj(
+            instance_record_type(&sop_class_uid),
+            format!("{:012}{}", instance_number, file_id.join("\\")),
+            &obj,
+            INSTANCE_ATTRIBUTES,
+        );
+        instance.elements.push((REFERENCED_FILE_ID, "CS", pad(file_id.join("\\").as_bytes(), "CS")));
+        instance.elements.push((REFERENCED_SOP_CLASS_UID_IN_FILE, "UI", pad(sop_class_uid.as_bytes(), "UI")));
+        instance.elements.push((REFERENCED_SOP_INSTANCE_UID_IN_FILE, "UI",
+                                pad(trim_uid(&meta.media_storage_sop_instance_uid).as_bytes(), "UI")));
+        instance.elements.push((Tag(0x0004, 0x1512), "UI",
+                                pad(trim_uid(&meta.transfer_syntax).as_bytes(), "UI")));
+
+        tree.child(&patient_id, || Record::from_obj("PATIENT", patient_id.clone(), &obj, PATIENT_ATTRIBUTES))
+            .child(&study_uid, || Record::from_obj("STUDY", study_uid.clone(), &obj, STUDY_ATTRIBUTES))
+            .child(&series_uid, || Record::from_obj("SERIES", series_uid.clone(), &obj, SERIES_ATTRIBUTES))
+            .children.push(instance);
+        added += 1;
+    }
+    tree.sort();
+    fs::write(root.join("DICOMDIR"), encode_dicomdir(&tree.children))?;
+    Ok((added, skipped))
+}
+
+/// Запись каталога в порядке обхода с индексами следующей и первой дочерней записей
+struct FlatRecord<'a> {
+    record: &'a Record,
+    next: Option<usize>,
+    lower: Option<usize>,
+}
+
+fn flatten<'a>(records: &'a [Record], flat: &mut Vec<FlatRecord<'a>>) {
+    let mut previous: Option<usize> = None;
+    for record in records {
+        let index = flat.len();
+        flat.push(FlatRecord { record, next: None, lower: None });
+        if let Some(previous) = previous {
+            flat[previous].next = Some(index);
+        }
+        if !record.children.is_empty() {
+            flat[index].lower = Some(flat.len());
+            flatten(&record.children, flat);
+        }
+        previous = Some(index);
+    }
+}
+
+/// Кодирует файл DICOMDIR целиком: преамбула, File Meta Information и каталог.
+/// Смещения записей отсчитываются от первого байта файла
+fn encode_dicomdir(roots: &[Record]) -> Vec<u8> {
+    let mut meta = Vec::new();
+    encode_element(&mut meta, Tag(0x0002, 0x0001), "OB", &[0, 1]);
+    encode_element(&mut meta, Tag(0x0002, 0x0002), "UI", &pad(MEDIA_STORAGE_DIRECTORY_SOP_CLASS.as_bytes(), "UI"));
+    encode_element(&mut meta, Tag(0x0002, 0x0003), "UI", &pad(work_dcm::generate_uid().as_bytes(), "UI"));
+    encode_element(&mut meta, Tag(0x0002, 0x0010), "UI", &pad(work_dcm::EXPLICIT_VR_LITTLE_ENDIAN.as_bytes(), "UI"));
+    encode_element(&mut meta, Tag(0x0002, 0x0012), "UI", &pad(work_dcm::IMPLEMENTATION_CLASS_UID.as_bytes(), "UI"));
+    encode_element(&mut meta, Tag(0x0002, 0x0013), "SH", &pad(work_dcm::IMPLEMENTATION_VERSION_NAME.as_bytes(), "SH"));
+
+    let mut out = vec![0u8; 128];
+    out.extend_from_slice(b"DICM");
+    encode_element(&mut out, Tag(0x0002, 0x0000), "UL", &(meta.len() as u32).to_le_bytes());
+    out.extend_from_slice(&meta);
+
+    let mut flat = Vec::new();
+    flatten(roots, &mut flat);
+    let sizes: Vec<u32> = flat.iter().map(|f| f.record.encode(0, 0).len() as u32).collect();
+
+    let header = |first: u32, last: u32, items_len: u32| {
+        let mut header = Vec::new();
+        encode_element(&mut header, Tag(0x0004, 0x1130), "CS", &pad(b"DCM_FINDER", "CS"));
+        encode_element(&mut header, Tag(0x0004, 0x1200), "UL", &first.to_le_bytes());
+        encode_element(&mut header, Tag(0x0004, 0x1202), "UL", &last.to_le_bytes());
+        encode_element(&mut header, Tag(0x0004, 0x1212), "US", &0u16.to_le_bytes());
+        header.extend_from_slice(&DIRECTORY_RECORD_SEQUENCE.group().to_le_bytes());
+        header.extend_from_slice(&DIRECTORY_RECORD_SEQUENCE.element().to_le_bytes());
+        header.extend_from_slice(b"SQ\0\0");
+        header.extend_from_slice(&items_len.to_le_bytes());
+        header
+    };
+    let start = (out.len() + header(0, 0, 0).len()) as u32;
+    let mut offsets = Vec::with_capacity(sizes.len());
+    let mut offset = start;
+    for size in &sizes {
+        offsets.push(offset);
+        offset += size;
+    }
+    let last_root = roots.last()
+        .and_then(|last| flat.iter().position(|f| std::ptr::eq(last, f.record)))
+        .map(|index| offsets[index])
+        .unwrap_or(0);
+    let first_root = offsets.first().copied().unwrap_or(0);
+    out.extend_from_slice(&header(first_root, last_root, offset - start));
+    for flat_record in &flat {
+        let next = flat_record.next.map(|i| offsets[i]).unwrap_or(0);
+        let lower = flat_record.lower.map(|i| offsets[i]).unwrap_or(0);
+        out.extend_from_slice(&flat_record.record.encode(next, lower));
+    }
+    out
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dicom::core::{DataElement, PrimitiveValue};
+    use dicom::object::FileMetaTableBuilder;
+
+    fn test_dir(name: &str) -> path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("dcm_finder_test_{}_dicomdir_{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Записывает в `root/file_id` файл КТ пациента `patient` с серией `series` исследования 1.2.9
+    fn write_instance(root: &path::Path, file_id: &str, patient: &str, series: &str, number: i32) {
+        let sop_instance_uid = format!("{}.{}", series, number);
+        let mut obj = InMemDicomObject::new_empty();
+        obj.put(DataElement::new(Tag(0x0010, 0x0020), VR::LO, PrimitiveValue::from(patient)));
+        obj.put(DataElement::new(Tag(0x0020, 0x000D), VR::UI, PrimitiveValue::from("1.2.9")));
+        obj.put(DataElement::new(Tag(0x0020, 0x000E), VR::UI, PrimitiveValue::from(series)));
+        obj.put(DataElement::new(Tag(0x0020, 0x0013), VR::IS, PrimitiveValue::from(number.to_string())));
+        obj.put(DataElement::new(Tag(0x0008, 0x0016), VR::UI, PrimitiveValue::from("1.2.840.10008.5.1.4.1.1.2")));
+        obj.put(DataElement::new(Tag(0x0008, 0x0018), VR::UI, PrimitiveValue::from(sop_instance_uid.as_str())));
+        let obj = obj.with_meta(FileMetaTableBuilder::new()
+            .media_storage_sop_class_uid("1.2.840.10008.5.1.4.1.1.2")
+            .media_storage_sop_instance_uid(sop_instance_uid.as_str())
+            .transfer_syntax(work_dcm::EXPLICIT_VR_LITTLE_ENDIAN))
+            .unwrap();
+        let file_path = root.join(file_id);
+        fs::create_dir_all(file_path.parent().unwrap()).unwrap();
+        obj.write_to_file(&file_path).unwrap();
+    }
+
+    /// Дерево из двух пациентов: P1 с сериями 1.2.9.1 (два экземпляра) и 1.2.9.2, P2 с серией 1.2.9.3
+    fn media_tree(name: &str) -> path::PathBuf {
+        let root = test_dir(name);
+        write_instance(&root, "DICOM/P1/S1/R1/I1", "P1", "1.2.9.1", 1);
+        write_instance(&root, "DICOM/P1/S1/R1/I2", "P1", "1.2.9.1", 2);
+        write_instance(&root, "DICOM/P1/S1/R2/I1", "P1", "1.2.9.2", 1);
+        write_instance(&root, "DICOM/P2/S1/R1/I1", "P2", "1.2.9.3", 1);
+        assert_eq!(write_dicomdir(&root).unwrap(), (4, 0));
+        root
+    }
+
+    /// Позиция значения элемента `tag` записи, начинающейся с `record`
+    fn value_position(data: &[u8], record: u32, tag: Tag) -> usize {
+        let mut pos = record as usize + 8;
+        loop {
+            let (element, _, header) = element_header(data, pos).unwrap();
+            if element == tag {
+                return pos + header;
+            }
+            pos = skip_element(data, pos).unwrap();
+        }
+    }
+
+    fn ul_at(data: &[u8], pos: usize) -> u32 {
+        u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap())
+    }
+
+    fn series_order(dicomdir: &path::Path) -> Vec<(String, String)> {
+        read_dicomdir(dicomdir, &[]).unwrap().iter()
+            .map(|meta| (meta.get_patient_ref().patient_id.trim().to_string(),
+                         meta.get_series_ref().series_uid.trim().to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn written_offsets_point_at_records() {
+        let root = media_tree("offsets");
+        let data = fs::read(root.join("DICOMDIR")).unwrap();
+        let offsets = record_offsets(&data).unwrap();
+        let obj = work_dcm::read_dcm(&root.join("DICOMDIR")).unwrap();
+        let records = obj.element(DIRECTORY_RECORD_SEQUENCE).unwrap().value().items().unwrap();
+        assert_eq!(offsets.len(), records.len());
+        assert_eq!(offsets.len(), 2 + 2 + 3 + 4);
+
+        let kind = |offset: u32| {
+            let index = offsets.iter().position(|&o| o == offset).unwrap();
+            records[index].element(DIRECTORY_RECORD_TYPE).unwrap().to_str().unwrap().trim().to_string()
+        };
+        let first = offset_value(&obj, FIRST_ROOT_RECORD_OFFSET);
+        let last = offset_value(&obj, Tag(0x0004, 0x1202));
+        assert_eq!(kind(first), "PATIENT");
+        assert_eq!(kind(last), "PATIENT");
+        assert_eq!(offset_value(&records[offsets.iter().position(|&o| o == first).unwrap()], NEXT_RECORD_OFFSET), last);
+        for (record, &offset) in records.iter().zip(&offsets) {
+            // смещения в объекте совпадают со значениями в байтах файла
+            assert_eq!(ul_at(&data, value_position(&data, offset, NEXT_RECORD_OFFSET)),
+                       offset_value(record, NEXT_RECORD_OFFSET));
+            let next = offset_value(record, NEXT_RECORD_OFFSET);
+            if next != 0 {
+                assert_eq!(kind(next), kind(offset));
+            }
+            let lower = offset_value(record, LOWER_LEVEL_RECORD_OFFSET);
+            let expected_lower = match kind(offset).as_str() {
+                "PATIENT" => Some("STUDY"),
+                "STUDY" => Some("SERIES"),
+                "SERIES" => Some("IMAGE"),
+                _ => None,
+            };
+            assert_eq!(expected_lower.map(str::to_string), (lower != 0).then(|| kind(lower)));
+        }
+
+        let instances = read_dicomdir(&root.join("DICOMDIR"), &[]).unwrap();
+        assert_eq!(instances.len(), 4);
+        assert!(instances.iter().all(|meta| path::Path::new(meta.get_path_ref()).is_file()));
+        assert_eq!(series_order(&root.join("DICOMDIR")), vec![
+            ("P1".to_string(), "1.2.9.1".to_string()),
+            ("P1".to_string(), "1.2.9.1".to_string()),
+            ("P1".to_string(), "1.2.9.2".to_string()),
+            ("P2".to_string(), "1.2.9.3".to_string()),
+        ]);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn records_are_read_by_offsets() {
+        let root = media_tree("links");
+        let dicomdir = root.join("DICOMDIR");
+        let mut data = fs::read(&dicomdir).unwrap();
+        let offsets = record_offsets(&data).unwrap();
+        let obj = work_dcm::read_dcm(&dicomdir).unwrap();
+        let first = offset_value(&obj, FIRST_ROOT_RECORD_OFFSET);
+        let second = offset_value(&obj, Tag(0x0004, 0x1202));
+
+        // второй пациент становится первым, а первая серия первого пациента — неиспользуемой
+        let first_root = 132 + data[132..].windows(6).position(|w| w == b"\x04\x00\x00\x12UL").unwrap() + 8;
+        data[first_root..first_root + 4].copy_from_slice(&second.to_le_bytes());
+        let next = value_position(&data, second, NEXT_RECORD_OFFSET);
+        data[next..next + 4].copy_from_slice(&first.to_le_bytes());
+        let next = value_position(&data, first, NEXT_RECORD_OFFSET);
+        data[next..next + 4].copy_from_slice(&0u32.to_le_bytes());
+        let first_series = offsets[2];
+        let in_use = value_position(&data, first_series, RECORD_IN_USE_FLAG);
+        data[in_use..in_use + 2].copy_from_slice(&0u16.to_le_bytes());
+        fs::write(&dicomdir, &data).unwrap();
+
+        assert_eq!(series_order(&dicomdir), vec![
+            ("P2".to_string(), "1.2.9.3".to_string()),
+            ("P1".to_string(), "1.2.9.2".to_string()),
+        ]);
+
+        // при цикле в ссылках записи читаются в порядке последовательности
+        let next = value_position(&data, first, NEXT_RECORD_OFFSET);
+        data[next..next + 4].copy_from_slice(&second.to_le_bytes());
+        fs::write(&dicomdir, &data).unwrap();
+        assert_eq!(series_order(&dicomdir).len(), 4);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn colliding_file_ids_get_distinct_components() {
+        let mut seen = HashMap::new();
+        let (a, b) = (0..)
+            .map(|i| format!("1.2.{}", i))
+            .find_map(|value| seen.insert(file_id_component('S', &value), value.clone())
+                .map(|previous| (previous, value)))
+            .unwrap();
+
+        let ids = FileIds::default();
+        let parent = path::Path::new("DICOM/P0000001");
+        let first = ids.component(parent, 'S', &a);
+        let second = ids.component(parent, 'S', &b);
+        assert_eq!(first, file_id_component('S', &a));
+        assert_ne!(first, second);
+        assert!(is_valid_file_id_component(&second));
+        assert_eq!(ids.component(parent, 'S', &b), second);
+        assert_eq!(ids.component(path::Path::new("DICOM/P0000002"), 'S', &b), first);
+    }
+}
@@ -0,0 +1,1332 @@
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::net::{TcpListener, TcpStream};
+use std::path;
+use std::str::FromStr;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+use dicom::core::{PrimitiveValue, Tag, VR};
+use dicom::object::mem::InMemElement;
+use dicom::object::{DefaultDicomObject, FileMetaTableBuilder, InMemDicomObject};
+use dicom::encoding::TransferSyntaxIndex;
+use dicom::transfer_syntax::TransferSyntaxRegistry;
+use dicom::object::OpenFileOptions;
+use dicom::ul::association::client::{ClientAssociation, ClientAssociationOptions};
+use dicom::ul::association::server::choose_supported;
+use dicom::ul::pdu::reader::{DEFAULT_MAX_PDU, MAXIMUM_PDU_SIZE};
+use dicom::ul::pdu::{read_pdu, write_pdu, AssociationRJResult, AssociationRJServiceUserReason,
+                     AssociationRJSource, AbortRQSource, PDataValue, PDataValueType, Pdu, PresentationContextResult,
+                     PresentationContextResultReason, UserVariableItem};
+
+use crate::dir_scan;
+use crate::work_archive;
+use crate::work_dcm;
+use crate::work_db;
+use crate::work_db::Dcm;
+use crate::work_qr;
+use crate::work_transcode;
+
+
+/// Verification SOP Class (C-ECHO)
+pub const VERIFICATION_SOP_CLASS: &str = "1.2.840.10008.1.1";
+
+/// Storage SOP Classes, экземпляры которых принимаются и отправляются по C-STORE
+pub const STORAGE_SOP_CLASSES: &[&str] = &[
+    "1.2.840.10008.5.1.4.1.1.1",        // Computed Radiography Image
+    "1.2.840.10008.5.1.4.1.1.1.1",      // Digital X-Ray Image - For Presentation
+    "1.2.840.10008.5.1.4.1.1.1.1.1",    // Digital X-Ray Image - For Processing
+    "1.2.840.10008.5.1.4.1.1.1.2",      // Digital Mammography X-Ray Image - For Presentation
+    "1.2.840.10008.5.1.4.1.1.1.2.1",    // Digital Mammography X-Ray Image - For Processing
+    "1.2.840.10008.5.1.4.1.1.1.3",      // Digital Intra-Oral X-Ray Image - For Presentation
+    "1.2.840.10008.5.1.4.1.1.2",        // CT Image
+    "1.2.840.10008.5.1.4.1.1.2.1",      // Enhanced CT Image
+    "1.2.840.10008.5.1.4.1.1.3.1",      // Ultrasound Multi-frame Image
+    "1.2.840.10008.5.1.4.1.1.4",        // MR Image
+    "1.2.840.10008.5.1.4.1.1.4.1",      // Enhanced MR Image
+    "1.2.840.10008.5.1.4.1.1.4.2",      // MR Spectroscopy
+    "1.2.840.10008.5.1.4.1.1.6.1",      // Ultrasound Image
+    "1.2.840.10008.5.1.4.1.1.7",        // Secondary Capture Image
+    "1.2.840.10008.5.1.4.1.1.7.1",      // Multi-frame Single Bit Secondary Capture Image
+    "1.2.840.10008.5.1.4.1.1.7.2",      // Multi-frame Grayscale Byte Secondary Capture Image
+    "1.2.840.10008.5.1.4.1.1.7.3",      // Multi-frame Grayscale Word Secondary Capture Image
+    "1.2.840.10008.5.1.4.1.1.7.4",      // Multi-frame True Color Secondary Capture Image
+    "1.2.840.10008.5.1.4.1.1.11.1",     // Grayscale Softcopy Presentation State
+    "1.2.840.10008.5.1.4.1.1.12.1",     // X-Ray Angiographic Image
+    "1.2.840.10008.5.1.4.1.1.12.1.1",   // Enhanced XA Image
+    "1.2.840.10008.5.1.4.1.1.12.2",     // X-Ray Radiofluoroscopic Image
+    "1.2.840.10008.5.1.4.1.1.12.2.1",   // Enhanced XRF Image
+    "1.2.840.10008.5.1.4.1.1.13.1.3",   // Breast Tomosynthesis Image
+    "1.2.840.10008.5.1.4.1.1.20",       // Nuclear Medicine Image
+    "1.2.840.10008.5.1.4.1.1.66",       // Raw Data
+    "1.2.840.10008.5.1.4.1.1.66.1",     // Spatial Registration
+    "1.2.840.10008.5.1.4.1.1.66.4",     // Segmentation
+    "1.2.840.10008.5.1.4.1.1.77.1.1",   // VL Endoscopic Image
+    "1.2.840.10008.5.1.4.1.1.77.1.2",   // VL Microscopic Image
+    "1.2.840.10008.5.1.4.1.1.77.1.4",   // VL Photographic Image
+    "1.2.840.10008.5.1.4.1.1.77.1.5.1", // Ophthalmic Photography 8 Bit Image
+    "1.2.840.10008.5.1.4.1.1.88.11",    // Basic Text SR
+    "1.2.840.10008.5.1.4.1.1.88.22",    // Enhanced SR
+    "1.2.840.10008.5.1.4.1.1.88.33",    // Comprehensive SR
+    "1.2.840.10008.5.1.4.1.1.88.59",    // Key Object Selection Document
+    "1.2.840.10008.5.1.4.1.1.104.1",    // Encapsulated PDF
+    "1.2.840.10008.5.1.4.1.1.128",      // Positron Emission Tomography Image
+    "1.2.840.10008.5.1.4.1.1.130",      // Enhanced PET Image
+    "1.2.840.10008.5.1.4.1.1.481.1",    // RT Image
+    "1.2.840.10008.5.1.4.1.1.481.2",    // RT Dose
+    "1.2.840.10008.5.1.4.1.1.481.3",    // RT Structure Set
+    "1.2.840.10008.5.1.4.1.1.481.5",    // RT Plan
+];
+
+/// DICOM Application Context Name
+const APPLICATION_CONTEXT_NAME: &str = "1.2.840.10008.3.1.1.1";
+/// Тип подэлемента SCP/SCU Role Selection в User Information
+const ROLE_SELECTION_ITEM: u8 = 0x54;
+
+// Атрибуты набора команд (группа 0000)
+const COMMAND_GROUP_LENGTH: Tag = Tag(0x0000, 0x0000);
+pub const AFFECTED_SOP_CLASS_UID: Tag = Tag(0x0000, 0x0002);
+pub const COMMAND_FIELD: Tag = Tag(0x0000, 0x0100);
+pub const MESSAGE_ID: Tag = Tag(0x0000, 0x0110);
+pub const PRIORITY: Tag = Tag(0x0000, 0x0700);
+pub const MESSAGE_ID_BEING_RESPONDED_TO: Tag = Tag(0x0000, 0x0120);
+pub const COMMAND_DATA_SET_TYPE: Tag = Tag(0x0000, 0x0800);
+pub const STATUS: Tag = Tag(0x0000, 0x0900);
+pub const AFFECTED_SOP_INSTANCE_UID: Tag = Tag(0x0000, 0x1000);
+
+pub const C_STORE_RQ: u16 = 0x0001;
+pub const C_STORE_RSP: u16 = 0x8001;
+pub const C_GET_RQ: u16 = 0x0010;
+pub const C_FIND_RQ: u16 = 0x0020;
+pub const C_MOVE_RQ: u16 = 0x0021;
+const C_ECHO_RQ: u16 = 0x0030;
+const C_ECHO_RSP: u16 = 0x8030;
+/// C-CANCEL-RQ не требует ответа
+const C_CANCEL_RQ: u16 = 0x0FFF;
+/// Значение Command Data Set Type для команды без набора данных
+pub const NO_DATA_SET: u16 = 0x0101;
+/// Значение Command Data Set Type для команды, за которой следует набор данных
+pub const DATA_SET_PRESENT: u16 = 0x0000;
+/// Максимальное количество контекстов представления в одной ассоциации
+const MAX_PRESENTATION_CONTEXTS: usize = 127;
+
+pub const STATUS_SUCCESS: u16 = 0x0000;
+/// Error: Cannot understand — экземпляр не удалось разобрать или сохранить
+pub const STATUS_CANNOT_UNDERSTAND: u16 = 0xC000;
+const STATUS_UNRECOGNIZED_OPERATION: u16 = 0x0211;
+
+
+/// Параметры приема экземпляров по DIMSE
+pub struct ListenOptions {
+    /// Адрес, на котором принимаются соединения
+    pub bind: String,
+    /// TCP порт
+    pub port: u16,
+    /// AE Title этого узла
+    pub aet: String,
+    /// Директория, в которую сохраняются принятые файлы
+    pub save_in: path::PathBuf,
+    /// Деперсонализировать экземпляры при приеме
+    pub depersonalize: bool,
+    /// Известные получатели C-MOVE (Move Destination ищется среди них по AE Title)
+    pub move_destinations: Vec<SendTarget>,
+    /// Наибольший размер набора команд или набора данных одного сообщения, байт
+    pub max_message: u64,
+}
+
+/// Запускает Storage SCP и Query/Retrieve SCP: принимает ассоциации, отвечает на C-ECHO,
+/// сохраняет экземпляры, принятые по C-STORE, в `save_in` (раскладка как у `depersonalize`),
+/// отвечает на C-FIND по индексу `db_path` и отправляет найденные файлы по C-MOVE/C-GET.
+/// Каждый принятый экземпляр сразу добавляется в индекс.
+/// Каждая ассоциация обслуживается в отдельном потоке, индекс пишет один поток.
+pub fn listen(options: ListenOptions, db_path: &path::Path) {
+    let conn = match work_db::Connection::open_dcm_tables(db_path) {
+        Ok(conn) => conn,
+        Err(e) => {
+            eprintln!("Error open data base [path: {}]: {:?}", db_path.display(), e);
+            return;
+        }
+    };
+    let listener = match TcpListener::bind((options.bind.as_str(), options.port)) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Error listening on {}:{}: {:?}", options.bind, options.port, e);
+            return;
+        }
+    };
+    println!("Listening on {}:{} as {}", options.bind, options.port, options.aet);
+
+    let (meta_tx, meta_rx) = mpsc::channel::<work_dcm::MetaDcm>();
+    thread::spawn(move || dir_scan::index_received(&conn, meta_rx));
+    let service = Arc::new(Service::new(options, db_path));
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let service = Arc::clone(&service);
+                let meta_tx = meta_tx.clone();
+                thread::spawn(move || serve_association(stream, &service, &meta_tx));
+            }
+            Err(e) => eprintln!("Error accepting connection: {:?}", e),
+        }
+    }
+}
+
+/// Параметры, общие для всех ассоциаций узла
+pub struct Service {
+    pub options: ListenOptions,
+    /// Индекс, по которому выполняются запросы C-FIND и C-MOVE/C-GET
+    pub db_path: path::PathBuf,
+    /// Принимаемые абстрактные синтаксисы
+    abstract_syntaxes: Vec<&'static str>,
+}
+
+impl Service {
+    /// Узел, принимающий Verification, Storage и Query/Retrieve SOP Class
+    fn new(options: ListenOptions, db_path: &path::Path) -> Service {
+        let mut abstract_syntaxes: Vec<&'static str> = vec![VERIFICATION_SOP_CLASS];
+        abstract_syntaxes.extend_from_slice(STORAGE_SOP_CLASSES);
+        abstract_syntaxes.extend_from_slice(work_qr::QUERY_RETRIEVE_SOP_CLASSES);
+        Service { options, db_path: db_path.to_path_buf(), abstract_syntaxes }
+    }
+}
+
+/// Контекст представления, принятый этим узлом
+pub struct AcceptedContext {
+    pub id: u8,
+    pub abstract_syntax: String,
+    pub transfer_syntax: String,
+}
+
+/// Сообщение DIMSE: идентификатор контекста, набор команд и набор данных (если есть)
+pub type Message = (u8, InMemDicomObject, Option<Vec<u8>>);
+
+/// Ассоциация, принятая этим узлом. Согласуется здесь, а не в `ServerAssociation`:
+/// для C-GET нужно знать абстрактный синтаксис каждого контекста и подтверждать
+/// роль SCP (SCP/SCU Role Selection), которую запрашивает вызывающий узел
+pub struct PeerAssociation {
+    socket: TcpStream,
+    calling_aet: String,
+    contexts: Vec<AcceptedContext>,
+    /// Максимальная длина PDU, которую принимает вызывающий узел
+    peer_max_pdu_length: u32,
+    /// Наибольший размер набора команд или набора данных принимаемого сообщения
+    max_message_length: u64,
+}
+
+impl PeerAssociation {
+    /// Принимает A-ASSOCIATE-RQ: проверяет вызываемый AE Title, принимает контексты
+    /// с известными абстрактными синтаксисами (первый поддерживаемый синтаксис передачи)
+    /// и подтверждает предложенные роли для принятых SOP Class
+    fn accept(mut socket: TcpStream, aet: &str, abstract_syntaxes: &[&str],
+              max_message_length: u64) -> Result<PeerAssociation, String> {
+        let pdu = read_pdu(&mut socket, MAXIMUM_PDU_SIZE, false).map_err(|e| e.to_string())?;
+        let (calling_ae_title, called_ae_title, application_context_name, proposed, user_variables) = match pdu {
+            Pdu::AssociationRQ { calling_ae_title, called_ae_title, application_context_name,
+                                 presentation_contexts, user_variables, .. } => {
+                (calling_ae_title, called_ae_title, application_context_name, presentation_contexts, user_variables)
+            }
+            pdu => return Err(format!("unexpected PDU {:?}", pdu)),
+        };
+        let reject = |socket: &mut TcpStream, reason: AssociationRJServiceUserReason| {
+            let rejection = Pdu::AssociationRJ {
+                result: AssociationRJResult::Permanent,
+                source: AssociationRJSource::ServiceUser(reason.clone()),
+            };
+            let mut buffer = Vec::new();
+            write_pdu(&mut buffer, &rejection).ok();
+            socket.write_all(&buffer).ok();
+            Err(reason.to_string())
+        };
+        if application_context_name.trim_end_matches(['\0', ' ']) != APPLICATION_CONTEXT_NAME {
+            return reject(&mut socket, AssociationRJServiceUserReason::ApplicationContextNameNotSupported);
+        }
+        if called_ae_title.trim() != aet {
+            return reject(&mut socket, AssociationRJServiceUserReason::CalledAETitleNotRecognized);
+        }
+        let peer_max_pdu_length = match user_variables.iter().find_map(|item| match item {
+            UserVariableItem::MaxLength(length) => Some(*length),
+            _ => None,
+        }) {
+            Some(0) => MAXIMUM_PDU_SIZE,
+            Some(length) => length,
+            None => DEFAULT_MAX_PDU,
+        };
+
+        let mut contexts: Vec<AcceptedContext> = Vec::new();
+        let results: Vec<PresentationContextResult> = proposed.into_iter()
+            .map(|pc| {
+                let abstract_syntax = pc.abstract_syntax.trim_end_matches(['\0', ' ']).to_string();
+                let transfer_syntax = choose_supported(pc.transfer_syntaxes.iter()
+                    .map(|ts| ts.trim_end_matches(['\0', ' '])));
+                let reason = match transfer_syntax {
+                    _ if !abstract_syntaxes.contains(&abstract_syntax.as_str()) => {
+                        PresentationContextResultReason::AbstractSyntaxNotSupported
+                    }
+                    None => PresentationContextResultReason::TransferSyntaxesNotSupported,
+                    Some(transfer_syntax) => {
+                        contexts.push(AcceptedContext {
+                            id: pc.id,
+                            abstract_syntax,
+                            transfer_syntax: transfer_syntax.to_string(),
+                        });
+                        PresentationContextResultReason::Acceptance
+                    }
+                };
+                PresentationContextResult {
+                    id: pc.id,
+                    reason,
+                    transfer_syntax: transfer_syntax.unwrap_or(work_dcm::IMPLICIT_VR_LITTLE_ENDIAN).to_string(),
+                }
+            })
+            .collect();
+
+        let mut accepted_variables = vec![
+            UserVariableItem::MaxLength(MAXIMUM_PDU_SIZE),
+            UserVariableItem::ImplementationClassUID(work_dcm::IMPLEMENTATION_CLASS_UID.to_string()),
+            UserVariableItem::ImplementationVersionName(work_dcm::IMPLEMENTATION_VERSION_NAME.to_string()),
+        ];
+        for item in user_variables {
+            if let UserVariableItem::Unknown(ROLE_SELECTION_ITEM, data) = item {
+                // UID-length (2 байта), SOP Class UID, SCU-role, SCP-role
+                let uid_length = data.get(..2).map(|b| u16::from_be_bytes([b[0], b[1]]) as usize).unwrap_or(0);
+                let sop_class_uid = data.get(2..2 + uid_length)
+                    .map(|uid| String::from_utf8_lossy(uid).trim_end_matches(['\0', ' ']).to_string());
+                let accepted = sop_class_uid
+                    .map(|uid| contexts.iter().any(|pc| pc.abstract_syntax == uid))
+                    .unwrap_or(false);
+                if accepted && data.len() == uid_length + 4 {
+                    accepted_variables.push(UserVariableItem::Unknown(ROLE_SELECTION_ITEM, data));
+                }
+            }
+        }
+        let mut association = PeerAssociation {
+            socket,
+            calling_aet: calling_ae_title.trim().to_string(),
+            contexts,
+            peer_max_pdu_length,
+            max_message_length,
+        };
+        association.send(&Pdu::AssociationAC {
+            protocol_version: 1,
+            calling_ae_title,
+            called_ae_title,
+            application_context_name,
+            presentation_contexts: results,
+            user_variables: accepted_variables,
+        })?;
+        Ok(association)
+    }
+
+    pub fn calling_aet(&self) -> &str {
+        &self.calling_aet
+    }
+
+    /// Принятый контекст представления по идентификатору
+    pub fn context(&self, pc_id: u8) -> Option<&AcceptedContext> {
+        self.contexts.iter().find(|pc| pc.id == pc_id)
+    }
+
+    pub fn contexts(&self) -> &[AcceptedContext] {
+        &self.contexts
+    }
+
+    pub fn send(&mut self, pdu: &Pdu) -> Result<(), String> {
+        let mut buffer = Vec::new();
+        write_pdu(&mut buffer, pdu).map_err(|e| e.to_string())?;
+        self.socket.write_all(&buffer).map_err(|e| e.to_string())
+    }
+
+    pub fn receive(&mut self) -> Result<Pdu, String> {
+        read_pdu(&mut self.socket, MAXIMUM_PDU_SIZE, false).map_err(|e| e.to_string())
+    }
+
+    /// Отправляет сообщение DIMSE: набор команд и (если есть) набор данных,
+    /// разбитый на фрагменты по максимальной длине PDU вызывающего узла
+    pub fn send_message(&mut self, pc_id: u8, command: &InMemDicomObject, data: Option<&[u8]>) -> Result<(), String> {
+        self.send_fragments(pc_id, PDataValueType::Command, &encode_command(command))?;
+        match data {
+            Some(data) => self.send_fragments(pc_id, PDataValueType::Data, data),
+            None => Ok(()),
+        }
+    }
+
+    fn send_fragments(&mut self, pc_id: u8, value_type: PDataValueType, data: &[u8]) -> Result<(), String> {
+        // Заголовок PDV: длина (4 байта), идентификатор контекста и признаки фрагмента
+        let max_fragment = self.peer_max_pdu_length.saturating_sub(6).max(1) as usize;
+        let mut fragments = data.chunks(max_fragment).peekable();
+        if fragments.peek().is_none() {
+            return self.send(&Pdu::PData {
+                data: vec![PDataValue { presentation_context_id: pc_id, value_type, is_last: true, data: Vec::new() }],
+            });
+        }
+        while let Some(fragment) = fragments.next() {
+            self.send(&Pdu::PData {
+                data: vec![PDataValue {
+                    presentation_context_id: pc_id,
+                    value_type: value_type.clone(),
+                    is_last: fragments.peek().is_none(),
+                    data: fragment.to_vec(),
+                }],
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Принимает следующее сообщение DIMSE: набор команд и набор данных, если он объявлен.
+    /// Возвращает `None`, когда ассоциация освобождена или прервана. Если набор команд или данных
+    /// больше `max_message_length`, ассоциация прерывается (A-ABORT)
+    pub fn receive_message(&mut self) -> Result<Option<Message>, String> {
+        let mut command: Option<(u8, InMemDicomObject)> = None;
+        let mut command_buf: Vec<u8> = Vec::new();
+        let mut data_buf: Vec<u8> = Vec::new();
+        loop {
+            match self.receive()? {
+                Pdu::PData { data } => {
+                    for value in data {
+                        match value.value_type {
+                            PDataValueType::Command => {
+                                self.check_message_length(command_buf.len() + value.data.len())?;
+                                command_buf.extend_from_slice(&value.data);
+                                if !value.is_last {
+                                    continue;
+                                }
+                                let received = decode_command(&command_buf)
+                                    .ok_or_else(|| format!("error decoding command from {}", self.calling_aet))?;
+                                command_buf.clear();
+                                if get_u16(&received, COMMAND_DATA_SET_TYPE) == Some(NO_DATA_SET) {
+                                    return Ok(Some((value.presentation_context_id, received, None)));
+                                }
+                                command = Some((value.presentation_context_id, received));
+                            }
+                            PDataValueType::Data => {
+                                self.check_message_length(data_buf.len() + value.data.len())?;
+                                data_buf.extend_from_slice(&value.data);
+                                if !value.is_last {
+                                    continue;
+                                }
+                                if let Some((pc_id, received)) = command.take() {
+                                    return Ok(Some((pc_id, received, Some(data_buf))));
+                                }
+                                data_buf.clear();
+                            }
+                        }
+                    }
+                }
+                Pdu::ReleaseRQ => {
+                    self.send(&Pdu::ReleaseRP)?;
+                    return Ok(None);
+                }
+                Pdu::AbortRQ { .. } => return Ok(None),
+                _ => {}
+            }
+        }
+    }
+
+    /// Прерывает ассоциацию, если принимаемый набор команд или данных превысил допустимый размер
+    fn check_message_length(&mut self, length: usize) -> Result<(), String> {
+        if length as u64 <= self.max_message_length {
+            return Ok(());
+        }
+        self.send(&Pdu::AbortRQ { source: AbortRQSource::ServiceUser }).ok();
+        Err(format!("message from {} exceeds {} bytes, association aborted", self.calling_aet, self.max_message_length))
+    }
+}
+
+fn serve_association(stream: TcpStream, service: &Service, meta_tx: &mpsc::Sender<work_dcm::MetaDcm>) {
+    let peer = stream.peer_addr().map(|a| a.to_string()).unwrap_or_default();
+    let mut association = match PeerAssociation::accept(stream, &service.options.aet, &service.abstract_syntaxes,
+                                                         service.options.max_message) {
+        Ok(association) => association,
+        Err(e) => {
+            eprintln!("Association from {} rejected: {}", peer, e);
+            return;
+        }
+    };
+    loop {
+        match association.receive_message() {
+            Ok(Some((pc_id, command, data))) => {
+                handle_command(&mut association, pc_id, &command, data.as_deref(), service, meta_tx);
+            }
+            Ok(None) => break,
+            Err(e) => {
+                eprintln!("Association with {} interrupted: {}", association.calling_aet(), e);
+                break;
+            }
+        }
+    }
+}
+
+/// Выполняет принятую команду и отправляет ответ
+fn handle_command(association: &mut PeerAssociation, pc_id: u8, command: &InMemDicomObject,
+                  data: Option<&[u8]>, service: &Service, meta_tx: &mpsc::Sender<work_dcm::MetaDcm>) {
+    let message_id = get_u16(command, MESSAGE_ID).unwrap_or(0);
+    let sop_class_uid = get_str(command, AFFECTED_SOP_CLASS_UID);
+    let calling_aet = association.calling_aet().to_string();
+    let response = match get_u16(command, COMMAND_FIELD) {
+        Some(C_ECHO_RQ) => {
+            response_command(C_ECHO_RSP, message_id, &sop_class_uid, None, STATUS_SUCCESS)
+        }
+        Some(C_STORE_RQ) => {
+            let sop_instance_uid = get_str(command, AFFECTED_SOP_INSTANCE_UID);
+            let transfer_syntax = association.context(pc_id)
+                .map(|pc| pc.transfer_syntax.clone())
+                .unwrap_or_else(|| work_dcm::IMPLICIT_VR_LITTLE_ENDIAN.to_string());
+            let status = match store_instance(data.unwrap_or_default(), &sop_class_uid, &sop_instance_uid,
+                                              &transfer_syntax, &service.options, &calling_aet) {
+                Ok(meta_dcm) => {
+                    println!("Received {} from {} -> {}", sop_instance_uid, calling_aet, meta_dcm.get_path_ref());
+                    meta_tx.send(meta_dcm).unwrap_or_default();
+                    STATUS_SUCCESS
+                }
+                Err(e) => {
+                    eprintln!("Error storing {} from {}: {}", sop_instance_uid, calling_aet, e);
+                    STATUS_CANNOT_UNDERSTAND
+                }
+            };
+            response_command(C_STORE_RSP, message_id, &sop_class_uid, Some(&sop_instance_uid), status)
+        }
+        Some(C_FIND_RQ) => {
+            work_qr::handle_find(association, pc_id, command, data, service);
+            return;
+        }
+        Some(C_MOVE_RQ) => {
+            work_qr::handle_move(association, pc_id, command, data, service);
+            return;
+        }
+        Some(C_GET_RQ) => {
+            work_qr::handle_get(association, pc_id, command, data, service);
+            return;
+        }
+        Some(C_CANCEL_RQ) => return,
+        Some(command_field) => {
+            eprintln!("Unsupported command 0x{:04X} from {}", command_field, calling_aet);
+            response_command(command_field | 0x8000, message_id, &sop_class_uid, None,
+                             STATUS_UNRECOGNIZED_OPERATION)
+        }
+        None => return,
+    };
+    association.send_message(pc_id, &response, None).unwrap_or_else(|e| {
+        eprintln!("Error sending response to {}: {}", calling_aet, e);
+    });
+}
+
+/// Сохраняет принятый экземпляр и возвращает его метаданные для индекса
+fn store_instance(data: &[u8], sop_class_uid: &str, sop_instance_uid: &str, transfer_syntax: &str,
+                  options: &ListenOptions, calling_aet: &str) -> Result<work_dcm::MetaDcm, String> {
+    let ts = TransferSyntaxRegistry.get(transfer_syntax)
+        .ok_or_else(|| format!("unknown transfer syntax {}", transfer_syntax))?;
+    let dataset = InMemDicomObject::read_dataset_with_ts(data, ts).map_err(|e| e.to_string())?;
+    let mut dcm_obj: DefaultDicomObject = dataset
+        .with_meta(FileMetaTableBuilder::new()
+            .media_storage_sop_class_uid(sop_class_uid)
+            .media_storage_sop_instance_uid(sop_instance_uid)
+            .transfer_syntax(transfer_syntax)
+            .implementation_class_uid(work_dcm::IMPLEMENTATION_CLASS_UID)
+            .implementation_version_name(work_dcm::IMPLEMENTATION_VERSION_NAME)
+            .source_application_entity_title(calling_aet))
+        .map_err(|e| e.to_string())?;
+    // UID команды C-STORE должны совпадать с UID набора данных
+    work_dcm::check_file_meta(&dcm_obj)?;
+    if options.depersonalize {
+        work_dcm::depersonalize_obj(&mut dcm_obj);
+    }
+    let meta_dcm = work_dcm::MetaDcm::from(&dcm_obj, "");
+    let new_path = dir_scan::create_new_path(&meta_dcm, &options.save_in);
+    work_dcm::save_dcm(&mut dcm_obj, &new_path, Some(calling_aet)).map_err(|e| e.to_string())?;
+    Ok(work_dcm::MetaDcm::from(&dcm_obj, &new_path))
+}
+
+/// Собирает набор команд ответа
+pub fn response_command(command_field: u16, message_id: u16, sop_class_uid: &str,
+                    sop_instance_uid: Option<&str>, status: u16) -> InMemDicomObject {
+    let mut command = InMemDicomObject::new_empty();
+    command.put(InMemElement::new(AFFECTED_SOP_CLASS_UID, VR::UI, PrimitiveValue::from(uid_value(sop_class_uid))));
+    command.put(InMemElement::new(COMMAND_FIELD, VR::US, PrimitiveValue::from(command_field)));
+    command.put(InMemElement::new(MESSAGE_ID_BEING_RESPONDED_TO, VR::US, PrimitiveValue::from(message_id)));
+    command.put(InMemElement::new(COMMAND_DATA_SET_TYPE, VR::US, PrimitiveValue::from(NO_DATA_SET)));
+    command.put(InMemElement::new(STATUS, VR::US, PrimitiveValue::from(status)));
+    if let Some(sop_instance_uid) = sop_instance_uid {
+        command.put(InMemElement::new(AFFECTED_SOP_INSTANCE_UID, VR::UI,
+                                      PrimitiveValue::from(uid_value(sop_instance_uid))));
+    }
+    command
+}
+
+/// Значение UI четной длины (дополняется нулевым байтом)
+pub fn uid_value(uid: &str) -> String {
+    let uid = uid.trim_end_matches('\0');
+    if uid.len() % 2 == 1 {
+        format!("{}\0", uid)
+    } else {
+        uid.to_string()
+    }
+}
+
+/// Кодирует набор команд в Implicit VR Little Endian с Command Group Length
+pub fn encode_command(command: &InMemDicomObject) -> Vec<u8> {
+    let ts = TransferSyntaxRegistry.get(work_dcm::IMPLICIT_VR_LITTLE_ENDIAN).unwrap();
+    let mut body = Vec::new();
+    command.write_dataset_with_ts(&mut body, ts).unwrap_or_else(|e| {
+        eprintln!("Error encoding command: {:?}", e);
+    });
+    let mut encoded = Vec::with_capacity(body.len() + 12);
+    encoded.extend_from_slice(&COMMAND_GROUP_LENGTH.group().to_le_bytes());
+    encoded.extend_from_slice(&COMMAND_GROUP_LENGTH.element().to_le_bytes());
+    encoded.extend_from_slice(&4u32.to_le_bytes());
+    encoded.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    encoded.extend_from_slice(&body);
+    encoded
+}
+
+/// Декодирует набор команд из Implicit VR Little Endian
+pub fn decode_command(data: &[u8]) -> Option<InMemDicomObject> {
+    let ts = TransferSyntaxRegistry.get(work_dcm::IMPLICIT_VR_LITTLE_ENDIAN)?;
+    InMemDicomObject::read_dataset_with_ts(data, ts).ok()
+}
+
+pub fn get_u16(obj: &InMemDicomObject, tag: Tag) -> Option<u16> {
+    obj.element(tag).ok()?.to_int::<u16>().ok()
+}
+
+pub fn get_str(obj: &InMemDicomObject, tag: Tag) -> String {
+    obj.element(tag).ok()
+        .and_then(|e| e.to_str().ok())
+        .map(|value| value.trim_end_matches(['\0', ' ']).to_string())
+        .unwrap_or_default()
+}
+
+
+/// Адрес получателя в виде `AET@host:port`
+#[derive(Debug, Clone)]
+pub struct SendTarget {
+    pub aet: String,
+    pub address: String,
+}
+
+impl FromStr for SendTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (aet, address) = s.split_once('@')
+            .ok_or_else(|| format!("expected AET@host:port, got `{}`", s))?;
+        if aet.is_empty() || aet.len() > 16 {
+            return Err(format!("invalid AE title `{}` (1 to 16 characters)", aet));
+        }
+        match address.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+                Ok(SendTarget { aet: aet.to_string(), address: address.to_string() })
+            }
+            _ => Err(format!("expected host:port, got `{}`", address)),
+        }
+    }
+}
+
+/// Параметры отправки экземпляров по C-STORE
+pub struct SendOptions {
+    /// Получатель
+    pub target: SendTarget,
+    /// AE Title этого узла
+    pub calling_aet: String,
+    /// Количество одновременно открытых ассоциаций
+    pub jobs: usize,
+    /// Количество повторных попыток отправки экземпляра
+    pub retries: usize,
+}
+
+/// Экземпляр для отправки
+struct Outgoing {
+    path: String,
+    sop_class_uid: String,
+    sop_instance_uid: String,
+    transfer_syntax: String,
+    /// Содержимое файла из архива: читается при проходе по архиву, чтобы не искать файл в нем заново
+    data: Option<Vec<u8>>,
+}
+
+/// Ошибка отправки: разрыв ассоциации (повторяется в новой ассоциации)
+/// или ошибка самого экземпляра (не повторяется)
+enum SendError {
+    Association(String),
+    Instance(String),
+}
+
+/// Количество отправленных экземпляров
+#[derive(Default, Clone)]
+pub struct SendCounts {
+    pub sent: usize,
+    pub warnings: usize,
+    pub failed: usize,
+}
+
+/// Итог отправки одного экземпляра
+pub enum SendStatus {
+    Success,
+    Warning(u16),
+    Failed(String),
+}
+
+/// Отправляет файлы `paths` получателю по C-STORE и выводит статус каждого экземпляра.
+/// Экземпляры распределяются между `jobs` ассоциациями, контексты представления
+/// предлагаются для SOP Class и синтаксиса передачи каждого файла (а также несжатые
+/// синтаксисы, в которые сжатый файл перекодируется, если его синтаксис не принят).
+/// Файлы из архивов отправляются по архивам: каждый архив читается за один проход
+pub fn send(paths: &[String], options: &SendOptions) {
+    let mut counts = SendCounts::default();
+    let groups = group_by_archive(paths);
+    if groups.is_empty() {
+        println!("No instances to send");
+        return;
+    }
+    for (archive, group) in groups {
+        let mut instances = load_outgoing(archive, &group, &mut |path, e| {
+            println!("[FAILED] {}: {}", path, e);
+            counts.failed += 1;
+        });
+        if instances.is_empty() {
+            continue;
+        }
+        instances.sort_by(|a, b| a.sop_class_uid.cmp(&b.sop_class_uid));
+        let jobs = options.jobs.clamp(1, instances.len());
+        let mut chunks: Vec<Vec<&Outgoing>> = (0..jobs).map(|_| Vec::new()).collect();
+        for (i, instance) in instances.iter().enumerate() {
+            chunks[i % jobs].push(instance);
+        }
+        let results: Vec<SendCounts> = thread::scope(|scope| {
+            let workers: Vec<_> = chunks.into_iter()
+                .map(|chunk| scope.spawn(move || send_chunk(&chunk, options, &mut print_status)))
+                .collect();
+            workers.into_iter().map(|worker| worker.join().unwrap_or_default()).collect()
+        });
+        for result in results {
+            counts.sent += result.sent;
+            counts.warnings += result.warnings;
+            counts.failed += result.failed;
+        }
+    }
+    println!("Sent to {}: {} (with warnings: {}), failed: {}",
+             options.target.aet, counts.sent, counts.warnings, counts.failed);
+}
+
+fn print_status(instance: &Outgoing, status: &SendStatus) {
+    match status {
+        SendStatus::Success => println!("[OK] {} {}", instance.sop_instance_uid, instance.path),
+        SendStatus::Warning(status) => {
+            println!("[WARNING 0x{:04X}] {} {}", status, instance.sop_instance_uid, instance.path)
+        }
+        SendStatus::Failed(e) => println!("[FAILED] {} {}: {}", instance.sop_instance_uid, instance.path, e),
+    }
+}
+
+/// Отправляет файлы `paths` получателю в одной ассоциации (для C-MOVE).
+/// После каждого экземпляра вызывает `progress` с количеством оставшихся экземпляров
+/// и текущими итогами; файлы, которые не удалось прочитать, считаются неотправленными
+pub fn send_with_progress(paths: &[String], options: &SendOptions,
+                          progress: &mut dyn FnMut(usize, &SendCounts)) -> SendCounts {
+    let mut counts = SendCounts::default();
+    let mut remaining = paths.len();
+    for (archive, group) in group_by_archive(paths) {
+        let mut instances = load_outgoing(archive, &group, &mut |path, e| {
+            eprintln!("Error reading {}: {}", path, e);
+            counts.failed += 1;
+            remaining -= 1;
+        });
+        instances.sort_by(|a, b| a.sop_class_uid.cmp(&b.sop_class_uid));
+        let instances: Vec<&Outgoing> = instances.iter().collect();
+        send_chunk(&instances, options, &mut |_, status| {
+            match status {
+                SendStatus::Success => counts.sent += 1,
+                SendStatus::Warning(_) => {
+                    counts.sent += 1;
+                    counts.warnings += 1;
+                }
+                SendStatus::Failed(_) => counts.failed += 1,
+            }
+            remaining -= 1;
+            progress(remaining, &counts);
+        });
+    }
+    counts
+}
+
+/// Группирует пути для отправки: обычные файлы — одна группа (архив `None`),
+/// файлы каждого архива — своя группа в порядке первого упоминания архива
+fn group_by_archive(paths: &[String]) -> Vec<(Option<&path::Path>, Vec<&str>)> {
+    let mut groups: Vec<(Option<&path::Path>, Vec<&str>)> = Vec::new();
+    for path in paths {
+        let archive = work_archive::split_virtual_path(path).map(|(archive, _)| archive);
+        match groups.iter_mut().find(|(group_archive, _)| *group_archive == archive) {
+            Some((_, group)) => group.push(path),
+            None => groups.push((archive, vec![path])),
+        }
+    }
+    groups
+}
+
+/// Читает экземпляры группы: у обычных файлов — только File Meta, файлы архива —
+/// целиком за один проход по архиву. Для файлов, которые не удалось прочитать, вызывает `on_error`
+fn load_outgoing(archive: Option<&path::Path>, paths: &[&str],
+                 on_error: &mut dyn FnMut(&str, String)) -> Vec<Outgoing> {
+    let archive = match archive {
+        Some(archive) => archive,
+        None => {
+            return paths.iter()
+                .filter_map(|path| read_outgoing(path).map_err(|e| on_error(path, e)).ok())
+                .collect();
+        }
+    };
+    let mut wanted: HashMap<&str, &str> = paths.iter()
+        .filter_map(|path| work_archive::split_virtual_path(path).map(|(_, member)| (member, *path)))
+        .collect();
+    let mut instances = Vec::with_capacity(wanted.len());
+    let mut errors: Vec<(&str, String)> = Vec::new();
+    let selected: HashSet<&str> = wanted.keys().copied().collect();
+    let result = work_archive::for_each_member(archive, |member| selected.contains(member), |member, data| {
+        if let Some(path) = wanted.remove(member) {
+            match outgoing_from_bytes(path, data) {
+                Ok(instance) => instances.push(instance),
+                Err(e) => errors.push((path, e)),
+            }
+        }
+    });
+    let missing = match result {
+        Ok(()) => "not found in archive".to_string(),
+        Err(e) => e.to_string(),
+    };
+    for (path, e) in errors {
+        on_error(path, e);
+    }
+    for path in wanted.into_values() {
+        on_error(path, missing.clone());
+    }
+    instances
+}
+
+/// Читает SOP Class, SOP Instance и синтаксис передачи файла из File Meta
+fn read_outgoing(path: &str) -> Result<Outgoing, String> {
+    let dcm_obj = OpenFileOptions::new()
+        .read_until(Tag(0x0008, 0x0000))
+        .open_file(path)
+        .map_err(|e| e.to_string())?;
+    Ok(outgoing(path, &dcm_obj, None))
+}
+
+/// Экземпляр для отправки из содержимого файла архива
+fn outgoing_from_bytes(path: &str, data: Vec<u8>) -> Result<Outgoing, String> {
+    if !work_dcm::is_dicom_bytes(&data) {
+        return Err("not a DICOM file".to_string());
+    }
+    let dcm_obj = work_dcm::read_dcm_from_bytes(&data).map_err(|e| e.to_string())?;
+    Ok(outgoing(path, &dcm_obj, Some(data)))
+}
+
+fn outgoing(path: &str, dcm_obj: &DefaultDicomObject, data: Option<Vec<u8>>) -> Outgoing {
+    let meta = dcm_obj.meta();
+    Outgoing {
+        path: path.to_string(),
+        sop_class_uid: meta.media_storage_sop_class_uid.trim_end_matches('\0').to_string(),
+        sop_instance_uid: meta.media_storage_sop_instance_uid.trim_end_matches('\0').to_string(),
+        transfer_syntax: meta.transfer_syntax.trim_end_matches('\0').to_string(),
+        data,
+    }
+}
+
+pub fn is_uncompressed(transfer_syntax: &str) -> bool {
+    transfer_syntax == work_dcm::IMPLICIT_VR_LITTLE_ENDIAN
+        || transfer_syntax == work_dcm::EXPLICIT_VR_LITTLE_ENDIAN
+        || transfer_syntax == work_dcm::EXPLICIT_VR_BIG_ENDIAN
+}
+
+/// Контексты представления для набора экземпляров: для каждого SOP Class — несжатые
+/// синтаксисы, для каждого сжатого синтаксиса файлов — отдельный контекст
+fn proposed_contexts(instances: &[&Outgoing]) -> Vec<(String, Vec<String>)> {
+    let mut contexts: Vec<(String, Vec<String>)> = Vec::new();
+    for instance in instances {
+        let uncompressed = vec![work_dcm::EXPLICIT_VR_LITTLE_ENDIAN.to_string(),
+                                work_dcm::IMPLICIT_VR_LITTLE_ENDIAN.to_string()];
+        if !contexts.iter().any(|(class, ts)| *class == instance.sop_class_uid && *ts == uncompressed) {
+            contexts.push((instance.sop_class_uid.clone(), uncompressed));
+        }
+        if !is_uncompressed(&instance.transfer_syntax) {
+            let compressed = vec![instance.transfer_syntax.clone()];
+            if !contexts.iter().any(|(class, ts)| *class == instance.sop_class_uid && *ts == compressed) {
+                contexts.push((instance.sop_class_uid.clone(), compressed));
+            }
+        }
+    }
+    contexts
+}
+
+/// Разбивает экземпляры на группы, контексты каждой из которых помещаются в одну ассоциацию
+fn split_by_contexts<'a>(instances: &[&'a Outgoing]) -> Vec<Vec<&'a Outgoing>> {
+    let mut batches: Vec<Vec<&Outgoing>> = vec![Vec::new()];
+    for instance in instances {
+        let batch = batches.last_mut().unwrap();
+        batch.push(instance);
+        if proposed_contexts(batch).len() > MAX_PRESENTATION_CONTEXTS {
+            batch.pop();
+            batches.push(vec![instance]);
+        }
+    }
+    batches
+}
+
+fn establish(contexts: &[(String, Vec<String>)], options: &SendOptions) -> Result<ClientAssociation, String> {
+    let mut client = ClientAssociationOptions::new()
+        .calling_ae_title(options.calling_aet.as_str())
+        .called_ae_title(options.target.aet.as_str());
+    for (abstract_syntax, transfer_syntaxes) in contexts {
+        client = client.with_presentation_context(
+            abstract_syntax.as_str(),
+            transfer_syntaxes.iter().map(|ts| ts.as_str()).collect());
+    }
+    client.establish(options.target.address.as_str()).map_err(|e| e.to_string())
+}
+
+/// Отправляет часть экземпляров в своей ассоциации (или нескольких, если контекстов много).
+/// При разрыве ассоциация открывается заново, экземпляр отправляется повторно
+/// до `retries` раз. Итог каждого экземпляра передается в `report`
+fn send_chunk(instances: &[&Outgoing], options: &SendOptions,
+              report: &mut dyn FnMut(&Outgoing, &SendStatus)) -> SendCounts {
+    let mut counts = SendCounts::default();
+    for batch in split_by_contexts(instances) {
+        let contexts = proposed_contexts(&batch);
+        let mut association: Option<ClientAssociation> = None;
+        // Если получатель не принял ассоциацию после всех попыток, остальные экземпляры не отправляются
+        let mut unreachable: Option<String> = None;
+        let mut message_id: u16 = 1;
+        for instance in batch {
+            let mut attempt = 0;
+            let result = loop {
+                if let Some(e) = &unreachable {
+                    break Err(e.clone());
+                }
+                attempt += 1;
+                let outcome = match association.as_mut() {
+                    Some(association) => store(association, &contexts, instance, message_id),
+                    None => match establish(&contexts, options) {
+                        Ok(established) => {
+                            let association = association.insert(established);
+                            store(association, &contexts, instance, message_id)
+                        }
+                        Err(e) if attempt > options.retries => {
+                            unreachable = Some(e.clone());
+                            Err(SendError::Association(e))
+                        }
+                        Err(e) => Err(SendError::Association(e)),
+                    },
+                };
+                message_id = message_id.wrapping_add(1);
+                match outcome {
+                    Ok(status) if is_success_or_warning(status) => break Ok(status),
+                    Ok(status) if attempt > options.retries => break Err(format!("status 0x{:04X}", status)),
+                    Err(SendError::Association(e)) => {
+                        association = None;
+                        if attempt > options.retries {
+                            break Err(e);
+                        }
+                    }
+                    Err(SendError::Instance(e)) => break Err(e),
+                    Ok(_) => {}
+                }
+                thread::sleep(Duration::from_millis(500 * attempt as u64));
+            };
+            let status = match result {
+                Ok(STATUS_SUCCESS) => {
+                    counts.sent += 1;
+                    SendStatus::Success
+                }
+                Ok(status) => {
+                    counts.sent += 1;
+                    counts.warnings += 1;
+                    SendStatus::Warning(status)
+                }
+                Err(e) => {
+                    counts.failed += 1;
+                    SendStatus::Failed(format!("{} (attempts: {})", e, attempt))
+                }
+            };
+            report(instance, &status);
+        }
+        if let Some(association) = association {
+            association.release().unwrap_or_else(|e| {
+                eprintln!("Error releasing association: {}", e);
+            });
+        }
+    }
+    counts
+}
+
+/// Успех (0000) или предупреждение (0001, Bxxx): экземпляр сохранен получателем
+pub fn is_success_or_warning(status: u16) -> bool {
+    status == STATUS_SUCCESS || status == 0x0001 || status & 0xF000 == 0xB000
+}
+
+/// Выбирает принятый контекст для экземпляра: с синтаксисом передачи файла,
+/// а если такого нет — любой принятый несжатый синтаксис (сжатый файл перекодируется)
+fn choose_context<'a>(accepted: &'a [PresentationContextResult], contexts: &[(String, Vec<String>)],
+                      instance: &Outgoing) -> Option<&'a PresentationContextResult> {
+    let for_class: Vec<&PresentationContextResult> = accepted.iter()
+        .filter(|pc| pc.reason == PresentationContextResultReason::Acceptance)
+        .filter(|pc| proposed_abstract_syntax(contexts, pc.id) == Some(instance.sop_class_uid.as_str()))
+        .collect();
+    for_class.iter()
+        .find(|pc| pc.transfer_syntax.trim_end_matches('\0') == instance.transfer_syntax)
+        .or_else(|| for_class.iter().find(|pc| is_uncompressed(pc.transfer_syntax.trim_end_matches('\0'))))
+        .copied()
+}
+
+/// Абстрактный синтаксис предложенного контекста по идентификатору из ответа
+/// (`ClientAssociationOptions` нумерует контексты по порядку, начиная с 1)
+fn proposed_abstract_syntax(contexts: &[(String, Vec<String>)], id: u8) -> Option<&str> {
+    (id as usize).checked_sub(1)
+        .and_then(|index| contexts.get(index))
+        .map(|(class, _)| class.as_str())
+}
+
+/// Собирает набор команд C-STORE-RQ
+pub fn store_command(sop_class_uid: &str, sop_instance_uid: &str, message_id: u16) -> InMemDicomObject {
+    let mut command = InMemDicomObject::new_empty();
+    command.put(InMemElement::new(AFFECTED_SOP_CLASS_UID, VR::UI, PrimitiveValue::from(uid_value(sop_class_uid))));
+    command.put(InMemElement::new(COMMAND_FIELD, VR::US, PrimitiveValue::from(C_STORE_RQ)));
+    command.put(InMemElement::new(MESSAGE_ID, VR::US, PrimitiveValue::from(message_id)));
+    command.put(InMemElement::new(PRIORITY, VR::US, PrimitiveValue::from(0u16)));
+    command.put(InMemElement::new(COMMAND_DATA_SET_TYPE, VR::US, PrimitiveValue::from(DATA_SET_PRESENT)));
+    command.put(InMemElement::new(AFFECTED_SOP_INSTANCE_UID, VR::UI,
+                                  PrimitiveValue::from(uid_value(sop_instance_uid))));
+    command
+}
+
+/// Отправляет C-STORE-RQ с набором данных и возвращает статус из C-STORE-RSP
+fn store(association: &mut ClientAssociation, contexts: &[(String, Vec<String>)],
+         instance: &Outgoing, message_id: u16) -> Result<u16, SendError> {
+    let pc = choose_context(association.presentation_contexts(), contexts, instance)
+        .ok_or_else(|| SendError::Instance(format!(
+            "no accepted presentation context for {} in {}", instance.sop_class_uid, instance.transfer_syntax)))?;
+    let pc_id = pc.id;
+    let transfer_syntax = pc.transfer_syntax.trim_end_matches('\0');
+    let ts = TransferSyntaxRegistry.get(transfer_syntax)
+        .ok_or_else(|| SendError::Instance(format!("unknown transfer syntax {}", pc.transfer_syntax)))?;
+    let mut dcm_obj = match &instance.data {
+        Some(data) => work_dcm::read_dcm_from_bytes(data).map_err(|e| SendError::Instance(e.to_string()))?,
+        None => work_dcm::read_indexed_dcm(&instance.path).map_err(|e| SendError::Instance(e.to_string()))?,
+    };
+    if !is_uncompressed(&instance.transfer_syntax) && transfer_syntax != instance.transfer_syntax {
+        let syntax: work_transcode::OutputSyntax = transfer_syntax.parse().map_err(SendError::Instance)?;
+        work_transcode::transcode(&mut dcm_obj, syntax)
+            .map_err(|e| SendError::Instance(format!("cannot transcode to {}: {}", transfer_syntax, e)))?;
+    }
+    let mut data = Vec::new();
+    dcm_obj.write_dataset_with_ts(&mut data, ts)
+        .map_err(|e| SendError::Instance(e.to_string()))?;
+
+    let command = store_command(&instance.sop_class_uid, &instance.sop_instance_uid, message_id);
+    association.send(&Pdu::PData {
+        data: vec![PDataValue {
+            presentation_context_id: pc_id,
+            value_type: PDataValueType::Command,
+            is_last: true,
+            data: encode_command(&command),
+        }],
+    }).map_err(|e| SendError::Association(e.to_string()))?;
+    let mut writer = association.send_pdata(pc_id);
+    writer.write_all(&data)
+        .and_then(|_| writer.finish())
+        .map_err(|e| SendError::Association(e.to_string()))?;
+
+    let mut response_buf: Vec<u8> = Vec::new();
+    loop {
+        match association.receive().map_err(|e| SendError::Association(e.to_string()))? {
+            Pdu::PData { data } => {
+                for value in data.into_iter().filter(|v| v.value_type == PDataValueType::Command) {
+                    response_buf.extend_from_slice(&value.data);
+                    if value.is_last {
+                        let response = decode_command(&response_buf)
+                            .ok_or_else(|| SendError::Association("invalid C-STORE response".to_string()))?;
+                        return get_u16(&response, STATUS)
+                            .ok_or_else(|| SendError::Association("C-STORE response without status".to_string()));
+                    }
+                }
+            }
+            Pdu::ReleaseRQ | Pdu::AbortRQ { .. } => {
+                return Err(SendError::Association("association closed by the receiver".to_string()));
+            }
+            _ => {}
+        }
+    }
+}
+
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::fs;
+    use dicom::core::DataElement;
+    use crate::work_json;
+
+    pub(crate) const CT_IMAGE_STORAGE: &str = "1.2.840.10008.5.1.4.1.1.2";
+
+    /// Несжатый срез КТ 4x4 с заданным SOP Instance UID
+    pub(crate) fn instance(sop_instance_uid: &str) -> DefaultDicomObject {
+        let mut obj = InMemDicomObject::new_empty();
+        let us = |tag, value: u16| InMemElement::new(tag, VR::US, PrimitiveValue::from(value));
+        obj.put(us(Tag(0x0028, 0x0010), 4));
+        obj.put(us(Tag(0x0028, 0x0011), 4));
+        obj.put(us(Tag(0x0028, 0x0002), 1));
+        obj.put(us(Tag(0x0028, 0x0100), 16));
+        obj.put(us(Tag(0x0028, 0x0101), 12));
+        obj.put(us(Tag(0x0028, 0x0103), 0));
+        obj.put(DataElement::new(Tag(0x0028, 0x0004), VR::CS, PrimitiveValue::from("MONOCHROME2")));
+        obj.put(DataElement::new(Tag(0x0010, 0x0020), VR::LO, PrimitiveValue::from("P1")));
+        obj.put(DataElement::new(Tag(0x0020, 0x000D), VR::UI, PrimitiveValue::from("1.2.7")));
+        obj.put(DataElement::new(Tag(0x0020, 0x000E), VR::UI, PrimitiveValue::from("1.2.7.1")));
+        obj.put(DataElement::new(Tag(0x0008, 0x0016), VR::UI, PrimitiveValue::from(CT_IMAGE_STORAGE)));
+        obj.put(DataElement::new(Tag(0x0008, 0x0018), VR::UI, PrimitiveValue::from(sop_instance_uid)));
+        let data: Vec<u8> = (0..16u16).flat_map(|value| (value * 100).to_le_bytes()).collect();
+        obj.put(DataElement::new(work_json::PIXEL_DATA, VR::OW, PrimitiveValue::from(data)));
+        obj.with_meta(FileMetaTableBuilder::new()
+            .media_storage_sop_class_uid(CT_IMAGE_STORAGE)
+            .media_storage_sop_instance_uid(sop_instance_uid)
+            .transfer_syntax(work_dcm::EXPLICIT_VR_LITTLE_ENDIAN))
+            .unwrap()
+    }
+
+    pub(crate) fn test_dir(name: &str) -> path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("dcm_finder_test_{}_dimse_{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Storage SCP на свободном порту, который обслуживает `associations` ассоциаций и сохраняет
+    /// экземпляры в `dir/received`; метаданные принятых экземпляров остаются в очереди
+    fn start_scp(dir: &path::Path, associations: usize, max_message: u64) -> (SendTarget, mpsc::Receiver<work_dcm::MetaDcm>) {
+        start_service(dir, associations, max_message, Vec::new())
+    }
+
+    /// То же, что `start_scp`, с известными получателями C-MOVE; запросы выполняются по индексу `dir/index.db`
+    pub(crate) fn start_service(dir: &path::Path, associations: usize, max_message: u64,
+                                move_destinations: Vec<SendTarget>) -> (SendTarget, mpsc::Receiver<work_dcm::MetaDcm>) {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let options = ListenOptions {
+            bind: "127.0.0.1".to_string(),
+            port,
+            aet: "DCMFINDER".to_string(),
+            save_in: dir.join("received"),
+            depersonalize: false,
+            move_destinations,
+            max_message,
+        };
+        let service = Service::new(options, &dir.join("index.db"));
+        let (meta_tx, meta_rx) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming().take(associations) {
+                serve_association(stream.unwrap(), &service, &meta_tx);
+            }
+        });
+        (SendTarget { aet: "DCMFINDER".to_string(), address: format!("127.0.0.1:{}", port) }, meta_rx)
+    }
+
+    fn send_options(target: SendTarget) -> SendOptions {
+        SendOptions { target, calling_aet: "SCU".to_string(), jobs: 1, retries: 0 }
+    }
+
+    /// Ассоциация с `target` от имени SCU; контексты получают идентификаторы 1, 2, ... по порядку
+    pub(crate) fn associate(target: SendTarget, contexts: &[(String, Vec<String>)]) -> ClientAssociation {
+        establish(contexts, &send_options(target)).unwrap()
+    }
+
+    #[test]
+    fn files_and_archive_members_reach_the_scp() {
+        let dir = test_dir("send");
+        let mut paths = Vec::new();
+        for name in ["a", "b"] {
+            let path = dir.join(format!("{}.dcm", name));
+            instance(&format!("1.2.7.1.{}", paths.len() + 1)).write_to_file(&path).unwrap();
+            paths.push(path.display().to_string());
+        }
+        let archive = dir.join("bundle.tar");
+        let mut builder = tar::Builder::new(fs::File::create(&archive).unwrap());
+        for (name, data) in [("c.dcm", Some("1.2.7.1.3")), ("d.dcm", Some("1.2.7.1.4")), ("readme.txt", None)] {
+            let mut content = Vec::new();
+            match data {
+                Some(uid) => instance(uid).write_all(&mut content).unwrap(),
+                None => content.extend_from_slice(b"not dicom"),
+            }
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, name, content.as_slice()).unwrap();
+        }
+        builder.finish().unwrap();
+        drop(builder);
+        for member in ["c.dcm", "readme.txt", "d.dcm", "missing.dcm"] {
+            paths.push(work_archive::virtual_path(&archive, member));
+        }
+
+        // Обычные файлы и файлы архива отправляются в двух ассоциациях
+        let (target, meta_rx) = start_scp(&dir, 2, 1 << 20);
+        let mut remaining = Vec::new();
+        let counts = send_with_progress(&paths, &send_options(target), &mut |left, _| remaining.push(left));
+        assert_eq!((counts.sent, counts.warnings, counts.failed), (4, 0, 2));
+        assert_eq!(remaining.last(), Some(&0));
+        let received: Vec<String> = meta_rx.try_iter().map(|meta| meta.get_path_ref().to_string()).collect();
+        assert_eq!(received.len(), 4);
+        assert_eq!(dir_scan::find_dicom_files(&dir.join("received")).len(), 4);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "codecs")]
+    #[test]
+    fn compressed_file_is_transcoded_for_uncompressed_context() {
+        let dir = test_dir("transcode");
+        let original = instance("1.2.7.1.9");
+        let mut compressed = original.clone();
+        work_transcode::transcode(&mut compressed, work_transcode::OutputSyntax::Rle).unwrap();
+        let path = dir.join("rle.dcm");
+        compressed.write_to_file(&path).unwrap();
+        let instance = read_outgoing(&path.display().to_string()).unwrap();
+        assert!(!is_uncompressed(&instance.transfer_syntax));
+
+        let (target, meta_rx) = start_scp(&dir, 1, 1 << 20);
+        // Получатель, который принимает только несжатые синтаксисы
+        let contexts = vec![(CT_IMAGE_STORAGE.to_string(), vec![work_dcm::EXPLICIT_VR_LITTLE_ENDIAN.to_string()])];
+        let mut association = establish(&contexts, &send_options(target)).unwrap();
+        assert_eq!(store(&mut association, &contexts, &instance, 1).ok(), Some(STATUS_SUCCESS));
+        association.release().unwrap();
+
+        let received = meta_rx.recv().unwrap();
+        let saved = work_dcm::read_indexed_dcm(received.get_path_ref()).unwrap();
+        assert_eq!(saved.meta().transfer_syntax().trim_end_matches('\0'), work_dcm::EXPLICIT_VR_LITTLE_ENDIAN);
+        assert_eq!(saved.element(work_json::PIXEL_DATA).unwrap().to_bytes().unwrap(),
+                   original.element(work_json::PIXEL_DATA).unwrap().to_bytes().unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Отправляет C-ECHO-RQ в контексте 1 и возвращает статус ответа
+    fn echo(association: &mut ClientAssociation) -> u16 {
+        let mut command = InMemDicomObject::new_empty();
+        command.put(InMemElement::new(AFFECTED_SOP_CLASS_UID, VR::UI, PrimitiveValue::from(uid_value(VERIFICATION_SOP_CLASS))));
+        command.put(InMemElement::new(COMMAND_FIELD, VR::US, PrimitiveValue::from(C_ECHO_RQ)));
+        command.put(InMemElement::new(MESSAGE_ID, VR::US, PrimitiveValue::from(1u16)));
+        command.put(InMemElement::new(COMMAND_DATA_SET_TYPE, VR::US, PrimitiveValue::from(NO_DATA_SET)));
+        association.send(&Pdu::PData {
+            data: vec![PDataValue {
+                presentation_context_id: 1,
+                value_type: PDataValueType::Command,
+                is_last: true,
+                data: encode_command(&command),
+            }],
+        }).unwrap();
+        match association.receive().unwrap() {
+            Pdu::PData { data } => {
+                let response = decode_command(&data[0].data).unwrap();
+                assert_eq!(get_u16(&response, COMMAND_FIELD), Some(C_ECHO_RSP));
+                get_u16(&response, STATUS).unwrap()
+            }
+            pdu => panic!("unexpected PDU {:?}", pdu),
+        }
+    }
+
+    #[test]
+    fn received_instance_is_saved_and_indexed() {
+        let dir = test_dir("listen");
+        let path = dir.join("sent.dcm");
+        instance("1.2.7.1.5").write_to_file(&path).unwrap();
+        let outgoing = read_outgoing(&path.display().to_string()).unwrap();
+
+        let (target, meta_rx) = start_scp(&dir, 1, 1 << 20);
+        let contexts = vec![
+            (VERIFICATION_SOP_CLASS.to_string(), vec![work_dcm::IMPLICIT_VR_LITTLE_ENDIAN.to_string()]),
+            (CT_IMAGE_STORAGE.to_string(), vec![work_dcm::EXPLICIT_VR_LITTLE_ENDIAN.to_string()]),
+        ];
+        let mut association = establish(&contexts, &send_options(target)).unwrap();
+        assert_eq!(echo(&mut association), STATUS_SUCCESS);
+        assert_eq!(store(&mut association, &contexts, &outgoing, 2).ok(), Some(STATUS_SUCCESS));
+        association.release().unwrap();
+
+        // Очередь закрывается, когда SCP завершает единственную ассоциацию
+        let conn = work_db::Connection::open_dcm_tables(&dir.join("index.db")).unwrap();
+        dir_scan::index_received(&conn, meta_rx);
+        let keys = [work_db::QueryKey { keyword: "SOPInstanceUID".to_string(), value: "1.2.7.1.5".to_string() }];
+        let paths = conn.query_paths(work_dcm::Level::Instance, &keys).unwrap();
+        assert_eq!(paths.len(), 1);
+        assert!(path::Path::new(&paths[0]).starts_with(dir.join("received").join("P1").join("1.2.7").join("1.2.7.1")));
+        let saved = work_dcm::read_indexed_dcm(&paths[0]).unwrap();
+        assert_eq!(saved.meta().media_storage_sop_instance_uid.trim_end_matches('\0'), "1.2.7.1.5");
+        assert_eq!(saved.meta().source_application_entity_title.as_deref().map(str::trim), Some("SCU"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn received_paths_stay_in_the_save_directory() {
+        let dir = test_dir("listen_paths");
+        let mut obj = instance("1.2.7.1.6");
+        obj.put(DataElement::new(Tag(0x0010, 0x0020), VR::LO, PrimitiveValue::from("../escaped")));
+        obj.put(DataElement::new(Tag(0x0020, 0x000E), VR::UI, PrimitiveValue::from("..")));
+        let path = dir.join("sent.dcm");
+        obj.write_to_file(&path).unwrap();
+        let outgoing = read_outgoing(&path.display().to_string()).unwrap();
+
+        let (target, meta_rx) = start_scp(&dir, 1, 1 << 20);
+        let contexts = vec![(CT_IMAGE_STORAGE.to_string(), vec![work_dcm::EXPLICIT_VR_LITTLE_ENDIAN.to_string()])];
+        let mut association = establish(&contexts, &send_options(target)).unwrap();
+        assert_eq!(store(&mut association, &contexts, &outgoing, 1).ok(), Some(STATUS_SUCCESS));
+        association.release().unwrap();
+
+        let received = meta_rx.recv().unwrap();
+        let saved = path::Path::new(received.get_path_ref());
+        assert_eq!(saved.parent().unwrap(), dir.join("received").join(".._escaped").join("1.2.7").join("__"));
+        assert!(!dir.join("escaped").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn oversized_message_aborts_the_association() {
+        let dir = test_dir("listen_limit");
+        let path = dir.join("sent.dcm");
+        instance("1.2.7.1.7").write_to_file(&path).unwrap();
+        let outgoing = read_outgoing(&path.display().to_string()).unwrap();
+
+        // Набор команд помещается в предел, набор данных с Pixel Data — нет
+        let command_length = encode_command(&store_command(CT_IMAGE_STORAGE, "1.2.7.1.7", 2)).len() as u64;
+        let (target, meta_rx) = start_scp(&dir, 1, command_length);
+        let contexts = vec![
+            (VERIFICATION_SOP_CLASS.to_string(), vec![work_dcm::IMPLICIT_VR_LITTLE_ENDIAN.to_string()]),
+            (CT_IMAGE_STORAGE.to_string(), vec![work_dcm::EXPLICIT_VR_LITTLE_ENDIAN.to_string()]),
+        ];
+        let mut association = establish(&contexts, &send_options(target)).unwrap();
+        assert_eq!(echo(&mut association), STATUS_SUCCESS);
+        assert!(store(&mut association, &contexts, &outgoing, 2).is_err());
+        // SCP завершил ассоциацию, ничего не сохранив
+        assert!(meta_rx.recv().is_err());
+        assert!(!dir.join("received").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn contexts_are_looked_up_by_id() {
+        let contexts = vec![
+            (CT_IMAGE_STORAGE.to_string(), vec![work_dcm::EXPLICIT_VR_LITTLE_ENDIAN.to_string()]),
+            (CT_IMAGE_STORAGE.to_string(), vec![work_transcode::OutputSyntax::Rle.uid().to_string()]),
+        ];
+        let accepted = |id, transfer_syntax: &str| PresentationContextResult {
+            id,
+            reason: PresentationContextResultReason::Acceptance,
+            transfer_syntax: transfer_syntax.to_string(),
+        };
+        let instance = Outgoing {
+            path: String::new(),
+            sop_class_uid: CT_IMAGE_STORAGE.to_string(),
+            sop_instance_uid: "1.2.7.1.9".to_string(),
+            transfer_syntax: work_transcode::OutputSyntax::Rle.uid().to_string(),
+            data: None,
+        };
+        // Идентификатор 0 не соответствует ни одному предложенному контексту
+        assert!(choose_context(&[accepted(0, work_transcode::OutputSyntax::Rle.uid())], &contexts, &instance).is_none());
+        let results = [accepted(1, work_dcm::EXPLICIT_VR_LITTLE_ENDIAN), accepted(2, work_transcode::OutputSyntax::Rle.uid())];
+        assert_eq!(choose_context(&results, &contexts, &instance).map(|pc| pc.id), Some(2));
+        assert_eq!(choose_context(&results[..1], &contexts, &instance).map(|pc| pc.id), Some(1));
+        assert_eq!(proposed_abstract_syntax(&contexts, 3), None);
+    }
+}
@@ -0,0 +1,645 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io;
+use std::path;
+use std::str::FromStr;
+use dicom::core::value::Value;
+use dicom::object::DefaultDicomObject;
+use indicatif::{ProgressBar, ProgressStyle};
+use rayon::prelude::*;
+use serde::Serialize;
+use twox_hash::xxh3;
+
+use crate::work_archive;
+use crate::work_db;
+use crate::work_db::Dcm;
+use crate::work_dcm;
+use crate::work_json;
+use crate::work_pixels;
+
+
+/// Признак, по которому файлы индекса считаются копиями одного экземпляра
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicateKey {
+    /// Одинаковый SOP Instance UID
+    Uid,
+    /// Одинаковый хеш содержимого (см. `content_hash`)
+    Content,
+    /// Одинаковые SOP Instance UID и хеш содержимого
+    Exact,
+}
+
+impl FromStr for DuplicateKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "uid" => Ok(DuplicateKey::Uid),
+            "content" => Ok(DuplicateKey::Content),
+            "exact" => Ok(DuplicateKey::Exact),
+            _ => Err(format!("unknown duplicate key '{}', expected uid, content or exact", s)),
+        }
+    }
+}
+
+impl DuplicateKey {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DuplicateKey::Uid => "uid",
+            DuplicateKey::Content => "content",
+            DuplicateKey::Exact => "exact",
+        }
+    }
+
+    fn describe(&self) -> &'static str {
+        match self {
+            DuplicateKey::Uid => "SOP Instance UID",
+            DuplicateKey::Content => "content hash",
+            DuplicateKey::Exact => "SOP Instance UID and content hash",
+        }
+    }
+
+    fn needs_hash(&self) -> bool {
+        *self != DuplicateKey::Uid
+    }
+}
+
+/// Какая из копий остается: с самым ранним или поздним временем изменения, или с самым коротким путем.
+/// При равенстве остается первая по пути
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Keep {
+    Oldest,
+    Newest,
+    ShortestPath,
+}
+
+impl FromStr for Keep {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "oldest" => Ok(Keep::Oldest),
+            "newest" => Ok(Keep::Newest),
+            "shortest-path" => Ok(Keep::ShortestPath),
+            _ => Err(format!("unknown keep rule '{}', expected oldest, newest or shortest-path", s)),
+        }
+    }
+}
+
+/// Что делается с лишними копиями: удаляются или заменяются жесткими ссылками на оставленную
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DedupeAction {
+    Delete,
+    Link,
+}
+
+impl FromStr for DedupeAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "delete" => Ok(DedupeAction::Delete),
+            "link" => Ok(DedupeAction::Link),
+            _ => Err(format!("unknown action '{}', expected delete or link", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DedupeOptions {
+    pub key: DuplicateKey,
+    pub keep: Keep,
+    pub action: DedupeAction,
+    /// Без `apply` только выводится, что будет сделано
+    pub apply: bool,
+}
+
+/// Файл группы копий; у файла внутри архива размер не определяется
+#[derive(Debug, Clone, Serialize)]
+pub struct DuplicateFile {
+    pub path: String,
+    pub size: Option<u64>,
+    /// Экземпляр по индексу: `study/series/SOP Instance UID`
+    #[serde(skip)]
+    pub instance: String,
+    /// Хеш содержимого по индексу (пустой, если не вычислен)
+    #[serde(skip)]
+    pub content_hash: String,
+}
+
+/// Файлы индекса с одинаковым ключом
+#[derive(Debug, Clone, Serialize)]
+pub struct DuplicateGroup {
+    pub key: String,
+    pub files: Vec<DuplicateFile>,
+}
+
+impl DuplicateGroup {
+    /// Байт, которые занимают копии сверх самой большой
+    pub fn reclaimable(&self) -> u64 {
+        let sizes = self.files.iter().filter_map(|file| file.size);
+        sizes.clone().sum::<u64>() - sizes.max().unwrap_or(0)
+    }
+}
+
+/// Итоги `dedupe`; в пробном запуске — что было бы сделано
+#[derive(Debug, Default, PartialEq)]
+pub struct DedupeCounts {
+    pub groups: usize,
+    pub removed: usize,
+    pub freed: u64,
+    pub skipped: usize,
+    pub failed: usize,
+}
+
+/// Хеш содержимого экземпляра, не зависящий от заголовка: по распакованным значениям пикселей
+/// и описывающим их атрибутам (размеры, число кадров, разрядность, Photometric Interpretation).
+/// Поэтому совпадают копии, которые различаются атрибутами пациента или синтаксисом передачи
+/// (без потерь). Если Pixel Data не распаковывается, хешируются его байты вместе с синтаксисом
+/// передачи. `None` — в объекте нет Pixel Data
+pub fn content_hash(obj: &DefaultDicomObject) -> Option<String> {
+    let element = obj.element(work_json::PIXEL_DATA).ok()?;
+    let mut data = Vec::new();
+    match work_pixels::decode(obj) {
+        Ok(pixels) => {
+            data.extend(format!("{}x{}x{}x{} {}/{} {}\n", pixels.frames, pixels.rows, pixels.columns,
+                                pixels.samples_per_pixel, pixels.bits_allocated, pixels.bits_stored,
+                                pixels.photometric).bytes());
+            data.extend(pixels.values.iter().flat_map(|value| value.to_le_bytes()));
+        }
+        Err(_) => {
+            data.extend(format!("{}\n", obj.meta().transfer_syntax().trim_end_matches('\0')).bytes());
+            match element.value() {
+                Value::Primitive(value) => data.extend(value.to_bytes().iter()),
+                Value::PixelSequence { fragments, .. } => fragments.iter().for_each(|fragment| data.extend(fragment)),
+                Value::Sequence { .. } => {}
+            }
+        }
+    }
+    Some(format!("{:032x}", xxh3::hash128(&data)))
+}
+
+/// Вычисляет (параллельно) хеши содержимого файлов индекса, у которых их еще нет, и сохраняет их.
+/// Файлы, которые не удалось прочитать, остаются без хеша и проверяются при следующем запуске
+pub fn update_hashes(conn: &work_db::Connection) {
+    let paths = match conn.get_unhashed_paths() {
+        Ok(paths) => paths,
+        Err(e) => {
+            eprintln!("Error reading paths from db: {:?}", e);
+            return;
+        }
+    };
+    if paths.is_empty() {
+        return;
+    }
+    let progress = ProgressBar::new(paths.len() as u64);
+    progress.set_style(ProgressStyle::default_bar()
+        .template("[{elapsed_precise}] {bar:40} hashed {pos} of {len} files ({per_sec})"));
+    let hashes: Vec<(&String, Result<Option<String>, String>)> = paths.par_iter()
+        .map(|path| {
+            let hash = work_dcm::read_indexed_dcm(path)
+                .map(|obj| content_hash(&obj))
+                .map_err(|e| e.to_string());
+            progress.inc(1);
+            (path, hash)
+        })
+        .collect();
+    progress.finish_and_clear();
+
+    conn.execute_batch("BEGIN;").unwrap_or_else(|e| eprintln!("Error begin transaction: {:?}", e));
+    for (path, hash) in hashes {
+        match hash {
+            Ok(hash) => conn.set_content_hash(path, hash.as_deref().unwrap_or(""))
+                .unwrap_or_else(|e| eprintln!("Error saving content hash of {} in db: {:?}", path, e)),
+            Err(e) => eprintln!("Error hashing {}: {}", path, e),
+        }
+    }
+    conn.execute_batch("COMMIT;").unwrap_or_else(|e| eprintln!("Error commit transaction: {:?}", e));
+}
+
+/// Группы копий по ключу `key`. Хеши содержимого должны быть вычислены (см. `update_hashes`).
+/// Файлы, которых больше нет на диске, в группы не входят
+pub fn find_duplicates(conn: &work_db::Connection, key: DuplicateKey) -> Result<Vec<DuplicateGroup>, work_db::Error> {
+    let mut groups: Vec<DuplicateGroup> = Vec::new();
+    for (key, path, instance, content_hash) in conn.get_duplicate_paths(key)? {
+        let size = if is_archive_member(&path) {
+            None
+        } else {
+            match fs::metadata(&path) {
+                Ok(metadata) => Some(metadata.len()),
+                Err(_) => continue,
+            }
+        };
+        let file = DuplicateFile { path, size, instance, content_hash };
+        match groups.last_mut() {
+            Some(group) if group.key == key => group.files.push(file),
+            _ => groups.push(DuplicateGroup { key, files: vec![file] }),
+        }
+    }
+    groups.retain(|group| group.files.len() > 1);
+    Ok(groups)
+}
+
+/// Выводит группы копий по каждому из ключей `keys` с путями и размерами файлов.
+/// С `output` отчет также пишется в JSON (группы по ключам)
+pub fn report_duplicates(db_path: &path::Path, keys: &[DuplicateKey], output: Option<&path::Path>) {
+    let conn = match open_index(db_path) {
+        Some(conn) => conn,
+        None => return,
+    };
+    if keys.iter().any(DuplicateKey::needs_hash) {
+        update_hashes(&conn);
+    }
+    let mut report: BTreeMap<&str, Vec<DuplicateGroup>> = BTreeMap::new();
+    for key in keys {
+        let groups = match find_duplicates(&conn, *key) {
+            Ok(groups) => groups,
+            Err(e) => {
+                eprintln!("Error searching duplicates in db: {:?}", e);
+                return;
+            }
+        };
+        let files: usize = groups.iter().map(|group| group.files.len()).sum();
+        let reclaimable: u64 = groups.iter().map(DuplicateGroup::reclaimable).sum();
+        println!("Duplicates by {}: {} groups, {} files, {} bytes reclaimable",
+                 key.describe(), groups.len(), files, reclaimable);
+        for group in &groups {
+            println!("  {} ({} files)", group.key, group.files.len());
+            for file in &group.files {
+                let size = file.size.map(|size| size.to_string()).unwrap_or_else(|| "-".to_string());
+                println!("    {:>12}  {}", size, file.path);
+            }
+        }
+        report.insert(key.as_str(), groups);
+    }
+
+    if let Some(output) = output {
+        serde_json::to_string_pretty(&report).map_err(|e| e.to_string())
+            .and_then(|text| fs::write(output, text).map_err(|e| e.to_string()))
+            .unwrap_or_else(|e| eprintln!("Error writing duplicates report {}: {}", output.display(), e));
+    }
+}
+
+/// Оставляет в каждой группе копий одну (см. `Keep`), остальные удаляет или заменяет жесткими
+/// ссылками на нее и убирает из индекса. По хешу содержимого копией считается только файл того же
+/// экземпляра (те же Study, Series и SOP Instance UID): одинаковые пиксели у файлов разных пациентов
+/// или обезличенных копий не повод их удалять. По SOP Instance UID копией считается только файл
+/// с тем же хешом содержимого: под одним UID могут оказаться разные изображения.
+/// По умолчанию (без `apply`) только выводит, что будет сделано
+pub fn dedupe(db_path: &path::Path, options: &DedupeOptions) {
+    let conn = match open_index(db_path) {
+        Some(conn) => conn,
+        None => return,
+    };
+    let counts = dedupe_index(&conn, options);
+    let verb = match options.action {
+        DedupeAction::Delete => "deleted",
+        DedupeAction::Link => "replaced by hard links",
+    };
+    if options.apply {
+        println!("Duplicate groups: {}, copies {}: {}, freed: {} bytes, skipped: {}, failed: {}",
+                 counts.groups, verb, counts.removed, counts.freed, counts.skipped, counts.failed);
+    } else {
+        println!("Dry run. Duplicate groups: {}, copies to be {}: {}, to be freed: {} bytes, skipped: {}",
+                 counts.groups, verb, counts.removed, counts.freed, counts.skipped);
+        println!("Run with --apply to change the files");
+    }
+}
+
+/// Выполняет `dedupe` над открытым индексом
+pub fn dedupe_index(conn: &work_db::Connection, options: &DedupeOptions) -> DedupeCounts {
+    let mut counts = DedupeCounts::default();
+    // Хеши нужны при любом ключе: копии по UID с другим содержимым не удаляются
+    update_hashes(conn);
+    let groups = match find_duplicates(conn, options.key) {
+        Ok(groups) => groups,
+        Err(e) => {
+            eprintln!("Error searching duplicates in db: {:?}", e);
+            return counts;
+        }
+    };
+    let mut series_uids: HashSet<String> = HashSet::new();
+    for group in &groups {
+        counts.groups += 1;
+        let canonical = match choose_canonical(&group.files, options.keep) {
+            Some(canonical) => canonical,
+            None => {
+                println!("[SKIP] {}: all copies are inside archives", group.key);
+                counts.skipped += group.files.len() - 1;
+                continue;
+            }
+        };
+        println!("{} ({} files), keep {}", group.key, group.files.len(), canonical.path);
+        // Перед изменением файлов ключ проверяется заново: файлы могли измениться после индексации
+        let canonical_key = if options.apply { file_key(&canonical.path, options.key) } else { Ok(Default::default()) };
+        let canonical_identity = match canonical_key {
+            Ok((_, identity)) if !options.apply => identity,
+            Ok((key, identity)) if key == group.key => identity,
+            _ => {
+                println!("  [SKIP] {} changed since it was indexed, run `find` again", canonical.path);
+                counts.skipped += group.files.len() - 1;
+                continue;
+            }
+        };
+        for file in group.files.iter().filter(|file| file.path != canonical.path) {
+            if is_archive_member(&file.path) {
+                println!("  [SKIP] {} is inside an archive", file.path);
+                counts.skipped += 1;
+                continue;
+            }
+            if options.key == DuplicateKey::Content && file.instance != canonical.instance {
+                println!("  [SKIP] {} is another instance (Study, Series or SOP Instance UID differ)", file.path);
+                counts.skipped += 1;
+                continue;
+            }
+            if options.key == DuplicateKey::Uid && (file.content_hash != canonical.content_hash || file.content_hash.is_empty()) {
+                println!("  [SKIP] {} has other content (content hash differs)", file.path);
+                counts.skipped += 1;
+                continue;
+            }
+            if !options.apply {
+                println!("  [DRY RUN] {} ({} bytes)", file.path, file.size.unwrap_or(0));
+                counts.removed += 1;
+                counts.freed += file.size.unwrap_or(0);
+                continue;
+            }
+            match remove_copy(&canonical.path, &canonical_identity, &file.path, &group.key, options) {
+                Ok(()) => {
+                    println!("  [OK] {} ({} bytes)", file.path, file.size.unwrap_or(0));
+                    counts.removed += 1;
+                    counts.freed += file.size.unwrap_or(0);
+                    match conn.remove_path(&file.path) {
+                        Ok(series_uid) => series_uids.extend(series_uid),
+                        Err(e) => eprintln!("Error removing {} from db: {:?}", file.path, e),
+                    }
+                }
+                Err(e) => {
+                    println!("  [FAILED] {}: {}", file.path, e);
+                    counts.failed += 1;
+                }
+            }
+        }
+    }
+    for series_uid in series_uids {
+        if let Err(e) = conn.update_series_geometry(&series_uid) {
+            eprintln!("Error analysing geometry of series {}: {:?}", series_uid, e);
+        }
+    }
+    counts
+}
+
+fn open_index(db_path: &path::Path) -> Option<work_db::Connection> {
+    if !db_path.is_file() {
+        eprintln!("Index database not found: {}", db_path.display());
+        return None;
+    }
+    work_db::Connection::open_dcm_tables(db_path)
+        .map_err(|e| eprintln!("Error open data base [path: {}]: {:?}", db_path.display(), e))
+        .ok()
+}
+
+fn is_archive_member(path: &str) -> bool {
+    work_archive::split_virtual_path(path).is_some()
+}
+
+/// Копия, которая остается в группе. Файлы внутри архивов не выбираются: на них нельзя сослаться
+fn choose_canonical(files: &[DuplicateFile], keep: Keep) -> Option<&DuplicateFile> {
+    let modified = |file: &DuplicateFile| fs::metadata(&file.path).and_then(|metadata| metadata.modified()).ok();
+    files.iter()
+        .filter(|file| !is_archive_member(&file.path))
+        .min_by(|a, b| match keep {
+            Keep::Oldest => modified(a).cmp(&modified(b)),
+            Keep::Newest => modified(b).cmp(&modified(a)),
+            Keep::ShortestPath => a.path.len().cmp(&b.path.len()),
+        })
+}
+
+/// Экземпляр файла (`study/series/SOP Instance UID`) и хеш его содержимого
+type FileIdentity = (String, String);
+
+/// Ключ файла, вычисленный заново по его содержимому, в том же виде, что и в индексе,
+/// экземпляр и хеш содержимого файла
+fn file_key(path: &str, key: DuplicateKey) -> Result<(String, FileIdentity), String> {
+    let obj = work_dcm::read_dcm(path::Path::new(path)).map_err(|e| e.to_string())?;
+    let uid = |keyword: &str| obj.element_by_name(keyword).ok()
+        .and_then(|element| element.to_str().ok().map(|value| value.trim_end_matches(['\0', ' ']).to_string()))
+        .unwrap_or_default();
+    let hash = content_hash(&obj).unwrap_or_default();
+    let instance = format!("{}/{}/{}", uid("StudyInstanceUID"), uid("SeriesInstanceUID"), uid("SOPInstanceUID"));
+    Ok((match key {
+        DuplicateKey::Uid => uid("SOPInstanceUID"),
+        DuplicateKey::Content => hash.clone(),
+        DuplicateKey::Exact => format!("{}/{}", uid("SOPInstanceUID"), hash),
+    }, (instance, hash)))
+}
+
+/// Удаляет копию или заменяет ее жесткой ссылкой на `canonical`, если ее ключ по-прежнему `key`
+/// и это не тот же файл, что `canonical` (под другим путем). По хешу содержимого копия
+/// должна оставаться тем же экземпляром, что и `canonical`, по SOP Instance UID — иметь то же
+/// содержимое (`canonical_identity`, см. `file_key`)
+fn remove_copy(canonical: &str, canonical_identity: &FileIdentity, copy: &str, key: &str,
+               options: &DedupeOptions) -> Result<(), String> {
+    if fs::canonicalize(canonical).map_err(|e| e.to_string())? == fs::canonicalize(copy).map_err(|e| e.to_string())? {
+        return Err(format!("the same file as {}", canonical));
+    }
+    let (copy_key, (instance, hash)) = file_key(copy, options.key)?;
+    if copy_key != key {
+        return Err("the file changed since it was indexed".to_string());
+    }
+    let (canonical_instance, canonical_hash) = canonical_identity;
+    if options.key == DuplicateKey::Content && &instance != canonical_instance {
+        return Err(format!("another instance than {}", canonical));
+    }
+    if options.key == DuplicateKey::Uid && (&hash != canonical_hash || hash.is_empty()) {
+        return Err(format!("other content than {}", canonical));
+    }
+    match options.action {
+        DedupeAction::Delete => fs::remove_file(copy),
+        DedupeAction::Link => replace_with_link(canonical, copy),
+    }.map_err(|e| e.to_string())
+}
+
+/// Заменяет файл жесткой ссылкой: ссылка создается рядом и переименовывается поверх файла
+fn replace_with_link(canonical: &str, copy: &str) -> io::Result<()> {
+    let temporary = format!("{}.dedupe", copy);
+    fs::hard_link(canonical, &temporary)?;
+    fs::rename(&temporary, copy).inspect_err(|_| {
+        let _ = fs::remove_file(&temporary);
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dicom::core::{DataElement, PrimitiveValue, Tag, VR};
+    use dicom::object::{FileMetaTableBuilder, InMemDicomObject};
+    use crate::work_pixels::tests::synthetic_image;
+
+    /// КТ-срез 40x30 с 12-битным диском; `uids` — Study, Series и SOP Instance UID
+    fn slice_obj(patient: &str, uids: [&str; 3]) -> DefaultDicomObject {
+        let (values, format) = synthetic_image(16, 12);
+        let mut obj = InMemDicomObject::new_empty();
+        let us = |tag, value: usize| DataElement::new(tag, VR::US, PrimitiveValue::from(value as u16));
+        obj.put(us(Tag(0x0028, 0x0010), format.rows));
+        obj.put(us(Tag(0x0028, 0x0011), format.columns));
+        obj.put(us(Tag(0x0028, 0x0002), 1));
+        obj.put(us(Tag(0x0028, 0x0100), 16));
+        obj.put(us(Tag(0x0028, 0x0101), 12));
+        obj.put(us(Tag(0x0028, 0x0103), 0));
+        obj.put(DataElement::new(Tag(0x0028, 0x0004), VR::CS, PrimitiveValue::from("MONOCHROME2")));
+        obj.put(DataElement::new(Tag(0x0010, 0x0010), VR::PN, PrimitiveValue::from(patient)));
+        obj.put(DataElement::new(Tag(0x0010, 0x0020), VR::LO, PrimitiveValue::from(patient)));
+        obj.put(DataElement::new(Tag(0x0020, 0x000D), VR::UI, PrimitiveValue::from(uids[0])));
+        obj.put(DataElement::new(Tag(0x0020, 0x000E), VR::UI, PrimitiveValue::from(uids[1])));
+        obj.put(DataElement::new(Tag(0x0008, 0x0016), VR::UI, PrimitiveValue::from("1.2.840.10008.5.1.4.1.1.2")));
+        obj.put(DataElement::new(Tag(0x0008, 0x0018), VR::UI, PrimitiveValue::from(uids[2])));
+        let data: Vec<u8> = values.iter().flat_map(|value| (*value as u16).to_le_bytes()).collect();
+        obj.put(DataElement::new(work_json::PIXEL_DATA, VR::OW, PrimitiveValue::from(data)));
+        obj.with_meta(FileMetaTableBuilder::new()
+            .media_storage_sop_class_uid("1.2.840.10008.5.1.4.1.1.2")
+            .media_storage_sop_instance_uid(uids[2])
+            .transfer_syntax(work_dcm::EXPLICIT_VR_LITTLE_ENDIAN))
+            .unwrap()
+    }
+
+    fn count(conn: &work_db::Connection, table: &str) -> i64 {
+        conn.query_row(&format!("SELECT count(*) FROM {};", table), [], |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn content_hash_ignores_the_header() {
+        let original = slice_obj("Patient^One", ["1.2.3.1", "1.2.3.1.1", "1.2.3.1.1.1"]);
+        let hash = content_hash(&original).unwrap();
+        assert_eq!(hash.len(), 32);
+        let copy = slice_obj("ANON", ["1.2.3.9", "1.2.3.9.1", "1.2.3.9.1.1"]);
+        assert_eq!(content_hash(&copy), Some(hash.clone()));
+
+        #[cfg(feature = "codecs")]
+        {
+            let mut compressed = copy.clone();
+            crate::work_transcode::transcode(&mut compressed, crate::work_transcode::OutputSyntax::Rle).unwrap();
+            assert_eq!(content_hash(&compressed), Some(hash.clone()));
+        }
+
+        let mut changed = copy.clone();
+        let mut data = changed.element(work_json::PIXEL_DATA).unwrap().to_bytes().unwrap().to_vec();
+        data[0] ^= 1;
+        changed.put(DataElement::new(work_json::PIXEL_DATA, VR::OW, PrimitiveValue::from(data)));
+        assert_ne!(content_hash(&changed), Some(hash));
+
+        let mut without_pixels = copy;
+        without_pixels.remove_element(work_json::PIXEL_DATA);
+        assert_eq!(content_hash(&without_pixels), None);
+    }
+
+    #[test]
+    fn names_are_parsed() {
+        assert_eq!("Exact".parse::<DuplicateKey>(), Ok(DuplicateKey::Exact));
+        assert_eq!("shortest-path".parse::<Keep>(), Ok(Keep::ShortestPath));
+        assert_eq!("link".parse::<DedupeAction>(), Ok(DedupeAction::Link));
+        assert!("pixels".parse::<DuplicateKey>().is_err());
+    }
+
+    #[test]
+    fn copies_are_grouped_and_removed() {
+        let dir = std::env::temp_dir().join(format!("dcm_finder_test_{}_duplicates", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let conn = work_db::Connection::create_dcm_tables(true).unwrap();
+        // Две копии одного экземпляра, тот же экземпляр, заново записанный в другое исследование,
+        // и обезличенная копия другого пациента с другими UID
+        let files = [
+            ("a/slice.dcm", slice_obj("Patient^One", ["1.2.3.1", "1.2.3.1.1", "1.2.3.1.1.1"])),
+            ("b/slice.dcm", slice_obj("Patient^One", ["1.2.3.1", "1.2.3.1.1", "1.2.3.1.1.1"])),
+            ("refiled/slice.dcm", slice_obj("Patient^One", ["1.2.3.5", "1.2.3.5.1", "1.2.3.1.1.1"])),
+            ("anonymized/slice.dcm", slice_obj("ANON", ["1.2.3.9", "1.2.3.9.1", "1.2.3.9.1.1"])),
+        ];
+        let mut paths = Vec::new();
+        for (name, obj) in files {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            obj.write_to_file(&path).unwrap();
+            let path = path.display().to_string();
+            conn.insert_dcm(&work_dcm::MetaDcm::from(&obj, &path));
+            paths.push(path);
+        }
+        update_hashes(&conn);
+        let exists = |index: usize| path::Path::new(&paths[index]).is_file();
+
+        let uid_groups = find_duplicates(&conn, DuplicateKey::Uid).unwrap();
+        assert_eq!(uid_groups.len(), 1);
+        assert_eq!(uid_groups[0].key, "1.2.3.1.1.1");
+        let size = fs::metadata(&paths[0]).unwrap().len();
+        assert_eq!(uid_groups[0].files.iter().map(|file| file.size).collect::<Vec<_>>(), vec![Some(size); 3]);
+        assert_eq!(uid_groups[0].reclaimable(), 2 * size);
+        assert_eq!(find_duplicates(&conn, DuplicateKey::Exact).unwrap()[0].files.len(), 3);
+        let content_groups = find_duplicates(&conn, DuplicateKey::Content).unwrap();
+        assert_eq!(content_groups.len(), 1);
+        assert_eq!(content_groups[0].files.len(), 4);
+
+        // По хешу содержимого удаляется только копия того же экземпляра
+        let mut options = DedupeOptions { key: DuplicateKey::Content, keep: Keep::ShortestPath, action: DedupeAction::Delete, apply: false };
+        let counts = dedupe_index(&conn, &options);
+        assert_eq!((counts.groups, counts.removed, counts.freed, counts.skipped), (1, 1, size, 2));
+        assert!((0..4).all(exists));
+        assert_eq!(count(&conn, "paths"), 4);
+
+        options.apply = true;
+        let counts = dedupe_index(&conn, &options);
+        assert_eq!((counts.removed, counts.skipped, counts.failed), (1, 2, 0));
+        assert!(exists(0) && !exists(1) && exists(2) && exists(3));
+        assert_eq!((count(&conn, "paths"), count(&conn, "study"), count(&conn, "patients")), (3, 3, 2));
+
+        // По SOP Instance UID удаляется и копия в другом исследовании; исследование, оставшееся
+        // без файлов, удаляется из индекса, обезличенная копия остается
+        options.key = DuplicateKey::Uid;
+        let counts = dedupe_index(&conn, &options);
+        assert_eq!((counts.removed, counts.failed), (1, 0));
+        assert!(exists(0) && !exists(2) && exists(3));
+        assert_eq!((count(&conn, "paths"), count(&conn, "series"), count(&conn, "study"), count(&conn, "patients")), (2, 2, 2, 2));
+        assert!(find_duplicates(&conn, DuplicateKey::Uid).unwrap().is_empty());
+        assert_eq!(find_duplicates(&conn, DuplicateKey::Content).unwrap()[0].files.len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn uid_copies_with_other_pixels_are_kept() {
+        let dir = std::env::temp_dir().join(format!("dcm_finder_test_{}_uid_duplicates", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let conn = work_db::Connection::create_dcm_tables(true).unwrap();
+        // Два файла с одним SOP Instance UID, но разными пикселями
+        let original = slice_obj("Patient^One", ["1.2.3.1", "1.2.3.1.1", "1.2.3.1.1.1"]);
+        let mut changed = original.clone();
+        let mut data = changed.element(work_json::PIXEL_DATA).unwrap().to_bytes().unwrap().to_vec();
+        data[0] ^= 1;
+        changed.put(DataElement::new(work_json::PIXEL_DATA, VR::OW, PrimitiveValue::from(data)));
+        let mut paths = Vec::new();
+        for (name, obj) in [("a/slice.dcm", original), ("b/slice.dcm", changed)] {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            obj.write_to_file(&path).unwrap();
+            let path = path.display().to_string();
+            conn.insert_dcm(&work_dcm::MetaDcm::from(&obj, &path));
+            paths.push(path);
+        }
+        assert_eq!(find_duplicates(&conn, DuplicateKey::Uid).unwrap()[0].files.len(), 2);
+
+        for action in [DedupeAction::Delete, DedupeAction::Link] {
+            let options = DedupeOptions { key: DuplicateKey::Uid, keep: Keep::ShortestPath, action, apply: true };
+            let counts = dedupe_index(&conn, &options);
+            assert_eq!((counts.groups, counts.removed, counts.skipped, counts.failed), (1, 0, 1, 0));
+            assert!(paths.iter().all(|path| path::Path::new(path).is_file()));
+            assert_eq!(count(&conn, "paths"), 2);
+        }
+        assert_ne!(work_dcm::read_dcm(path::Path::new(&paths[0])).ok().and_then(|obj| content_hash(&obj)),
+                   work_dcm::read_dcm(path::Path::new(&paths[1])).ok().and_then(|obj| content_hash(&obj)));
+
+        // Проверка при удалении: копия, подмененная после индексации, тоже не удаляется
+        let canonical = file_key(&paths[0], DuplicateKey::Uid).unwrap();
+        let options = DedupeOptions { key: DuplicateKey::Uid, keep: Keep::ShortestPath, action: DedupeAction::Delete, apply: true };
+        assert!(remove_copy(&paths[0], &canonical.1, &paths[1], &canonical.0, &options).is_err());
+        assert!(path::Path::new(&paths[1]).is_file());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,539 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use dicom::core::value::Value;
+use dicom::core::Tag;
+use dicom::object::DefaultDicomObject;
+use indicatif::{ProgressBar, ProgressStyle};
+use parquet::basic::{Compression, ConvertedType, Repetition, Type as PhysicalType};
+use parquet::data_type::{ByteArray, ByteArrayType, DoubleType, Int64Type};
+use parquet::errors::ParquetError;
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::types::Type;
+use rayon::prelude::*;
+
+use crate::work_archive;
+use crate::work_dcm;
+use crate::work_db;
+use crate::work_db::{ColumnKind, Dcm};
+use crate::work_json;
+use crate::work_xml;
+
+
+/// Формат экспорта
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    /// Заголовок каждого экземпляра в DICOM JSON (PS3.18 F.2)
+    DicomJson,
+    /// Заголовок каждого экземпляра в Native DICOM Model XML (PS3.19 A.1)
+    DicomXml,
+    /// Плоская таблица уровня в CSV
+    Csv,
+    /// Плоская таблица уровня в Apache Parquet
+    Parquet,
+    /// Результат поиска (пациенты -> исследования -> серии -> файлы) одним документом JSON
+    Json,
+    /// Результат поиска в JSON Lines: строка на серию
+    JsonLines,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "dicom-json" => Ok(ExportFormat::DicomJson),
+            "dicom-xml" => Ok(ExportFormat::DicomXml),
+            "csv" => Ok(ExportFormat::Csv),
+            "parquet" => Ok(ExportFormat::Parquet),
+            "json" => Ok(ExportFormat::Json),
+            "jsonl" => Ok(ExportFormat::JsonLines),
+            _ => Err(format!("unknown export format '{}' (expected dicom-json, dicom-xml, csv, parquet, json or jsonl)", s)),
+        }
+    }
+}
+
+impl ExportFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::DicomJson => "json",
+            ExportFormat::DicomXml => "xml",
+            ExportFormat::Csv => "csv",
+            ExportFormat::Parquet => "parquet",
+            ExportFormat::Json => "json",
+            ExportFormat::JsonLines => "jsonl",
+        }
+    }
+
+    /// Формат выгружает дерево результата поиска (иначе — таблицу или файлы экземпляров)
+    pub fn is_result(&self) -> bool {
+        matches!(self, ExportFormat::Json | ExportFormat::JsonLines)
+    }
+
+    /// Формат пишет по файлу на экземпляр (иначе — одну таблицу)
+    pub fn is_per_instance(&self) -> bool {
+        matches!(self, ExportFormat::DicomJson | ExportFormat::DicomXml)
+    }
+}
+
+/// Куда и в каком виде выгружается результат поиска
+#[derive(Debug, Clone)]
+pub struct ResultOptions {
+    /// Файл результата, `-` — стандартный вывод
+    pub output: path::PathBuf,
+    /// JSON Lines: строка на серию вместо одного документа
+    pub json_lines: bool,
+}
+
+impl ResultOptions {
+    pub fn to_stdout(&self) -> bool {
+        self.output.as_os_str() == "-"
+    }
+
+    /// Поток для сообщений о ходе работы: если результат пишется в stdout, они уходят в stderr
+    pub fn status(&self) -> Box<dyn Write> {
+        if self.to_stdout() {
+            Box::new(io::stderr())
+        } else {
+            Box::new(io::stdout())
+        }
+    }
+}
+
+/// Открывает файл для записи с буферизацией, `-` — стандартный вывод
+pub fn open_output(output: &path::Path) -> io::Result<Box<dyn Write>> {
+    if output.as_os_str() == "-" {
+        Ok(Box::new(io::BufWriter::new(io::stdout().lock())))
+    } else {
+        Ok(Box::new(io::BufWriter::new(File::create(output)?)))
+    }
+}
+
+/// Выгружает результат поиска из индекса в `options.output` и выводит количество найденного по пациентам
+pub fn write_result(conn: &work_db::Connection, options: &ResultOptions) {
+    let result = open_output(&options.output)
+        .map_err(|e| e.into())
+        .and_then(|mut writer| conn.export_result(&mut writer, options.json_lines));
+    match result {
+        Ok(counts) => {
+            work_db::Connection::print_count(&counts, &mut options.status()).unwrap_or_default();
+        }
+        Err(e) => eprintln!("Error exporting result to {}: {:?}", options.output.display(), e),
+    }
+}
+
+/// Открывает сохраненный индекс и выгружает из него результат поиска (см. `write_result`)
+pub fn export_result(db_path: &path::Path, options: &ResultOptions) {
+    if !db_path.is_file() {
+        eprintln!("Index database not found: {}", db_path.display());
+        return;
+    }
+    match work_db::Connection::open_dcm_tables(db_path) {
+        Ok(conn) => write_result(&conn, options),
+        Err(e) => eprintln!("Error open data base [path: {}]: {:?}", db_path.display(), e),
+    }
+}
+
+/// Строк в одной группе строк Parquet: таблица пишется группами, а не целиком
+const PARQUET_ROW_GROUP_SIZE: usize = 65536;
+
+/// Выгружает индекс в плоскую таблицу уровня `level` (CSV или Parquet):
+/// одна строка на пациента, исследование, серию или экземпляр со всеми атрибутами индекса
+/// и количествами. Строки читаются курсором и сразу пишутся в файл
+pub fn export_table(db_path: &path::Path, level: work_dcm::Level, format: ExportFormat, output: &path::Path) {
+    if !db_path.is_file() {
+        eprintln!("Index database not found: {}", db_path.display());
+        return;
+    }
+    let conn = match work_db::Connection::open_dcm_tables(db_path) {
+        Ok(conn) => conn,
+        Err(e) => {
+            eprintln!("Error open data base [path: {}]: {:?}", db_path.display(), e);
+            return;
+        }
+    };
+    let result = conn.table_columns(level)
+        .map_err(|e| e.into())
+        .and_then(|columns| {
+            let file = File::create(output)?;
+            match format {
+                ExportFormat::Parquet => write_parquet(&conn, level, &columns, file),
+                _ => write_csv(&conn, level, &columns, file),
+            }
+        });
+    match result {
+        Ok(rows) => println!("Exported {} rows ({} level) to {}", rows, level.as_str(), output.display()),
+        Err(e) => eprintln!("Error exporting {} table to {}: {:?}", format.extension(), output.display(), e),
+    }
+}
+
+type TableResult = Result<usize, Box<dyn std::error::Error>>;
+
+fn write_csv(conn: &work_db::Connection, level: work_dcm::Level, columns: &[work_db::TableColumn],
+             file: File) -> TableResult {
+    let mut writer = csv::Writer::from_writer(io::BufWriter::new(file));
+    writer.write_record(columns.iter().map(|column| column.name.as_str()))?;
+    let mut rows = 0;
+    conn.for_each_table_row(level, columns, &mut |row| {
+        rows += 1;
+        writer.write_record(row.iter().map(|value| value.as_deref().unwrap_or_default()))
+            .map_err(io::Error::from)
+    })?;
+    writer.flush()?;
+    Ok(rows)
+}
+
+fn write_parquet(conn: &work_db::Connection, level: work_dcm::Level, columns: &[work_db::TableColumn],
+                 file: File) -> TableResult {
+    let fields: Vec<Arc<Type>> = columns.iter()
+        .map(|column| {
+            let builder = match column.kind {
+                ColumnKind::Integer => Type::primitive_type_builder(&column.name, PhysicalType::INT64),
+                ColumnKind::Real => Type::primitive_type_builder(&column.name, PhysicalType::DOUBLE),
+                ColumnKind::Text => Type::primitive_type_builder(&column.name, PhysicalType::BYTE_ARRAY)
+                    .with_converted_type(ConvertedType::UTF8),
+            };
+            builder.with_repetition(Repetition::OPTIONAL).build().map(Arc::new)
+        })
+        .collect::<Result<_, _>>()?;
+    let schema = Type::group_type_builder("dcm_finder").with_fields(fields).build()?;
+    let properties = WriterProperties::builder().set_compression(Compression::UNCOMPRESSED).build();
+    let mut writer = SerializedFileWriter::new(file, Arc::new(schema), Arc::new(properties))?;
+    let mut group: Vec<work_db::TableRow> = Vec::with_capacity(PARQUET_ROW_GROUP_SIZE);
+    let mut rows = 0;
+    let mut write_error: Option<ParquetError> = None;
+    conn.for_each_table_row(level, columns, &mut |row| {
+        rows += 1;
+        group.push(row);
+        if group.len() == PARQUET_ROW_GROUP_SIZE {
+            write_row_group(&mut writer, columns, &group).map_err(|e| {
+                let message = e.to_string();
+                write_error = Some(e);
+                io::Error::other(message)
+            })?;
+            group.clear();
+        }
+        Ok(())
+    }).map_err(|e| write_error.take().map(|e| e.into()).unwrap_or(e))?;
+    if !group.is_empty() || rows == 0 {
+        write_row_group(&mut writer, columns, &group)?;
+    }
+    writer.close()?;
+    Ok(rows)
+}
+
+/// Пишет группу строк Parquet: столбец за столбцом, отсутствующие значения — через уровни определения
+fn write_row_group(writer: &mut SerializedFileWriter<File>, columns: &[work_db::TableColumn],
+                   rows: &[work_db::TableRow]) -> Result<(), ParquetError> {
+    let mut group_writer = writer.next_row_group()?;
+    let mut index = 0;
+    while let Some(mut column_writer) = group_writer.next_column()? {
+        let values = rows.iter().map(|row| row[index].as_deref());
+        let definition_levels: Vec<i16> = values.clone().map(|value| value.is_some() as i16).collect();
+        match columns[index].kind {
+            ColumnKind::Integer => {
+                let numbers: Vec<i64> = values.flatten().map(|value| value.parse().unwrap_or_default()).collect();
+                column_writer.typed::<Int64Type>().write_batch(&numbers, Some(&definition_levels), None)?;
+            }
+            ColumnKind::Real => {
+                let numbers: Vec<f64> = values.flatten().map(|value| value.parse().unwrap_or_default()).collect();
+                column_writer.typed::<DoubleType>().write_batch(&numbers, Some(&definition_levels), None)?;
+            }
+            ColumnKind::Text => {
+                let strings: Vec<ByteArray> = values.flatten().map(ByteArray::from).collect();
+                column_writer.typed::<ByteArrayType>().write_batch(&strings, Some(&definition_levels), None)?;
+            }
+        }
+        column_writer.close()?;
+        index += 1;
+    }
+    group_writer.close()?;
+    Ok(())
+}
+
+/// Экспортирует полный заголовок каждого экземпляра в отдельный файл
+/// `output/<Study UID>/<Series UID>/<SOP Instance UID>.json|xml`.
+/// Pixel Data заменяется ссылкой BulkDataURI на значение в исходном файле
+pub fn export_instances(paths: &[String], format: ExportFormat, output: &path::Path) {
+    let progress = ProgressBar::new(paths.len() as u64);
+    progress.set_style(ProgressStyle::default_bar()
+        .template("[{elapsed_precise}] {bar:40} exported {pos} of {len} ({per_sec})"));
+    let failed = AtomicUsize::new(0);
+    paths.par_iter().for_each(|path| {
+        export_instance(path, format, output).unwrap_or_else(|e| {
+            progress.println(format!("Error exporting {}: {}", path, e));
+            failed.fetch_add(1, Ordering::Relaxed);
+        });
+        progress.inc(1);
+    });
+    progress.finish();
+    let failed = failed.into_inner();
+    println!("Exported instances: {} to {}, failed: {}", paths.len() - failed, output.display(), failed);
+}
+
+fn export_instance(path: &str, format: ExportFormat, output: &path::Path) -> Result<(), String> {
+    let dcm_obj = work_dcm::read_indexed_dcm(path).map_err(|e| e.to_string())?;
+    let pixel_data_uri = pixel_data_uri(path, &dcm_obj);
+    let bulk_data_uri = |tag: Tag| if tag == work_json::PIXEL_DATA { pixel_data_uri.clone() } else { None };
+    let dataset = work_json::dataset_to_json(&dcm_obj, Some(&bulk_data_uri));
+    let content = match format {
+        ExportFormat::DicomJson => serde_json::to_string_pretty(&dataset).map_err(|e| e.to_string())?,
+        ExportFormat::DicomXml => work_xml::json_to_xml(&dataset),
+        _ => return Err("not a per-instance format".to_string()),
+    };
+    let file_name = |keyword: &str, default: &str| {
+        let value = uid(&dcm_obj, keyword);
+        if value.is_empty() { default.to_string() } else { value }
+    };
+    let folder = output
+        .join(file_name("StudyInstanceUID", "NoStudyUid"))
+        .join(file_name("SeriesInstanceUID", "NoSeriesUid"));
+    fs::create_dir_all(&folder).map_err(|e| e.to_string())?;
+    let source_name = path::Path::new(path).file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let target = folder.join(format!("{}.{}", file_name("SOPInstanceUID", &source_name), format.extension()));
+    fs::write(target, content).map_err(|e| e.to_string())
+}
+
+fn uid(obj: &DefaultDicomObject, keyword: &str) -> String {
+    obj.element_by_name(keyword).ok()
+        .and_then(|e| e.to_str().ok())
+        .map(|value| value.trim_end_matches(['\0', ' ']).to_string())
+        .unwrap_or_default()
+}
+
+/// Ссылка на значение Pixel Data в исходном файле: `file:///путь#offset=N&length=M`.
+/// Смещение определяется по длине значения: Pixel Data обычно последний атрибут файла,
+/// заголовок атрибута перед найденным смещением проверяется; если он не совпал,
+/// заголовок ищется по всему файлу. Для сжатых данных ссылка указывает на всю
+/// инкапсулированную последовательность фрагментов (вместе с разделителями)
+fn pixel_data_uri(path: &str, obj: &DefaultDicomObject) -> Option<String> {
+    let (length, encapsulated) = match obj.element(work_json::PIXEL_DATA).ok()?.value() {
+        Value::Primitive(value) => (value.to_bytes().len() as u64, false),
+        Value::PixelSequence { offset_table, fragments } => {
+            let items: u64 = fragments.iter().map(|fragment| 8 + fragment.len() as u64).sum();
+            (8 + offset_table.len() as u64 * 4 + items + 8, true)
+        }
+        Value::Sequence { .. } => return None,
+    };
+    let header_length = if encapsulated { 0xFFFF_FFFF } else { length as u32 };
+    let offset = match work_archive::split_virtual_path(path) {
+        Some((archive, member)) => {
+            let data = work_archive::read_member(archive, member).ok()?;
+            find_pixel_data(&data, header_length)?
+        }
+        None => {
+            let mut file = File::open(path).ok()?;
+            let file_length = file.metadata().ok()?.len();
+            let candidate = file_length.checked_sub(length)?;
+            if candidate >= 12 && is_pixel_data_header(&read_at(&mut file, candidate - 12, 12).ok()?, header_length) {
+                candidate
+            } else {
+                let mut data = Vec::new();
+                file.seek(SeekFrom::Start(0)).ok()?;
+                file.read_to_end(&mut data).ok()?;
+                find_pixel_data(&data, header_length)?
+            }
+        }
+    };
+    Some(format!("{}#offset={}&length={}", file_uri(path), offset, length))
+}
+
+fn read_at(file: &mut File, offset: u64, length: usize) -> io::Result<Vec<u8>> {
+    let mut buffer = vec![0u8; length];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut buffer)?;
+    Ok(buffer)
+}
+
+/// Проверяет, что 12 байт — заголовок Pixel Data с длиной `length`:
+/// явный VR (LE или BE) либо неявный VR LE в последних 8 байтах
+fn is_pixel_data_header(header: &[u8], length: u32) -> bool {
+    let explicit_le = header[..4] == [0xE0, 0x7F, 0x10, 0x00] && matches!(&header[4..6], b"OB" | b"OW")
+        && header[8..12] == length.to_le_bytes();
+    let explicit_be = header[..4] == [0x7F, 0xE0, 0x00, 0x10] && matches!(&header[4..6], b"OB" | b"OW")
+        && header[8..12] == length.to_be_bytes();
+    let implicit = header[4..8] == [0xE0, 0x7F, 0x10, 0x00] && header[8..12] == length.to_le_bytes();
+    explicit_le || explicit_be || implicit
+}
+
+/// Ищет в содержимом файла заголовок Pixel Data верхнего уровня и возвращает смещение значения.
+/// Берется последнее совпадение: значок (Icon Image Sequence) хранится раньше изображения
+fn find_pixel_data(data: &[u8], length: u32) -> Option<u64> {
+    (12..=data.len()).rev()
+        .find(|end| is_pixel_data_header(&data[end - 12..*end], length))
+        .map(|end| end as u64)
+}
+
+/// URI файла с абсолютным путем; символы, недопустимые в пути URI, кодируются
+fn file_uri(path: &str) -> String {
+    let absolute = match work_archive::split_virtual_path(path) {
+        Some((archive, member)) => fs::canonicalize(archive)
+            .map(|archive| work_archive::virtual_path(&archive, member))
+            .unwrap_or_else(|_| path.to_string()),
+        None => fs::canonicalize(path)
+            .map(|path| path.to_string_lossy().to_string())
+            .unwrap_or_else(|_| path.to_string()),
+    };
+    let absolute = absolute.replace('\\', "/");
+    let absolute = absolute.strip_prefix("//?/").unwrap_or(&absolute);
+    let mut uri = String::from("file://");
+    if !absolute.starts_with('/') {
+        uri.push('/');
+    }
+    for c in absolute.chars() {
+        match c {
+            ' ' | '#' | '%' | '?' | '"' | '<' | '>' => uri.push_str(&format!("%{:02X}", c as u32)),
+            _ => uri.push(c),
+        }
+    }
+    uri
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dicom::core::{DataElement, PrimitiveValue, VR};
+    use dicom::object::{FileMetaTableBuilder, InMemDicomObject};
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use parquet::record::{Field, Row, RowAccessor};
+
+    fn test_dir(name: &str) -> path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("dcm_finder_test_{}_export_{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn instances_are_exported_with_pixel_data_reference() {
+        let dir = test_dir("instances");
+        let pixels: Vec<u8> = (0..32u8).collect();
+        let mut obj = InMemDicomObject::new_empty();
+        obj.put(DataElement::new(Tag(0x0008, 0x0016), VR::UI, PrimitiveValue::from("1.2.840.10008.5.1.4.1.1.7")));
+        obj.put(DataElement::new(Tag(0x0008, 0x0018), VR::UI, PrimitiveValue::from("1.2.8.1.1")));
+        obj.put(DataElement::new(Tag(0x0010, 0x0010), VR::PN, PrimitiveValue::from("Doe^Jane")));
+        obj.put(DataElement::new(Tag(0x0020, 0x000D), VR::UI, PrimitiveValue::from("1.2.8")));
+        obj.put(DataElement::new(Tag(0x0020, 0x000E), VR::UI, PrimitiveValue::from("1.2.8.1")));
+        obj.put(DataElement::new(work_json::PIXEL_DATA, VR::OB, PrimitiveValue::from(pixels.clone())));
+        let obj = obj.with_meta(FileMetaTableBuilder::new()
+            .media_storage_sop_class_uid("1.2.840.10008.5.1.4.1.1.7")
+            .media_storage_sop_instance_uid("1.2.8.1.1")
+            .transfer_syntax(work_dcm::EXPLICIT_VR_LITTLE_ENDIAN))
+            .unwrap();
+        let source = dir.join("source dir").join("image.dcm");
+        fs::create_dir_all(source.parent().unwrap()).unwrap();
+        obj.write_to_file(&source).unwrap();
+        let paths = [source.display().to_string()];
+
+        let output = dir.join("export");
+        export_instances(&paths, ExportFormat::DicomJson, &output);
+        let exported = output.join("1.2.8").join("1.2.8.1").join("1.2.8.1.1.json");
+        let json: serde_json::Value = serde_json::from_slice(&fs::read(&exported).unwrap()).unwrap();
+        assert_eq!(json["00100010"]["Value"][0]["Alphabetic"], "Doe^Jane");
+        // Ссылка указывает на значение Pixel Data в исходном файле
+        let uri = json["7FE00010"]["BulkDataURI"].as_str().unwrap();
+        let (file, fragment) = uri.split_once('#').unwrap();
+        assert!(file.starts_with("file://") && file.ends_with("source%20dir/image.dcm"), "{}", uri);
+        let numbers: Vec<usize> = fragment.split('&')
+            .map(|part| part.split_once('=').unwrap().1.parse().unwrap())
+            .collect();
+        let data = fs::read(&source).unwrap();
+        assert_eq!(&data[numbers[0]..numbers[0] + numbers[1]], pixels.as_slice());
+
+        export_instances(&paths, ExportFormat::DicomXml, &output);
+        let xml = fs::read_to_string(output.join("1.2.8").join("1.2.8.1").join("1.2.8.1.1.xml")).unwrap();
+        assert!(xml.contains(&format!("<BulkData uri=\"{}\"/>", uri.replace('&', "&amp;"))));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Набор данных из пар (ключевое слово, значение); VR берется из словаря
+    fn dataset(attributes: &[(&str, &str)]) -> InMemDicomObject {
+        let mut obj = InMemDicomObject::new_empty();
+        for (keyword, value) in attributes {
+            let tag = work_dcm::tag_by_keyword(keyword).unwrap();
+            obj.put(DataElement::new(tag, work_json::vr_by_tag(tag), PrimitiveValue::from(*value)));
+        }
+        obj
+    }
+
+    /// Индекс в файле: пациент P1 с аксиальной серией из двух срезов, пациент P2 с серией без геометрии
+    fn table_index(db_path: &path::Path) {
+        let conn = work_db::Connection::open_dcm_tables(db_path).unwrap();
+        for (patient_id, study_uid, z) in [("P1", "1.2.9.1", "0"), ("P1", "1.2.9.1", "2.5"), ("P2", "1.2.9.2", "")] {
+            let series_uid = format!("{}.1", study_uid);
+            let sop_instance_uid = format!("{}.{}", series_uid, if z == "2.5" { 2 } else { 1 });
+            let mut attributes = vec![
+                ("PatientID", patient_id), ("PatientSex", "F"), ("StudyInstanceUID", study_uid),
+                ("StudyDescription", "Head, \"routine\""), ("SeriesInstanceUID", &series_uid), ("Modality", "CT"),
+                ("SOPInstanceUID", &sop_instance_uid), ("Rows", "512"), ("Columns", "512"),
+            ];
+            let position = format!("-100\\-100\\{}", z);
+            if !z.is_empty() {
+                attributes.extend([("ImagePositionPatient", position.as_str()),
+                                   ("ImageOrientationPatient", "1\\0\\0\\0\\1\\0"), ("PixelSpacing", "0.5\\0.5")]);
+            }
+            let path = format!("/data/{}.dcm", sop_instance_uid);
+            conn.insert_dcm(&work_dcm::MetaDcm::from_with_tags(&dataset(&attributes), &path, &[]));
+        }
+        for series_uid in ["1.2.9.1.1", "1.2.9.2.1"] {
+            conn.update_series_geometry(series_uid).unwrap();
+        }
+    }
+
+    #[test]
+    fn tables_have_a_row_per_entity() {
+        let dir = test_dir("tables");
+        let db_path = dir.join("index.db");
+        table_index(&db_path);
+
+        let csv_path = dir.join("series.csv");
+        export_table(&db_path, work_dcm::Level::Series, ExportFormat::Csv, &csv_path);
+        let mut reader = csv::Reader::from_path(&csv_path).unwrap();
+        let header: Vec<String> = reader.headers().unwrap().iter().map(|name| name.to_string()).collect();
+        let rows: Vec<csv::StringRecord> = reader.records().map(|row| row.unwrap()).collect();
+        let cell = |row: usize, name: &str| rows[row][header.iter().position(|column| column == name).unwrap()].to_string();
+        // Столбцы вышестоящих уровней, количества и геометрия серии; без столбцов уровня файла
+        assert_eq!(&header[..5], ["patient_id", "birth_date", "sex", "age", "study_uid"]);
+        assert!(!header.contains(&"path".to_string()));
+        assert_eq!(rows.len(), 2);
+        assert_eq!((cell(0, "patient_id"), cell(0, "series_uid")), ("P1".to_string(), "1.2.9.1.1".to_string()));
+        assert_eq!(cell(0, "study_description"), "Head, \"routine\"");
+        assert_eq!(cell(0, "number_of_series_related_instances"), "2");
+        assert_eq!(cell(0, "number_of_patient_related_instances"), "2");
+        assert_eq!((cell(0, "slice_spacing"), cell(0, "extent_z"), cell(0, "geometry_issues")),
+                   ("2.5".to_string(), "5".to_string(), String::new()));
+        // Отсутствующие значения — пустые ячейки
+        assert_eq!((cell(1, "birth_date"), cell(1, "slice_spacing"), cell(1, "geometry_issues")),
+                   (String::new(), String::new(), "no_position".to_string()));
+
+        let csv_path = dir.join("instances.csv");
+        export_table(&db_path, work_dcm::Level::Instance, ExportFormat::Csv, &csv_path);
+        let mut reader = csv::Reader::from_path(&csv_path).unwrap();
+        assert!(reader.headers().unwrap().iter().any(|name| name == "path"));
+        assert_eq!(reader.records().count(), 3);
+
+        let parquet_path = dir.join("series.parquet");
+        export_table(&db_path, work_dcm::Level::Series, ExportFormat::Parquet, &parquet_path);
+        let reader = SerializedFileReader::new(File::open(&parquet_path).unwrap()).unwrap();
+        let schema = reader.metadata().file_metadata().schema_descr_ptr();
+        let column = |name: &str| (0..schema.num_columns()).find(|&i| schema.column(i).name() == name).unwrap();
+        assert_eq!(schema.num_columns(), header.len());
+        assert_eq!(schema.column(column("number_of_series_related_instances")).physical_type(), PhysicalType::INT64);
+        assert_eq!(schema.column(column("slice_spacing")).physical_type(), PhysicalType::DOUBLE);
+        assert_eq!(schema.column(column("patient_id")).physical_type(), PhysicalType::BYTE_ARRAY);
+        let rows: Vec<Row> = reader.get_row_iter(None).unwrap().map(|row| row.unwrap()).collect();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].get_string(column("patient_id")).unwrap(), "P1");
+        assert_eq!(rows[0].get_long(column("number_of_series_related_instances")).unwrap(), 2);
+        assert_eq!(rows[0].get_double(column("extent_x")).unwrap(), 256.0);
+        let (_, spacing) = rows[1].get_column_iter().nth(column("slice_spacing")).unwrap();
+        assert_eq!(spacing, &Field::Null);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
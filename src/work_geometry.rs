@@ -0,0 +1,290 @@
+use serde::{Deserialize, Serialize};
+
+
+/// Допуск при сравнении направляющих косинусов ориентации
+const ORIENTATION_TOLERANCE: f64 = 1e-3;
+/// Допуск при сравнении размера пикселя, мм
+const SPACING_TOLERANCE: f64 = 1e-3;
+/// Срезы, положения которых вдоль нормали отличаются меньше, считаются совпадающими, мм
+const POSITION_TOLERANCE: f64 = 1e-2;
+/// Допустимое относительное отклонение шага между срезами от медианного
+const SLICE_SPACING_TOLERANCE: f64 = 0.1;
+/// Наклон гентри, начиная с которого он отмечается в проблемах серии, градусы
+const GANTRY_TILT_TOLERANCE: f64 = 0.5;
+
+/// Геометрия одного экземпляра серии (значения атрибутов из индекса)
+#[derive(Debug, Clone, Default)]
+pub struct SliceGeometry {
+    /// Image Position (Patient)
+    pub position: Option<[f64; 3]>,
+    /// Image Orientation (Patient): косинусы строки и столбца
+    pub orientation: Option<[f64; 6]>,
+    /// Pixel Spacing: расстояние между строками, между столбцами
+    pub pixel_spacing: Option<[f64; 2]>,
+    pub rows: Option<u32>,
+    pub columns: Option<u32>,
+}
+
+impl SliceGeometry {
+    /// Разбирает строковые значения атрибутов, как они хранятся в индексе (`Unknown` — нет значения)
+    pub fn parse(position: &str, orientation: &str, pixel_spacing: &str, rows: &str, columns: &str) -> SliceGeometry {
+        SliceGeometry {
+            position: parse_numbers(position),
+            orientation: parse_numbers(orientation),
+            pixel_spacing: parse_numbers(pixel_spacing),
+            rows: rows.trim().parse().ok(),
+            columns: columns.trim().parse().ok(),
+        }
+    }
+}
+
+/// Сводка геометрии серии: срезы упорядочены по положению вдоль нормали к плоскости среза
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct SeriesGeometry {
+    /// Количество экземпляров серии
+    pub slices: usize,
+    /// Медианный шаг между соседними срезами вдоль нормали, мм
+    pub slice_spacing: Option<f64>,
+    /// Размер вокселя: между столбцами, между строками, между срезами, мм
+    pub voxel_size: Option<[f64; 3]>,
+    /// Размер объема по тем же осям, мм
+    pub extent: Option<[f64; 3]>,
+    /// Количество срезов, которое должно быть при равномерном шаге от первого до последнего
+    pub expected_slices: usize,
+    pub missing_slices: usize,
+    /// Срезы, положение которых совпадает с положением другого среза
+    pub duplicate_positions: usize,
+    /// Доля присутствующих срезов от ожидаемого количества
+    pub completeness: f64,
+    /// Угол между нормалью к срезу и направлением, в котором сдвигаются срезы, градусы
+    pub gantry_tilt: Option<f64>,
+    /// Найденные проблемы: missing_slices, duplicate_positions, irregular_spacing,
+    /// inconsistent_orientation, inconsistent_pixel_spacing, gantry_tilt, mixed_dimensions, no_position
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub issues: Vec<String>,
+}
+
+/// Анализирует геометрию серии по геометрии ее экземпляров
+pub fn analyze(slices: &[SliceGeometry]) -> SeriesGeometry {
+    let mut geometry = SeriesGeometry {
+        slices: slices.len(),
+        expected_slices: slices.len(),
+        completeness: 1.0,
+        ..Default::default()
+    };
+    if slices.is_empty() {
+        return geometry;
+    }
+    let orientation = slices.iter().find_map(|slice| slice.orientation);
+    let pixel_spacing = slices.iter().find_map(|slice| slice.pixel_spacing);
+    if slices.iter().any(|slice| !same(slice.orientation, orientation, ORIENTATION_TOLERANCE)) {
+        geometry.issues.push("inconsistent_orientation".to_string());
+    }
+    if slices.iter().any(|slice| !same(slice.pixel_spacing, pixel_spacing, SPACING_TOLERANCE)) {
+        geometry.issues.push("inconsistent_pixel_spacing".to_string());
+    }
+    let first = &slices[0];
+    if slices.iter().any(|slice| slice.rows != first.rows || slice.columns != first.columns) {
+        geometry.issues.push("mixed_dimensions".to_string());
+    }
+
+    let (orientation, positions) = match (orientation, slices.iter().map(|slice| slice.position).collect::<Option<Vec<_>>>()) {
+        (Some(orientation), Some(positions)) => (orientation, positions),
+        _ => {
+            geometry.issues.push("no_position".to_string());
+            return geometry;
+        }
+    };
+    let normal = normal(&orientation);
+    let mut ordered: Vec<(f64, [f64; 3])> = positions.iter().map(|position| (dot(position, &normal), *position)).collect();
+    ordered.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let mut unique: Vec<(f64, [f64; 3])> = Vec::with_capacity(ordered.len());
+    for slice in ordered {
+        match unique.last() {
+            Some(last) if slice.0 - last.0 < POSITION_TOLERANCE => geometry.duplicate_positions += 1,
+            _ => unique.push(slice),
+        }
+    }
+    if geometry.duplicate_positions != 0 {
+        geometry.issues.push("duplicate_positions".to_string());
+    }
+
+    let gaps: Vec<f64> = unique.windows(2).map(|pair| pair[1].0 - pair[0].0).collect();
+    let spacing = median(&gaps);
+    if let Some(spacing) = spacing {
+        let mut irregular = false;
+        for gap in &gaps {
+            let steps = (gap / spacing).round().max(1.0);
+            irregular |= (gap - steps * spacing).abs() > SLICE_SPACING_TOLERANCE * spacing;
+            geometry.missing_slices += steps as usize - 1;
+        }
+        if geometry.missing_slices != 0 {
+            geometry.issues.push("missing_slices".to_string());
+        }
+        if irregular {
+            geometry.issues.push("irregular_spacing".to_string());
+        }
+        geometry.expected_slices = unique.len() + geometry.missing_slices;
+        geometry.completeness = unique.len() as f64 / geometry.expected_slices as f64;
+
+        let (first, last) = (unique[0].1, unique[unique.len() - 1].1);
+        let shift = [last[0] - first[0], last[1] - first[1], last[2] - first[2]];
+        let length = dot(&shift, &shift).sqrt();
+        let tilt = (dot(&shift, &normal).abs() / length).clamp(-1.0, 1.0).acos().to_degrees();
+        if tilt > GANTRY_TILT_TOLERANCE {
+            geometry.issues.push("gantry_tilt".to_string());
+        }
+        geometry.gantry_tilt = Some(tilt);
+    } else {
+        geometry.expected_slices = unique.len();
+    }
+    geometry.slice_spacing = spacing;
+    if let (Some(pixel_spacing), Some(spacing)) = (pixel_spacing, spacing) {
+        geometry.voxel_size = Some([pixel_spacing[1], pixel_spacing[0], spacing]);
+        if let (Some(rows), Some(columns)) = (first.rows, first.columns) {
+            geometry.extent = Some([columns as f64 * pixel_spacing[1], rows as f64 * pixel_spacing[0],
+                                    geometry.expected_slices as f64 * spacing]);
+        }
+    }
+    geometry
+}
+
+/// Нормаль к плоскости среза: векторное произведение косинусов строки и столбца
+pub fn normal(orientation: &[f64; 6]) -> [f64; 3] {
+    let (row, column) = (&orientation[..3], &orientation[3..]);
+    [
+        row[1] * column[2] - row[2] * column[1],
+        row[2] * column[0] - row[0] * column[2],
+        row[0] * column[1] - row[1] * column[0],
+    ]
+}
+
+pub fn dot(a: &[f64; 3], b: &[f64; 3]) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+/// Разбирает многозначное числовое значение `a\b\c` ровно из N чисел
+pub fn parse_numbers<const N: usize>(value: &str) -> Option<[f64; N]> {
+    let numbers: Vec<f64> = value.split('\\')
+        .map(|number| number.trim().parse::<f64>())
+        .collect::<Result<_, _>>()
+        .ok()?;
+    numbers.try_into().ok()
+}
+
+fn same<const N: usize>(value: Option<[f64; N]>, reference: Option<[f64; N]>, tolerance: f64) -> bool {
+    match (value, reference) {
+        (Some(value), Some(reference)) => value.iter().zip(reference.iter()).all(|(a, b)| (a - b).abs() <= tolerance),
+        (value, reference) => value.is_none() && reference.is_none(),
+    }
+}
+
+fn median(values: &[f64]) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let middle = sorted.len() / 2;
+    Some(if sorted.len().is_multiple_of(2) { (sorted[middle - 1] + sorted[middle]) / 2.0 } else { sorted[middle] })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AXIAL: [f64; 6] = [1.0, 0.0, 0.0, 0.0, 1.0, 0.0];
+
+    /// Срезы 512x512 с пикселем 0.5x0.7 мм в положениях `z` вдоль нормали,
+    /// каждый сдвинут на `y_shift` мм по Y на 1 мм вдоль нормали
+    fn stack(z: &[f64], y_shift: f64) -> Vec<SliceGeometry> {
+        z.iter().map(|z| SliceGeometry {
+            position: Some([-100.0, -120.0 + z * y_shift, *z]),
+            orientation: Some(AXIAL),
+            pixel_spacing: Some([0.7, 0.5]),
+            rows: Some(512),
+            columns: Some(512),
+        }).collect()
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-6
+    }
+
+    #[test]
+    fn regular_stack() {
+        // Порядок срезов в серии не важен
+        let geometry = analyze(&stack(&[7.5, 0.0, 2.5, 5.0, 10.0], 0.0));
+        assert!(geometry.issues.is_empty(), "{:?}", geometry.issues);
+        assert_eq!((geometry.slices, geometry.expected_slices, geometry.missing_slices), (5, 5, 0));
+        assert_eq!(geometry.slice_spacing, Some(2.5));
+        assert_eq!(geometry.voxel_size, Some([0.5, 0.7, 2.5]));
+        let extent = geometry.extent.unwrap();
+        assert!(close(extent[0], 256.0) && close(extent[1], 358.4) && close(extent[2], 12.5));
+        assert!(close(geometry.completeness, 1.0));
+        assert!(close(geometry.gantry_tilt.unwrap(), 0.0));
+    }
+
+    #[test]
+    fn one_missing_slice() {
+        let geometry = analyze(&stack(&[0.0, 2.5, 5.0, 10.0, 12.5], 0.0));
+        assert_eq!(geometry.issues, ["missing_slices"]);
+        assert_eq!((geometry.slices, geometry.expected_slices, geometry.missing_slices), (5, 6, 1));
+        assert_eq!(geometry.slice_spacing, Some(2.5));
+        assert!(close(geometry.completeness, 5.0 / 6.0));
+        assert!(close(geometry.extent.unwrap()[2], 15.0));
+
+        let geometry = analyze(&stack(&[0.0, 2.5, 5.0, 6.5, 9.0], 0.0));
+        assert!(geometry.issues.contains(&"irregular_spacing".to_string()));
+    }
+
+    #[test]
+    fn duplicate_positions() {
+        let geometry = analyze(&stack(&[0.0, 2.5, 2.5, 5.0, 5.001], 0.0));
+        assert_eq!(geometry.issues, ["duplicate_positions"]);
+        assert_eq!(geometry.duplicate_positions, 2);
+        assert_eq!((geometry.slices, geometry.expected_slices, geometry.missing_slices), (5, 3, 0));
+        assert_eq!(geometry.slice_spacing, Some(2.5));
+    }
+
+    #[test]
+    fn tilted_stack() {
+        // Срезы сдвигаются по Y: наклон гентри atan(0.25) ≈ 14.04°, шаг считается вдоль нормали
+        let geometry = analyze(&stack(&[0.0, 2.0, 4.0, 6.0], 0.25));
+        assert_eq!(geometry.issues, ["gantry_tilt"]);
+        assert!(close(geometry.gantry_tilt.unwrap(), 0.25f64.atan().to_degrees()));
+        assert_eq!(geometry.slice_spacing, Some(2.0));
+        assert_eq!(geometry.missing_slices, 0);
+    }
+
+    #[test]
+    fn mixed_orientation_and_missing_attributes() {
+        let mut slices = stack(&[0.0, 2.5, 5.0], 0.0);
+        slices[1].orientation = Some([0.0, 1.0, 0.0, 0.0, 0.0, -1.0]);
+        slices[2].pixel_spacing = Some([0.8, 0.5]);
+        slices[2].rows = Some(256);
+        let geometry = analyze(&slices);
+        for issue in ["inconsistent_orientation", "inconsistent_pixel_spacing", "mixed_dimensions"] {
+            assert!(geometry.issues.contains(&issue.to_string()), "{}", issue);
+        }
+
+        let mut slices = stack(&[0.0, 2.5], 0.0);
+        slices[1].position = None;
+        let geometry = analyze(&slices);
+        assert_eq!(geometry.issues, ["no_position"]);
+        assert_eq!(geometry.slice_spacing, None);
+        assert_eq!(analyze(&[]).slices, 0);
+    }
+
+    #[test]
+    fn attributes_are_parsed_from_index_values() {
+        let slice = SliceGeometry::parse("-100\\-120.5\\7.5", "1\\0\\0\\0\\1\\0", "0.7\\0.5", "512", " 256 ");
+        assert_eq!(slice.position, Some([-100.0, -120.5, 7.5]));
+        assert_eq!(slice.orientation, Some(AXIAL));
+        assert_eq!((slice.rows, slice.columns), (Some(512), Some(256)));
+        let slice = SliceGeometry::parse("Unknown", "1\\0\\0\\0\\1", "0.7", "", "Unknown");
+        assert!(slice.position.is_none() && slice.orientation.is_none() && slice.pixel_spacing.is_none());
+        assert!(slice.rows.is_none() && slice.columns.is_none());
+        assert_eq!(normal(&AXIAL), [0.0, 0.0, 1.0]);
+    }
+}
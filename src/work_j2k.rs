@@ -0,0 +1,1793 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::work_pixels::FrameFormat;
+
+
+/// Коэффициенты лифтинга необратимого фильтра 9/7 (ITU-T T.800 табл. F.4)
+const ALPHA: f64 = -1.586134342059924;
+const BETA: f64 = -0.052980118572961;
+const GAMMA: f64 = 0.882911075530934;
+const DELTA: f64 = 0.443506852043971;
+const K: f64 = 1.230174104914001;
+
+/// Состояния MQ-кодера: Qe, NMPS, NLPS, SWITCH (T.800 табл. C.2)
+const MQ_STATES: [(u32, u8, u8, u8); 47] = [
+    (0x5601, 1, 1, 1), (0x3401, 2, 6, 0), (0x1801, 3, 9, 0), (0x0AC1, 4, 12, 0),
+    (0x0521, 5, 29, 0), (0x0221, 38, 33, 0), (0x5601, 7, 6, 1), (0x5401, 8, 14, 0),
+    (0x4801, 9, 14, 0), (0x3801, 10, 14, 0), (0x3001, 11, 17, 0), (0x2401, 12, 18, 0),
+    (0x1C01, 13, 20, 0), (0x1601, 29, 21, 0), (0x5601, 15, 14, 1), (0x5401, 16, 14, 0),
+    (0x5101, 17, 15, 0), (0x4801, 18, 16, 0), (0x3801, 19, 17, 0), (0x3401, 20, 18, 0),
+    (0x3001, 21, 19, 0), (0x2801, 22, 19, 0), (0x2401, 23, 20, 0), (0x2201, 24, 21, 0),
+    (0x1C01, 25, 22, 0), (0x1801, 26, 23, 0), (0x1601, 27, 24, 0), (0x1401, 28, 25, 0),
+    (0x1201, 29, 26, 0), (0x1101, 30, 27, 0), (0x0AC1, 31, 28, 0), (0x09C1, 32, 29, 0),
+    (0x08A1, 33, 30, 0), (0x0521, 34, 31, 0), (0x0441, 35, 32, 0), (0x02A1, 36, 33, 0),
+    (0x0221, 37, 34, 0), (0x0141, 38, 35, 0), (0x0111, 39, 36, 0), (0x0085, 40, 37, 0),
+    (0x0049, 41, 38, 0), (0x0025, 42, 39, 0), (0x0015, 43, 40, 0), (0x0009, 44, 41, 0),
+    (0x0005, 45, 42, 0), (0x0001, 45, 43, 0), (0x5601, 46, 46, 0),
+];
+
+/// Контексты EBCOT: 0-8 значимость, 9-13 знак, 14-16 уточнение, серия и равномерный
+const CONTEXTS: usize = 19;
+const CONTEXT_RUN: usize = 17;
+const CONTEXT_UNIFORM: usize = 18;
+
+/// Флаги коэффициента при проходах по битовым плоскостям
+const SIGNIFICANT: u8 = 1;
+const NEGATIVE: u8 = 2;
+const VISITED: u8 = 4;
+const REFINED: u8 = 8;
+
+/// Стили кодовых блоков (T.800 табл. A.19)
+const STYLE_BYPASS: u8 = 1;
+const STYLE_RESET: u8 = 2;
+const STYLE_TERMINATE_ALL: u8 = 4;
+const STYLE_VERTICALLY_CAUSAL: u8 = 8;
+const STYLE_SEGMENTATION_SYMBOLS: u8 = 32;
+
+
+/// Распаковывает кадр JPEG 2000 (кодовый поток или файл JP2).
+/// Возвращает значения по порядку строка, столбец, компонента и признак того,
+/// что компоненты переведены обратным многокомпонентным преобразованием в RGB
+pub fn decode(data: &[u8], format: &FrameFormat) -> Result<(Vec<i32>, bool), String> {
+    let codestream = codestream(data)?;
+    let stream = parse(codestream)?;
+    let size = &stream.size;
+    let (width, height) = ((size.x1 - size.x0) as usize, (size.y1 - size.y0) as usize);
+    if width != format.columns || height != format.rows {
+        return Err(format!("JPEG 2000 frame is {}x{}, expected {}x{}", width, height, format.columns, format.rows));
+    }
+    if size.components.len() != format.samples_per_pixel {
+        return Err(format!("JPEG 2000 frame has {} components, expected {}",
+                           size.components.len(), format.samples_per_pixel));
+    }
+    let mut values = vec![0i32; format.samples()];
+    let mut transformed = false;
+    for (index, (markers, data)) in &stream.tiles {
+        transformed |= decode_tile(&stream, *index, markers, data, &mut values)?;
+    }
+    Ok((values, transformed))
+}
+
+/// Кодовый поток из файла JP2 (коробка jp2c) или сам поток
+fn codestream(data: &[u8]) -> Result<&[u8], String> {
+    if data.starts_with(&[0xFF, 0x4F]) {
+        return Ok(data);
+    }
+    if data.get(4..8) != Some(b"jP  ") {
+        return Err("neither a JPEG 2000 codestream nor a JP2 file".to_string());
+    }
+    let mut position = 0;
+    while position + 8 <= data.len() {
+        let mut length = u32::from_be_bytes([data[position], data[position + 1], data[position + 2], data[position + 3]]) as usize;
+        let kind = &data[position + 4..position + 8];
+        let mut header = 8;
+        if length == 1 {
+            let extended = data.get(position + 8..position + 16).ok_or("JP2 box is out of the file")?;
+            length = u64::from_be_bytes(extended.try_into().unwrap()) as usize;
+            header = 16;
+        } else if length == 0 {
+            length = data.len() - position;
+        }
+        if length < header || position + length > data.len() {
+            return Err("JP2 box is out of the file".to_string());
+        }
+        if kind == b"jp2c" {
+            return Ok(&data[position + header..position + length]);
+        }
+        position += length;
+    }
+    Err("JP2 file has no codestream box".to_string())
+}
+
+fn ceil_div(value: i64, divisor: i64) -> i64 {
+    (value + divisor - 1).div_euclid(divisor)
+}
+
+
+struct Component {
+    depth: u32,
+    signed: bool,
+}
+
+/// Сетка изображения и тайлов из SIZ
+struct Size {
+    x0: i64,
+    y0: i64,
+    x1: i64,
+    y1: i64,
+    tile_x0: i64,
+    tile_y0: i64,
+    tile_width: i64,
+    tile_height: i64,
+    components: Vec<Component>,
+}
+
+/// Параметры кодирования компоненты из SPcod/SPcoc
+#[derive(Debug, Clone)]
+struct Coding {
+    levels: usize,
+    xcb: u32,
+    ycb: u32,
+    style: u8,
+    reversible: bool,
+    /// PPx, PPy по уровням разрешения
+    precincts: Vec<(u32, u32)>,
+}
+
+/// Порядок пакетов и прочее из SGcod
+#[derive(Debug, Clone)]
+struct Order {
+    sop: bool,
+    eph: bool,
+    progression: u8,
+    layers: usize,
+    mct: bool,
+}
+
+#[derive(Debug, Clone)]
+struct Quantization {
+    guard: u32,
+    style: u8,
+    /// Экспонента и мантисса шага по поддиапазонам
+    steps: Vec<(u32, u32)>,
+}
+
+/// Маркеры COD/COC/QCD/QCC основного заголовка или заголовка тайла
+#[derive(Default, Clone)]
+struct Markers {
+    order: Option<Order>,
+    coding: Option<Coding>,
+    component_coding: HashMap<usize, Coding>,
+    quantization: Option<Quantization>,
+    component_quantization: HashMap<usize, Quantization>,
+}
+
+struct Codestream {
+    size: Size,
+    main: Markers,
+    /// Заголовок первой части и склеенные данные частей по номеру тайла
+    tiles: BTreeMap<usize, (Markers, Vec<u8>)>,
+}
+
+fn parse(data: &[u8]) -> Result<Codestream, String> {
+    let mut size = None;
+    let mut main = Markers::default();
+    let mut tiles: BTreeMap<usize, (Markers, Vec<u8>)> = BTreeMap::new();
+    let mut position = 2;
+    loop {
+        let marker = match data.get(position..position + 2) {
+            Some(&[0xFF, marker]) => marker,
+            Some(_) => return Err(format!("JPEG 2000 marker expected at byte {}", position)),
+            None => break,
+        };
+        if marker == 0xD9 {
+            break;
+        }
+        let length = data.get(position + 2..position + 4)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]) as usize)
+            .ok_or("JPEG 2000 marker segment is out of the codestream")?;
+        let segment = data.get(position + 4..position + 2 + length).ok_or("JPEG 2000 marker segment is out of the codestream")?;
+        match marker {
+            0x51 => size = Some(parse_size(segment)?),
+            0x90 => {
+                let components = size.as_ref().ok_or("JPEG 2000 tile before SIZ")?.components.len();
+                let tile = u16::from_be_bytes([segment[0], segment[1]]) as usize;
+                let part_length = u32::from_be_bytes([segment[2], segment[3], segment[4], segment[5]]) as usize;
+                let end = if part_length == 0 { data.len().saturating_sub(2) } else { position + part_length };
+                if end > data.len() {
+                    return Err("JPEG 2000 tile-part is out of the codestream".to_string());
+                }
+                let entry = tiles.entry(tile).or_insert_with(|| (Markers::default(), Vec::new()));
+                let mut cursor = position + 2 + length;
+                loop {
+                    let (tile_marker, tile_segment) = match data.get(cursor..cursor + 2) {
+                        Some(&[0xFF, 0x93]) => break,
+                        Some(&[0xFF, tile_marker]) => {
+                            let tile_length = data.get(cursor + 2..cursor + 4)
+                                .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]) as usize)
+                                .ok_or("JPEG 2000 tile-part header is out of the codestream")?;
+                            let tile_segment = data.get(cursor + 4..cursor + 2 + tile_length)
+                                .ok_or("JPEG 2000 tile-part header is out of the codestream")?;
+                            cursor += 2 + tile_length;
+                            (tile_marker, tile_segment)
+                        }
+                        _ => return Err("JPEG 2000 tile-part has no SOD".to_string()),
+                    };
+                    parse_marker(&mut entry.0, tile_marker, tile_segment, components)?;
+                }
+                entry.1.extend_from_slice(&data[cursor + 2..end]);
+                position = end;
+                continue;
+            }
+            _ => {
+                let components = size.as_ref().map_or(0, |size: &Size| size.components.len());
+                parse_marker(&mut main, marker, segment, components)?;
+            }
+        }
+        position += 2 + length;
+    }
+    let size = size.ok_or("JPEG 2000 codestream has no SIZ")?;
+    Ok(Codestream { size, main, tiles })
+}
+
+fn parse_size(segment: &[u8]) -> Result<Size, String> {
+    let number = |index: usize| segment.get(2 + index * 4..6 + index * 4)
+        .map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as i64)
+        .ok_or("JPEG 2000 SIZ is too short");
+    let count = segment.get(34..36).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]) as usize)
+        .ok_or("JPEG 2000 SIZ is too short")?;
+    let mut components = Vec::with_capacity(count);
+    for index in 0..count {
+        let component = segment.get(36 + index * 3..39 + index * 3).ok_or("JPEG 2000 SIZ is too short")?;
+        if component[1] != 1 || component[2] != 1 {
+            return Err("JPEG 2000 subsampled components are not supported".to_string());
+        }
+        components.push(Component { depth: (component[0] & 0x7F) as u32 + 1, signed: component[0] & 0x80 != 0 });
+    }
+    let size = Size {
+        x1: number(0)?,
+        y1: number(1)?,
+        x0: number(2)?,
+        y0: number(3)?,
+        tile_width: number(4)?,
+        tile_height: number(5)?,
+        tile_x0: number(6)?,
+        tile_y0: number(7)?,
+        components,
+    };
+    if size.tile_width == 0 || size.tile_height == 0 || size.x1 <= size.x0 || size.y1 <= size.y0 {
+        return Err("JPEG 2000 SIZ has an empty image or tile".to_string());
+    }
+    Ok(size)
+}
+
+fn parse_marker(markers: &mut Markers, marker: u8, segment: &[u8], components: usize) -> Result<(), String> {
+    let short = || "JPEG 2000 marker segment is too short".to_string();
+    let component_index = |segment: &[u8]| -> Result<(usize, usize), String> {
+        if components < 257 {
+            segment.first().map(|value| (*value as usize, 1)).ok_or_else(short)
+        } else {
+            segment.get(..2).map(|bytes| (u16::from_be_bytes([bytes[0], bytes[1]]) as usize, 2)).ok_or_else(short)
+        }
+    };
+    match marker {
+        0x52 => {
+            let scod = *segment.first().ok_or_else(short)?;
+            let general = segment.get(1..5).ok_or_else(short)?;
+            markers.order = Some(Order {
+                sop: scod & 2 != 0,
+                eph: scod & 4 != 0,
+                progression: general[0],
+                layers: u16::from_be_bytes([general[1], general[2]]) as usize,
+                mct: general[3] != 0,
+            });
+            markers.coding = Some(parse_coding(&segment[5..], scod & 1 != 0)?);
+        }
+        0x53 => {
+            let (component, skip) = component_index(segment)?;
+            let scoc = *segment.get(skip).ok_or_else(short)?;
+            markers.component_coding.insert(component, parse_coding(&segment[skip + 1..], scoc & 1 != 0)?);
+        }
+        0x5C => {
+            markers.quantization = Some(parse_quantization(segment)?);
+        }
+        0x5D => {
+            let (component, skip) = component_index(segment)?;
+            markers.component_quantization.insert(component, parse_quantization(&segment[skip..])?);
+        }
+        0x5E => return Err("JPEG 2000 regions of interest are not supported".to_string()),
+        0x5F => return Err("JPEG 2000 progression order changes are not supported".to_string()),
+        0x60 | 0x61 => return Err("JPEG 2000 packed packet headers are not supported".to_string()),
+        _ => {}
+    }
+    Ok(())
+}
+
+fn parse_coding(segment: &[u8], custom_precincts: bool) -> Result<Coding, String> {
+    let values = segment.get(..5).ok_or("JPEG 2000 coding style is too short")?;
+    let levels = values[0] as usize;
+    let precincts = if custom_precincts {
+        let sizes = segment.get(5..6 + levels).ok_or("JPEG 2000 precinct sizes are too short")?;
+        sizes.iter().map(|size| ((size & 0x0F) as u32, (size >> 4) as u32)).collect()
+    } else {
+        vec![(15, 15); levels + 1]
+    };
+    let coding = Coding {
+        levels,
+        xcb: values[1] as u32 + 2,
+        ycb: values[2] as u32 + 2,
+        style: values[3],
+        reversible: values[4] == 1,
+        precincts,
+    };
+    if levels > 32 || coding.xcb + coding.ycb > 12 {
+        return Err("JPEG 2000 coding style is out of range".to_string());
+    }
+    if coding.style & (STYLE_BYPASS | STYLE_TERMINATE_ALL) != 0 {
+        return Err("JPEG 2000 selective bypass and per-pass termination are not supported".to_string());
+    }
+    Ok(coding)
+}
+
+fn parse_quantization(segment: &[u8]) -> Result<Quantization, String> {
+    let sqcd = *segment.first().ok_or("JPEG 2000 quantization is too short")?;
+    let style = sqcd & 0x1F;
+    let steps = match style {
+        0 => segment[1..].iter().map(|value| ((value >> 3) as u32, 0)).collect(),
+        1 | 2 => segment[1..].chunks_exact(2)
+            .map(|bytes| {
+                let value = u16::from_be_bytes([bytes[0], bytes[1]]) as u32;
+                (value >> 11, value & 0x7FF)
+            })
+            .collect(),
+        _ => return Err(format!("JPEG 2000 quantization style {} is not supported", style)),
+    };
+    Ok(Quantization { guard: (sqcd >> 5) as u32, style, steps })
+}
+
+
+struct Block {
+    x0: i64,
+    y0: i64,
+    x1: i64,
+    y1: i64,
+    included: bool,
+    lblock: u32,
+    zero_planes: u32,
+    passes: u32,
+    data: Vec<u8>,
+}
+
+/// Поддиапазон: 0 — LL, 1 — HL, 2 — LH, 3 — HH
+struct Band {
+    kind: usize,
+    x0: i64,
+    y0: i64,
+    x1: i64,
+    y1: i64,
+    /// Число битовых плоскостей величины Mb
+    planes: u32,
+    step: f64,
+    blocks: Vec<Block>,
+}
+
+/// Кодовые блоки поддиапазона внутри участка (precinct) и их деревья меток
+struct PrecinctBand {
+    blocks: Vec<usize>,
+    inclusion: TagTree,
+    zero_planes: TagTree,
+}
+
+struct Resolution {
+    x0: i64,
+    y0: i64,
+    x1: i64,
+    y1: i64,
+    ppx: u32,
+    ppy: u32,
+    precincts_wide: i64,
+    bands: Vec<Band>,
+    precincts: Vec<Vec<PrecinctBand>>,
+}
+
+impl Resolution {
+    fn is_empty(&self) -> bool {
+        self.x1 <= self.x0 || self.y1 <= self.y0
+    }
+}
+
+struct TileComponent {
+    coding: Coding,
+    resolutions: Vec<Resolution>,
+}
+
+/// Распаковывает тайл и раскладывает его отсчеты по изображению. Возвращает признак обратного MCT
+fn decode_tile(stream: &Codestream, index: usize, tile: &Markers, data: &[u8], values: &mut [i32]) -> Result<bool, String> {
+    let size = &stream.size;
+    let main = &stream.main;
+    let tiles_wide = ceil_div(size.x1 - size.tile_x0, size.tile_width);
+    let (p, q) = (index as i64 % tiles_wide, index as i64 / tiles_wide);
+    let tx0 = (size.tile_x0 + p * size.tile_width).max(size.x0);
+    let ty0 = (size.tile_y0 + q * size.tile_height).max(size.y0);
+    let tx1 = (size.tile_x0 + (p + 1) * size.tile_width).min(size.x1);
+    let ty1 = (size.tile_y0 + (q + 1) * size.tile_height).min(size.y1);
+    if tx1 <= tx0 || ty1 <= ty0 {
+        return Err(format!("JPEG 2000 tile {} is out of the image", index));
+    }
+    let order = tile.order.as_ref().or(main.order.as_ref()).ok_or("JPEG 2000 codestream has no COD")?;
+
+    let mut components = Vec::with_capacity(size.components.len());
+    for (number, component) in size.components.iter().enumerate() {
+        let coding = tile.component_coding.get(&number).or(tile.coding.as_ref())
+            .or(main.component_coding.get(&number)).or(main.coding.as_ref())
+            .ok_or("JPEG 2000 codestream has no COD")?;
+        let quantization = tile.component_quantization.get(&number).or(tile.quantization.as_ref())
+            .or(main.component_quantization.get(&number)).or(main.quantization.as_ref())
+            .ok_or("JPEG 2000 codestream has no QCD")?;
+        let resolutions = build_resolutions((tx0, ty0, tx1, ty1), coding, quantization, component.depth)?;
+        components.push(TileComponent { coding: coding.clone(), resolutions });
+    }
+
+    let mut position = 0;
+    for (layer, resolution, component, precinct) in packet_order(order, &components, (tx0, ty0, tx1, ty1))? {
+        if position >= data.len() {
+            break;
+        }
+        position = read_packet(data, position, &mut components[component].resolutions[resolution], precinct, layer, order)?;
+    }
+
+    let mut samples = Vec::with_capacity(components.len());
+    for component in &components {
+        samples.push(reconstruct(component)?);
+    }
+    let transformed = order.mct && samples.len() >= 3;
+    if transformed {
+        let reversible = components[0].coding.reversible;
+        let (first, rest) = samples.split_at_mut(1);
+        let (second, third) = rest.split_at_mut(1);
+        for ((y, cb), cr) in first[0].iter_mut().zip(second[0].iter_mut()).zip(third[0].iter_mut()) {
+            let (r, g, b) = if reversible {
+                let g = *y - ((*cb + *cr) / 4.0).floor();
+                (*cr + g, g, *cb + g)
+            } else {
+                (*y + 1.402 * *cr, *y - 0.344136 * *cb - 0.714136 * *cr, *y + 1.772 * *cb)
+            };
+            (*y, *cb, *cr) = (r, g, b);
+        }
+    }
+
+    let image_width = (size.x1 - size.x0) as usize;
+    let spp = size.components.len();
+    let tile_width = (tx1 - tx0) as usize;
+    for (number, (component, samples)) in size.components.iter().zip(&samples).enumerate() {
+        let (low, high) = if component.signed {
+            (-(1i64 << (component.depth - 1)), (1i64 << (component.depth - 1)) - 1)
+        } else {
+            (0, (1i64 << component.depth) - 1)
+        };
+        let shift = if component.signed { 0.0 } else { (1i64 << (component.depth - 1)) as f64 };
+        let mask = ((1u64 << component.depth) - 1) as i64;
+        for (offset, sample) in samples.iter().enumerate() {
+            let (x, y) = (tx0 as usize + offset % tile_width, ty0 as usize + offset / tile_width);
+            let value = ((sample + shift).round() as i64).clamp(low, high) & mask;
+            let pixel = (y - size.y0 as usize) * image_width + (x - size.x0 as usize);
+            values[pixel * spp + number] = value as i32;
+        }
+    }
+    Ok(transformed)
+}
+
+/// Уровни разрешения компоненты тайла с поддиапазонами, участками и кодовыми блоками (T.800 B.5-B.7)
+fn build_resolutions(bounds: (i64, i64, i64, i64), coding: &Coding, quantization: &Quantization,
+                     depth: u32) -> Result<Vec<Resolution>, String> {
+    let (tcx0, tcy0, tcx1, tcy1) = bounds;
+    let levels = coding.levels;
+    let mut resolutions = Vec::with_capacity(levels + 1);
+    for r in 0..=levels {
+        let level = (levels - r) as u32;
+        let scale = 1i64 << level;
+        let (x0, y0, x1, y1) = (ceil_div(tcx0, scale), ceil_div(tcy0, scale), ceil_div(tcx1, scale), ceil_div(tcy1, scale));
+        let (ppx, ppy) = coding.precincts.get(r).copied().unwrap_or((15, 15));
+        if r > 0 && (ppx == 0 || ppy == 0) {
+            return Err("JPEG 2000 precinct size 1 is only allowed at the lowest resolution".to_string());
+        }
+        let kinds: &[usize] = if r == 0 { &[0] } else { &[1, 2, 3] };
+        let mut bands = Vec::with_capacity(kinds.len());
+        for &kind in kinds {
+            let (xob, yob) = ((kind & 1) as i64, (kind >> 1) as i64);
+            let nb = if r == 0 { level } else { level + 1 };
+            let (bx0, by0, bx1, by1) = if r == 0 {
+                (x0, y0, x1, y1)
+            } else {
+                let (offset, band_scale) = (1i64 << (nb - 1), 1i64 << nb);
+                (ceil_div(tcx0 - xob * offset, band_scale), ceil_div(tcy0 - yob * offset, band_scale),
+                 ceil_div(tcx1 - xob * offset, band_scale), ceil_div(tcy1 - yob * offset, band_scale))
+            };
+            let band_index = if r == 0 { 0 } else { 3 * (r - 1) + kind };
+            let (exponent, mantissa) = match quantization.style {
+                1 => {
+                    let (exponent, mantissa) = *quantization.steps.first().ok_or("JPEG 2000 QCD has no step")?;
+                    ((exponent as i64 - levels as i64 + nb as i64).max(0) as u32, mantissa)
+                }
+                _ => *quantization.steps.get(band_index).ok_or("JPEG 2000 QCD has too few steps")?,
+            };
+            let gain = [0, 1, 1, 2][kind];
+            let planes = (quantization.guard + exponent).saturating_sub(1);
+            if planes > 30 {
+                return Err(format!("JPEG 2000 band has {} bit-planes", planes));
+            }
+            let step = if coding.reversible {
+                1.0
+            } else {
+                2f64.powi(depth as i32 + gain - exponent as i32) * (1.0 + mantissa as f64 / 2048.0)
+            };
+            bands.push(Band { kind, x0: bx0, y0: by0, x1: bx1, y1: by1, planes, step, blocks: Vec::new() });
+        }
+
+        let (precincts_wide, precincts_high) = if x1 > x0 && y1 > y0 {
+            (ceil_div(x1, 1 << ppx) - (x0 >> ppx), ceil_div(y1, 1 << ppy) - (y0 >> ppy))
+        } else {
+            (0, 0)
+        };
+        let (band_ppx, band_ppy) = if r == 0 { (ppx, ppy) } else { (ppx - 1, ppy - 1) };
+        let (xcb, ycb) = (coding.xcb.min(band_ppx), coding.ycb.min(band_ppy));
+        let mut precincts = Vec::with_capacity((precincts_wide * precincts_high) as usize);
+        for j in 0..precincts_high {
+            for i in 0..precincts_wide {
+                let px0 = ((x0 >> ppx) + i) << band_ppx;
+                let py0 = ((y0 >> ppy) + j) << band_ppy;
+                let mut precinct = Vec::with_capacity(bands.len());
+                for band in &mut bands {
+                    let (rx0, ry0) = (px0.max(band.x0), py0.max(band.y0));
+                    let (rx1, ry1) = ((px0 + (1 << band_ppx)).min(band.x1), (py0 + (1 << band_ppy)).min(band.y1));
+                    let (mut wide, mut high) = (0, 0);
+                    let mut blocks = Vec::new();
+                    if rx1 > rx0 && ry1 > ry0 {
+                        let (cx0, cy0) = (rx0 >> xcb, ry0 >> ycb);
+                        let (cx1, cy1) = (ceil_div(rx1, 1 << xcb), ceil_div(ry1, 1 << ycb));
+                        (wide, high) = ((cx1 - cx0) as usize, (cy1 - cy0) as usize);
+                        for cy in cy0..cy1 {
+                            for cx in cx0..cx1 {
+                                blocks.push(band.blocks.len());
+                                band.blocks.push(Block {
+                                    x0: (cx << xcb).max(band.x0),
+                                    y0: (cy << ycb).max(band.y0),
+                                    x1: ((cx + 1) << xcb).min(band.x1),
+                                    y1: ((cy + 1) << ycb).min(band.y1),
+                                    included: false,
+                                    lblock: 3,
+                                    zero_planes: 0,
+                                    passes: 0,
+                                    data: Vec::new(),
+                                });
+                            }
+                        }
+                    }
+                    precinct.push(PrecinctBand { blocks, inclusion: TagTree::new(wide, high), zero_planes: TagTree::new(wide, high) });
+                }
+                precincts.push(precinct);
+            }
+        }
+        resolutions.push(Resolution { x0, y0, x1, y1, ppx, ppy, precincts_wide, bands, precincts });
+    }
+    Ok(resolutions)
+}
+
+/// Порядок пакетов тайла: слой, уровень разрешения, компонента, участок (T.800 B.12)
+fn packet_order(order: &Order, components: &[TileComponent], tile: (i64, i64, i64, i64))
+                -> Result<Vec<(usize, usize, usize, usize)>, String> {
+    let mut packets = Vec::new();
+    let layers = order.layers;
+    let max_levels = components.iter().map(|component| component.coding.levels).max().unwrap_or(0);
+    let precincts = |component: usize, r: usize| components[component].resolutions.get(r).map_or(0, |resolution| resolution.precincts.len());
+    match order.progression {
+        0 => for l in 0..layers {
+            for r in 0..=max_levels {
+                for c in 0..components.len() {
+                    packets.extend((0..precincts(c, r)).map(|k| (l, r, c, k)));
+                }
+            }
+        },
+        1 => for r in 0..=max_levels {
+            for l in 0..layers {
+                for c in 0..components.len() {
+                    packets.extend((0..precincts(c, r)).map(|k| (l, r, c, k)));
+                }
+            }
+        },
+        2..=4 => {
+            let (tx0, ty0, tx1, ty1) = tile;
+            let mut step_x = i64::MAX;
+            let mut step_y = i64::MAX;
+            for component in components {
+                for (r, resolution) in component.resolutions.iter().enumerate() {
+                    let level = (component.coding.levels - r) as u32;
+                    step_x = step_x.min(1 << (resolution.ppx + level));
+                    step_y = step_y.min(1 << (resolution.ppy + level));
+                }
+            }
+            let mut positions = Vec::new();
+            let mut y = ty0;
+            while y < ty1 {
+                let mut x = tx0;
+                while x < tx1 {
+                    positions.push((x, y));
+                    x += step_x - x % step_x;
+                }
+                y += step_y - y % step_y;
+            }
+            // Участок уровня r компоненты c, начинающийся в точке (x, y) опорной сетки
+            let precinct_at = |c: usize, r: usize, x: i64, y: i64| -> Option<usize> {
+                let component = &components[c];
+                let resolution = component.resolutions.get(r)?;
+                if resolution.is_empty() {
+                    return None;
+                }
+                let level = (component.coding.levels - r) as u32;
+                let (rpx, rpy) = (resolution.ppx + level, resolution.ppy + level);
+                let aligned_y = y % (1 << rpy) == 0 || (y == ty0 && (resolution.y0 << level) % (1 << rpy) != 0);
+                let aligned_x = x % (1 << rpx) == 0 || (x == tx0 && (resolution.x0 << level) % (1 << rpx) != 0);
+                if !aligned_x || !aligned_y {
+                    return None;
+                }
+                let i = (ceil_div(x, 1 << level) >> resolution.ppx) - (resolution.x0 >> resolution.ppx);
+                let j = (ceil_div(y, 1 << level) >> resolution.ppy) - (resolution.y0 >> resolution.ppy);
+                Some((i + j * resolution.precincts_wide) as usize)
+            };
+            let mut push = |c: usize, r: usize, x: i64, y: i64| {
+                if let Some(k) = precinct_at(c, r, x, y) {
+                    packets.extend((0..layers).map(|l| (l, r, c, k)));
+                }
+            };
+            match order.progression {
+                2 => for r in 0..=max_levels {
+                    for &(x, y) in &positions {
+                        for c in 0..components.len() {
+                            push(c, r, x, y);
+                        }
+                    }
+                },
+                3 => for &(x, y) in &positions {
+                    for (c, component) in components.iter().enumerate() {
+                        for r in 0..=component.coding.levels {
+                            push(c, r, x, y);
+                        }
+                    }
+                },
+                _ => for (c, component) in components.iter().enumerate() {
+                    for &(x, y) in &positions {
+                        for r in 0..=component.coding.levels {
+                            push(c, r, x, y);
+                        }
+                    }
+                },
+            }
+        }
+        progression => return Err(format!("JPEG 2000 progression order {} is not supported", progression)),
+    }
+    Ok(packets)
+}
+
+/// Читает пакет (T.800 B.10): заголовок с деревьями меток, затем данные кодовых блоков.
+/// Возвращает позицию следующего пакета
+fn read_packet(data: &[u8], mut position: usize, resolution: &mut Resolution, precinct: usize, layer: usize,
+               order: &Order) -> Result<usize, String> {
+    if order.sop && data.get(position..position + 2) == Some(&[0xFF, 0x91]) {
+        position += 6;
+    }
+    let Resolution { bands, precincts, .. } = resolution;
+    let mut bits = HeaderBits::new(data, position);
+    let mut included = Vec::new();
+    if bits.bit()? == 1 {
+        for (band_index, precinct_band) in precincts[precinct].iter_mut().enumerate() {
+            let wide = precinct_band.inclusion.width.max(1);
+            for (place, &block_index) in precinct_band.blocks.iter().enumerate() {
+                let (x, y) = (place % wide, place / wide);
+                let block = &mut bands[band_index].blocks[block_index];
+                let first = !block.included;
+                let include = if first {
+                    precinct_band.inclusion.decode(&mut bits, x, y, layer as i32 + 1)?
+                } else {
+                    bits.bit()? == 1
+                };
+                if !include {
+                    continue;
+                }
+                if first {
+                    let mut threshold = 1;
+                    while !precinct_band.zero_planes.decode(&mut bits, x, y, threshold)? {
+                        threshold += 1;
+                        if threshold > 64 {
+                            return Err("JPEG 2000 zero bit-plane count is out of range".to_string());
+                        }
+                    }
+                    block.zero_planes = precinct_band.zero_planes.value(x, y) as u32;
+                    block.included = true;
+                }
+                let passes = read_passes(&mut bits)?;
+                while bits.bit()? == 1 {
+                    block.lblock += 1;
+                }
+                let length_bits = block.lblock + (31 - passes.leading_zeros());
+                if length_bits > 32 {
+                    return Err("JPEG 2000 code-block length is out of range".to_string());
+                }
+                included.push((band_index, block_index, passes, bits.bits(length_bits)? as usize));
+            }
+        }
+    }
+    position = bits.align();
+    if order.eph && data.get(position..position + 2) == Some(&[0xFF, 0x92]) {
+        position += 2;
+    }
+    for (band, block, passes, length) in included {
+        let body = data.get(position..position + length).ok_or("JPEG 2000 packet body is out of the tile")?;
+        let block = &mut bands[band].blocks[block];
+        block.data.extend_from_slice(body);
+        block.passes += passes;
+        position += length;
+    }
+    Ok(position)
+}
+
+/// Число проходов кодирования в пакете (T.800 табл. B.4)
+fn read_passes(bits: &mut HeaderBits) -> Result<u32, String> {
+    if bits.bit()? == 0 {
+        return Ok(1);
+    }
+    if bits.bit()? == 0 {
+        return Ok(2);
+    }
+    let value = bits.bits(2)?;
+    if value < 3 {
+        return Ok(3 + value);
+    }
+    let value = bits.bits(5)?;
+    if value < 31 {
+        return Ok(6 + value);
+    }
+    Ok(37 + bits.bits(7)?)
+}
+
+/// Распаковывает кодовые блоки, восстанавливает коэффициенты и выполняет обратное вейвлет-преобразование.
+/// Возвращает отсчеты компоненты тайла до сдвига уровня
+fn reconstruct(component: &TileComponent) -> Result<Vec<f64>, String> {
+    let coding = &component.coding;
+    let mut previous: Option<(Vec<f64>, &Resolution)> = None;
+    for (r, resolution) in component.resolutions.iter().enumerate() {
+        let mut bands = Vec::with_capacity(resolution.bands.len());
+        for band in &resolution.bands {
+            bands.push(band_coefficients(band, coding)?);
+        }
+        let (width, height) = ((resolution.x1 - resolution.x0).max(0) as usize, (resolution.y1 - resolution.y0).max(0) as usize);
+        let samples = if r == 0 {
+            bands.remove(0)
+        } else {
+            let (low, low_resolution) = previous.take().unwrap();
+            let low_width = (low_resolution.x1 - low_resolution.x0).max(0) as usize;
+            let mut samples = vec![0.0; width * height];
+            for v in resolution.y0..resolution.y1 {
+                for u in resolution.x0..resolution.x1 {
+                    let value = match (u & 1, v & 1) {
+                        (0, 0) => low[(v / 2 - low_resolution.y0) as usize * low_width + (u / 2 - low_resolution.x0) as usize],
+                        (x, y) => {
+                            let kind = (x + 2 * y) as usize;
+                            let band = &resolution.bands[kind - 1];
+                            let band_width = (band.x1 - band.x0) as usize;
+                            bands[kind - 1][(v / 2 - band.y0) as usize * band_width + (u / 2 - band.x0) as usize]
+                        }
+                    };
+                    samples[(v - resolution.y0) as usize * width + (u - resolution.x0) as usize] = value;
+                }
+            }
+            for row in samples.chunks_mut(width.max(1)) {
+                inverse_1d(row, resolution.x0, coding.reversible);
+            }
+            let mut column = vec![0.0; height];
+            for x in 0..width {
+                for (y, value) in column.iter_mut().enumerate() {
+                    *value = samples[y * width + x];
+                }
+                inverse_1d(&mut column, resolution.y0, coding.reversible);
+                for (y, value) in column.iter().enumerate() {
+                    samples[y * width + x] = *value;
+                }
+            }
+            samples
+        };
+        previous = Some((samples, resolution));
+    }
+    Ok(previous.map(|(samples, _)| samples).unwrap_or_default())
+}
+
+/// Коэффициенты поддиапазона после распаковки кодовых блоков и деквантования
+fn band_coefficients(band: &Band, coding: &Coding) -> Result<Vec<f64>, String> {
+    let band_width = (band.x1 - band.x0).max(0) as usize;
+    let mut coefficients = vec![0.0; band_width * (band.y1 - band.y0).max(0) as usize];
+    for block in &band.blocks {
+        let planes = band.planes as i32 - block.zero_planes as i32;
+        if block.passes == 0 || planes <= 0 {
+            continue;
+        }
+        let (width, height) = ((block.x1 - block.x0) as usize, (block.y1 - block.y0) as usize);
+        let mut coder = BlockCoder::new(width, height, band.kind, coding.style, &[]);
+        let (lowest, significance_last) = coder.run(&mut MqDecoder::new(&block.data), planes, block.passes, coding.style);
+        for y in 0..height {
+            for x in 0..width {
+                let magnitude = coder.magnitudes[y * width + x];
+                if magnitude == 0 {
+                    continue;
+                }
+                let flags = coder.flags[coder.index(x, y)];
+                let negative = flags & NEGATIVE != 0;
+                // Если поток обрезан после прохода значимости, ранее значимые коэффициенты
+                // последнюю плоскость не получили (T.800 E.1.1.2: Nb для каждого коэффициента)
+                let lowest = if significance_last && flags & VISITED == 0 { lowest + 1 } else { lowest };
+                // Величины хранятся с одним дробным битом: для 9/7 восстанавливаем середину интервала
+                let value = if coding.reversible {
+                    (magnitude >> 1) as f64
+                } else {
+                    (magnitude + (1 << lowest)) as f64 / 2.0 * band.step
+                };
+                let offset = (block.y0 - band.y0) as usize + y;
+                coefficients[offset * band_width + (block.x0 - band.x0) as usize + x] = if negative { -value } else { value };
+            }
+        }
+    }
+    Ok(coefficients)
+}
+
+/// Отражение индекса за границы отрезка (симметричное продолжение, T.800 F.3.7)
+fn extended(signal: &[f64], index: i64) -> f64 {
+    let last = signal.len() as i64 - 1;
+    let mut index = index;
+    while index < 0 || index > last {
+        index = if index < 0 { -index } else { 2 * last - index };
+    }
+    signal[index as usize]
+}
+
+/// Отступ продолженного сигнала: на шаги лифтинга 9/7 хватает четырех отсчетов
+const EXTENSION: usize = 5;
+
+/// Обратное одномерное преобразование отрезка с началом `start` в опорной сетке (T.800 F.3.8)
+fn inverse_1d(signal: &mut [f64], start: i64, reversible: bool) {
+    if signal.len() == 1 {
+        if start % 2 != 0 {
+            signal[0] /= 2.0;
+        }
+        return;
+    }
+    if signal.is_empty() {
+        return;
+    }
+    let mut buffer: Vec<f64> = (0..signal.len() + 2 * EXTENSION)
+        .map(|index| extended(signal, index as i64 - EXTENSION as i64))
+        .collect();
+    let even = |index: usize| (start + index as i64 - EXTENSION as i64) % 2 == 0;
+    let lift = |buffer: &mut Vec<f64>, low: bool, step: &dyn Fn(f64, f64, f64) -> f64| {
+        for index in 1..buffer.len() - 1 {
+            if even(index) == low {
+                buffer[index] = step(buffer[index], buffer[index - 1], buffer[index + 1]);
+            }
+        }
+    };
+    if reversible {
+        lift(&mut buffer, true, &|value, left, right| value - ((left + right + 2.0) / 4.0).floor());
+        lift(&mut buffer, false, &|value, left, right| value + ((left + right) / 2.0).floor());
+    } else {
+        for (index, value) in buffer.iter_mut().enumerate() {
+            *value *= if even(index) { K } else { 1.0 / K };
+        }
+        lift(&mut buffer, true, &|value, left, right| value - DELTA * (left + right));
+        lift(&mut buffer, false, &|value, left, right| value - GAMMA * (left + right));
+        lift(&mut buffer, true, &|value, left, right| value - BETA * (left + right));
+        lift(&mut buffer, false, &|value, left, right| value - ALPHA * (left + right));
+    }
+    signal.copy_from_slice(&buffer[EXTENSION..EXTENSION + signal.len()]);
+}
+
+
+/// Арифметический MQ-кодер: распаковщик работает при чтении, упаковщик есть в тестах
+trait MqCoder {
+    /// Декодирует решение в контексте; упаковщик кодирует `bit` и возвращает его же
+    fn code(&mut self, context: usize, bit: u32) -> u32;
+    fn reset(&mut self);
+}
+
+/// Начальные состояния контекстов (T.800 табл. D.7): серия — 3, равномерный — 46, значимость без соседей — 4
+fn initial_states() -> [(u8, u8); CONTEXTS] {
+    let mut states = [(0u8, 0u8); CONTEXTS];
+    states[0] = (4, 0);
+    states[CONTEXT_RUN] = (3, 0);
+    states[CONTEXT_UNIFORM] = (46, 0);
+    states
+}
+
+struct MqDecoder<'a> {
+    data: &'a [u8],
+    position: usize,
+    a: u32,
+    c: u32,
+    count: u32,
+    states: [(u8, u8); CONTEXTS],
+}
+
+impl<'a> MqDecoder<'a> {
+    fn new(data: &'a [u8]) -> MqDecoder<'a> {
+        let mut decoder = MqDecoder { data, position: 0, a: 0x8000, c: 0, count: 0, states: initial_states() };
+        decoder.c = decoder.byte(0) << 16;
+        decoder.byte_in();
+        decoder.c <<= 7;
+        decoder.count -= 7;
+        decoder
+    }
+
+    /// За концом сегмента читаются 0xFF
+    fn byte(&self, index: usize) -> u32 {
+        self.data.get(index).map_or(0xFF, |byte| *byte as u32)
+    }
+
+    fn byte_in(&mut self) {
+        if self.byte(self.position) == 0xFF {
+            if self.byte(self.position + 1) > 0x8F {
+                self.c += 0xFF00;
+                self.count = 8;
+            } else {
+                self.position += 1;
+                self.c += self.byte(self.position) << 9;
+                self.count = 7;
+            }
+        } else {
+            self.position += 1;
+            self.c += self.byte(self.position) << 8;
+            self.count = 8;
+        }
+    }
+
+    fn renormalize(&mut self) {
+        loop {
+            if self.count == 0 {
+                self.byte_in();
+            }
+            self.a <<= 1;
+            self.c <<= 1;
+            self.count -= 1;
+            if self.a & 0x8000 != 0 {
+                break;
+            }
+        }
+    }
+}
+
+impl MqCoder for MqDecoder<'_> {
+    fn code(&mut self, context: usize, _bit: u32) -> u32 {
+        let (state, mps) = self.states[context];
+        let (qe, next_mps, next_lps, switch) = MQ_STATES[state as usize];
+        let lps = |states: &mut [(u8, u8); CONTEXTS]| {
+            states[context] = (next_lps, if switch == 1 { 1 - mps } else { mps });
+            1 - mps as u32
+        };
+        self.a -= qe;
+        let decision;
+        if (self.c >> 16) < qe {
+            if self.a < qe {
+                self.states[context] = (next_mps, mps);
+                decision = mps as u32;
+            } else {
+                decision = lps(&mut self.states);
+            }
+            self.a = qe;
+            self.renormalize();
+        } else {
+            self.c -= qe << 16;
+            if self.a & 0x8000 == 0 {
+                if self.a < qe {
+                    decision = lps(&mut self.states);
+                } else {
+                    self.states[context] = (next_mps, mps);
+                    decision = mps as u32;
+                }
+                self.renormalize();
+            } else {
+                decision = mps as u32;
+            }
+        }
+        decision
+    }
+
+    fn reset(&mut self) {
+        self.states = initial_states();
+    }
+}
+
+
+/// Проходы EBCOT по битовым плоскостям кодового блока (T.800 D).
+/// Величины хранятся с одним дробным битом; `source` задан только при упаковке
+struct BlockCoder<'a> {
+    width: usize,
+    height: usize,
+    kind: usize,
+    causal: bool,
+    flags: Vec<u8>,
+    magnitudes: Vec<i32>,
+    source: &'a [i32],
+}
+
+impl<'a> BlockCoder<'a> {
+    fn new(width: usize, height: usize, kind: usize, style: u8, source: &'a [i32]) -> BlockCoder<'a> {
+        BlockCoder {
+            width,
+            height,
+            kind,
+            causal: style & STYLE_VERTICALLY_CAUSAL != 0,
+            flags: vec![0; (width + 2) * (height + 2)],
+            magnitudes: vec![0; width * height],
+            source,
+        }
+    }
+
+    fn index(&self, x: usize, y: usize) -> usize {
+        (y + 1) * (self.width + 2) + x + 1
+    }
+
+    /// Выполняет `passes` проходов начиная с очистки старшей плоскости; возвращает последнюю плоскость
+    /// и признак того, что последним был проход значимости
+    fn run(&mut self, coder: &mut impl MqCoder, planes: i32, passes: u32, style: u8) -> (i32, bool) {
+        let mut plane = planes - 1;
+        let mut kind = 2;
+        let mut lowest = plane;
+        let mut last_kind = kind;
+        for _ in 0..passes {
+            if plane < 0 {
+                break;
+            }
+            match kind {
+                0 => self.significance_pass(coder, plane),
+                1 => self.refinement_pass(coder, plane),
+                _ => {
+                    self.cleanup_pass(coder, plane);
+                    if style & STYLE_SEGMENTATION_SYMBOLS != 0 {
+                        for bit in [1, 0, 1, 0] {
+                            coder.code(CONTEXT_UNIFORM, bit);
+                        }
+                    }
+                }
+            }
+            lowest = plane;
+            last_kind = kind;
+            if style & STYLE_RESET != 0 {
+                coder.reset();
+            }
+            if kind == 2 {
+                plane -= 1;
+                kind = 0;
+            } else {
+                kind += 1;
+            }
+        }
+        (lowest.max(0), last_kind == 0)
+    }
+
+    /// Порядок обхода: полосы по 4 строки, внутри полосы — по столбцам
+    fn stripes(&self) -> impl Iterator<Item = (usize, usize, usize)> {
+        let (width, height) = (self.width, self.height);
+        (0..height).step_by(4).flat_map(move |y0| (0..width).map(move |x| (x, y0, (y0 + 4).min(height))))
+    }
+
+    /// Число значимых соседей по горизонтали, вертикали и диагонали
+    fn neighbours(&self, x: usize, y: usize) -> (u32, u32, u32) {
+        let (index, stride) = (self.index(x, y), self.width + 2);
+        let significant = |index: usize| (self.flags[index] & SIGNIFICANT) as u32;
+        let below = !(self.causal && y % 4 == 3);
+        let h = significant(index - 1) + significant(index + 1);
+        let mut v = significant(index - stride);
+        let mut d = significant(index - stride - 1) + significant(index - stride + 1);
+        if below {
+            v += significant(index + stride);
+            d += significant(index + stride - 1) + significant(index + stride + 1);
+        }
+        (h, v, d)
+    }
+
+    /// Контекст значимости по соседям и ориентации поддиапазона (T.800 табл. D.1)
+    fn zero_context(&self, x: usize, y: usize) -> usize {
+        let (h, v, d) = self.neighbours(x, y);
+        if self.kind == 3 {
+            return match (d, h + v) {
+                (0, 0) => 0,
+                (0, 1) => 1,
+                (0, _) => 2,
+                (1, 0) => 3,
+                (1, 1) => 4,
+                (1, _) => 5,
+                (2, 0) => 6,
+                (2, _) => 7,
+                _ => 8,
+            };
+        }
+        let (h, v) = if self.kind == 1 { (v, h) } else { (h, v) };
+        match (h, v, d) {
+            (0, 0, 0) => 0,
+            (0, 0, 1) => 1,
+            (0, 0, _) => 2,
+            (0, 1, _) => 3,
+            (0, _, _) => 4,
+            (1, 0, 0) => 5,
+            (1, 0, _) => 6,
+            (1, _, _) => 7,
+            _ => 8,
+        }
+    }
+
+    /// Контекст и инверсия знака (T.800 табл. D.3)
+    fn sign_context(&self, x: usize, y: usize) -> (usize, u32) {
+        let (index, stride) = (self.index(x, y), self.width + 2);
+        let contribution = |index: usize| match self.flags[index] {
+            flags if flags & SIGNIFICANT == 0 => 0,
+            flags if flags & NEGATIVE != 0 => -1,
+            _ => 1,
+        };
+        let below = if self.causal && y % 4 == 3 { 0 } else { contribution(index + stride) };
+        let h = (contribution(index - 1) + contribution(index + 1)).clamp(-1, 1);
+        let v = (contribution(index - stride) + below).clamp(-1, 1);
+        match (h, v) {
+            (1, 1) => (13, 0),
+            (1, 0) => (12, 0),
+            (1, _) => (11, 0),
+            (0, 1) => (10, 0),
+            (0, 0) => (9, 0),
+            (0, _) => (10, 1),
+            (_, 1) => (11, 1),
+            (_, 0) => (12, 1),
+            _ => (13, 1),
+        }
+    }
+
+    fn source_bit(&self, x: usize, y: usize, plane: i32) -> u32 {
+        self.source.get(y * self.width + x).map_or(0, |value| (value.unsigned_abs() >> plane) & 1)
+    }
+
+    /// Коэффициент стал значимым: кодируется знак, в величину записывается бит плоскости
+    fn become_significant(&mut self, coder: &mut impl MqCoder, x: usize, y: usize, plane: i32) {
+        let (context, inversion) = self.sign_context(x, y);
+        let source_sign = self.source.get(y * self.width + x).map_or(0, |value| (*value < 0) as u32);
+        let negative = coder.code(context, source_sign ^ inversion) ^ inversion;
+        let index = self.index(x, y);
+        self.flags[index] |= SIGNIFICANT | if negative == 1 { NEGATIVE } else { 0 };
+        self.magnitudes[y * self.width + x] |= 1 << (plane + 1);
+    }
+
+    fn significance_pass(&mut self, coder: &mut impl MqCoder, plane: i32) {
+        let stripes: Vec<_> = self.stripes().collect();
+        for (x, y0, end) in stripes {
+            for y in y0..end {
+                let index = self.index(x, y);
+                let (h, v, d) = self.neighbours(x, y);
+                if self.flags[index] & SIGNIFICANT != 0 || h + v + d == 0 {
+                    continue;
+                }
+                if coder.code(self.zero_context(x, y), self.source_bit(x, y, plane)) == 1 {
+                    self.become_significant(coder, x, y, plane);
+                }
+                self.flags[index] |= VISITED;
+            }
+        }
+    }
+
+    fn refinement_pass(&mut self, coder: &mut impl MqCoder, plane: i32) {
+        let stripes: Vec<_> = self.stripes().collect();
+        for (x, y0, end) in stripes {
+            for y in y0..end {
+                let index = self.index(x, y);
+                if self.flags[index] & (SIGNIFICANT | VISITED) != SIGNIFICANT {
+                    continue;
+                }
+                let (h, v, d) = self.neighbours(x, y);
+                let context = if self.flags[index] & REFINED != 0 { 16 } else if h + v + d > 0 { 15 } else { 14 };
+                let bit = coder.code(context, self.source_bit(x, y, plane));
+                self.magnitudes[y * self.width + x] |= (bit as i32) << (plane + 1);
+                self.flags[index] |= REFINED;
+            }
+        }
+    }
+
+    fn cleanup_pass(&mut self, coder: &mut impl MqCoder, plane: i32) {
+        let stripes: Vec<_> = self.stripes().collect();
+        for (x, y0, end) in stripes {
+            let mut start = y0;
+            let quiet = |coder: &Self, y: usize| {
+                let (h, v, d) = coder.neighbours(x, y);
+                coder.flags[coder.index(x, y)] & (SIGNIFICANT | VISITED) == 0 && h + v + d == 0
+            };
+            if end - y0 == 4 && (y0..end).all(|y| quiet(self, y)) {
+                let first = (y0..end).position(|y| self.source_bit(x, y, plane) == 1);
+                if coder.code(CONTEXT_RUN, first.is_some() as u32) == 0 {
+                    continue;
+                }
+                let first = first.unwrap_or(0) as u32;
+                let high = coder.code(CONTEXT_UNIFORM, first >> 1);
+                let run = (high << 1 | coder.code(CONTEXT_UNIFORM, first & 1)) as usize;
+                self.become_significant(coder, x, y0 + run, plane);
+                start = y0 + run + 1;
+            }
+            for y in start..end {
+                let index = self.index(x, y);
+                if self.flags[index] & (SIGNIFICANT | VISITED) != 0 {
+                    continue;
+                }
+                if coder.code(self.zero_context(x, y), self.source_bit(x, y, plane)) == 1 {
+                    self.become_significant(coder, x, y, plane);
+                }
+            }
+        }
+        for flags in &mut self.flags {
+            *flags &= !VISITED;
+        }
+    }
+}
+
+
+/// Дерево меток (T.800 B.10.2): узлы уровней от листьев к корню
+struct TagTree {
+    width: usize,
+    levels: Vec<(usize, usize, usize)>,
+    values: Vec<i32>,
+    lows: Vec<i32>,
+}
+
+impl TagTree {
+    fn new(width: usize, height: usize) -> TagTree {
+        let mut levels = Vec::new();
+        let (mut w, mut h, mut offset) = (width, height, 0);
+        if width > 0 && height > 0 {
+            loop {
+                levels.push((w, h, offset));
+                offset += w * h;
+                if w == 1 && h == 1 {
+                    break;
+                }
+                (w, h) = (w.div_ceil(2), h.div_ceil(2));
+            }
+        }
+        TagTree { width, levels, values: vec![i32::MAX; offset], lows: vec![0; offset] }
+    }
+
+    /// Узлы от корня к листу (x, y)
+    fn path(&self, x: usize, y: usize) -> Vec<usize> {
+        self.levels.iter().enumerate().rev()
+            .map(|(level, (w, _, offset))| offset + (y >> level) * w + (x >> level))
+            .collect()
+    }
+
+    fn value(&self, x: usize, y: usize) -> i32 {
+        self.values[self.levels[0].2 + y * self.levels[0].0 + x]
+    }
+
+    /// Читает биты, пока не выяснится, меньше ли значение листа порога
+    fn decode(&mut self, bits: &mut HeaderBits, x: usize, y: usize, threshold: i32) -> Result<bool, String> {
+        let mut low = 0;
+        let mut leaf = 0;
+        for node in self.path(x, y) {
+            if low > self.lows[node] {
+                self.lows[node] = low;
+            } else {
+                low = self.lows[node];
+            }
+            while low < threshold && low < self.values[node] {
+                if bits.bit()? == 1 {
+                    self.values[node] = low;
+                } else {
+                    low += 1;
+                }
+            }
+            self.lows[node] = low;
+            leaf = node;
+        }
+        Ok(self.values[leaf] < threshold)
+    }
+}
+
+/// Чтение битов заголовка пакета: после 0xFF старший бит следующего байта пропускается
+struct HeaderBits<'a> {
+    data: &'a [u8],
+    position: usize,
+    byte: u8,
+    count: u32,
+}
+
+impl<'a> HeaderBits<'a> {
+    fn new(data: &'a [u8], position: usize) -> HeaderBits<'a> {
+        HeaderBits { data, position, byte: 0, count: 0 }
+    }
+
+    fn bit(&mut self) -> Result<u32, String> {
+        if self.count == 0 {
+            let stuffed = self.byte == 0xFF;
+            self.byte = *self.data.get(self.position).ok_or("JPEG 2000 packet header is out of the tile")?;
+            self.position += 1;
+            self.count = if stuffed { 7 } else { 8 };
+        }
+        self.count -= 1;
+        Ok((self.byte >> self.count) as u32 & 1)
+    }
+
+    fn bits(&mut self, length: u32) -> Result<u32, String> {
+        let mut value = 0u32;
+        for _ in 0..length {
+            value = (value << 1) | self.bit()?;
+        }
+        Ok(value)
+    }
+
+    /// Позиция после заголовка: за байтом 0xFF следует еще байт со вставленным битом
+    fn align(&self) -> usize {
+        if self.byte == 0xFF { self.position + 1 } else { self.position }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::work_pixels::tests::{synthetic_image, synthetic_rgb};
+
+    const LEVELS: usize = 3;
+    const GUARD: u32 = 2;
+    /// Кодовые блоки 16x16
+    const BLOCK: u32 = 4;
+
+    struct MqEncoder {
+        a: u32,
+        c: u32,
+        count: u32,
+        bytes: Vec<u8>,
+        states: [(u8, u8); CONTEXTS],
+    }
+
+    impl MqEncoder {
+        fn new() -> MqEncoder {
+            // Первый байт — вспомогательный, в результат не попадает
+            MqEncoder { a: 0x8000, c: 0, count: 12, bytes: vec![0], states: initial_states() }
+        }
+
+        fn byte_out(&mut self) {
+            let last = self.bytes.len() - 1;
+            if self.bytes[last] == 0xFF {
+                self.bytes.push((self.c >> 20) as u8);
+                self.c &= 0xFFFFF;
+                self.count = 7;
+            } else if self.c < 0x8000000 {
+                self.bytes.push((self.c >> 19) as u8);
+                self.c &= 0x7FFFF;
+                self.count = 8;
+            } else {
+                self.bytes[last] += 1;
+                if self.bytes[last] == 0xFF {
+                    self.c &= 0x7FFFFFF;
+                    self.bytes.push((self.c >> 20) as u8);
+                    self.c &= 0xFFFFF;
+                    self.count = 7;
+                } else {
+                    self.bytes.push((self.c >> 19) as u8);
+                    self.c &= 0x7FFFF;
+                    self.count = 8;
+                }
+            }
+        }
+
+        fn renormalize(&mut self) {
+            loop {
+                self.a <<= 1;
+                self.c <<= 1;
+                self.count -= 1;
+                if self.count == 0 {
+                    self.byte_out();
+                }
+                if self.a & 0x8000 != 0 {
+                    break;
+                }
+            }
+        }
+
+        fn finish(mut self) -> Vec<u8> {
+            let bound = self.c + self.a;
+            self.c |= 0xFFFF;
+            if self.c >= bound {
+                self.c -= 0x8000;
+            }
+            self.c <<= self.count;
+            self.byte_out();
+            self.c <<= self.count;
+            self.byte_out();
+            if self.bytes.last() == Some(&0xFF) {
+                self.bytes.pop();
+            }
+            self.bytes.remove(0);
+            self.bytes
+        }
+    }
+
+    impl MqCoder for MqEncoder {
+        fn code(&mut self, context: usize, bit: u32) -> u32 {
+            let (state, mps) = self.states[context];
+            let (qe, next_mps, next_lps, switch) = MQ_STATES[state as usize];
+            self.a -= qe;
+            if bit == mps as u32 {
+                if self.a & 0x8000 == 0 {
+                    if self.a < qe {
+                        self.a = qe;
+                    } else {
+                        self.c += qe;
+                    }
+                    self.states[context] = (next_mps, mps);
+                    self.renormalize();
+                } else {
+                    self.c += qe;
+                }
+            } else {
+                if self.a < qe {
+                    self.c += qe;
+                } else {
+                    self.a = qe;
+                }
+                self.states[context] = (next_lps, if switch == 1 { 1 - mps } else { mps });
+                self.renormalize();
+            }
+            bit
+        }
+
+        fn reset(&mut self) {
+            self.states = initial_states();
+        }
+    }
+
+    /// Запись битов заголовка пакета со вставкой бита после 0xFF
+    #[derive(Default)]
+    struct HeaderWriter {
+        bytes: Vec<u8>,
+        current: u32,
+        count: u32,
+    }
+
+    impl HeaderWriter {
+        fn put(&mut self, value: u32, length: u32) {
+            for bit in (0..length).rev() {
+                self.current = (self.current << 1) | ((value >> bit) & 1);
+                self.count += 1;
+                let capacity = if self.bytes.last() == Some(&0xFF) { 7 } else { 8 };
+                if self.count == capacity {
+                    self.bytes.push(self.current as u8);
+                    self.current = 0;
+                    self.count = 0;
+                }
+            }
+        }
+
+        fn finish(mut self) -> Vec<u8> {
+            while self.count > 0 {
+                self.put(0, 1);
+            }
+            if self.bytes.last() == Some(&0xFF) {
+                self.bytes.push(0);
+            }
+            self.bytes
+        }
+    }
+
+    /// Дерево меток с известными значениями листьев для упаковки
+    fn tag_tree(width: usize, height: usize, leaves: &[i32]) -> TagTree {
+        let mut tree = TagTree::new(width, height);
+        for (index, value) in leaves.iter().enumerate() {
+            let path = tree.path(index % width, index / width);
+            for node in path {
+                tree.values[node] = tree.values[node].min(*value);
+            }
+        }
+        tree
+    }
+
+    fn encode_tag(tree: &mut TagTree, known: &mut [bool], writer: &mut HeaderWriter, x: usize, y: usize, threshold: i32) {
+        let mut low = 0;
+        for node in tree.path(x, y) {
+            if low > tree.lows[node] {
+                tree.lows[node] = low;
+            } else {
+                low = tree.lows[node];
+            }
+            while low < threshold {
+                if low >= tree.values[node] {
+                    if !known[node] {
+                        writer.put(1, 1);
+                        known[node] = true;
+                    }
+                    break;
+                }
+                writer.put(0, 1);
+                low += 1;
+            }
+            tree.lows[node] = low;
+        }
+    }
+
+    fn forward_1d(signal: &mut [f64], reversible: bool) {
+        if signal.len() < 2 {
+            return;
+        }
+        let mut buffer: Vec<f64> = (0..signal.len() + 2 * EXTENSION)
+            .map(|index| extended(signal, index as i64 - EXTENSION as i64))
+            .collect();
+        let even = |index: usize| (index + EXTENSION).is_multiple_of(2);
+        let lift = |buffer: &mut Vec<f64>, low: bool, step: &dyn Fn(f64, f64, f64) -> f64| {
+            for index in 1..buffer.len() - 1 {
+                if even(index) == low {
+                    buffer[index] = step(buffer[index], buffer[index - 1], buffer[index + 1]);
+                }
+            }
+        };
+        if reversible {
+            lift(&mut buffer, false, &|value, left, right| value - ((left + right) / 2.0).floor());
+            lift(&mut buffer, true, &|value, left, right| value + ((left + right + 2.0) / 4.0).floor());
+        } else {
+            lift(&mut buffer, false, &|value, left, right| value + ALPHA * (left + right));
+            lift(&mut buffer, true, &|value, left, right| value + BETA * (left + right));
+            lift(&mut buffer, false, &|value, left, right| value + GAMMA * (left + right));
+            lift(&mut buffer, true, &|value, left, right| value + DELTA * (left + right));
+            for (index, value) in buffer.iter_mut().enumerate() {
+                *value *= if even(index) { 1.0 / K } else { K };
+            }
+        }
+        signal.copy_from_slice(&buffer[EXTENSION..EXTENSION + signal.len()]);
+    }
+
+    /// Поддиапазоны по порядку LL, затем HL, LH, HH от младшего уровня к старшему: (вид, ширина, высота, коэффициенты)
+    fn forward_2d(samples: &[f64], width: usize, height: usize, reversible: bool) -> Vec<(usize, usize, usize, Vec<f64>)> {
+        let mut current = samples.to_vec();
+        let (mut w, mut h) = (width, height);
+        let mut levels = Vec::new();
+        for _ in 0..LEVELS {
+            let mut column = vec![0.0; h];
+            for x in 0..w {
+                for (y, value) in column.iter_mut().enumerate() {
+                    *value = current[y * w + x];
+                }
+                forward_1d(&mut column, reversible);
+                for (y, value) in column.iter().enumerate() {
+                    current[y * w + x] = *value;
+                }
+            }
+            for row in current.chunks_mut(w) {
+                forward_1d(row, reversible);
+            }
+            let mut bands: Vec<(usize, usize, usize, Vec<f64>)> = Vec::new();
+            for kind in 0..4 {
+                let (xob, yob) = (kind & 1, kind >> 1);
+                let band_width = (w + 1 - xob) / 2;
+                let band_height = (h + 1 - yob) / 2;
+                let values = (0..band_height)
+                    .flat_map(|y| (0..band_width).map(move |x| (x, y)))
+                    .map(|(x, y)| current[(2 * y + yob) * w + 2 * x + xob])
+                    .collect();
+                bands.push((kind, band_width, band_height, values));
+            }
+            let low = bands.remove(0);
+            levels.push(bands);
+            (w, h, current) = (low.1, low.2, low.3);
+        }
+        let mut result = vec![(0, w, h, current)];
+        for bands in levels.into_iter().rev() {
+            result.extend(bands);
+        }
+        result
+    }
+
+    /// Один тайл, один слой, LRCP, участки по умолчанию
+    fn encode(values: &[i32], format: &FrameFormat, reversible: bool) -> Vec<u8> {
+        let (width, height, spp) = (format.columns, format.rows, format.samples_per_pixel);
+        let depth = format.bits_stored as u32;
+        let shift = (1 << (depth - 1)) as f64;
+        let mut components: Vec<Vec<f64>> = (0..spp)
+            .map(|c| values.iter().skip(c).step_by(spp).map(|value| *value as f64 - shift).collect())
+            .collect();
+        let mct = spp == 3;
+        if mct {
+            for index in 0..width * height {
+                let (r, g, b) = (components[0][index], components[1][index], components[2][index]);
+                let transformed = if reversible {
+                    [((r + 2.0 * g + b) / 4.0).floor(), b - g, r - g]
+                } else {
+                    [0.299 * r + 0.587 * g + 0.114 * b,
+                     -0.168736 * r - 0.331264 * g + 0.5 * b,
+                     0.5 * r - 0.418688 * g - 0.081312 * b]
+                };
+                for (component, value) in components.iter_mut().zip(transformed) {
+                    component[index] = value;
+                }
+            }
+        }
+        let gains = [0, 1, 1, 2];
+        let exponent = |kind: usize| depth + gains[kind] + if mct && reversible { 1 } else { 0 };
+
+        // Пакеты по уровням разрешения и компонентам
+        let bands: Vec<_> = components.iter().map(|samples| forward_2d(samples, width, height, reversible)).collect();
+        let mut packets = Vec::new();
+        for r in 0..=LEVELS {
+            for component in &bands {
+                let range = if r == 0 { 0..1 } else { 3 * r - 2..3 * r + 1 };
+                let mut writer = HeaderWriter::default();
+                let mut body = Vec::new();
+                writer.put(1, 1);
+                for (kind, band_width, band_height, coefficients) in &component[range] {
+                    let planes = (GUARD + exponent(*kind) - 1) as i32;
+                    let quantized: Vec<i32> = coefficients.iter()
+                        .map(|value| if reversible { *value as i32 } else { value.signum() as i32 * value.abs().floor() as i32 })
+                        .collect();
+                    let size = 1usize << BLOCK;
+                    let (wide, high) = (band_width.div_ceil(size), band_height.div_ceil(size));
+                    let mut blocks = Vec::new();
+                    for by in 0..high {
+                        for bx in 0..wide {
+                            let (x0, y0) = (bx * size, by * size);
+                            let (w, h) = (size.min(band_width - x0), size.min(band_height - y0));
+                            let source: Vec<i32> = (0..h).flat_map(|y| (0..w).map(move |x| (x, y)))
+                                .map(|(x, y)| quantized[(y0 + y) * band_width + x0 + x])
+                                .collect();
+                            let bits = 32 - source.iter().map(|value| value.unsigned_abs()).max().unwrap_or(0).leading_zeros() as i32;
+                            if bits == 0 {
+                                blocks.push(None);
+                                continue;
+                            }
+                            let passes = 3 * bits as u32 - 2;
+                            let mut encoder = MqEncoder::new();
+                            BlockCoder::new(w, h, *kind, 0, &source).run(&mut encoder, bits, passes, 0);
+                            blocks.push(Some((planes - bits, passes, encoder.finish())));
+                        }
+                    }
+                    let inclusion: Vec<i32> = blocks.iter().map(|block| block.is_none() as i32).collect();
+                    let zero_planes: Vec<i32> = blocks.iter().map(|block| block.as_ref().map_or(planes, |block| block.0)).collect();
+                    let mut inclusion = tag_tree(wide, high, &inclusion);
+                    let mut inclusion_known = vec![false; inclusion.values.len()];
+                    let mut zero_planes = tag_tree(wide, high, &zero_planes);
+                    let mut zero_known = vec![false; zero_planes.values.len()];
+                    for (index, block) in blocks.iter().enumerate() {
+                        let (x, y) = (index % wide, index / wide);
+                        encode_tag(&mut inclusion, &mut inclusion_known, &mut writer, x, y, 1);
+                        let Some((zero, passes, data)) = block else { continue };
+                        encode_tag(&mut zero_planes, &mut zero_known, &mut writer, x, y, zero + 1);
+                        match passes {
+                            1 => writer.put(0, 1),
+                            2 => writer.put(0b10, 2),
+                            3..=5 => writer.put(0b1100 | (passes - 3), 4),
+                            6..=36 => writer.put((0b1111 << 5) | (passes - 6), 9),
+                            _ => writer.put((0b1_1111_1111 << 7) | (passes - 37), 16),
+                        }
+                        let length = data.len() as u32;
+                        let mut lblock = 3;
+                        let extra = 31 - passes.leading_zeros();
+                        while 32 - length.leading_zeros() > lblock + extra {
+                            writer.put(1, 1);
+                            lblock += 1;
+                        }
+                        writer.put(0, 1);
+                        writer.put(length, lblock + extra);
+                        body.extend_from_slice(data);
+                    }
+                }
+                packets.extend(writer.finish());
+                packets.extend(body);
+            }
+        }
+
+        let mut out = vec![0xFF, 0x4F, 0xFF, 0x51];
+        out.extend((38 + 3 * spp as u16).to_be_bytes());
+        out.extend([0, 0]);
+        for value in [width, height, 0, 0, width, height, 0, 0] {
+            out.extend((value as u32).to_be_bytes());
+        }
+        out.extend((spp as u16).to_be_bytes());
+        for _ in 0..spp {
+            out.extend([depth as u8 - 1, 1, 1]);
+        }
+        out.extend([0xFF, 0x52, 0, 12, 0, 0, 0, 1, mct as u8, LEVELS as u8, BLOCK as u8 - 2, BLOCK as u8 - 2, 0, reversible as u8]);
+        let count = 3 * LEVELS + 1;
+        let kinds = (0..count).map(|band| if band == 0 { 0 } else { (band - 1) % 3 + 1 });
+        if reversible {
+            out.extend([0xFF, 0x5C]);
+            out.extend((3 + count as u16).to_be_bytes());
+            out.push((GUARD << 5) as u8);
+            out.extend(kinds.map(|kind| (exponent(kind) << 3) as u8));
+        } else {
+            out.extend([0xFF, 0x5C]);
+            out.extend((3 + 2 * count as u16).to_be_bytes());
+            out.push((GUARD << 5) as u8 | 2);
+            out.extend(kinds.flat_map(|kind| ((exponent(kind) << 11) as u16).to_be_bytes()));
+        }
+        out.extend([0xFF, 0x90, 0, 10, 0, 0]);
+        out.extend((14 + packets.len() as u32).to_be_bytes());
+        out.extend([0, 1, 0xFF, 0x93]);
+        out.extend(packets);
+        out.extend([0xFF, 0xD9]);
+        out
+    }
+
+    #[test]
+    fn mq_coder_round_trip() {
+        let decisions: Vec<(usize, u32)> = (0..2000u32).map(|index| ((index % 7) as usize, ((index * 7919) % 13 < 3) as u32)).collect();
+        let mut encoder = MqEncoder::new();
+        for (context, bit) in &decisions {
+            encoder.code(*context, *bit);
+        }
+        let data = encoder.finish();
+        let mut decoder = MqDecoder::new(&data);
+        for (context, bit) in &decisions {
+            assert_eq!(decoder.code(*context, 0), *bit);
+        }
+    }
+
+    #[test]
+    fn reversible_grayscale_is_exact() {
+        let (values, format) = synthetic_image(16, 12);
+        let frame = encode(&values, &format, true);
+        let (decoded, transformed) = decode(&frame, &format).unwrap();
+        assert!(!transformed);
+        assert_eq!(decoded, values);
+    }
+
+    #[test]
+    fn reversible_color_is_exact() {
+        let (values, format) = synthetic_rgb();
+        let frame = encode(&values, &format, true);
+        let (decoded, transformed) = decode(&frame, &format).unwrap();
+        assert!(transformed);
+        assert_eq!(decoded, values);
+    }
+
+    #[test]
+    fn irreversible_grayscale_is_close() {
+        let (values, format) = synthetic_image(8, 8);
+        let frame = encode(&values, &format, false);
+        let (decoded, _) = decode(&frame, &format).unwrap();
+        let worst = decoded.iter().zip(&values).map(|(a, b)| (a - b).abs()).max().unwrap();
+        assert!(worst <= 2, "worst error {}", worst);
+    }
+
+    /// Кодовые потоки, сжатые OpenJPEG 2.5.3 (tests/data/gen_j2k.c)
+    #[test]
+    fn openjpeg_reversible_codestreams() {
+        let (values, format) = synthetic_image(16, 12);
+        let (decoded, transformed) = decode(include_bytes!("../tests/data/gray12_lossless.j2k"), &format).unwrap();
+        assert!(!transformed);
+        assert_eq!(decoded, values);
+        // значения со знаком возвращаются в дополнительном коде разрядности компоненты
+        let signed: Vec<i32> = values.iter().map(|value| (value - 2048) & 0xFFF).collect();
+        let (decoded, _) = decode(include_bytes!("../tests/data/gray12_signed_sop_eph.j2k"), &format).unwrap();
+        assert_eq!(decoded, signed);
+        let (values, format) = synthetic_rgb();
+        let (decoded, transformed) = decode(include_bytes!("../tests/data/rgb8_tiles_layers_rpcl.j2k"), &format).unwrap();
+        assert!(transformed);
+        assert_eq!(decoded, values);
+    }
+
+    #[test]
+    fn openjpeg_irreversible_codestream() {
+        let (_, format) = synthetic_image(8, 8);
+        let (decoded, _) = decode(include_bytes!("../tests/data/gray8_lossy.j2k"), &format).unwrap();
+        // OpenJPEG считает 9/7 в другой арифметике, поэтому допускается расхождение на единицу
+        let expected = include_bytes!("../tests/data/gray8_lossy.raw");
+        let worst = decoded.iter().zip(expected).map(|(a, b)| (a - *b as i32).abs()).max().unwrap();
+        assert!(worst <= 1, "worst difference {}", worst);
+    }
+
+    #[test]
+    fn codestream_inside_jp2_file() {
+        let (values, format) = synthetic_image(8, 8);
+        let stream = encode(&values, &format, true);
+        let mut file = vec![0, 0, 0, 12];
+        file.extend(b"jP  \r\n\x87\n");
+        file.extend((8 + stream.len() as u32).to_be_bytes());
+        file.extend(b"jp2c");
+        file.extend(&stream);
+        assert_eq!(decode(&file, &format).unwrap().0, values);
+        assert!(decode(&file[..8], &format).is_err());
+    }
+}
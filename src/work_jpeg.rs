@@ -0,0 +1,148 @@
+use jpeg_decoder::{ColorTransform, Decoder, PixelFormat};
+
+use crate::work_pixels::FrameFormat;
+
+
+/// Распаковывает кадр JPEG Baseline, Extended (8 бит) или Lossless.
+/// При `ybr` компоненты YCbCr переводятся в RGB, иначе возвращаются как есть.
+/// Возвращает значения по порядку строка, столбец, компонента
+pub fn decode(data: &[u8], format: &FrameFormat, ybr: bool) -> Result<Vec<i32>, String> {
+    let mut decoder = Decoder::new(data);
+    decoder.set_color_transform(if ybr { ColorTransform::YCbCr } else { ColorTransform::None });
+    let decoded = decoder.decode().map_err(|e| format!("JPEG: {}", e))?;
+    let info = decoder.info().ok_or("JPEG has no frame header")?;
+    if info.width as usize != format.columns || info.height as usize != format.rows {
+        return Err(format!("JPEG frame is {}x{}, expected {}x{}",
+                           info.width, info.height, format.columns, format.rows));
+    }
+    if info.pixel_format == PixelFormat::CMYK32 {
+        return Err("JPEG frames with 4 components are not supported".to_string());
+    }
+    // Lossless с точностью больше 8 бит отдает по 2 байта на значение в порядке платформы
+    match decoded.len() / format.samples().max(1) {
+        1 => Ok(decoded.iter().map(|value| *value as i32).collect()),
+        2 => Ok(decoded.chunks_exact(2).map(|chunk| u16::from_ne_bytes([chunk[0], chunk[1]]) as i32).collect()),
+        _ => Err(format!("JPEG frame holds {} bytes for {} values", decoded.len(), format.samples())),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::work_pixels::tests::{synthetic_image, synthetic_rgb};
+
+    /// Сжимает монохромный кадр в JPEG Lossless (SOF3) с предсказателем 1 и одной таблицей Хаффмана
+    /// из 17 категорий разности, все коды по 5 бит
+    fn encode_lossless(values: &[i32], format: &FrameFormat) -> Vec<u8> {
+        let precision = format.bits_stored as u8;
+        let mut out = vec![0xFF, 0xD8];
+        out.extend([0xFF, 0xC3, 0, 11, precision]);
+        out.extend((format.rows as u16).to_be_bytes());
+        out.extend((format.columns as u16).to_be_bytes());
+        out.extend([1, 1, 0x11, 0]);
+        out.extend([0xFF, 0xC4, 0, 36, 0x00]);
+        out.extend((1..=16).map(|length| if length == 5 { 17 } else { 0 }));
+        out.extend(0..=16u8);
+        out.extend([0xFF, 0xDA, 0, 8, 1, 1, 0x00, 1, 0, 0]);
+
+        let mut bits = BitWriter::default();
+        for y in 0..format.rows {
+            for x in 0..format.columns {
+                let index = y * format.columns + x;
+                let prediction = match (x, y) {
+                    (0, 0) => 1 << (precision - 1),
+                    (0, _) => values[index - format.columns],
+                    _ => values[index - 1],
+                };
+                let mut diff = (values[index] - prediction).rem_euclid(65536);
+                if diff > 32768 {
+                    diff -= 65536;
+                }
+                let category = 32 - diff.unsigned_abs().leading_zeros();
+                bits.put(category, 5);
+                if category > 0 && category < 16 {
+                    let extra = if diff > 0 { diff } else { diff - 1 };
+                    bits.put(extra as u32 & ((1 << category) - 1), category);
+                }
+            }
+        }
+        out.extend(bits.finish());
+        out.extend([0xFF, 0xD9]);
+        out
+    }
+
+    #[derive(Default)]
+    struct BitWriter {
+        bytes: Vec<u8>,
+        current: u32,
+        count: u32,
+    }
+
+    impl BitWriter {
+        fn put(&mut self, value: u32, length: u32) {
+            for bit in (0..length).rev() {
+                self.current = (self.current << 1) | ((value >> bit) & 1);
+                self.count += 1;
+                if self.count == 8 {
+                    self.bytes.push(self.current as u8);
+                    if self.current == 0xFF {
+                        self.bytes.push(0);
+                    }
+                    self.current = 0;
+                    self.count = 0;
+                }
+            }
+        }
+
+        fn finish(mut self) -> Vec<u8> {
+            if self.count > 0 {
+                let padding = 8 - self.count;
+                self.put((1 << padding) - 1, padding);
+            }
+            self.bytes
+        }
+    }
+
+    #[test]
+    fn lossless_16_bit_is_exact() {
+        let (values, format) = synthetic_image(16, 12);
+        let frame = encode_lossless(&values, &format);
+        assert_eq!(decode(&frame, &format, false).unwrap(), values);
+    }
+
+    #[test]
+    fn lossless_8_bit_is_exact() {
+        let (values, format) = synthetic_image(8, 8);
+        let frame = encode_lossless(&values, &format);
+        assert_eq!(decode(&frame, &format, false).unwrap(), values);
+    }
+
+    #[test]
+    fn baseline_grayscale_is_close() {
+        let (values, format) = synthetic_image(8, 8);
+        let bytes: Vec<u8> = values.iter().map(|value| *value as u8).collect();
+        let mut frame = Vec::new();
+        jpeg_encoder::Encoder::new(&mut frame, 95)
+            .encode(&bytes, format.columns as u16, format.rows as u16, jpeg_encoder::ColorType::Luma)
+            .unwrap();
+        let decoded = decode(&frame, &format, false).unwrap();
+        let error = decoded.iter().zip(&values).map(|(a, b)| (a - b).abs()).sum::<i32>() / values.len() as i32;
+        assert!(error <= 3, "mean error {}", error);
+    }
+
+    #[test]
+    fn baseline_ybr_becomes_rgb() {
+        let (values, format) = synthetic_rgb();
+        let bytes: Vec<u8> = values.iter().map(|value| *value as u8).collect();
+        let mut frame = Vec::new();
+        jpeg_encoder::Encoder::new(&mut frame, 95)
+            .encode(&bytes, format.columns as u16, format.rows as u16, jpeg_encoder::ColorType::Rgb)
+            .unwrap();
+        let decoded = decode(&frame, &format, true).unwrap();
+        assert_eq!(decoded.len(), values.len());
+        let error = decoded.iter().zip(&values).map(|(a, b)| (a - b).abs()).sum::<i32>() / values.len() as i32;
+        assert!(error <= 6, "mean error {}", error);
+        assert!(decode(&frame, &FrameFormat { rows: 8, ..format }, true).is_err());
+    }
+}
@@ -0,0 +1,739 @@
+use crate::work_pixels::FrameFormat;
+
+
+/// Длины серий по RUNindex (ITU-T T.87 A.7.1.1)
+const J: [u32; 32] = [0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+/// Контексты обычного режима и два контекста прерывания серии
+const REGULAR_CONTEXTS: usize = 365;
+/// Базовые пороги квантования градиентов (T.87 C.2.4.1.1.1)
+const BASIC_T1: i32 = 3;
+const BASIC_T2: i32 = 7;
+const BASIC_T3: i32 = 21;
+const SOF55: u8 = 0xF7;
+const LSE: u8 = 0xF8;
+
+/// Распаковывает кадр JPEG-LS (без потерь или near-lossless, чередование по компонентам или строкам).
+/// Возвращает значения по порядку строка, столбец, компонента
+pub fn decode(data: &[u8], format: &FrameFormat) -> Result<Vec<i32>, String> {
+    let (width, height) = (format.columns, format.rows);
+    let mut components: Vec<u8> = Vec::new();
+    let mut precision = 0;
+    let mut preset = [0i32; 5];
+    let mut values = vec![0i32; format.samples()];
+    let mut decoded = 0;
+    let mut position = 2;
+    if data.get(..2) != Some(&[0xFF, 0xD8]) {
+        return Err("JPEG-LS frame does not start with SOI".to_string());
+    }
+    while position + 4 <= data.len() {
+        if data[position] != 0xFF {
+            return Err(format!("JPEG-LS marker expected at byte {}", position));
+        }
+        let marker = data[position + 1];
+        if marker == 0xFF {
+            position += 1;
+            continue;
+        }
+        if marker == 0xD9 {
+            break;
+        }
+        let length = u16::from_be_bytes([data[position + 2], data[position + 3]]) as usize;
+        let segment = data.get(position + 4..position + 2 + length).ok_or("JPEG-LS segment is out of the frame")?;
+        position += 2 + length;
+        match marker {
+            SOF55 => {
+                precision = segment[0] as usize;
+                let rows = u16::from_be_bytes([segment[1], segment[2]]) as usize;
+                let columns = u16::from_be_bytes([segment[3], segment[4]]) as usize;
+                if rows != height || columns != width {
+                    return Err(format!("JPEG-LS frame is {}x{}, expected {}x{}", columns, rows, width, height));
+                }
+                components = segment[6..].chunks_exact(3).map(|component| component[0]).collect();
+                if components.len() != format.samples_per_pixel {
+                    return Err(format!("JPEG-LS frame has {} components, expected {}",
+                                       components.len(), format.samples_per_pixel));
+                }
+            }
+            LSE => {
+                if segment[0] != 1 {
+                    return Err(format!("JPEG-LS preset parameters of type {} are not supported", segment[0]));
+                }
+                for (index, value) in preset.iter_mut().enumerate() {
+                    *value = u16::from_be_bytes([segment[1 + index * 2], segment[2 + index * 2]]) as i32;
+                }
+            }
+            0xDA => {
+                if precision == 0 {
+                    return Err("JPEG-LS scan before the frame header".to_string());
+                }
+                let count = segment[0] as usize;
+                let scan: Vec<usize> = segment[1..1 + count * 2].chunks_exact(2)
+                    .map(|component| components.iter().position(|id| *id == component[0]).ok_or("unknown JPEG-LS component"))
+                    .collect::<Result<_, _>>()?;
+                let near = segment[1 + count * 2] as i32;
+                let interleave = segment[2 + count * 2];
+                if segment[3 + count * 2] != 0 {
+                    return Err("JPEG-LS point transform is not supported".to_string());
+                }
+                let end = scan_end(data, position);
+                let parameters = Parameters::new(precision, near, preset)?;
+                let mut reader = BitReader::new(&data[position..end]);
+                decode_scan(&mut reader, &parameters, &scan, interleave, format, &mut values)?;
+                decoded += scan.len();
+                position = end;
+            }
+            0xDD => return Err("JPEG-LS restart intervals are not supported".to_string()),
+            _ => {}
+        }
+    }
+    if decoded < format.samples_per_pixel {
+        return Err(format!("JPEG-LS frame holds {} of {} components", decoded, format.samples_per_pixel));
+    }
+    Ok(values)
+}
+
+/// Сжимает кадр в JPEG-LS без потерь: монохромный одним сканом, цветной с чередованием строк
+pub fn encode(values: &[i32], format: &FrameFormat) -> Result<Vec<u8>, String> {
+    encode_with(values, format, 0)
+}
+
+fn encode_with(values: &[i32], format: &FrameFormat, near: i32) -> Result<Vec<u8>, String> {
+    let spp = format.samples_per_pixel;
+    if !(2..=16).contains(&format.bits_stored) || !(1..=4).contains(&spp) {
+        return Err(format!("JPEG-LS does not support {} bits stored with {} samples per pixel",
+                           format.bits_stored, spp));
+    }
+    if values.len() < format.samples() {
+        return Err(format!("frame holds {} values, expected {}", values.len(), format.samples()));
+    }
+    let parameters = Parameters::new(format.bits_stored, near, [0; 5])?;
+    let interleave = if spp > 1 { 1 } else { 0 };
+    let mut out = vec![0xFF, 0xD8, 0xFF, SOF55];
+    out.extend((8 + 3 * spp as u16).to_be_bytes());
+    out.push(format.bits_stored as u8);
+    out.extend((format.rows as u16).to_be_bytes());
+    out.extend((format.columns as u16).to_be_bytes());
+    out.push(spp as u8);
+    for component in 1..=spp as u8 {
+        out.extend([component, 0x11, 0]);
+    }
+    out.extend([0xFF, 0xDA]);
+    out.extend((6 + 2 * spp as u16).to_be_bytes());
+    out.push(spp as u8);
+    for component in 1..=spp as u8 {
+        out.extend([component, 0]);
+    }
+    out.extend([near as u8, interleave, 0]);
+
+    let width = format.columns;
+    let mut writer = BitWriter::default();
+    let mut contexts = Contexts::new(&parameters);
+    let mut lines = vec![Lines::new(width); spp];
+    let mut run_index = vec![0usize; spp];
+    let mut source = vec![0i32; width];
+    for y in 0..format.rows {
+        for (component, lines) in lines.iter_mut().enumerate() {
+            for (x, value) in source.iter_mut().enumerate() {
+                *value = values[(y * width + x) * spp + component] & parameters.maxval;
+            }
+            lines.start();
+            encode_line(&mut writer, &mut contexts, &mut run_index[component], &parameters, &source, lines);
+            lines.finish();
+        }
+    }
+    out.extend(writer.finish());
+    out.extend([0xFF, 0xD9]);
+    Ok(out)
+}
+
+/// Конец данных скана: первый маркер (0xFF со следующим байтом не меньше 0x80)
+fn scan_end(data: &[u8], start: usize) -> usize {
+    (start..data.len().saturating_sub(1))
+        .find(|&index| data[index] == 0xFF && data[index + 1] >= 0x80)
+        .unwrap_or(data.len())
+}
+
+fn decode_scan(reader: &mut BitReader, parameters: &Parameters, scan: &[usize], interleave: u8,
+               format: &FrameFormat, values: &mut [i32]) -> Result<(), String> {
+    if interleave > 1 || (interleave == 0 && scan.len() > 1) {
+        return Err(format!("JPEG-LS interleave mode {} is not supported", interleave));
+    }
+    let (width, spp) = (format.columns, format.samples_per_pixel);
+    let mut contexts = Contexts::new(parameters);
+    let mut lines = vec![Lines::new(width); scan.len()];
+    let mut run_index = vec![0usize; scan.len()];
+    for y in 0..format.rows {
+        for (index, component) in scan.iter().enumerate() {
+            let lines = &mut lines[index];
+            lines.start();
+            decode_line(reader, &mut contexts, &mut run_index[index], parameters, lines)?;
+            for x in 0..width {
+                values[(y * width + x) * spp + component] = lines.current[x + 1];
+            }
+            lines.finish();
+        }
+    }
+    Ok(())
+}
+
+
+/// Параметры кодирования скана (T.87 C.2.4.1.1)
+#[derive(Debug, Clone)]
+struct Parameters {
+    maxval: i32,
+    t1: i32,
+    t2: i32,
+    t3: i32,
+    reset: i32,
+    near: i32,
+    range: i32,
+    qbpp: u32,
+    limit: u32,
+}
+
+impl Parameters {
+    /// `preset` — MAXVAL, T1, T2, T3, RESET из LSE; нули означают значения по умолчанию
+    fn new(precision: usize, near: i32, preset: [i32; 5]) -> Result<Parameters, String> {
+        if !(2..=16).contains(&precision) {
+            return Err(format!("JPEG-LS precision {} is not supported", precision));
+        }
+        let maxval = if preset[0] > 0 { preset[0] } else { (1 << precision) - 1 };
+        if near < 0 || near > maxval.min(255) / 2 {
+            return Err(format!("JPEG-LS NEAR {} is out of range", near));
+        }
+        let (t1, t2, t3) = if maxval >= 128 {
+            let factor = (maxval.min(4095) + 128) / 256;
+            let t1 = (factor * (BASIC_T1 - 2) + 2 + 3 * near).clamp(near + 1, maxval);
+            let t2 = (factor * (BASIC_T2 - 3) + 3 + 5 * near).clamp(t1, maxval);
+            (t1, t2, (factor * (BASIC_T3 - 4) + 4 + 7 * near).clamp(t2, maxval))
+        } else {
+            let factor = 256 / (maxval + 1);
+            let t1 = (BASIC_T1 / factor + 3 * near).max(2).clamp(near + 1, maxval);
+            let t2 = (BASIC_T2 / factor + 5 * near).max(3).clamp(t1, maxval);
+            (t1, t2, (BASIC_T3 / factor + 7 * near).max(4).clamp(t2, maxval))
+        };
+        let range = (maxval + 2 * near) / (2 * near + 1) + 1;
+        let bits = |value: i32| 32 - (value.max(2) as u32 - 1).leading_zeros();
+        let bpp = bits(maxval + 1).max(2);
+        Ok(Parameters {
+            maxval,
+            t1: if preset[1] > 0 { preset[1] } else { t1 },
+            t2: if preset[2] > 0 { preset[2] } else { t2 },
+            t3: if preset[3] > 0 { preset[3] } else { t3 },
+            reset: if preset[4] > 0 { preset[4] } else { 64 },
+            near,
+            range,
+            qbpp: bits(range),
+            limit: 2 * (bpp + bpp.max(8)),
+        })
+    }
+
+    fn quantize_gradient(&self, d: i32) -> i32 {
+        match d {
+            d if d <= -self.t3 => -4,
+            d if d <= -self.t2 => -3,
+            d if d <= -self.t1 => -2,
+            d if d < -self.near => -1,
+            d if d <= self.near => 0,
+            d if d < self.t1 => 1,
+            d if d < self.t2 => 2,
+            d if d < self.t3 => 3,
+            _ => 4,
+        }
+    }
+
+    /// Приводит ошибку предсказания к диапазону -RANGE/2..RANGE/2
+    fn reduce(&self, mut error: i32) -> i32 {
+        if error < 0 {
+            error += self.range;
+        }
+        if error >= (self.range + 1) / 2 {
+            error -= self.range;
+        }
+        error
+    }
+
+    /// Квантует ошибку near-lossless
+    fn quantize_error(&self, error: i32) -> i32 {
+        if error > 0 {
+            (error + self.near) / (2 * self.near + 1)
+        } else {
+            -((self.near - error) / (2 * self.near + 1))
+        }
+    }
+
+    /// Восстанавливает значение по предсказанию и ошибке с учетом переноса по модулю
+    fn reconstruct(&self, prediction: i32, error: i32) -> i32 {
+        let step = 2 * self.near + 1;
+        let mut value = prediction + error * step;
+        if value < -self.near {
+            value += self.range * step;
+        } else if value > self.maxval + self.near {
+            value -= self.range * step;
+        }
+        value.clamp(0, self.maxval)
+    }
+}
+
+
+/// Статистика контекстов A, B, C, N и счетчики Nn для прерываний серий
+struct Contexts {
+    a: Vec<i32>,
+    b: Vec<i32>,
+    c: Vec<i32>,
+    n: Vec<i32>,
+    nn: [i32; 2],
+}
+
+impl Contexts {
+    fn new(parameters: &Parameters) -> Contexts {
+        let a = ((parameters.range + 32) / 64).max(2);
+        Contexts {
+            a: vec![a; REGULAR_CONTEXTS + 2],
+            b: vec![0; REGULAR_CONTEXTS],
+            c: vec![0; REGULAR_CONTEXTS],
+            n: vec![1; REGULAR_CONTEXTS + 2],
+            nn: [0; 2],
+        }
+    }
+
+    fn golomb_k(&self, context: usize, total: i32) -> u32 {
+        let mut k = 0;
+        while (self.n[context] << k) < total {
+            k += 1;
+        }
+        k
+    }
+
+    fn update(&mut self, q: usize, error: i32, parameters: &Parameters) {
+        self.b[q] += error * (2 * parameters.near + 1);
+        self.a[q] += error.abs();
+        if self.n[q] == parameters.reset {
+            self.a[q] >>= 1;
+            self.b[q] >>= 1;
+            self.n[q] >>= 1;
+        }
+        self.n[q] += 1;
+        if self.b[q] <= -self.n[q] {
+            self.b[q] += self.n[q];
+            if self.c[q] > -128 {
+                self.c[q] -= 1;
+            }
+            if self.b[q] <= -self.n[q] {
+                self.b[q] = -self.n[q] + 1;
+            }
+        } else if self.b[q] > 0 {
+            self.b[q] -= self.n[q];
+            if self.c[q] < 127 {
+                self.c[q] += 1;
+            }
+            if self.b[q] > 0 {
+                self.b[q] = 0;
+            }
+        }
+    }
+
+    fn update_interruption(&mut self, kind: usize, error: i32, mapped: i32, parameters: &Parameters) {
+        let q = REGULAR_CONTEXTS + kind;
+        if error < 0 {
+            self.nn[kind] += 1;
+        }
+        self.a[q] += (mapped + 1 - kind as i32) >> 1;
+        if self.n[q] == parameters.reset {
+            self.a[q] >>= 1;
+            self.n[q] >>= 1;
+            self.nn[kind] >>= 1;
+        }
+        self.n[q] += 1;
+    }
+}
+
+
+/// Предыдущая и текущая строки компоненты с краевыми значениями по бокам (T.87 A.2.1)
+#[derive(Clone)]
+struct Lines {
+    previous: Vec<i32>,
+    current: Vec<i32>,
+}
+
+impl Lines {
+    fn new(width: usize) -> Lines {
+        Lines { previous: vec![0; width + 2], current: vec![0; width + 2] }
+    }
+
+    fn start(&mut self) {
+        let width = self.previous.len() - 2;
+        self.previous[width + 1] = self.previous[width];
+        self.current[0] = self.previous[1];
+    }
+
+    fn finish(&mut self) {
+        std::mem::swap(&mut self.previous, &mut self.current);
+    }
+
+    fn width(&self) -> usize {
+        self.previous.len() - 2
+    }
+
+    /// Ra, Rb, Rc, Rd для позиции `x` (с единицы)
+    fn neighbours(&self, x: usize) -> (i32, i32, i32, i32) {
+        (self.current[x - 1], self.previous[x], self.previous[x - 1], self.previous[x + 1])
+    }
+}
+
+/// Контекст обычного режима и знак по градиентам; None — режим серий
+fn context(parameters: &Parameters, ra: i32, rb: i32, rc: i32, rd: i32) -> Option<(usize, i32)> {
+    let q = [rd - rb, rb - rc, rc - ra].map(|d| parameters.quantize_gradient(d));
+    let index = q[0] * 81 + q[1] * 9 + q[2];
+    match index {
+        0 => None,
+        index if index < 0 => Some((-index as usize, -1)),
+        index => Some((index as usize, 1)),
+    }
+}
+
+/// Предсказание по медиане (MED)
+fn predict(ra: i32, rb: i32, rc: i32) -> i32 {
+    if rc >= ra.max(rb) {
+        ra.min(rb)
+    } else if rc <= ra.min(rb) {
+        ra.max(rb)
+    } else {
+        ra + rb - rc
+    }
+}
+
+fn encode_line(writer: &mut BitWriter, contexts: &mut Contexts, run_index: &mut usize, parameters: &Parameters,
+               source: &[i32], lines: &mut Lines) {
+    let width = lines.width();
+    let mut x = 1;
+    while x <= width {
+        let (ra, rb, rc, rd) = lines.neighbours(x);
+        let Some((q, sign)) = context(parameters, ra, rb, rc, rd) else {
+            let mut count = 0;
+            while x + count <= width && (source[x + count - 1] - ra).abs() <= parameters.near {
+                lines.current[x + count] = ra;
+                count += 1;
+            }
+            let end_of_line = x + count > width;
+            let mut rest = count;
+            while rest >= 1 << J[*run_index] {
+                writer.put(1, 1);
+                rest -= 1 << J[*run_index];
+                *run_index = (*run_index + 1).min(31);
+            }
+            x += count;
+            if end_of_line {
+                if rest > 0 {
+                    writer.put(1, 1);
+                }
+                continue;
+            }
+            writer.put(0, 1);
+            writer.put(rest as u32, J[*run_index]);
+            lines.current[x] = encode_interruption(writer, contexts, *run_index, parameters, source[x - 1], lines, x);
+            *run_index = run_index.saturating_sub(1);
+            x += 1;
+            continue;
+        };
+        let prediction = (predict(ra, rb, rc) + sign * contexts.c[q]).clamp(0, parameters.maxval);
+        let mut error = sign * (source[x - 1] - prediction);
+        if parameters.near > 0 {
+            error = parameters.quantize_error(error);
+        }
+        lines.current[x] = parameters.reconstruct(prediction, sign * error);
+        let error = parameters.reduce(error);
+        let k = contexts.golomb_k(q, contexts.a[q]);
+        let mapped = if parameters.near == 0 && k == 0 && 2 * contexts.b[q] <= -contexts.n[q] {
+            if error >= 0 { 2 * error + 1 } else { -2 * (error + 1) }
+        } else if error >= 0 {
+            2 * error
+        } else {
+            -2 * error - 1
+        };
+        writer.golomb(mapped as u32, k, parameters.limit, parameters.qbpp);
+        contexts.update(q, error, parameters);
+        x += 1;
+    }
+}
+
+/// Кодирует значение, прервавшее серию, и возвращает восстановленное значение
+fn encode_interruption(writer: &mut BitWriter, contexts: &mut Contexts, run_index: usize, parameters: &Parameters,
+                       value: i32, lines: &Lines, x: usize) -> i32 {
+    let (ra, rb, _, _) = lines.neighbours(x);
+    let kind = ((ra - rb).abs() <= parameters.near) as usize;
+    let (prediction, sign) = match kind {
+        1 => (ra, 1),
+        _ if ra > rb => (rb, -1),
+        _ => (rb, 1),
+    };
+    let mut error = sign * (value - prediction);
+    if parameters.near > 0 {
+        error = parameters.quantize_error(error);
+    }
+    let reconstructed = parameters.reconstruct(prediction, sign * error);
+    let error = parameters.reduce(error);
+    let q = REGULAR_CONTEXTS + kind;
+    let k = contexts.golomb_k(q, contexts.a[q] + (contexts.n[q] >> 1) * kind as i32);
+    let nn = contexts.nn[kind];
+    let map = (k == 0 && error > 0 && 2 * nn < contexts.n[q])
+        || (error < 0 && 2 * nn >= contexts.n[q])
+        || (error < 0 && k != 0);
+    let mapped = 2 * error.abs() - kind as i32 - map as i32;
+    writer.golomb(mapped as u32, k, parameters.limit - J[run_index] - 1, parameters.qbpp);
+    contexts.update_interruption(kind, error, mapped, parameters);
+    reconstructed
+}
+
+fn decode_line(reader: &mut BitReader, contexts: &mut Contexts, run_index: &mut usize, parameters: &Parameters,
+               lines: &mut Lines) -> Result<(), String> {
+    let width = lines.width();
+    let mut x = 1;
+    while x <= width {
+        let (ra, rb, rc, rd) = lines.neighbours(x);
+        let Some((q, sign)) = context(parameters, ra, rb, rc, rd) else {
+            let remaining = width + 1 - x;
+            let mut count = 0;
+            while count < remaining && reader.bit()? == 1 {
+                let block = 1 << J[*run_index];
+                let length = block.min(remaining - count);
+                count += length;
+                if length == block {
+                    *run_index = (*run_index + 1).min(31);
+                }
+            }
+            if count < remaining {
+                count += reader.bits(J[*run_index])? as usize;
+                if count >= remaining {
+                    return Err("JPEG-LS run crosses the end of line".to_string());
+                }
+            }
+            for value in &mut lines.current[x..x + count] {
+                *value = ra;
+            }
+            x += count;
+            if x > width {
+                continue;
+            }
+            lines.current[x] = decode_interruption(reader, contexts, *run_index, parameters, lines, x)?;
+            *run_index = run_index.saturating_sub(1);
+            x += 1;
+            continue;
+        };
+        let prediction = (predict(ra, rb, rc) + sign * contexts.c[q]).clamp(0, parameters.maxval);
+        let k = contexts.golomb_k(q, contexts.a[q]);
+        let mapped = reader.golomb(k, parameters.limit, parameters.qbpp)? as i32;
+        let mut error = if mapped % 2 == 0 { mapped / 2 } else { -(mapped + 1) / 2 };
+        if parameters.near == 0 && k == 0 && 2 * contexts.b[q] <= -contexts.n[q] {
+            error = -error - 1;
+        }
+        contexts.update(q, error, parameters);
+        lines.current[x] = parameters.reconstruct(prediction, sign * error);
+        x += 1;
+    }
+    Ok(())
+}
+
+fn decode_interruption(reader: &mut BitReader, contexts: &mut Contexts, run_index: usize, parameters: &Parameters,
+                       lines: &Lines, x: usize) -> Result<i32, String> {
+    let (ra, rb, _, _) = lines.neighbours(x);
+    let kind = ((ra - rb).abs() <= parameters.near) as usize;
+    let q = REGULAR_CONTEXTS + kind;
+    let k = contexts.golomb_k(q, contexts.a[q] + (contexts.n[q] >> 1) * kind as i32);
+    let mapped = reader.golomb(k, parameters.limit - J[run_index] - 1, parameters.qbpp)? as i32;
+    let temp = mapped + kind as i32;
+    let map = temp & 1 == 1;
+    let magnitude = (temp + map as i32) / 2;
+    let negative = (k != 0 || 2 * contexts.nn[kind] >= contexts.n[q]) == map;
+    let error = if negative { -magnitude } else { magnitude };
+    contexts.update_interruption(kind, error, mapped, parameters);
+    Ok(match kind {
+        1 => parameters.reconstruct(ra, error),
+        _ if ra > rb => parameters.reconstruct(rb, -error),
+        _ => parameters.reconstruct(rb, error),
+    })
+}
+
+
+/// Запись битов со вставкой нулевого бита после каждого байта 0xFF
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    current: u32,
+    count: u32,
+}
+
+impl BitWriter {
+    fn capacity(&self) -> u32 {
+        if self.bytes.last() == Some(&0xFF) { 7 } else { 8 }
+    }
+
+    fn put(&mut self, value: u32, length: u32) {
+        for bit in (0..length).rev() {
+            self.current = (self.current << 1) | (value.checked_shr(bit).unwrap_or(0) & 1);
+            self.count += 1;
+            if self.count == self.capacity() {
+                self.bytes.push(self.current as u8);
+                self.current = 0;
+                self.count = 0;
+            }
+        }
+    }
+
+    /// Код Голомба с ограничением длины (T.87 A.5.3)
+    fn golomb(&mut self, value: u32, k: u32, limit: u32, qbpp: u32) {
+        let high = value >> k;
+        if high < limit - qbpp - 1 {
+            self.put(0, high);
+            self.put(1, 1);
+            self.put(value, k);
+        } else {
+            self.put(0, limit - qbpp - 1);
+            self.put(1, 1);
+            self.put(value - 1, qbpp);
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            let padding = self.capacity() - self.count;
+            self.put(0, padding);
+        }
+        if self.bytes.last() == Some(&0xFF) {
+            self.bytes.push(0);
+        }
+        self.bytes
+    }
+}
+
+/// Чтение битов скана; после 0xFF старший бит следующего байта пропускается
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+    current: u32,
+    count: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader { data, position: 0, current: 0, count: 0 }
+    }
+
+    fn bit(&mut self) -> Result<u32, String> {
+        if self.count == 0 {
+            let byte = *self.data.get(self.position).ok_or("JPEG-LS scan ends early")? as u32;
+            let stuffed = self.position > 0 && self.data[self.position - 1] == 0xFF;
+            self.position += 1;
+            self.current = byte;
+            self.count = if stuffed { 7 } else { 8 };
+        }
+        self.count -= 1;
+        Ok((self.current >> self.count) & 1)
+    }
+
+    fn bits(&mut self, length: u32) -> Result<u32, String> {
+        let mut value = 0;
+        for _ in 0..length {
+            value = (value << 1) | self.bit()?;
+        }
+        Ok(value)
+    }
+
+    fn golomb(&mut self, k: u32, limit: u32, qbpp: u32) -> Result<u32, String> {
+        let mut high = 0;
+        while self.bit()? == 0 {
+            high += 1;
+            if high > limit - qbpp - 1 {
+                return Err("JPEG-LS code is longer than the limit".to_string());
+            }
+        }
+        if high < limit - qbpp - 1 {
+            Ok((high << k) | self.bits(k)?)
+        } else {
+            Ok(self.bits(qbpp)? + 1)
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::work_pixels::tests::{synthetic_image, synthetic_rgb};
+
+    #[test]
+    fn grayscale_16_bit_round_trip() {
+        let (values, format) = synthetic_image(16, 12);
+        let frame = encode(&values, &format).unwrap();
+        assert!(frame.len() < values.len() * 2);
+        assert_eq!(decode(&frame, &format).unwrap(), values);
+    }
+
+    #[test]
+    fn grayscale_8_bit_round_trip() {
+        let (values, format) = synthetic_image(8, 8);
+        let frame = encode(&values, &format).unwrap();
+        assert_eq!(decode(&frame, &format).unwrap(), values);
+    }
+
+    #[test]
+    fn rgb_line_interleaved_round_trip() {
+        let (values, format) = synthetic_rgb();
+        let frame = encode(&values, &format).unwrap();
+        assert_eq!(decode(&frame, &format).unwrap(), values);
+    }
+
+    #[test]
+    fn near_lossless_stays_within_near() {
+        let (values, format) = synthetic_image(16, 12);
+        let frame = encode_with(&values, &format, 3).unwrap();
+        let decoded = decode(&frame, &format).unwrap();
+        assert!(decoded.iter().zip(&values).all(|(a, b)| (a - b).abs() <= 3));
+        assert!(decoded != values);
+    }
+
+    #[test]
+    fn stuffed_bits_round_trip() {
+        let mut writer = BitWriter::default();
+        writer.put(0xFF, 8);
+        writer.put(0x7F, 7);
+        writer.put(0b101, 3);
+        let bytes = writer.finish();
+        assert_eq!(bytes[..2], [0xFF, 0x7F]);
+        let mut reader = BitReader::new(&bytes);
+        assert_eq!(reader.bits(8).unwrap(), 0xFF);
+        assert_eq!(reader.bits(7).unwrap(), 0x7F);
+        assert_eq!(reader.bits(3).unwrap(), 0b101);
+    }
+
+    /// Кадры, сжатые CharLS 2.4.2 (tests/data/gen_jls.cpp)
+    #[test]
+    fn charls_lossless_frames() {
+        let (values, format) = synthetic_image(8, 8);
+        assert_eq!(decode(include_bytes!("../tests/data/gray8_lossless.jls"), &format).unwrap(), values);
+        let (values, format) = synthetic_image(16, 12);
+        assert_eq!(decode(include_bytes!("../tests/data/gray12_lossless.jls"), &format).unwrap(), values);
+        assert_eq!(decode(include_bytes!("../tests/data/gray12_preset.jls"), &format).unwrap(), values);
+        let (values, format) = synthetic_rgb();
+        assert_eq!(decode(include_bytes!("../tests/data/rgb8_none.jls"), &format).unwrap(), values);
+        assert_eq!(decode(include_bytes!("../tests/data/rgb8_line.jls"), &format).unwrap(), values);
+        // чередование по отсчетам не поддерживается и должно давать ошибку, а не искаженный кадр
+        assert!(decode(include_bytes!("../tests/data/rgb8_sample.jls"), &format).is_err());
+    }
+
+    #[test]
+    fn charls_near_lossless_frame() {
+        let (_, format) = synthetic_image(16, 12);
+        let expected: Vec<i32> = include_bytes!("../tests/data/gray12_near3.raw").chunks_exact(2)
+            .map(|value| u16::from_le_bytes([value[0], value[1]]) as i32)
+            .collect();
+        assert_eq!(decode(include_bytes!("../tests/data/gray12_near3.jls"), &format).unwrap(), expected);
+    }
+
+    #[test]
+    fn wrong_size_is_rejected() {
+        let (values, format) = synthetic_image(8, 8);
+        let frame = encode(&values, &format).unwrap();
+        assert!(decode(&frame, &FrameFormat { columns: 20, ..format }).is_err());
+    }
+}
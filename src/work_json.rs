@@ -0,0 +1,196 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use dicom::core::dictionary::{DataDictionary, DictionaryEntry};
+use dicom::core::value::Value;
+use dicom::core::{PrimitiveValue, Tag, VR};
+use dicom::object::mem::InMemElement;
+use dicom::object::{InMemDicomObject, StandardDataDictionary};
+use serde_json::{json, Map};
+
+
+/// Pixel Data
+pub const PIXEL_DATA: Tag = Tag(0x7FE0, 0x0010);
+/// Бинарные значения длиннее этого размера передаются ссылкой BulkDataURI (если она задана)
+const BULK_DATA_THRESHOLD: usize = 1024;
+
+/// Ключ атрибута в DICOM JSON: 8 шестнадцатеричных цифр тега
+pub fn tag_key(tag: Tag) -> String {
+    format!("{:04X}{:04X}", tag.group(), tag.element())
+}
+
+/// VR атрибута по словарю DICOM (UN для неизвестных тегов)
+pub fn vr_by_tag(tag: Tag) -> VR {
+    StandardDataDictionary.by_tag(tag).map(|entry| entry.vr()).unwrap_or(VR::UN)
+}
+
+/// Ссылка BulkDataURI на значение атрибута (`None` — значение передается как InlineBinary)
+pub type BulkDataUri<'a> = dyn Fn(Tag) -> Option<String> + 'a;
+
+/// Кодирует набор данных в DICOM JSON (PS3.18 F.2).
+/// Pixel Data и длинные бинарные значения верхнего уровня передаются ссылкой,
+/// которую возвращает `bulk_data_uri`; без ссылки (и во вложенных наборах) — как InlineBinary
+pub fn dataset_to_json(obj: &InMemDicomObject, bulk_data_uri: Option<&BulkDataUri>) -> serde_json::Value {
+    let mut attributes = Map::new();
+    for element in obj {
+        attributes.insert(tag_key(element.header().tag), element_to_json(element, bulk_data_uri));
+    }
+    serde_json::Value::Object(attributes)
+}
+
+fn element_to_json(element: &InMemElement, bulk_data_uri: Option<&BulkDataUri>) -> serde_json::Value {
+    let bulk_data_uri = |tag: Tag| bulk_data_uri.and_then(|uri| uri(tag));
+    let tag = element.header().tag;
+    let vr = element.vr();
+    match element.value() {
+        Value::Sequence { items, .. } => {
+            let items: Vec<serde_json::Value> = items.iter().map(|item| dataset_to_json(item, None)).collect();
+            if items.is_empty() {
+                json!({ "vr": "SQ" })
+            } else {
+                json!({ "vr": "SQ", "Value": items })
+            }
+        }
+        Value::PixelSequence { fragments, .. } => match bulk_data_uri(tag) {
+            Some(uri) => json!({ "vr": vr.to_string(), "BulkDataURI": uri }),
+            None => json!({ "vr": vr.to_string(), "InlineBinary": BASE64.encode(fragments.concat()) }),
+        },
+        Value::Primitive(value) if is_binary(vr) => {
+            let bytes = value.to_bytes();
+            let uri = if tag == PIXEL_DATA || bytes.len() > BULK_DATA_THRESHOLD { bulk_data_uri(tag) } else { None };
+            match uri {
+                Some(uri) => json!({ "vr": vr.to_string(), "BulkDataURI": uri }),
+                _ if bytes.is_empty() => json!({ "vr": vr.to_string() }),
+                _ => json!({ "vr": vr.to_string(), "InlineBinary": BASE64.encode(bytes) }),
+            }
+        }
+        Value::Primitive(value) => match vr {
+            VR::AT => {
+                // Парсер читает AT как список тегов, значение из других источников — как пары u16
+                let tags: Vec<Tag> = match value {
+                    PrimitiveValue::Tags(tags) => tags.to_vec(),
+                    _ => value.to_multi_int::<u16>().unwrap_or_default()
+                        .chunks(2)
+                        .filter(|pair| pair.len() == 2)
+                        .map(|pair| Tag(pair[0], pair[1]))
+                        .collect(),
+                };
+                with_values(vr, tags.into_iter().map(|tag| json!(tag_key(tag))).collect())
+            }
+            // Десятичные строки разбираются по одному значению: неверное значение становится null,
+            // а не удаляет все значения атрибута
+            VR::IS | VR::DS => strings_to_json(vr, &value.to_multi_str()),
+            _ if is_integer(vr) => with_values(vr, value.to_multi_int::<i64>().unwrap_or_default()
+                .into_iter().map(|n| json!(n)).collect()),
+            VR::FL | VR::FD => with_values(vr, value.to_multi_float64().unwrap_or_default()
+                .into_iter().map(|n| json!(n)).collect()),
+            _ => strings_to_json(vr, &value.to_multi_str()),
+        },
+    }
+}
+
+/// Атрибут DICOM JSON из строковых значений (например, значений из индекса).
+/// Числовые VR преобразуются в числа, PN — в объекты с компонентами имени
+pub fn strings_to_json(vr: VR, values: &[String]) -> serde_json::Value {
+    let values: Vec<&str> = values.iter()
+        .flat_map(|value| value.split('\\'))
+        .map(|value| value.trim_end_matches(['\0', ' ']).trim_start())
+        .collect();
+    if values.iter().all(|value| value.is_empty()) {
+        return json!({ "vr": vr.to_string() });
+    }
+    let values = values.into_iter()
+        .map(|value| match vr {
+            _ if value.is_empty() => serde_json::Value::Null,
+            VR::PN => {
+                let mut name = Map::new();
+                for (group, component) in ["Alphabetic", "Ideographic", "Phonetic"].iter().zip(value.split('=')) {
+                    if !component.is_empty() {
+                        name.insert(group.to_string(), json!(component));
+                    }
+                }
+                serde_json::Value::Object(name)
+            }
+            _ if is_integer(vr) => value.parse::<i64>().map(|n| json!(n)).unwrap_or(serde_json::Value::Null),
+            VR::FL | VR::FD | VR::DS => value.parse::<f64>().map(|n| json!(n)).unwrap_or(serde_json::Value::Null),
+            _ => json!(value),
+        })
+        .collect();
+    with_values(vr, values)
+}
+
+fn with_values(vr: VR, values: Vec<serde_json::Value>) -> serde_json::Value {
+    if values.is_empty() {
+        json!({ "vr": vr.to_string() })
+    } else {
+        json!({ "vr": vr.to_string(), "Value": values })
+    }
+}
+
+fn is_binary(vr: VR) -> bool {
+    matches!(vr, VR::OB | VR::OD | VR::OF | VR::OL | VR::OV | VR::OW | VR::UN)
+}
+
+fn is_integer(vr: VR) -> bool {
+    matches!(vr, VR::IS | VR::SL | VR::SS | VR::SV | VR::UL | VR::US | VR::UV)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use dicom::core::{DataElement, Length};
+
+    /// Набор данных со строками, числами, PN, AT, последовательностью, частным атрибутом и Pixel Data
+    pub(crate) fn sample_dataset() -> InMemDicomObject {
+        let mut item = InMemDicomObject::new_empty();
+        item.put(DataElement::new(Tag(0x0008, 0x0100), VR::SH, PrimitiveValue::from("T-D1100")));
+        let mut obj = InMemDicomObject::new_empty();
+        obj.put(DataElement::new(Tag(0x0008, 0x0060), VR::CS, PrimitiveValue::from("CT")));
+        obj.put(DataElement::new(Tag(0x0010, 0x0010), VR::PN, PrimitiveValue::from("Doe^John=Иванов^Иван")));
+        obj.put(DataElement::new(Tag(0x0008, 0x1030), VR::LO, PrimitiveValue::from("Head <contrast> & \"neck\"")));
+        obj.put(DataElement::new(Tag(0x0020, 0x0013), VR::IS, PrimitiveValue::from("7 ")));
+        obj.put(DataElement::new(Tag(0x0018, 0x0050), VR::DS, PrimitiveValue::from("1.25\\bad")));
+        obj.put(DataElement::new(Tag(0x0028, 0x0030), VR::DS, PrimitiveValue::from("0.5\\0.75")));
+        obj.put(DataElement::new(Tag(0x0028, 0x0010), VR::US, PrimitiveValue::from(512u16)));
+        obj.put(DataElement::new(Tag(0x0020, 0x5000), VR::AT, PrimitiveValue::Tags([Tag(0x0010, 0x0020)].into_iter().collect())));
+        obj.put(DataElement::new(Tag(0x0008, 0x0050), VR::SH, PrimitiveValue::Empty));
+        obj.put(InMemElement::new(Tag(0x0008, 0x2218), VR::SQ, Value::Sequence {
+            items: smallvec::smallvec![item],
+            size: Length::UNDEFINED,
+        }));
+        obj.put(DataElement::new(Tag(0x0009, 0x0010), VR::LO, PrimitiveValue::from("ACME 1.0")));
+        obj.put(DataElement::new(Tag(0x0009, 0x1001), VR::UN, PrimitiveValue::from(vec![1u8, 2, 3, 4])));
+        obj.put(DataElement::new(PIXEL_DATA, VR::OW, PrimitiveValue::from(vec![7u8; 2048])));
+        obj
+    }
+
+    #[test]
+    fn values_are_encoded_by_vr() {
+        let uri = |tag: Tag| if tag == PIXEL_DATA { Some("file:///data/1.dcm#offset=10&length=2048".to_string()) } else { None };
+        let json = dataset_to_json(&sample_dataset(), Some(&uri));
+        assert_eq!(json["00080060"], json!({ "vr": "CS", "Value": ["CT"] }));
+        assert_eq!(json["00100010"], json!({ "vr": "PN", "Value": [{ "Alphabetic": "Doe^John", "Ideographic": "Иванов^Иван" }] }));
+        assert_eq!(json["00200013"], json!({ "vr": "IS", "Value": [7] }));
+        assert_eq!(json["00280030"], json!({ "vr": "DS", "Value": [0.5, 0.75] }));
+        assert_eq!(json["00180050"], json!({ "vr": "DS", "Value": [1.25, null] }));
+        assert_eq!(json["00280010"], json!({ "vr": "US", "Value": [512] }));
+        assert_eq!(json["00205000"], json!({ "vr": "AT", "Value": ["00100020"] }));
+        assert_eq!(json["00080050"], json!({ "vr": "SH" }));
+        assert_eq!(json["00082218"], json!({ "vr": "SQ", "Value": [{ "00080100": { "vr": "SH", "Value": ["T-D1100"] } }] }));
+        assert_eq!(json["00091001"], json!({ "vr": "UN", "InlineBinary": "AQIDBA==" }));
+        assert_eq!(json["7FE00010"], json!({ "vr": "OW", "BulkDataURI": "file:///data/1.dcm#offset=10&length=2048" }));
+
+        // Без ссылки Pixel Data передается как InlineBinary
+        let json = dataset_to_json(&sample_dataset(), None);
+        assert_eq!(json["7FE00010"]["InlineBinary"].as_str().unwrap().len(), 2732);
+    }
+
+    #[test]
+    fn index_strings_are_converted() {
+        let values = |values: &[&str]| values.iter().map(|value| value.to_string()).collect::<Vec<_>>();
+        assert_eq!(strings_to_json(VR::DS, &values(&["0.5\\1e1 ", "x"])), json!({ "vr": "DS", "Value": [0.5, 10.0, null] }));
+        assert_eq!(strings_to_json(VR::IS, &values(&["12\\"])), json!({ "vr": "IS", "Value": [12, null] }));
+        assert_eq!(strings_to_json(VR::PN, &values(&["==Phonetic"])), json!({ "vr": "PN", "Value": [{ "Phonetic": "Phonetic" }] }));
+        assert_eq!(strings_to_json(VR::LO, &values(&["value\0"])), json!({ "vr": "LO", "Value": ["value"] }));
+        assert_eq!(strings_to_json(VR::DA, &values(&["", " "])), json!({ "vr": "DA" }));
+    }
+}
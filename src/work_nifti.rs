@@ -0,0 +1,271 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use crate::work_geometry;
+use crate::work_volume::{SampleType, Volume};
+
+
+/// Размер заголовка NIfTI-1
+const HEADER_SIZE: usize = 348;
+/// Смещение данных: заголовок и 4 байта признака расширений (расширений нет)
+const VOX_OFFSET: usize = HEADER_SIZE + 4;
+/// Единицы: мм (2) для пространства и секунды (8) для времени
+const XYZT_UNITS: u8 = 2 | 8;
+/// NIFTI_XFORM_SCANNER_ANAT: координаты сканера
+const XFORM_SCANNER_ANAT: i16 = 1;
+
+/// Записывает объем в NIfTI-1 (один файл `.nii`, сжатый gzip).
+/// Координаты LPS из DICOM переводятся в RAS: sform содержит полную матрицу
+/// (в том числе со скосом при наклоне гентри), qform — поворот плоскости среза
+pub fn write(volume: &Volume, target: &path::Path) -> io::Result<()> {
+    let sample_type = volume.sample_type();
+    let mut writer = GzEncoder::new(BufWriter::new(File::create(target)?), Compression::default());
+    writer.write_all(&header(volume, sample_type))?;
+    writer.write_all(&[0u8; VOX_OFFSET - HEADER_SIZE])?;
+    writer.write_all(&volume.sample_bytes(sample_type))?;
+    writer.finish()?.flush()
+}
+
+fn header(volume: &Volume, sample_type: SampleType) -> Vec<u8> {
+    let mut header = vec![0u8; HEADER_SIZE];
+    let (datatype, bitpix): (i16, i16) = match sample_type {
+        SampleType::Int16 => (4, 16),
+        SampleType::Int32 => (8, 32),
+        SampleType::Float32 => (16, 32),
+    };
+    // LPS -> RAS: меняются знаки координат x и y
+    let mut affine = volume.affine();
+    for row in affine.iter_mut().take(2) {
+        row.iter_mut().for_each(|value| *value = -*value);
+    }
+    let (quatern, qfac) = quaternion(volume);
+
+    put_i32(&mut header, 0, HEADER_SIZE as i32);
+    header[38] = b'r';
+    let dim = [3, volume.dims[0] as i16, volume.dims[1] as i16, volume.dims[2] as i16, 1, 1, 1, 1];
+    for (i, value) in dim.iter().enumerate() {
+        put_i16(&mut header, 40 + i * 2, *value);
+    }
+    put_i16(&mut header, 70, datatype);
+    put_i16(&mut header, 72, bitpix);
+    let pixdim = [qfac, volume.spacing[0], volume.spacing[1], volume.spacing[2], 0.0, 0.0, 0.0, 0.0];
+    for (i, value) in pixdim.iter().enumerate() {
+        put_f32(&mut header, 76 + i * 4, *value);
+    }
+    put_f32(&mut header, 108, VOX_OFFSET as f64);
+    put_f32(&mut header, 112, 1.0);
+    header[123] = XYZT_UNITS;
+    let description = format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+    let description = &description.as_bytes()[..description.len().min(79)];
+    header[148..148 + description.len()].copy_from_slice(description);
+    put_i16(&mut header, 252, XFORM_SCANNER_ANAT);
+    put_i16(&mut header, 254, XFORM_SCANNER_ANAT);
+    for (i, value) in quatern.iter().enumerate() {
+        put_f32(&mut header, 256 + i * 4, *value);
+    }
+    for (i, row) in affine.iter().enumerate() {
+        put_f32(&mut header, 268 + i * 4, row[3]);
+        for (j, value) in row.iter().enumerate() {
+            put_f32(&mut header, 280 + i * 16 + j * 4, *value);
+        }
+    }
+    header[344..348].copy_from_slice(b"n+1\0");
+    header
+}
+
+/// Кватернион поворота (b, c, d) для qform и qfac. Третья ось — нормаль к плоскости среза,
+/// направленная в сторону сдвига срезов; если тройка осей левая, qfac = -1 (NIfTI-1, mat44_to_quatern)
+fn quaternion(volume: &Volume) -> ([f64; 3], f64) {
+    let [row, column, slices] = volume.directions;
+    let normal = work_geometry::normal(&[row[0], row[1], row[2], column[0], column[1], column[2]]);
+    let qfac = if work_geometry::dot(&normal, &slices) < 0.0 { -1.0 } else { 1.0 };
+    // Матрица поворота в RAS: столбцы — косинусы строки, столбца и нормаль (правая тройка);
+    // обратное направление срезов задается через qfac
+    let axes = [row, column, normal];
+    let r = |i: usize, j: usize| if i < 2 { -axes[j][i] } else { axes[j][i] };
+    let (r11, r12, r13) = (r(0, 0), r(0, 1), r(0, 2));
+    let (r21, r22, r23) = (r(1, 0), r(1, 1), r(1, 2));
+    let (r31, r32, r33) = (r(2, 0), r(2, 1), r(2, 2));
+    let trace = r11 + r22 + r33 + 1.0;
+    let (a, b, c, d);
+    if trace > 0.5 {
+        a = 0.5 * trace.sqrt();
+        b = 0.25 * (r32 - r23) / a;
+        c = 0.25 * (r13 - r31) / a;
+        d = 0.25 * (r21 - r12) / a;
+    } else {
+        let xd = 1.0 + r11 - (r22 + r33);
+        let yd = 1.0 + r22 - (r11 + r33);
+        let zd = 1.0 + r33 - (r11 + r22);
+        if xd > 1.0 {
+            b = 0.5 * xd.sqrt();
+            c = 0.25 * (r12 + r21) / b;
+            d = 0.25 * (r13 + r31) / b;
+            a = 0.25 * (r32 - r23) / b;
+        } else if yd > 1.0 {
+            c = 0.5 * yd.sqrt();
+            b = 0.25 * (r12 + r21) / c;
+            d = 0.25 * (r23 + r32) / c;
+            a = 0.25 * (r13 - r31) / c;
+        } else {
+            d = 0.5 * zd.sqrt();
+            b = 0.25 * (r13 + r31) / d;
+            c = 0.25 * (r23 + r32) / d;
+            a = 0.25 * (r21 - r12) / d;
+        }
+    }
+    let quatern = if a < 0.0 { [-b, -c, -d] } else { [b, c, d] };
+    (quatern, qfac)
+}
+
+fn put_i16(header: &mut [u8], offset: usize, value: i16) {
+    header[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_i32(header: &mut [u8], offset: usize, value: i32) {
+    header[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_f32(header: &mut [u8], offset: usize, value: f64) {
+    header[offset..offset + 4].copy_from_slice(&(value as f32).to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Map;
+
+    /// Объем 4x3x2 с направлениями `row`, `column`, `slices` в LPS и шагом 0.5x0.8x2.5 мм
+    fn volume(row: [f64; 3], column: [f64; 3], slices: [f64; 3]) -> Volume {
+        Volume {
+            dims: [4, 3, 2],
+            spacing: [0.5, 0.8, 2.5],
+            origin: [-100.0, 20.0, 50.0],
+            directions: [row, column, slices],
+            voxels: vec![0.0; 24],
+            filled_slices: 0,
+            issues: Vec::new(),
+            metadata: Map::new(),
+        }
+    }
+
+    fn get_i16(header: &[u8], offset: usize) -> i16 {
+        i16::from_le_bytes(header[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn get_f32(header: &[u8], offset: usize) -> f64 {
+        f32::from_le_bytes(header[offset..offset + 4].try_into().unwrap()) as f64
+    }
+
+    /// srow_x, srow_y, srow_z из заголовка
+    fn srow(header: &[u8]) -> [[f64; 4]; 3] {
+        let mut srow = [[0.0; 4]; 3];
+        for (i, row) in srow.iter_mut().enumerate() {
+            for (j, value) in row.iter_mut().enumerate() {
+                *value = get_f32(header, 280 + i * 16 + j * 4);
+            }
+        }
+        srow
+    }
+
+    /// Матрица 3x3 qform, восстановленная из кватерниона, qfac и pixdim (NIfTI-1, quatern_to_mat44)
+    fn qform(header: &[u8]) -> [[f64; 3]; 3] {
+        let (b, c, d) = (get_f32(header, 256), get_f32(header, 260), get_f32(header, 264));
+        let a = (1.0 - (b * b + c * c + d * d)).max(0.0).sqrt();
+        let rotation = [
+            [a * a + b * b - c * c - d * d, 2.0 * (b * c - a * d), 2.0 * (b * d + a * c)],
+            [2.0 * (b * c + a * d), a * a + c * c - b * b - d * d, 2.0 * (c * d - a * b)],
+            [2.0 * (b * d - a * c), 2.0 * (c * d + a * b), a * a + d * d - c * c - b * b],
+        ];
+        let qfac = get_f32(header, 76);
+        let pixdim = [get_f32(header, 80), get_f32(header, 84), get_f32(header, 88) * qfac];
+        let mut matrix = [[0.0; 3]; 3];
+        for (i, row) in matrix.iter_mut().enumerate() {
+            for (j, value) in row.iter_mut().enumerate() {
+                *value = rotation[i][j] * pixdim[j];
+            }
+        }
+        matrix
+    }
+
+    fn assert_close_within(actual: &[f64], expected: &[f64], tolerance: f64) {
+        assert!(actual.iter().zip(expected).all(|(a, b)| (a - b).abs() < tolerance), "{:?} != {:?}", actual, expected);
+    }
+
+    fn assert_close(actual: &[f64], expected: &[f64]) {
+        assert_close_within(actual, expected, 1e-5);
+    }
+
+    fn assert_srow(header: &[u8], expected: [[f64; 4]; 3]) {
+        for (row, expected) in srow(header).iter().zip(expected.iter()) {
+            assert_close(row, expected);
+        }
+    }
+
+    /// qform совпадает с поворотной частью sform (наклона гентри нет). Компонента a
+    /// кватерниона не хранится и при повороте около 180° восстанавливается с погрешностью float
+    fn assert_qform_matches_srow(header: &[u8]) {
+        let (qform, srow) = (qform(header), srow(header));
+        for i in 0..3 {
+            assert_close_within(&qform[i], &srow[i][..3], 1e-3);
+        }
+        // Смещение qform хранится отдельно и совпадает со смещением sform
+        assert_close(&[get_f32(header, 268), get_f32(header, 272), get_f32(header, 276)],
+                     &[srow[0][3], srow[1][3], srow[2][3]]);
+    }
+
+    #[test]
+    fn axial_stack_is_flipped_to_ras() {
+        let volume = volume([1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]);
+        let header = header(&volume, volume.sample_type());
+        assert_eq!(&header[344..348], b"n+1\0");
+        assert_eq!((get_i16(&header, 40), get_i16(&header, 42), get_i16(&header, 44), get_i16(&header, 46)), (3, 4, 3, 2));
+        assert_eq!((get_i16(&header, 252), get_i16(&header, 254)), (XFORM_SCANNER_ANAT, XFORM_SCANNER_ANAT));
+        assert_eq!(get_f32(&header, 108), VOX_OFFSET as f64);
+        // Left и Posterior становятся Right и Anterior со сменой знака
+        assert_srow(&header, [
+            [-0.5, 0.0, 0.0, 100.0],
+            [0.0, -0.8, 0.0, -20.0],
+            [0.0, 0.0, 2.5, 50.0],
+        ]);
+        // Поворот на 180° вокруг z, правая тройка осей
+        assert_eq!(get_f32(&header, 76), 1.0);
+        assert_close(&[get_f32(&header, 256), get_f32(&header, 260), get_f32(&header, 264)], &[0.0, 0.0, 1.0]);
+        assert_qform_matches_srow(&header);
+    }
+
+    #[test]
+    fn reversed_stack_sets_negative_qfac() {
+        // Срезы идут от головы к ногам: нормаль к плоскости противоположна направлению срезов
+        let volume = volume([1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, -1.0]);
+        let header = header(&volume, volume.sample_type());
+        assert_close(&srow(&header)[2], &[0.0, 0.0, -2.5, 50.0]);
+        assert_eq!(get_f32(&header, 76), -1.0);
+        assert_close(&[get_f32(&header, 256), get_f32(&header, 260), get_f32(&header, 264)], &[0.0, 0.0, 1.0]);
+        assert_qform_matches_srow(&header);
+    }
+
+    #[test]
+    fn oblique_and_coronal_stacks_keep_the_rotation() {
+        let (sin, cos) = 30f64.to_radians().sin_cos();
+        let oblique = volume([1.0, 0.0, 0.0], [0.0, cos, sin], [0.0, -sin, cos]);
+        let header = header(&oblique, oblique.sample_type());
+        assert_eq!(get_f32(&header, 76), 1.0);
+        assert_close(&srow(&header)[1], &[0.0, -0.8 * cos, 2.5 * sin, -20.0]);
+        assert_close(&srow(&header)[2], &[0.0, 0.8 * sin, 2.5 * cos, 50.0]);
+        assert_qform_matches_srow(&header);
+
+        // Корональная серия из work_volume: след матрицы поворота мал, ветвь без a
+        let coronal = crate::work_volume::tests::synthetic_volume();
+        let header = super::header(&coronal, coronal.sample_type());
+        assert_srow(&header, [
+            [-0.5, 0.0, 0.0, 100.0],
+            [0.0, 0.0, -2.5, -10.0],
+            [0.0, -0.8, 0.0, 50.0],
+        ]);
+        assert_qform_matches_srow(&header);
+    }
+}
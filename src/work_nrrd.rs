@@ -0,0 +1,83 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use crate::work_volume::{SampleType, Volume};
+
+
+/// Записывает объем в NRRD с заголовком в том же файле и данными, сжатыми gzip.
+/// Координаты остаются в LPS (`space: left-posterior-superior`), как в DICOM
+pub fn write(volume: &Volume, target: &path::Path) -> io::Result<()> {
+    let sample_type = volume.sample_type();
+    let mut file = BufWriter::new(File::create(target)?);
+    file.write_all(header(volume, sample_type).as_bytes())?;
+    let mut writer = GzEncoder::new(file, Compression::default());
+    writer.write_all(&volume.sample_bytes(sample_type))?;
+    writer.finish()?.flush()
+}
+
+/// Заголовок NRRD0004 (вместе с пустой строкой, после которой идут данные).
+/// `space directions` — векторы шага по столбцам, строкам и срезам, `space origin` — центр первого вокселя
+fn header(volume: &Volume, sample_type: SampleType) -> String {
+    let affine = volume.affine();
+    let direction = |axis: usize| format!("({},{},{})", affine[0][axis], affine[1][axis], affine[2][axis]);
+    let type_name = match sample_type {
+        SampleType::Int16 => "short",
+        SampleType::Int32 => "int",
+        SampleType::Float32 => "float",
+    };
+    format!(
+        "NRRD0004\n\
+         # Complete NRRD file format specification at:\n\
+         # http://teem.sourceforge.net/nrrd/format.html\n\
+         type: {}\n\
+         dimension: 3\n\
+         space: left-posterior-superior\n\
+         sizes: {} {} {}\n\
+         space directions: {} {} {}\n\
+         kinds: domain domain domain\n\
+         endian: little\n\
+         encoding: gzip\n\
+         space origin: ({},{},{})\n\n",
+        type_name,
+        volume.dims[0], volume.dims[1], volume.dims[2],
+        direction(0), direction(1), direction(2),
+        volume.origin[0], volume.origin[1], volume.origin[2])
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::work_volume::tests::synthetic_volume;
+
+    #[test]
+    fn header_holds_space_directions_and_origin() {
+        let volume = synthetic_volume();
+        let header = header(&volume, volume.sample_type());
+        let lines: Vec<&str> = header.lines().collect();
+        assert_eq!(lines[0], "NRRD0004");
+        assert!(lines.contains(&"type: short"));
+        assert!(lines.contains(&"space: left-posterior-superior"));
+        assert!(lines.contains(&"sizes: 4 3 3"));
+        assert!(lines.contains(&"space directions: (0.5,0,0) (0,0,-0.8) (0,2.5,0)"));
+        assert!(lines.contains(&"space origin: (-100,10,50)"));
+        assert!(header.ends_with("\n\n"));
+    }
+
+    #[test]
+    fn data_follows_the_header() {
+        let volume = synthetic_volume();
+        let target = std::env::temp_dir().join(format!("dcm_finder_test_{}.nrrd", std::process::id()));
+        write(&volume, &target).unwrap();
+        let data = std::fs::read(&target).unwrap();
+        std::fs::remove_file(&target).unwrap();
+        let header = header(&volume, volume.sample_type());
+        assert!(data.starts_with(header.as_bytes()));
+        let mut samples = Vec::new();
+        std::io::Read::read_to_end(&mut flate2::read::GzDecoder::new(&data[header.len()..]), &mut samples).unwrap();
+        assert_eq!(samples, volume.sample_bytes(SampleType::Int16));
+    }
+}
@@ -0,0 +1,348 @@
+use dicom::core::value::Value;
+use dicom::object::DefaultDicomObject;
+
+use crate::work_json;
+#[cfg(feature = "codecs")]
+use crate::{work_j2k, work_jpeg, work_jpegls, work_rle};
+
+
+/// Пиксели экземпляра: сохраненные значения (до Rescale) по порядку кадр, строка, столбец, компонента
+#[derive(Debug, Clone)]
+pub struct Pixels {
+    pub rows: usize,
+    pub columns: usize,
+    pub samples_per_pixel: usize,
+    pub frames: usize,
+    pub bits_allocated: usize,
+    pub bits_stored: usize,
+    /// Photometric Interpretation значений: после перевода YBR в RGB здесь "RGB"
+    pub photometric: String,
+    pub values: Vec<i32>,
+}
+
+impl Pixels {
+    /// Формат одного кадра
+    pub fn frame_format(&self) -> FrameFormat {
+        FrameFormat {
+            rows: self.rows,
+            columns: self.columns,
+            samples_per_pixel: self.samples_per_pixel,
+            bits_allocated: self.bits_allocated,
+            bits_stored: self.bits_stored,
+        }
+    }
+}
+
+/// Размеры и разрядность одного кадра — то, что нужно кодекам
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameFormat {
+    pub rows: usize,
+    pub columns: usize,
+    pub samples_per_pixel: usize,
+    pub bits_allocated: usize,
+    pub bits_stored: usize,
+}
+
+impl FrameFormat {
+    pub fn bytes_per_sample(&self) -> usize {
+        (self.bits_allocated / 8).max(1)
+    }
+
+    /// Число значений в кадре
+    pub fn samples(&self) -> usize {
+        self.rows * self.columns * self.samples_per_pixel
+    }
+}
+
+/// Извлекает значения пикселей из Pixel Data.
+/// Поддерживаются 8, 16 и 32 бита на значение; значения со знаком расширяются по Bits Stored,
+/// цветные кадры с Planar Configuration 1 приводятся к чередованию компонент, YBR_FULL — к RGB.
+/// Сжатые синтаксисы (RLE, JPEG, JPEG-LS, JPEG 2000) распаковываются при включенной функции `codecs`
+pub fn decode(obj: &DefaultDicomObject) -> Result<Pixels, String> {
+    let rows = number(obj, "Rows").ok_or("no Rows")?;
+    let columns = number(obj, "Columns").ok_or("no Columns")?;
+    let samples_per_pixel = number(obj, "SamplesPerPixel").unwrap_or(1);
+    let frames = number(obj, "NumberOfFrames").unwrap_or(1).max(1);
+    let bits_allocated = number(obj, "BitsAllocated").ok_or("no Bits Allocated")?;
+    let bits_stored = number(obj, "BitsStored").unwrap_or(bits_allocated);
+    let signed = number(obj, "PixelRepresentation") == Some(1);
+    let mut photometric = obj.element_by_name("PhotometricInterpretation").ok()
+        .and_then(|element| element.to_str().ok().map(|value| value.trim().to_string()))
+        .unwrap_or_else(|| "MONOCHROME2".to_string());
+    let format = FrameFormat { rows, columns, samples_per_pixel, bits_allocated, bits_stored };
+    if !matches!(bits_allocated, 8 | 16 | 32) {
+        return Err(format!("Bits Allocated {} is not supported", bits_allocated));
+    }
+    let shift = 32 - bits_stored.clamp(1, 32) as u32;
+    let extend = |raw: u32| if signed {
+        ((raw << shift) as i32) >> shift
+    } else {
+        ((raw << shift) >> shift) as i32
+    };
+
+    let mut values = match obj.element(work_json::PIXEL_DATA).map_err(|_| "no Pixel Data")?.value() {
+        Value::Primitive(value) => {
+            let data = value.to_bytes();
+            let count = format.samples().checked_mul(frames)
+                .ok_or_else(|| format!("Number of Frames {} is too large", frames))?;
+            let bytes = format.bytes_per_sample();
+            let expected = count.checked_mul(bytes)
+                .ok_or_else(|| format!("Number of Frames {} is too large", frames))?;
+            if data.len() < expected {
+                return Err(format!("Pixel Data is too short: {} bytes, expected {}", data.len(), expected));
+            }
+            let values: Vec<i32> = data.chunks_exact(bytes).take(count)
+                .map(|chunk| extend(match bytes {
+                    1 => chunk[0] as u32,
+                    2 => u16::from_le_bytes([chunk[0], chunk[1]]) as u32,
+                    _ => u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]),
+                }))
+                .collect();
+            if samples_per_pixel > 1 && number(obj, "PlanarConfiguration") == Some(1) {
+                interleave(&values, rows * columns, samples_per_pixel)
+            } else {
+                values
+            }
+        }
+        Value::PixelSequence { offset_table, fragments } => {
+            let transfer_syntax = obj.meta().transfer_syntax().trim_end_matches('\0').trim();
+            let frame_data = split_frames(offset_table, fragments, frames)?;
+            if frame_data.len() != frames {
+                return Err(format!("{} frames in Pixel Data, Number of Frames is {}", frame_data.len(), frames));
+            }
+            let mut values = Vec::new();
+            for frame in frame_data {
+                let decoded = decode_frame(transfer_syntax, &frame, &format, &mut photometric)?;
+                if decoded.len() < format.samples() {
+                    return Err(format!("frame holds {} values, expected {}", decoded.len(), format.samples()));
+                }
+                values.extend(decoded.into_iter().take(format.samples()).map(|value| extend(value as u32)));
+            }
+            values
+        }
+        Value::Sequence { .. } => return Err("Pixel Data is a sequence".to_string()),
+    };
+    if samples_per_pixel == 3 && photometric == "YBR_FULL" && bits_allocated == 8 {
+        ybr_to_rgb(&mut values);
+        photometric = "RGB".to_string();
+    }
+    Ok(Pixels { rows, columns, samples_per_pixel, frames, bits_allocated, bits_stored, photometric, values })
+}
+
+/// Собирает кадры из фрагментов инкапсулированного Pixel Data (PS3.5 A.4): по Basic Offset Table,
+/// а без нее — по фрагменту на кадр, все фрагменты в единственный кадр или по маркерам начала
+/// кодового потока (SOI JPEG/JPEG-LS, SOC JPEG 2000) в начале фрагментов
+pub fn split_frames(offset_table: &[u32], fragments: &[Vec<u8>], frames: usize) -> Result<Vec<Vec<u8>>, String> {
+    if !offset_table.is_empty() {
+        // Смещение фрагмента отсчитывается от первого фрагмента и включает 8 байт заголовков предыдущих
+        let mut starts = Vec::with_capacity(fragments.len());
+        let mut position = 0u64;
+        for fragment in fragments {
+            starts.push(position);
+            position += 8 + fragment.len() as u64;
+        }
+        let mut result = Vec::with_capacity(offset_table.len());
+        for (index, offset) in offset_table.iter().enumerate() {
+            let end = offset_table.get(index + 1).map_or(u64::MAX, |next| *next as u64);
+            let frame: Vec<u8> = starts.iter().zip(fragments)
+                .filter(|(start, _)| **start >= *offset as u64 && **start < end)
+                .flat_map(|(_, fragment)| fragment.iter().copied())
+                .collect();
+            if frame.is_empty() {
+                return Err(format!("Basic Offset Table entry {} does not point to a fragment", index + 1));
+            }
+            result.push(frame);
+        }
+        return Ok(result);
+    }
+    if fragments.len() == frames {
+        Ok(fragments.to_vec())
+    } else if frames == 1 {
+        Ok(vec![fragments.concat()])
+    } else {
+        let mut result: Vec<Vec<u8>> = Vec::with_capacity(fragments.len());
+        for fragment in fragments {
+            match result.last_mut() {
+                Some(frame) if !starts_codestream(fragment) => frame.extend_from_slice(fragment),
+                _ => result.push(fragment.clone()),
+            }
+        }
+        if result.len() == frames {
+            Ok(result)
+        } else {
+            Err(format!("{} fragments for {} frames and no Basic Offset Table", fragments.len(), frames))
+        }
+    }
+}
+
+/// Фрагмент начинается с маркера начала кодового потока JPEG (FFD8) или JPEG 2000 (FF4F)
+fn starts_codestream(fragment: &[u8]) -> bool {
+    matches!(fragment, [0xFF, 0xD8, 0xFF, ..] | [0xFF, 0x4F, 0xFF, 0x51, ..])
+}
+
+/// Распаковывает один кадр по синтаксису передачи. Кодеки возвращают беззнаковые значения,
+/// знак восстанавливает вызывающий. `photometric` обновляется, если кодек перевел цвет в RGB
+#[cfg(feature = "codecs")]
+fn decode_frame(transfer_syntax: &str, frame: &[u8], format: &FrameFormat, photometric: &mut String) -> Result<Vec<i32>, String> {
+    match transfer_syntax {
+        "1.2.840.10008.1.2.5" => work_rle::decode(frame, format),
+        "1.2.840.10008.1.2.4.50" | "1.2.840.10008.1.2.4.51" | "1.2.840.10008.1.2.4.57" | "1.2.840.10008.1.2.4.70" => {
+            let ybr = photometric.starts_with("YBR");
+            let values = work_jpeg::decode(frame, format, ybr)?;
+            if ybr && format.samples_per_pixel == 3 {
+                *photometric = "RGB".to_string();
+            }
+            Ok(values)
+        }
+        "1.2.840.10008.1.2.4.80" | "1.2.840.10008.1.2.4.81" => work_jpegls::decode(frame, format),
+        "1.2.840.10008.1.2.4.90" | "1.2.840.10008.1.2.4.91" => {
+            let (values, transformed) = work_j2k::decode(frame, format)?;
+            if transformed {
+                *photometric = "RGB".to_string();
+            }
+            Ok(values)
+        }
+        _ => Err(format!("compressed Pixel Data is not supported (transfer syntax {})", transfer_syntax)),
+    }
+}
+
+#[cfg(not(feature = "codecs"))]
+fn decode_frame(transfer_syntax: &str, _frame: &[u8], _format: &FrameFormat, _photometric: &mut String) -> Result<Vec<i32>, String> {
+    Err(format!("compressed Pixel Data is not supported without the codecs feature (transfer syntax {})", transfer_syntax))
+}
+
+/// Переводит 8-битные YBR_FULL (PS3.3 C.7.6.3.1.2) в RGB
+fn ybr_to_rgb(values: &mut [i32]) {
+    for pixel in values.chunks_exact_mut(3) {
+        let (y, cb, cr) = (pixel[0] as f64, pixel[1] as f64 - 128.0, pixel[2] as f64 - 128.0);
+        let rgb = [y + 1.402 * cr, y - 0.344136 * cb - 0.714136 * cr, y + 1.772 * cb];
+        for (value, component) in pixel.iter_mut().zip(rgb) {
+            *value = component.round().clamp(0.0, 255.0) as i32;
+        }
+    }
+}
+
+/// Переставляет кадры из плоскостей (RRR...GGG...BBB...) в чередование компонент (RGBRGB...)
+fn interleave(values: &[i32], pixels: usize, samples: usize) -> Vec<i32> {
+    let mut result = Vec::with_capacity(values.len());
+    for frame in values.chunks(pixels * samples) {
+        for pixel in 0..pixels {
+            result.extend((0..samples).map(|sample| frame[sample * pixels + pixel]));
+        }
+    }
+    result
+}
+
+fn number(obj: &DefaultDicomObject, keyword: &str) -> Option<usize> {
+    obj.element_by_name(keyword).ok()?.to_int::<usize>().ok()
+}
+
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use dicom::core::{DataElement, PrimitiveValue, Tag, VR};
+    use dicom::object::{FileMetaTableBuilder, InMemDicomObject};
+
+    /// Монохромный кадр 40x30: фон нулями и диск с градиентом до максимума Bits Stored
+    pub(crate) fn synthetic_image(bits_allocated: usize, bits_stored: usize) -> (Vec<i32>, FrameFormat) {
+        let format = FrameFormat { rows: 30, columns: 40, samples_per_pixel: 1, bits_allocated, bits_stored };
+        let max = (1i64 << bits_stored) - 1;
+        let mut values = Vec::with_capacity(format.samples());
+        for y in 0..format.rows as i64 {
+            for x in 0..format.columns as i64 {
+                let (dx, dy) = (x - 20, y - 15);
+                let inside = dx * dx + dy * dy < 144;
+                values.push(if inside { (max * (x * 3 + y * 5) / 140).min(max) as i32 } else { 0 });
+            }
+        }
+        values[15 * 40 + 31] = max as i32;
+        (values, format)
+    }
+
+    /// Цветной 8-битный кадр 24x16: красный и зеленый градиенты, синяя шахматка
+    pub(crate) fn synthetic_rgb() -> (Vec<i32>, FrameFormat) {
+        let format = FrameFormat { rows: 16, columns: 24, samples_per_pixel: 3, bits_allocated: 8, bits_stored: 8 };
+        let mut values = Vec::with_capacity(format.samples());
+        for y in 0..format.rows as i32 {
+            for x in 0..format.columns as i32 {
+                values.extend([x * 10, y * 15, if (x / 8 + y / 8) % 2 == 0 { 200 } else { 50 }]);
+            }
+        }
+        (values, format)
+    }
+
+    #[test]
+    fn frames_follow_offset_table() {
+        let fragments = vec![vec![1, 2], vec![3, 4, 5, 6], vec![7, 8]];
+        // кадр 1 — фрагменты 1 и 2, кадр 2 — фрагмент 3 (смещение 8 + 2 + 8 + 4)
+        let frames = split_frames(&[0, 22], &fragments, 2).unwrap();
+        assert_eq!(frames, vec![vec![1, 2, 3, 4, 5, 6], vec![7, 8]]);
+        assert!(split_frames(&[0, 40], &fragments, 2).is_err());
+    }
+
+    #[test]
+    fn frames_without_offset_table() {
+        let fragments = vec![vec![1, 2], vec![3, 4]];
+        assert_eq!(split_frames(&[], &fragments, 2).unwrap(), fragments);
+        assert_eq!(split_frames(&[], &fragments, 1).unwrap(), vec![vec![1, 2, 3, 4]]);
+        assert!(split_frames(&[], &fragments, 3).is_err());
+    }
+
+    #[test]
+    fn frames_split_on_codestream_markers() {
+        let fragments = vec![
+            vec![0xFF, 0xD8, 0xFF, 0xDB, 1], vec![2, 0xFF, 0xD9],
+            vec![0xFF, 0xD8, 0xFF, 0xDB, 3], vec![4], vec![0xFF, 0xD9],
+        ];
+        let frames = split_frames(&[], &fragments, 2).unwrap();
+        assert_eq!(frames, vec![vec![0xFF, 0xD8, 0xFF, 0xDB, 1, 2, 0xFF, 0xD9],
+                                vec![0xFF, 0xD8, 0xFF, 0xDB, 3, 4, 0xFF, 0xD9]]);
+        let j2k = vec![vec![0xFF, 0x4F, 0xFF, 0x51, 1], vec![2], vec![0xFF, 0x4F, 0xFF, 0x51, 3]];
+        assert_eq!(split_frames(&[], &j2k, 2).unwrap().len(), 2);
+        assert!(split_frames(&[], &fragments, 3).is_err());
+    }
+
+    /// Экземпляр RLE с кадрами 2x2 по 8 бит из фрагментов `fragments`
+    fn encapsulated(number_of_frames: &str, offset_table: Vec<u32>, fragments: Vec<Vec<u8>>) -> DefaultDicomObject {
+        let mut obj = InMemDicomObject::new_empty();
+        let us = |tag, value: u16| DataElement::new(tag, VR::US, PrimitiveValue::from(value));
+        obj.put(us(Tag(0x0028, 0x0010), 2));
+        obj.put(us(Tag(0x0028, 0x0011), 2));
+        obj.put(us(Tag(0x0028, 0x0100), 8));
+        obj.put(DataElement::new(Tag(0x0028, 0x0008), VR::IS, PrimitiveValue::from(number_of_frames)));
+        obj.put(DataElement::new(work_json::PIXEL_DATA, VR::OB,
+                                 Value::PixelSequence { offset_table: offset_table.into(), fragments: fragments.into() }));
+        obj.with_meta(FileMetaTableBuilder::new()
+            .media_storage_sop_class_uid("1.2.840.10008.5.1.4.1.1.7")
+            .media_storage_sop_instance_uid("1.2.3.4")
+            .transfer_syntax("1.2.840.10008.1.2.5"))
+            .unwrap()
+    }
+
+    #[test]
+    fn frame_count_must_match_number_of_frames() {
+        // Basic Offset Table указывает на один кадр из четырех
+        let fragments = vec![vec![0; 8]; 4];
+        let error = decode(&encapsulated("4", vec![0], fragments.clone())).unwrap_err();
+        assert_eq!(error, "1 frames in Pixel Data, Number of Frames is 4");
+        // Огромное Number of Frames не приводит к выделению памяти по заголовку
+        assert!(decode(&encapsulated("2000000000", Vec::new(), fragments)).is_err());
+
+        let mut obj = encapsulated("2000000000", Vec::new(), Vec::new());
+        obj.put(DataElement::new(Tag(0x0028, 0x0010), VR::US, PrimitiveValue::from(u16::MAX)));
+        obj.put(DataElement::new(Tag(0x0028, 0x0011), VR::US, PrimitiveValue::from(u16::MAX)));
+        obj.put(DataElement::new(Tag(0x0028, 0x0002), VR::US, PrimitiveValue::from(3u16)));
+        obj.put(DataElement::new(Tag(0x0028, 0x0100), VR::US, PrimitiveValue::from(32u16)));
+        obj.put(DataElement::new(work_json::PIXEL_DATA, VR::OB, PrimitiveValue::from(vec![0u8; 16])));
+        assert_eq!(decode(&obj).unwrap_err(), "Number of Frames 2000000000 is too large");
+    }
+
+    #[test]
+    fn ybr_full_becomes_rgb() {
+        let mut values = vec![128, 128, 128, 76, 85, 255];
+        ybr_to_rgb(&mut values);
+        assert_eq!(&values[..3], &[128, 128, 128]);
+        assert!(values[3] > 250 && values[4] < 5 && values[5] < 5);
+    }
+}
@@ -0,0 +1,621 @@
+use std::collections::BTreeMap;
+use dicom::core::{PrimitiveValue, Tag, VR};
+use dicom::encoding::TransferSyntaxIndex;
+use dicom::object::mem::InMemElement;
+use dicom::object::InMemDicomObject;
+use dicom::transfer_syntax::TransferSyntaxRegistry;
+
+use crate::work_dcm;
+use crate::work_db;
+use crate::work_db::Dcm;
+use crate::work_dimse;
+use crate::work_dimse::{PeerAssociation, SendCounts, Service};
+
+
+/// Query/Retrieve SOP Classes, по которым индекс доступен как PACS
+pub const QUERY_RETRIEVE_SOP_CLASSES: &[&str] = &[
+    "1.2.840.10008.5.1.4.1.2.1.1", // Patient Root Query/Retrieve Information Model - FIND
+    "1.2.840.10008.5.1.4.1.2.1.2", // Patient Root Query/Retrieve Information Model - MOVE
+    "1.2.840.10008.5.1.4.1.2.1.3", // Patient Root Query/Retrieve Information Model - GET
+    "1.2.840.10008.5.1.4.1.2.2.1", // Study Root Query/Retrieve Information Model - FIND
+    "1.2.840.10008.5.1.4.1.2.2.2", // Study Root Query/Retrieve Information Model - MOVE
+    "1.2.840.10008.5.1.4.1.2.2.3", // Study Root Query/Retrieve Information Model - GET
+];
+
+/// Общий префикс SOP Class UID модели Patient Root
+const PATIENT_ROOT_PREFIX: &str = "1.2.840.10008.5.1.4.1.2.1.";
+
+const MOVE_DESTINATION: Tag = Tag(0x0000, 0x0600);
+const NUMBER_OF_REMAINING_SUBOPERATIONS: Tag = Tag(0x0000, 0x1020);
+const NUMBER_OF_COMPLETED_SUBOPERATIONS: Tag = Tag(0x0000, 0x1021);
+const NUMBER_OF_FAILED_SUBOPERATIONS: Tag = Tag(0x0000, 0x1022);
+const NUMBER_OF_WARNING_SUBOPERATIONS: Tag = Tag(0x0000, 0x1023);
+
+const SPECIFIC_CHARACTER_SET: Tag = Tag(0x0008, 0x0005);
+const QUERY_RETRIEVE_LEVEL: Tag = Tag(0x0008, 0x0052);
+const RETRIEVE_AE_TITLE: Tag = Tag(0x0008, 0x0054);
+
+const C_GET_RSP: u16 = 0x8010;
+const C_FIND_RSP: u16 = 0x8020;
+const C_MOVE_RSP: u16 = 0x8021;
+
+/// Pending: найдено совпадение (C-FIND) или выполняются подоперации (C-MOVE/C-GET)
+const STATUS_PENDING: u16 = 0xFF00;
+/// Warning: подоперации выполнены, одна или несколько с ошибкой или предупреждением
+const STATUS_SUBOPERATIONS_WARNING: u16 = 0xB000;
+/// Failure: Unable to perform sub-operations — ни одна подоперация не выполнена
+const STATUS_SUBOPERATIONS_FAILED: u16 = 0xA702;
+/// Failure: Move Destination unknown
+const STATUS_MOVE_DESTINATION_UNKNOWN: u16 = 0xA801;
+/// Failure: Identifier does not match SOP Class
+const STATUS_IDENTIFIER_DOES_NOT_MATCH: u16 = 0xA900;
+
+
+/// Отвечает на C-FIND: по одному ответу Pending с идентификатором на каждое совпадение
+/// в индексе, затем итоговый ответ
+pub fn handle_find(association: &mut PeerAssociation, pc_id: u8, command: &InMemDicomObject,
+                   data: Option<&[u8]>, service: &Service) {
+    let message_id = work_dimse::get_u16(command, work_dimse::MESSAGE_ID).unwrap_or(0);
+    let sop_class_uid = work_dimse::get_str(command, work_dimse::AFFECTED_SOP_CLASS_UID);
+    let transfer_syntax = context_transfer_syntax(association, pc_id);
+    let status = match read_query(data, &transfer_syntax).and_then(|(identifier, level, keys)| {
+        let entities = open_index(service)?
+            .query_entities(level, &keys, None, 0)
+            .map_err(|e| (work_dimse::STATUS_CANNOT_UNDERSTAND, format!("{:?}", e)))?;
+        Ok((identifier, entities))
+    }) {
+        Ok((identifier, entities)) => {
+            println!("C-FIND from {}: {} matches", association.calling_aet(), entities.len());
+            let ts = TransferSyntaxRegistry.get(&transfer_syntax);
+            for entity in &entities {
+                let response = response_identifier(&identifier, entity, &service.options.aet);
+                let mut encoded = Vec::new();
+                if let Some(ts) = ts {
+                    response.write_dataset_with_ts(&mut encoded, ts).unwrap_or_else(|e| {
+                        eprintln!("Error encoding C-FIND response: {:?}", e);
+                    });
+                }
+                let mut pending = work_dimse::response_command(
+                    C_FIND_RSP, message_id, &sop_class_uid, None, STATUS_PENDING);
+                pending.put(InMemElement::new(work_dimse::COMMAND_DATA_SET_TYPE, VR::US,
+                                              PrimitiveValue::from(work_dimse::DATA_SET_PRESENT)));
+                if let Err(e) = association.send_message(pc_id, &pending, Some(&encoded)) {
+                    eprintln!("Error sending C-FIND response to {}: {}", association.calling_aet(), e);
+                    return;
+                }
+            }
+            work_dimse::STATUS_SUCCESS
+        }
+        Err((status, e)) => {
+            eprintln!("C-FIND from {} failed: {}", association.calling_aet(), e);
+            status
+        }
+    };
+    let response = work_dimse::response_command(C_FIND_RSP, message_id, &sop_class_uid, None, status);
+    association.send_message(pc_id, &response, None).unwrap_or_else(|e| {
+        eprintln!("Error sending C-FIND response to {}: {}", association.calling_aet(), e);
+    });
+}
+
+/// Выполняет C-MOVE: отправляет найденные файлы получателю из `move_destinations`
+/// в отдельной ассоциации, сообщая о ходе ответами Pending
+pub fn handle_move(association: &mut PeerAssociation, pc_id: u8, command: &InMemDicomObject,
+                   data: Option<&[u8]>, service: &Service) {
+    let message_id = work_dimse::get_u16(command, work_dimse::MESSAGE_ID).unwrap_or(0);
+    let sop_class_uid = work_dimse::get_str(command, work_dimse::AFFECTED_SOP_CLASS_UID);
+    let destination = work_dimse::get_str(command, MOVE_DESTINATION);
+    let transfer_syntax = context_transfer_syntax(association, pc_id);
+    let calling_aet = association.calling_aet().to_string();
+    let target = match service.options.move_destinations.iter().find(|target| target.aet == destination) {
+        Some(target) => target.clone(),
+        None => {
+            eprintln!("C-MOVE from {}: unknown destination {}", calling_aet, destination);
+            let response = work_dimse::response_command(
+                C_MOVE_RSP, message_id, &sop_class_uid, None, STATUS_MOVE_DESTINATION_UNKNOWN);
+            association.send_message(pc_id, &response, None).unwrap_or_else(|e| {
+                eprintln!("Error sending C-MOVE response to {}: {}", calling_aet, e);
+            });
+            return;
+        }
+    };
+    let response = match retrieve_paths(data, &transfer_syntax, &sop_class_uid, service) {
+        Ok(paths) => {
+            println!("C-MOVE from {} to {}: {} instances", calling_aet, destination, paths.len());
+            let options = work_dimse::SendOptions {
+                target,
+                calling_aet: service.options.aet.clone(),
+                jobs: 1,
+                retries: 1,
+            };
+            let counts = work_dimse::send_with_progress(&paths, &options, &mut |remaining, counts| {
+                if remaining == 0 {
+                    return;
+                }
+                let pending = suboperations_response(
+                    C_MOVE_RSP, message_id, &sop_class_uid, STATUS_PENDING, Some(remaining), counts);
+                association.send_message(pc_id, &pending, None).unwrap_or_else(|e| {
+                    eprintln!("Error sending C-MOVE response to {}: {}", calling_aet, e);
+                });
+            });
+            suboperations_response(C_MOVE_RSP, message_id, &sop_class_uid, final_status(&counts), None, &counts)
+        }
+        Err((status, e)) => {
+            eprintln!("C-MOVE from {} failed: {}", calling_aet, e);
+            work_dimse::response_command(C_MOVE_RSP, message_id, &sop_class_uid, None, status)
+        }
+    };
+    association.send_message(pc_id, &response, None).unwrap_or_else(|e| {
+        eprintln!("Error sending C-MOVE response to {}: {}", calling_aet, e);
+    });
+}
+
+/// Выполняет C-GET: отправляет найденные файлы по C-STORE в той же ассоциации.
+/// Вызывающий узел должен предложить контексты Storage SOP Class с ролью SCP
+pub fn handle_get(association: &mut PeerAssociation, pc_id: u8, command: &InMemDicomObject,
+                  data: Option<&[u8]>, service: &Service) {
+    let message_id = work_dimse::get_u16(command, work_dimse::MESSAGE_ID).unwrap_or(0);
+    let sop_class_uid = work_dimse::get_str(command, work_dimse::AFFECTED_SOP_CLASS_UID);
+    let transfer_syntax = context_transfer_syntax(association, pc_id);
+    let calling_aet = association.calling_aet().to_string();
+    let response = match retrieve_paths(data, &transfer_syntax, &sop_class_uid, service) {
+        Ok(paths) => {
+            println!("C-GET from {}: {} instances", calling_aet, paths.len());
+            let mut counts = SendCounts::default();
+            for (i, path) in paths.iter().enumerate() {
+                let sub_message_id = message_id.wrapping_add(1).wrapping_add(i as u16);
+                match store_suboperation(association, path, sub_message_id) {
+                    Ok(work_dimse::STATUS_SUCCESS) => counts.sent += 1,
+                    Ok(status) if work_dimse::is_success_or_warning(status) => {
+                        counts.sent += 1;
+                        counts.warnings += 1;
+                    }
+                    Ok(status) => {
+                        eprintln!("C-GET {} to {}: status 0x{:04X}", path, calling_aet, status);
+                        counts.failed += 1;
+                    }
+                    Err(e) => {
+                        eprintln!("C-GET {} to {}: {}", path, calling_aet, e);
+                        counts.failed += 1;
+                    }
+                }
+                let remaining = paths.len() - i - 1;
+                if remaining > 0 {
+                    let pending = suboperations_response(
+                        C_GET_RSP, message_id, &sop_class_uid, STATUS_PENDING, Some(remaining), &counts);
+                    if let Err(e) = association.send_message(pc_id, &pending, None) {
+                        eprintln!("Error sending C-GET response to {}: {}", calling_aet, e);
+                        return;
+                    }
+                }
+            }
+            suboperations_response(C_GET_RSP, message_id, &sop_class_uid, final_status(&counts), None, &counts)
+        }
+        Err((status, e)) => {
+            eprintln!("C-GET from {} failed: {}", calling_aet, e);
+            work_dimse::response_command(C_GET_RSP, message_id, &sop_class_uid, None, status)
+        }
+    };
+    association.send_message(pc_id, &response, None).unwrap_or_else(|e| {
+        eprintln!("Error sending C-GET response to {}: {}", calling_aet, e);
+    });
+}
+
+/// Отправляет один файл по C-STORE в ассоциации C-GET и возвращает статус из C-STORE-RSP.
+/// Контекст выбирается по SOP Class файла: с его синтаксисом передачи,
+/// а для несжатых файлов — любой несжатый
+fn store_suboperation(association: &mut PeerAssociation, path: &str, message_id: u16) -> Result<u16, String> {
+    let dcm_obj = work_dcm::read_indexed_dcm(path).map_err(|e| e.to_string())?;
+    let meta = dcm_obj.meta();
+    let sop_class_uid = meta.media_storage_sop_class_uid.trim_end_matches('\0');
+    let sop_instance_uid = meta.media_storage_sop_instance_uid.trim_end_matches('\0');
+    let file_transfer_syntax = meta.transfer_syntax.trim_end_matches('\0');
+    let for_class: Vec<&work_dimse::AcceptedContext> = association.contexts().iter()
+        .filter(|pc| pc.abstract_syntax == sop_class_uid)
+        .collect();
+    let pc = for_class.iter()
+        .find(|pc| pc.transfer_syntax == file_transfer_syntax)
+        .or_else(|| for_class.iter().find(|pc| work_dimse::is_uncompressed(file_transfer_syntax)
+            && work_dimse::is_uncompressed(&pc.transfer_syntax)))
+        .ok_or_else(|| format!("no accepted presentation context for {} in {}", sop_class_uid, file_transfer_syntax))?;
+    let pc_id = pc.id;
+    let ts = TransferSyntaxRegistry.get(&pc.transfer_syntax)
+        .ok_or_else(|| format!("unknown transfer syntax {}", pc.transfer_syntax))?;
+    let mut data = Vec::new();
+    dcm_obj.write_dataset_with_ts(&mut data, ts).map_err(|e| e.to_string())?;
+    let command = work_dimse::store_command(sop_class_uid, sop_instance_uid, message_id);
+    association.send_message(pc_id, &command, Some(&data))?;
+    loop {
+        match association.receive_message()? {
+            Some((_, response, _)) => {
+                if work_dimse::get_u16(&response, work_dimse::COMMAND_FIELD) == Some(work_dimse::C_STORE_RSP)
+                    && work_dimse::get_u16(&response, work_dimse::MESSAGE_ID_BEING_RESPONDED_TO) == Some(message_id) {
+                    return work_dimse::get_u16(&response, work_dimse::STATUS)
+                        .ok_or_else(|| "C-STORE response without status".to_string());
+                }
+            }
+            None => return Err("association closed by the requestor".to_string()),
+        }
+    }
+}
+
+fn context_transfer_syntax(association: &PeerAssociation, pc_id: u8) -> String {
+    association.context(pc_id)
+        .map(|pc| pc.transfer_syntax.clone())
+        .unwrap_or_else(|| work_dcm::IMPLICIT_VR_LITTLE_ENDIAN.to_string())
+}
+
+fn open_index(service: &Service) -> Result<work_db::Connection, (u16, String)> {
+    work_db::Connection::open_dcm_tables(&service.db_path)
+        .map_err(|e| (work_dimse::STATUS_CANNOT_UNDERSTAND, format!("{:?}", e)))
+}
+
+/// Разбирает идентификатор запроса: уровень (Query/Retrieve Level) и ключи
+fn read_query(data: Option<&[u8]>, transfer_syntax: &str)
+              -> Result<(InMemDicomObject, work_dcm::Level, Vec<work_db::QueryKey>), (u16, String)> {
+    let ts = TransferSyntaxRegistry.get(transfer_syntax)
+        .ok_or_else(|| (work_dimse::STATUS_CANNOT_UNDERSTAND, format!("unknown transfer syntax {}", transfer_syntax)))?;
+    let identifier = InMemDicomObject::read_dataset_with_ts(data.unwrap_or_default(), ts)
+        .map_err(|e| (STATUS_IDENTIFIER_DOES_NOT_MATCH, e.to_string()))?;
+    let level = work_dimse::get_str(&identifier, QUERY_RETRIEVE_LEVEL);
+    let level = match level.to_uppercase().as_str() {
+        "PATIENT" => work_dcm::Level::Patient,
+        "STUDY" => work_dcm::Level::Study,
+        "SERIES" => work_dcm::Level::Series,
+        "IMAGE" => work_dcm::Level::Instance,
+        _ => return Err((STATUS_IDENTIFIER_DOES_NOT_MATCH, format!("invalid Query/Retrieve Level '{}'", level))),
+    };
+    let keys = identifier.iter()
+        .filter(|element| element.vr() != VR::SQ)
+        .filter_map(|element| {
+            let keyword = work_dcm::keyword_by_tag(element.header().tag)?;
+            let value = element.value().to_str().map(|value| value.to_string()).unwrap_or_default();
+            Some(work_db::QueryKey { keyword: keyword.to_string(), value })
+        })
+        .collect();
+    Ok((identifier, level, keys))
+}
+
+/// Пути файлов, подходящих под идентификатор C-MOVE/C-GET
+fn retrieve_paths(data: Option<&[u8]>, transfer_syntax: &str, sop_class_uid: &str,
+                  service: &Service) -> Result<Vec<String>, (u16, String)> {
+    let (_, level, keys) = read_query(data, transfer_syntax)?;
+    check_unique_keys(sop_class_uid, level, &keys)?;
+    open_index(service)?
+        .query_paths(level, &keys)
+        .map_err(|e| (work_dimse::STATUS_CANNOT_UNDERSTAND, format!("{:?}", e)))
+}
+
+/// Проверяет уникальные ключи идентификатора C-MOVE/C-GET (PS3.4 C.4.2.2.1): на уровне запроса
+/// и выше каждый должен быть задан без шаблонов, выше уровня запроса — одним значением.
+/// Пустой или универсальный идентификатор не выбирает весь индекс, а отклоняется с 0xA900
+fn check_unique_keys(sop_class_uid: &str, level: work_dcm::Level,
+                     keys: &[work_db::QueryKey]) -> Result<(), (u16, String)> {
+    let patient_root = sop_class_uid.starts_with(PATIENT_ROOT_PREFIX);
+    let depth = match level {
+        work_dcm::Level::Patient if !patient_root => {
+            return Err((STATUS_IDENTIFIER_DOES_NOT_MATCH, "PATIENT level in the Study Root model".to_string()));
+        }
+        work_dcm::Level::Patient => 0,
+        work_dcm::Level::Study => 1,
+        work_dcm::Level::Series => 2,
+        work_dcm::Level::Instance => 3,
+    };
+    let mut required: Vec<&str> = if patient_root { vec!["PatientID"] } else { Vec::new() };
+    required.extend(["StudyInstanceUID", "SeriesInstanceUID", "SOPInstanceUID"].iter().take(depth));
+    for (i, keyword) in required.iter().enumerate() {
+        let value = keys.iter()
+            .find(|key| key.keyword == *keyword)
+            .map(|key| key.value.trim_matches(['\0', ' ']))
+            .unwrap_or_default();
+        let is_list = value.contains('\\');
+        if value.is_empty() || value.contains(['*', '?']) || (is_list && i + 1 < required.len()) {
+            return Err((STATUS_IDENTIFIER_DOES_NOT_MATCH, format!("no unique {} for the {} level", keyword, level.as_str())));
+        }
+    }
+    Ok(())
+}
+
+/// Идентификатор ответа C-FIND: запрошенные атрибуты со значениями из индекса.
+/// Атрибуты, которых нет в индексе, возвращаются пустыми, последовательности не возвращаются
+fn response_identifier(request: &InMemDicomObject, entity: &BTreeMap<String, String>,
+                       aet: &str) -> InMemDicomObject {
+    let mut response = InMemDicomObject::new_empty();
+    for element in request.iter().filter(|element| element.vr() != VR::SQ) {
+        let tag = element.header().tag;
+        let value = match tag {
+            SPECIFIC_CHARACTER_SET => continue,
+            QUERY_RETRIEVE_LEVEL => work_dimse::get_str(request, QUERY_RETRIEVE_LEVEL),
+            RETRIEVE_AE_TITLE => aet.to_string(),
+            _ => work_dcm::keyword_by_tag(tag)
+                .and_then(|keyword| entity.get(keyword))
+                .cloned()
+                .unwrap_or_default(),
+        };
+        let value = if value.is_empty() {
+            PrimitiveValue::Empty
+        } else if element.vr() == VR::UI {
+            PrimitiveValue::from(work_dimse::uid_value(&value))
+        } else {
+            PrimitiveValue::from(value)
+        };
+        response.put(InMemElement::new(tag, element.vr(), value));
+    }
+    response
+}
+
+/// Ответ C-MOVE/C-GET с количеством подопераций; оставшиеся указываются только в ответах Pending
+fn suboperations_response(command_field: u16, message_id: u16, sop_class_uid: &str, status: u16,
+                          remaining: Option<usize>, counts: &SendCounts) -> InMemDicomObject {
+    let mut response = work_dimse::response_command(command_field, message_id, sop_class_uid, None, status);
+    let mut numbers = vec![
+        (NUMBER_OF_COMPLETED_SUBOPERATIONS, counts.sent - counts.warnings),
+        (NUMBER_OF_FAILED_SUBOPERATIONS, counts.failed),
+        (NUMBER_OF_WARNING_SUBOPERATIONS, counts.warnings),
+    ];
+    if let Some(remaining) = remaining {
+        numbers.push((NUMBER_OF_REMAINING_SUBOPERATIONS, remaining));
+    }
+    for (tag, number) in numbers {
+        response.put(InMemElement::new(tag, VR::US, PrimitiveValue::from(number.min(u16::MAX as usize) as u16)));
+    }
+    response
+}
+
+fn final_status(counts: &SendCounts) -> u16 {
+    if counts.sent == 0 && counts.failed > 0 {
+        STATUS_SUBOPERATIONS_FAILED
+    } else if counts.failed == 0 && counts.warnings == 0 {
+        work_dimse::STATUS_SUCCESS
+    } else {
+        STATUS_SUBOPERATIONS_WARNING
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, path};
+    use dicom::ul::association::client::ClientAssociation;
+    use dicom::ul::pdu::{PDataValue, PDataValueType, Pdu};
+    use crate::work_dimse::tests::{associate, instance, start_service, test_dir, CT_IMAGE_STORAGE};
+
+    const PATIENT_ROOT_GET: &str = "1.2.840.10008.5.1.4.1.2.1.3";
+    const STUDY_ROOT_FIND: &str = "1.2.840.10008.5.1.4.1.2.2.1";
+    const STUDY_ROOT_MOVE: &str = "1.2.840.10008.5.1.4.1.2.2.2";
+    const STUDY_ROOT_GET: &str = "1.2.840.10008.5.1.4.1.2.2.3";
+
+    const PATIENT_ID: Tag = Tag(0x0010, 0x0020);
+    const STUDY_INSTANCE_UID: Tag = Tag(0x0020, 0x000D);
+    const SERIES_INSTANCE_UID: Tag = Tag(0x0020, 0x000E);
+    const SOP_INSTANCE_UID: Tag = Tag(0x0008, 0x0018);
+
+    /// Индекс `dir/index.db` с экземплярами 1.2.7.1.1 и 1.2.7.1.2 серии 1.2.7.1 исследования 1.2.7 пациента P1
+    fn indexed_dir(name: &str) -> path::PathBuf {
+        let dir = test_dir(name);
+        let conn = work_db::Connection::open_dcm_tables(&dir.join("index.db")).unwrap();
+        for sop_instance_uid in ["1.2.7.1.1", "1.2.7.1.2"] {
+            let path = dir.join(format!("{}.dcm", sop_instance_uid));
+            let obj = instance(sop_instance_uid);
+            obj.write_to_file(&path).unwrap();
+            conn.insert_dcm(&work_dcm::MetaDcm::from(&obj, &path.display().to_string()));
+        }
+        dir
+    }
+
+    /// Контексты Query/Retrieve (идентификаторы 1–3) и CT Image Storage (4) в Explicit VR Little Endian
+    fn contexts(find: &str, r#move: &str, get: &str) -> Vec<(String, Vec<String>)> {
+        [find, r#move, get, CT_IMAGE_STORAGE].iter()
+            .map(|uid| (uid.to_string(), vec![work_dcm::EXPLICIT_VR_LITTLE_ENDIAN.to_string()]))
+            .collect()
+    }
+
+    /// Отправляет запрос C-FIND/C-MOVE/C-GET с идентификатором уровня `level` и ключами `keys`
+    fn request(association: &mut ClientAssociation, pc_id: u8, command_field: u16, sop_class_uid: &str,
+               destination: Option<&str>, level: &str, keys: &[(Tag, &str)]) {
+        let mut command = InMemDicomObject::new_empty();
+        command.put(InMemElement::new(work_dimse::AFFECTED_SOP_CLASS_UID, VR::UI,
+                                      PrimitiveValue::from(work_dimse::uid_value(sop_class_uid))));
+        command.put(InMemElement::new(work_dimse::COMMAND_FIELD, VR::US, PrimitiveValue::from(command_field)));
+        command.put(InMemElement::new(work_dimse::MESSAGE_ID, VR::US, PrimitiveValue::from(1u16)));
+        command.put(InMemElement::new(work_dimse::PRIORITY, VR::US, PrimitiveValue::from(0u16)));
+        command.put(InMemElement::new(work_dimse::COMMAND_DATA_SET_TYPE, VR::US,
+                                      PrimitiveValue::from(work_dimse::DATA_SET_PRESENT)));
+        if let Some(destination) = destination {
+            command.put(InMemElement::new(MOVE_DESTINATION, VR::AE, PrimitiveValue::from(destination)));
+        }
+        let mut identifier = InMemDicomObject::new_empty();
+        identifier.put(InMemElement::new(QUERY_RETRIEVE_LEVEL, VR::CS, PrimitiveValue::from(level)));
+        for (tag, value) in keys {
+            let vr = if *tag == PATIENT_ID { VR::LO } else { VR::UI };
+            identifier.put(InMemElement::new(*tag, vr, PrimitiveValue::from(*value)));
+        }
+        let mut data = Vec::new();
+        identifier.write_dataset_with_ts(&mut data, TransferSyntaxRegistry.get(work_dcm::EXPLICIT_VR_LITTLE_ENDIAN).unwrap())
+            .unwrap();
+        association.send(&Pdu::PData {
+            data: vec![
+                PDataValue { presentation_context_id: pc_id, value_type: PDataValueType::Command, is_last: true,
+                             data: work_dimse::encode_command(&command) },
+                PDataValue { presentation_context_id: pc_id, value_type: PDataValueType::Data, is_last: true, data },
+            ],
+        }).unwrap();
+    }
+
+    /// Принимает сообщение от SCP: контекст, набор команд и набор данных, если он объявлен
+    fn receive(association: &mut ClientAssociation) -> (u8, InMemDicomObject, Option<InMemDicomObject>) {
+        let mut command: Option<(u8, InMemDicomObject)> = None;
+        let mut buffer = Vec::new();
+        loop {
+            let values = match association.receive().unwrap() {
+                Pdu::PData { data } => data,
+                pdu => panic!("unexpected PDU {:?}", pdu),
+            };
+            for value in values {
+                buffer.extend_from_slice(&value.data);
+                if !value.is_last {
+                    continue;
+                }
+                let data = std::mem::take(&mut buffer);
+                match (value.value_type, command.take()) {
+                    (PDataValueType::Command, None) => {
+                        let received = work_dimse::decode_command(&data).unwrap();
+                        if work_dimse::get_u16(&received, work_dimse::COMMAND_DATA_SET_TYPE) == Some(work_dimse::NO_DATA_SET) {
+                            return (value.presentation_context_id, received, None);
+                        }
+                        command = Some((value.presentation_context_id, received));
+                    }
+                    (PDataValueType::Data, Some((pc_id, received))) => {
+                        let ts = TransferSyntaxRegistry.get(work_dcm::EXPLICIT_VR_LITTLE_ENDIAN).unwrap();
+                        return (pc_id, received, Some(InMemDicomObject::read_dataset_with_ts(data.as_slice(), ts).unwrap()));
+                    }
+                    (value_type, _) => panic!("unexpected {:?} fragment", value_type),
+                }
+            }
+        }
+    }
+
+    /// Принимает ответы до итогового (не Pending) и возвращает его вместе с идентификаторами ответов Pending
+    fn final_response(association: &mut ClientAssociation) -> (InMemDicomObject, Vec<InMemDicomObject>) {
+        let mut identifiers = Vec::new();
+        loop {
+            let (_, response, identifier) = receive(association);
+            if work_dimse::get_u16(&response, work_dimse::STATUS) != Some(STATUS_PENDING) {
+                return (response, identifiers);
+            }
+            identifiers.extend(identifier);
+        }
+    }
+
+    fn status(response: &InMemDicomObject) -> Option<u16> {
+        work_dimse::get_u16(response, work_dimse::STATUS)
+    }
+
+    #[test]
+    fn find_answers_from_the_index() {
+        let dir = indexed_dir("qr_find");
+        let (target, _) = start_service(&dir, 1, 1 << 20, Vec::new());
+        let mut association = associate(target, &contexts(STUDY_ROOT_FIND, STUDY_ROOT_MOVE, STUDY_ROOT_GET));
+
+        request(&mut association, 1, work_dimse::C_FIND_RQ, STUDY_ROOT_FIND, None, "STUDY",
+                &[(PATIENT_ID, ""), (STUDY_INSTANCE_UID, "")]);
+        let (response, identifiers) = final_response(&mut association);
+        assert_eq!(work_dimse::get_u16(&response, work_dimse::COMMAND_FIELD), Some(C_FIND_RSP));
+        assert_eq!(status(&response), Some(work_dimse::STATUS_SUCCESS));
+        assert_eq!(identifiers.len(), 1);
+        assert_eq!(work_dimse::get_str(&identifiers[0], STUDY_INSTANCE_UID), "1.2.7");
+        assert_eq!(work_dimse::get_str(&identifiers[0], PATIENT_ID), "P1");
+
+        request(&mut association, 1, work_dimse::C_FIND_RQ, STUDY_ROOT_FIND, None, "IMAGE",
+                &[(SERIES_INSTANCE_UID, "1.2.7.1"), (SOP_INSTANCE_UID, "")]);
+        assert_eq!(final_response(&mut association).1.len(), 2);
+
+        request(&mut association, 1, work_dimse::C_FIND_RQ, STUDY_ROOT_FIND, None, "FRAME", &[]);
+        let (response, identifiers) = final_response(&mut association);
+        assert_eq!((status(&response), identifiers.len()), (Some(STATUS_IDENTIFIER_DOES_NOT_MATCH), 0));
+        association.release().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn move_requires_unique_keys() {
+        let dir = indexed_dir("qr_move");
+        let (destination, meta_rx) = start_service(&dir.join("destination"), 1, 1 << 20, Vec::new());
+        let destination_aet = destination.aet.clone();
+        let (target, _) = start_service(&dir, 1, 1 << 20, vec![destination]);
+        let mut association = associate(target, &contexts(STUDY_ROOT_FIND, STUDY_ROOT_MOVE, STUDY_ROOT_GET));
+
+        // Пустой, универсальный и шаблонный ключ не выбирают весь индекс
+        for study_uid in ["", "*", "1.2.?"] {
+            request(&mut association, 2, work_dimse::C_MOVE_RQ, STUDY_ROOT_MOVE, Some(&destination_aet), "STUDY",
+                    &[(STUDY_INSTANCE_UID, study_uid)]);
+            assert_eq!(status(&final_response(&mut association).0), Some(STATUS_IDENTIFIER_DOES_NOT_MATCH));
+        }
+        request(&mut association, 2, work_dimse::C_MOVE_RQ, STUDY_ROOT_MOVE, Some(&destination_aet), "STUDY", &[]);
+        assert_eq!(status(&final_response(&mut association).0), Some(STATUS_IDENTIFIER_DOES_NOT_MATCH));
+        request(&mut association, 2, work_dimse::C_MOVE_RQ, STUDY_ROOT_MOVE, Some("UNKNOWN"), "STUDY",
+                &[(STUDY_INSTANCE_UID, "1.2.7")]);
+        assert_eq!(status(&final_response(&mut association).0), Some(STATUS_MOVE_DESTINATION_UNKNOWN));
+        assert!(meta_rx.try_recv().is_err());
+
+        request(&mut association, 2, work_dimse::C_MOVE_RQ, STUDY_ROOT_MOVE, Some(&destination_aet), "STUDY",
+                &[(STUDY_INSTANCE_UID, "1.2.7")]);
+        let (response, _) = final_response(&mut association);
+        assert_eq!(work_dimse::get_u16(&response, work_dimse::COMMAND_FIELD), Some(C_MOVE_RSP));
+        assert_eq!(status(&response), Some(work_dimse::STATUS_SUCCESS));
+        assert_eq!(work_dimse::get_u16(&response, NUMBER_OF_COMPLETED_SUBOPERATIONS), Some(2));
+        association.release().unwrap();
+        let mut received: Vec<String> = meta_rx.iter()
+            .map(|meta| meta.get_instance_ref().sop_instance_uid.clone())
+            .collect();
+        received.sort();
+        assert_eq!(received, ["1.2.7.1.1", "1.2.7.1.2"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn get_requires_unique_keys_at_every_level() {
+        let dir = indexed_dir("qr_get");
+        let (target, _) = start_service(&dir, 1, 1 << 20, Vec::new());
+        let mut association = associate(target, &contexts(STUDY_ROOT_FIND, PATIENT_ROOT_GET, STUDY_ROOT_GET));
+
+        let rejected = [
+            // Patient Root: Patient ID обязателен на любом уровне
+            (2u8, PATIENT_ROOT_GET, "PATIENT", vec![(PATIENT_ID, "")]),
+            (2, PATIENT_ROOT_GET, "STUDY", vec![(STUDY_INSTANCE_UID, "1.2.7")]),
+            // В Study Root нет уровня PATIENT
+            (3, STUDY_ROOT_GET, "PATIENT", vec![(PATIENT_ID, "P1")]),
+            // Нет Series Instance UID выше уровня IMAGE
+            (3, STUDY_ROOT_GET, "IMAGE", vec![(STUDY_INSTANCE_UID, "1.2.7"), (SOP_INSTANCE_UID, "1.2.7.1.1")]),
+            // Список UID допустим только на уровне запроса
+            (3, STUDY_ROOT_GET, "SERIES", vec![(STUDY_INSTANCE_UID, "1.2.7\\1.2.8"), (SERIES_INSTANCE_UID, "1.2.7.1")]),
+        ];
+        for (pc_id, sop_class_uid, level, keys) in &rejected {
+            request(&mut association, *pc_id, work_dimse::C_GET_RQ, sop_class_uid, None, level, keys);
+            let (response, _) = final_response(&mut association);
+            assert_eq!(status(&response), Some(STATUS_IDENTIFIER_DOES_NOT_MATCH), "{} {:?}", level, keys);
+        }
+
+        request(&mut association, 2, work_dimse::C_GET_RQ, PATIENT_ROOT_GET, None, "IMAGE",
+                &[(PATIENT_ID, "P1"), (STUDY_INSTANCE_UID, "1.2.7"), (SERIES_INSTANCE_UID, "1.2.7.1"),
+                  (SOP_INSTANCE_UID, "1.2.7.1.1\\1.2.7.1.2")]);
+        let mut stored = Vec::new();
+        let response = loop {
+            let (pc_id, command, data) = receive(&mut association);
+            match work_dimse::get_u16(&command, work_dimse::COMMAND_FIELD) {
+                Some(work_dimse::C_STORE_RQ) => {
+                    assert_eq!(pc_id, 4);
+                    let sop_instance_uid = work_dimse::get_str(&command, work_dimse::AFFECTED_SOP_INSTANCE_UID);
+                    assert_eq!(data.map(|data| work_dimse::get_str(&data, SOP_INSTANCE_UID)), Some(sop_instance_uid.clone()));
+                    let message_id = work_dimse::get_u16(&command, work_dimse::MESSAGE_ID).unwrap();
+                    let response = work_dimse::response_command(work_dimse::C_STORE_RSP, message_id, CT_IMAGE_STORAGE,
+                                                                Some(&sop_instance_uid), work_dimse::STATUS_SUCCESS);
+                    association.send(&Pdu::PData {
+                        data: vec![PDataValue { presentation_context_id: pc_id, value_type: PDataValueType::Command,
+                                                is_last: true, data: work_dimse::encode_command(&response) }],
+                    }).unwrap();
+                    stored.push(sop_instance_uid);
+                }
+                _ if status(&command) == Some(STATUS_PENDING) => {}
+                _ => break command,
+            }
+        };
+        assert_eq!(work_dimse::get_u16(&response, work_dimse::COMMAND_FIELD), Some(C_GET_RSP));
+        assert_eq!(status(&response), Some(work_dimse::STATUS_SUCCESS));
+        assert_eq!(work_dimse::get_u16(&response, NUMBER_OF_COMPLETED_SUBOPERATIONS), Some(2));
+        stored.sort();
+        assert_eq!(stored, ["1.2.7.1.1", "1.2.7.1.2"]);
+        association.release().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn final_status_reflects_suboperations() {
+        let counts = |sent, warnings, failed| SendCounts { sent, warnings, failed };
+        assert_eq!(final_status(&counts(3, 0, 0)), work_dimse::STATUS_SUCCESS);
+        assert_eq!(final_status(&counts(0, 0, 0)), work_dimse::STATUS_SUCCESS);
+        assert_eq!(final_status(&counts(3, 1, 0)), STATUS_SUBOPERATIONS_WARNING);
+        assert_eq!(final_status(&counts(2, 0, 1)), STATUS_SUBOPERATIONS_WARNING);
+        assert_eq!(final_status(&counts(0, 0, 3)), STATUS_SUBOPERATIONS_FAILED);
+    }
+}
@@ -0,0 +1,94 @@
+use std::fs;
+use std::io;
+use std::path;
+use serde::Serialize;
+
+use crate::work_volume::Volume;
+
+
+/// Заголовок несжатых данных объема (`<series>.raw.json`)
+#[derive(Serialize, Debug, PartialEq)]
+pub struct RawHeader {
+    /// Имя файла данных рядом с заголовком
+    pub data_file: String,
+    /// Тип значения: int16, int32 или float32
+    pub sample_type: String,
+    pub byte_order: String,
+    /// Размер: столбцы, строки, срезы (столбец меняется быстрее всего)
+    pub dimensions: [usize; 3],
+    /// Шаг между столбцами, строками и срезами, мм
+    pub spacing: [f64; 3],
+    /// Система координат пациента
+    pub space: String,
+    /// Центр первого вокселя, мм
+    pub origin: [f64; 3],
+    /// Единичные направления осей столбцов, строк и срезов
+    pub directions: [[f64; 3]; 3],
+    /// Матрица 4x4 из индексов вокселя в координаты пациента
+    pub affine: [[f64; 4]; 4],
+}
+
+/// Записывает значения объема как есть (`<series>.raw`) и заголовок в JSON рядом (`<series>.raw.json`)
+pub fn write(volume: &Volume, target: &path::Path) -> io::Result<()> {
+    let data_file = target.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+    let header = serde_json::to_string_pretty(&header(volume, &data_file))?;
+    fs::write(target, volume.sample_bytes(volume.sample_type()))?;
+    fs::write(target.with_extension("raw.json"), header)
+}
+
+fn header(volume: &Volume, data_file: &str) -> RawHeader {
+    let [x, y, z] = volume.affine();
+    RawHeader {
+        data_file: data_file.to_string(),
+        sample_type: volume.sample_type().as_str().to_string(),
+        byte_order: "little".to_string(),
+        dimensions: volume.dims,
+        spacing: volume.spacing,
+        space: "LPS".to_string(),
+        origin: volume.origin,
+        directions: volume.directions,
+        affine: [x, y, z, [0.0, 0.0, 0.0, 1.0]],
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::work_volume::tests::synthetic_volume;
+
+    #[test]
+    fn header_holds_spatial_metadata() {
+        let volume = synthetic_volume();
+        let header = header(&volume, "1.2.3.raw");
+        assert_eq!(header.data_file, "1.2.3.raw");
+        assert_eq!(header.sample_type, "int16");
+        assert_eq!(header.dimensions, [4, 3, 3]);
+        assert_eq!(header.spacing, [0.5, 0.8, 2.5]);
+        assert_eq!(header.space, "LPS");
+        assert_eq!(header.origin, [-100.0, 10.0, 50.0]);
+        assert_eq!(header.directions, [[1.0, 0.0, 0.0], [0.0, 0.0, -1.0], [0.0, 1.0, 0.0]]);
+        assert_eq!(header.affine, [
+            [0.5, 0.0, 0.0, -100.0],
+            [0.0, 0.0, 2.5, 10.0],
+            [0.0, -0.8, 0.0, 50.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ]);
+    }
+
+    #[test]
+    fn data_and_header_are_written_side_by_side() {
+        let volume = synthetic_volume();
+        let target = std::env::temp_dir().join(format!("dcm_finder_test_{}.1.2.3.raw", std::process::id()));
+        write(&volume, &target).unwrap();
+        let data = fs::read(&target).unwrap();
+        let header_path = target.with_extension("raw.json");
+        let header: serde_json::Value = serde_json::from_slice(&fs::read(&header_path).unwrap()).unwrap();
+        fs::remove_file(&target).unwrap();
+        fs::remove_file(&header_path).unwrap();
+        assert_eq!(data.len(), 4 * 3 * 3 * 2);
+        assert_eq!(data, volume.sample_bytes(volume.sample_type()));
+        assert_eq!(header["data_file"], target.file_name().unwrap().to_string_lossy().as_ref());
+        assert_eq!(header["origin"], serde_json::json!([-100.0, 10.0, 50.0]));
+    }
+}
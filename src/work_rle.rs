@@ -0,0 +1,152 @@
+use crate::work_pixels::FrameFormat;
+
+
+/// Размер заголовка кадра RLE: число сегментов и 15 смещений
+const HEADER_SIZE: usize = 64;
+/// Наибольшее число сегментов в кадре
+const MAX_SEGMENTS: usize = 15;
+
+/// Распаковывает кадр RLE Lossless (PS3.5 Annex G). Сегменты идут по компонентам,
+/// внутри компоненты — от старшего байта значения к младшему.
+/// Возвращает значения по порядку строка, столбец, компонента
+pub fn decode(data: &[u8], format: &FrameFormat) -> Result<Vec<i32>, String> {
+    let bytes = format.bytes_per_sample();
+    let pixels = format.rows * format.columns;
+    let expected = format.samples_per_pixel * bytes;
+    if data.len() < HEADER_SIZE {
+        return Err("RLE frame is shorter than its header".to_string());
+    }
+    let header: Vec<usize> = data[..HEADER_SIZE].chunks_exact(4)
+        .map(|chunk| u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]) as usize)
+        .collect();
+    let segments = header[0];
+    if segments != expected {
+        return Err(format!("RLE frame has {} segments, expected {}", segments, expected));
+    }
+    let mut values = vec![0i32; pixels * format.samples_per_pixel];
+    for segment in 0..segments {
+        let start = header[1 + segment];
+        let end = if segment + 1 < segments { header[2 + segment] } else { data.len() };
+        if start > end || end > data.len() {
+            return Err(format!("RLE segment {} is out of the frame", segment + 1));
+        }
+        let decoded = unpack(&data[start..end], pixels)?;
+        let (sample, byte) = (segment / bytes, segment % bytes);
+        let shift = 8 * (bytes - 1 - byte);
+        for (pixel, value) in decoded.iter().enumerate() {
+            values[pixel * format.samples_per_pixel + sample] |= ((*value as u32) << shift) as i32;
+        }
+    }
+    Ok(values)
+}
+
+/// Сжимает кадр в RLE Lossless. Значения — по порядку строка, столбец, компонента,
+/// повторы не переходят через границу строки
+pub fn encode(values: &[i32], format: &FrameFormat) -> Result<Vec<u8>, String> {
+    let bytes = format.bytes_per_sample();
+    let segments = format.samples_per_pixel * bytes;
+    if segments > MAX_SEGMENTS {
+        return Err(format!("{} RLE segments are needed, at most {} are allowed", segments, MAX_SEGMENTS));
+    }
+    let mut frame = vec![0u8; HEADER_SIZE];
+    frame[..4].copy_from_slice(&(segments as u32).to_le_bytes());
+    for segment in 0..segments {
+        let offset = frame.len() as u32;
+        frame[4 + segment * 4..8 + segment * 4].copy_from_slice(&offset.to_le_bytes());
+        let (sample, byte) = (segment / bytes, segment % bytes);
+        let shift = 8 * (bytes - 1 - byte);
+        let plane: Vec<u8> = values.iter().skip(sample).step_by(format.samples_per_pixel)
+            .map(|value| ((*value as u32) >> shift) as u8)
+            .collect();
+        for row in plane.chunks(format.columns.max(1)) {
+            pack(row, &mut frame);
+        }
+        if frame.len() % 2 == 1 {
+            frame.push(0);
+        }
+    }
+    Ok(frame)
+}
+
+/// Распаковывает сегмент PackBits до `count` байт
+fn unpack(segment: &[u8], count: usize) -> Result<Vec<u8>, String> {
+    let mut result = Vec::with_capacity(count);
+    let mut position = 0;
+    while result.len() < count && position < segment.len() {
+        let header = segment[position] as i8;
+        position += 1;
+        if header >= 0 {
+            let length = header as usize + 1;
+            let literal = segment.get(position..position + length).ok_or("RLE literal run is out of the segment")?;
+            result.extend_from_slice(literal);
+            position += length;
+        } else if header != -128 {
+            let value = *segment.get(position).ok_or("RLE replicate run is out of the segment")?;
+            result.extend(std::iter::repeat_n(value, (1 - header as isize) as usize));
+            position += 1;
+        }
+    }
+    if result.len() < count {
+        return Err(format!("RLE segment holds {} bytes, expected {}", result.len(), count));
+    }
+    result.truncate(count);
+    Ok(result)
+}
+
+/// Сжимает строку PackBits: повторы от 3 байт кодируются повтором, остальное — литералами до 128 байт
+fn pack(row: &[u8], out: &mut Vec<u8>) {
+    let mut position = 0;
+    while position < row.len() {
+        let run = row[position..].iter().take(128).take_while(|&&value| value == row[position]).count();
+        if run >= 3 {
+            out.push((1 - run as i32) as i8 as u8);
+            out.push(row[position]);
+            position += run;
+            continue;
+        }
+        let start = position;
+        while position < row.len() && position - start < 128 {
+            let ahead = row[position..].iter().take(3).take_while(|&&value| value == row[position]).count();
+            if ahead == 3 {
+                break;
+            }
+            position += 1;
+        }
+        out.push((position - start - 1) as u8);
+        out.extend_from_slice(&row[start..position]);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::work_pixels::tests::{synthetic_image, synthetic_rgb};
+
+    #[test]
+    fn grayscale_16_bit_round_trip() {
+        let (values, format) = synthetic_image(16, 12);
+        let frame = encode(&values, &format).unwrap();
+        assert!(frame.len() < values.len() * 2);
+        assert_eq!(decode(&frame, &format).unwrap(), values);
+    }
+
+    #[test]
+    fn rgb_round_trip() {
+        let (values, format) = synthetic_rgb();
+        let frame = encode(&values, &format).unwrap();
+        assert_eq!(u32::from_le_bytes([frame[0], frame[1], frame[2], frame[3]]), 3);
+        assert_eq!(decode(&frame, &format).unwrap(), values);
+    }
+
+    #[test]
+    fn packbits_runs_and_literals() {
+        let row = [1, 2, 3, 3, 3, 3, 4, 5, 5];
+        let mut packed = Vec::new();
+        pack(&row, &mut packed);
+        assert_eq!(packed, vec![1, 1, 2, 253, 3, 2, 4, 5, 5]);
+        assert_eq!(unpack(&packed, row.len()).unwrap(), row);
+        // -128 — пустая команда
+        assert_eq!(unpack(&[128, 0, 7], 1).unwrap(), vec![7]);
+    }
+}
@@ -0,0 +1,310 @@
+use std::fs;
+use std::io::{self, BufWriter};
+use std::path;
+use std::str::FromStr;
+use dicom::object::DefaultDicomObject;
+use indicatif::{ProgressBar, ProgressStyle};
+use rayon::prelude::*;
+
+use crate::work_db;
+use crate::work_db::Dcm;
+use crate::work_dcm;
+use crate::work_geometry;
+use crate::work_pixels;
+
+
+/// Окно по умолчанию для КТ, если в файле нет Window Center/Width: мягкие ткани, HU
+const CT_WINDOW: (f64, f64) = (40.0, 400.0);
+
+/// Формат файла миниатюры
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ThumbnailFormat {
+    Png,
+    Jpeg,
+}
+
+impl FromStr for ThumbnailFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "png" => Ok(ThumbnailFormat::Png),
+            "jpeg" | "jpg" => Ok(ThumbnailFormat::Jpeg),
+            _ => Err(format!("unknown thumbnail format '{}' (expected png or jpeg)", s)),
+        }
+    }
+}
+
+impl ThumbnailFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            ThumbnailFormat::Png => "png",
+            ThumbnailFormat::Jpeg => "jpg",
+        }
+    }
+}
+
+/// Параметры создания миниатюр серий
+#[derive(Debug, Clone)]
+pub struct ThumbnailOptions {
+    /// Директория, в которую сохраняются миниатюры (`<study>/<series>.<ext>`)
+    pub save_in: path::PathBuf,
+    pub format: ThumbnailFormat,
+    /// Наибольшая сторона миниатюры, пиксели
+    pub size: u32,
+}
+
+/// Изображение миниатюры: 8 бит на компоненту, 1 (оттенки серого) или 3 (RGB) компоненты
+#[derive(Debug, Clone, PartialEq)]
+pub struct Thumbnail {
+    pub width: usize,
+    pub height: usize,
+    pub channels: usize,
+    pub pixels: Vec<u8>,
+}
+
+/// Создает миниатюры среднего экземпляра каждой серии индекса и сохраняет пути к ним в таблице `series`.
+/// Возвращает количество созданных миниатюр и серий, для которых миниатюру создать не удалось
+pub fn write_thumbnails(conn: &work_db::Connection, options: &ThumbnailOptions) -> (usize, usize) {
+    let sources: Vec<(work_db::SelectedSeries, String)> = match conn.select_series(None, &[], &[]) {
+        Ok(series) => series.into_iter()
+            .filter_map(|series| match conn.get_middle_instance(&series.series_uid) {
+                Ok(middle) => middle.map(|path| (series, path)),
+                Err(e) => {
+                    eprintln!("Error selecting middle instance of series {}: {:?}", series.series_uid, e);
+                    None
+                }
+            })
+            .collect(),
+        Err(e) => {
+            eprintln!("Error selecting series in index: {:?}", e);
+            return (0, 0);
+        }
+    };
+    let progress = ProgressBar::new(sources.len() as u64);
+    progress.set_style(ProgressStyle::default_bar()
+        .template("[{elapsed_precise}] {bar:40} thumbnails {pos} of {len} series ({per_sec})"));
+    let written: Vec<Option<(String, String)>> = sources.par_iter()
+        .map(|(series, path)| {
+            let target = options.save_in.join(&series.study_uid)
+                .join(format!("{}.{}", series.series_uid, options.format.extension()));
+            let result = write_thumbnail(path, &target, options);
+            progress.inc(1);
+            match result {
+                Ok(()) => Some((series.series_uid.clone(), target.to_string_lossy().to_string())),
+                Err(e) => {
+                    progress.println(format!("Error creating thumbnail of series {} [path: {}]: {}",
+                                             series.series_uid, path, e));
+                    None
+                }
+            }
+        })
+        .collect();
+    progress.finish_and_clear();
+    let mut count = 0;
+    for (series_uid, thumbnail) in written.iter().flatten() {
+        match conn.set_series_thumbnail(series_uid, thumbnail) {
+            Ok(()) => count += 1,
+            Err(e) => eprintln!("Error saving thumbnail path of series {}: {:?}", series_uid, e),
+        }
+    }
+    (count, sources.len() - count)
+}
+
+fn write_thumbnail(path: &str, target: &path::Path, options: &ThumbnailOptions) -> Result<(), String> {
+    let obj = work_dcm::read_indexed_dcm(path).map_err(|e| e.to_string())?;
+    let thumbnail = render(&obj, options.size)?;
+    if let Some(folder) = target.parent() {
+        fs::create_dir_all(folder).map_err(|e| e.to_string())?;
+    }
+    match options.format {
+        ThumbnailFormat::Png => write_png(&thumbnail, target).map_err(|e| e.to_string()),
+        ThumbnailFormat::Jpeg => write_jpeg(&thumbnail, target),
+    }
+}
+
+/// Значения среднего кадра; ошибка, если кадр пуст или значений меньше, чем кадров в заголовке
+fn middle_frame(pixels: &work_pixels::Pixels) -> Result<&[i32], String> {
+    let frame_size = pixels.frame_format().samples();
+    (pixels.frames / 2).checked_mul(frame_size)
+        .and_then(|start| pixels.values.get(start..))
+        .and_then(|values| values.get(..frame_size))
+        .filter(|frame| !frame.is_empty())
+        .ok_or_else(|| format!("Pixel Data holds {} values, not {} frames of {}",
+                               pixels.values.len(), pixels.frames, frame_size))
+}
+
+/// Строит миниатюру среднего кадра экземпляра. К значениям оттенков серого применяются
+/// Rescale Slope/Intercept и окно из Window Center/Width (для КТ без окна — окно мягких тканей,
+/// иначе — диапазон значений кадра); MONOCHROME1 инвертируется, цветные кадры берутся в RGB после распаковки.
+/// Пропорции учитывают Pixel Spacing, наибольшая сторона уменьшается до `size`
+pub fn render(obj: &DefaultDicomObject, size: u32) -> Result<Thumbnail, String> {
+    let pixels = work_pixels::decode(obj)?;
+    let photometric = pixels.photometric.as_str();
+    let frame = middle_frame(&pixels)?;
+    let image: Vec<u8> = match (pixels.samples_per_pixel, photometric) {
+        (1, "MONOCHROME1" | "MONOCHROME2") => {
+            let slope = number(obj, "RescaleSlope").unwrap_or(1.0);
+            let intercept = number(obj, "RescaleIntercept").unwrap_or(0.0);
+            let values: Vec<f64> = frame.iter().map(|&value| value as f64 * slope + intercept).collect();
+            let window = match (number(obj, "WindowCenter"), number(obj, "WindowWidth")) {
+                (Some(center), Some(width)) if width >= 1.0 => (center, width),
+                _ if text(obj, "Modality").as_deref() == Some("CT") => CT_WINDOW,
+                _ => {
+                    let min = values.iter().copied().fold(f64::INFINITY, f64::min);
+                    let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+                    ((min + max + 1.0) / 2.0, (max - min + 1.0).max(1.0))
+                }
+            };
+            let invert = photometric == "MONOCHROME1";
+            values.iter()
+                .map(|&value| apply_window(value, window))
+                .map(|value| if invert { 255 - value } else { value })
+                .collect()
+        }
+        (3, "RGB") => {
+            let max = frame.iter().copied().max().unwrap_or_default();
+            let scale = if max > 255 { 255.0 / max as f64 } else { 1.0 };
+            frame.iter().map(|&value| (value.max(0) as f64 * scale).round() as u8).collect()
+        }
+        (samples, photometric) => return Err(format!(
+            "{} samples per pixel with Photometric Interpretation {} is not supported", samples, photometric)),
+    };
+    let aspect = match text(obj, "PixelSpacing").and_then(|value| work_geometry::parse_numbers::<2>(&value)) {
+        Some([row_spacing, column_spacing]) if row_spacing > 0.0 && column_spacing > 0.0 => row_spacing / column_spacing,
+        _ => 1.0,
+    };
+    let (width, height) = thumbnail_size(pixels.columns, pixels.rows, aspect, size);
+    Ok(Thumbnail {
+        width,
+        height,
+        channels: pixels.samples_per_pixel,
+        pixels: resize(&image, pixels.columns, pixels.rows, pixels.samples_per_pixel, width, height),
+    })
+}
+
+/// Линейное окно (PS3.3 C.11.2.1.2.1) в значения 0..255
+fn apply_window(value: f64, (center, width): (f64, f64)) -> u8 {
+    let low = center - 0.5 - (width - 1.0) / 2.0;
+    let high = center - 0.5 + (width - 1.0) / 2.0;
+    if value <= low {
+        0
+    } else if value > high {
+        255
+    } else {
+        (((value - (center - 0.5)) / (width - 1.0).max(1.0) + 0.5) * 255.0).round().clamp(0.0, 255.0) as u8
+    }
+}
+
+/// Размер миниатюры: высота растягивается на `aspect` (отношение шага строк к шагу столбцов),
+/// наибольшая сторона не больше `size`. Изображение не увеличивается
+fn thumbnail_size(columns: usize, rows: usize, aspect: f64, size: u32) -> (usize, usize) {
+    let (width, height) = (columns as f64, rows as f64 * aspect);
+    let scale = (size as f64 / width.max(height)).min(1.0);
+    (((width * scale).round() as usize).max(1), ((height * scale).round() as usize).max(1))
+}
+
+/// Уменьшает изображение усреднением пикселей, попадающих в каждый пиксель результата
+fn resize(image: &[u8], columns: usize, rows: usize, channels: usize, width: usize, height: usize) -> Vec<u8> {
+    let mut result = Vec::with_capacity(width * height * channels);
+    for y in 0..height {
+        let (y0, y1) = (y * rows / height, ((y + 1) * rows / height).max(y * rows / height + 1));
+        for x in 0..width {
+            let (x0, x1) = (x * columns / width, ((x + 1) * columns / width).max(x * columns / width + 1));
+            for channel in 0..channels {
+                let mut sum = 0usize;
+                for row in y0..y1 {
+                    for column in x0..x1 {
+                        sum += image[(row * columns + column) * channels + channel] as usize;
+                    }
+                }
+                let count = (y1 - y0) * (x1 - x0);
+                result.push(((sum + count / 2) / count) as u8);
+            }
+        }
+    }
+    result
+}
+
+fn write_png(thumbnail: &Thumbnail, target: &path::Path) -> io::Result<()> {
+    let mut encoder = png::Encoder::new(BufWriter::new(fs::File::create(target)?),
+                                        thumbnail.width as u32, thumbnail.height as u32);
+    encoder.set_color(if thumbnail.channels == 3 { png::ColorType::Rgb } else { png::ColorType::Grayscale });
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&thumbnail.pixels)?;
+    writer.finish()?;
+    Ok(())
+}
+
+fn write_jpeg(thumbnail: &Thumbnail, target: &path::Path) -> Result<(), String> {
+    let encoder = jpeg_encoder::Encoder::new_file(target, 90).map_err(|e| e.to_string())?;
+    let color = if thumbnail.channels == 3 { jpeg_encoder::ColorType::Rgb } else { jpeg_encoder::ColorType::Luma };
+    encoder.encode(&thumbnail.pixels, thumbnail.width as u16, thumbnail.height as u16, color)
+        .map_err(|e| e.to_string())
+}
+
+fn text(obj: &DefaultDicomObject, keyword: &str) -> Option<String> {
+    obj.element_by_name(keyword).ok()
+        .and_then(|e| e.to_str().ok())
+        .map(|value| value.trim_end_matches(['\0', ' ']).to_string())
+        .filter(|value| !value.is_empty())
+}
+
+/// Первое значение числового атрибута (у Window Center/Width их может быть несколько)
+fn number(obj: &DefaultDicomObject, keyword: &str) -> Option<f64> {
+    text(obj, keyword)?.split('\\').next()?.trim().parse().ok()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window_maps_to_full_range() {
+        let window = (40.0, 400.0);
+        assert_eq!(apply_window(-1000.0, window), 0);
+        assert_eq!(apply_window(-160.0, window), 0);
+        assert_eq!(apply_window(40.0, window), 128);
+        assert_eq!(apply_window(240.0, window), 255);
+        assert_eq!(apply_window(3000.0, window), 255);
+    }
+
+    #[test]
+    fn size_keeps_physical_proportions() {
+        assert_eq!(thumbnail_size(512, 512, 1.0, 128), (128, 128));
+        assert_eq!(thumbnail_size(512, 256, 1.0, 128), (128, 64));
+        assert_eq!(thumbnail_size(256, 128, 2.0, 128), (128, 128));
+        assert_eq!(thumbnail_size(64, 32, 1.0, 128), (64, 32));
+    }
+
+    #[test]
+    fn middle_frame_is_checked() {
+        let mut pixels = work_pixels::Pixels {
+            rows: 2,
+            columns: 2,
+            samples_per_pixel: 1,
+            frames: 3,
+            bits_allocated: 8,
+            bits_stored: 8,
+            photometric: "MONOCHROME2".to_string(),
+            values: (0..12).collect(),
+        };
+        assert_eq!(middle_frame(&pixels).unwrap(), &[4, 5, 6, 7]);
+        pixels.frames = 7;
+        assert!(middle_frame(&pixels).is_err());
+        pixels.frames = usize::MAX;
+        assert!(middle_frame(&pixels).is_err());
+        pixels.rows = 0;
+        assert!(middle_frame(&pixels).is_err());
+    }
+
+    #[test]
+    fn resize_averages_pixels() {
+        let image = [0, 100, 200, 255, 0, 100, 200, 255];
+        assert_eq!(resize(&image, 4, 2, 1, 2, 1), vec![50, 228]);
+        let rgb = [10, 20, 30, 30, 40, 50];
+        assert_eq!(resize(&rgb, 2, 1, 3, 1, 1), vec![20, 30, 40]);
+    }
+}
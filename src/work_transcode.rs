@@ -0,0 +1,272 @@
+use std::str::FromStr;
+use dicom::core::{DataElement, PrimitiveValue, Tag, VR};
+use dicom::core::value::Value;
+use dicom::object::DefaultDicomObject;
+
+use crate::work_dcm;
+use crate::work_json;
+use crate::work_pixels;
+use crate::work_pixels::{FrameFormat, Pixels};
+#[cfg(feature = "codecs")]
+use crate::{work_jpegls, work_rle};
+
+
+/// RLE Lossless
+const RLE_LOSSLESS: &str = "1.2.840.10008.1.2.5";
+/// JPEG-LS Lossless Image Compression
+const JPEG_LS_LOSSLESS: &str = "1.2.840.10008.1.2.4.80";
+
+/// Синтаксис передачи, в котором сохраняются файлы (`--transfer-syntax`)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputSyntax {
+    ExplicitLittle,
+    ImplicitLittle,
+    /// Deflated Explicit VR Little Endian
+    Deflated,
+    /// RLE Lossless
+    Rle,
+    /// JPEG-LS Lossless
+    JpegLs,
+}
+
+impl FromStr for OutputSyntax {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "explicit-le" | work_dcm::EXPLICIT_VR_LITTLE_ENDIAN => Ok(OutputSyntax::ExplicitLittle),
+            "implicit-le" | work_dcm::IMPLICIT_VR_LITTLE_ENDIAN => Ok(OutputSyntax::ImplicitLittle),
+            "deflate" | work_dcm::DEFLATED_EXPLICIT_VR_LITTLE_ENDIAN => Ok(OutputSyntax::Deflated),
+            "rle" | RLE_LOSSLESS => Ok(OutputSyntax::Rle),
+            "jpeg-ls" | JPEG_LS_LOSSLESS => Ok(OutputSyntax::JpegLs),
+            _ => Err(format!("unknown transfer syntax '{}' (expected explicit-le, implicit-le, deflate, rle or jpeg-ls)", s)),
+        }
+    }
+}
+
+impl OutputSyntax {
+    pub fn uid(&self) -> &'static str {
+        match self {
+            OutputSyntax::ExplicitLittle => work_dcm::EXPLICIT_VR_LITTLE_ENDIAN,
+            OutputSyntax::ImplicitLittle => work_dcm::IMPLICIT_VR_LITTLE_ENDIAN,
+            OutputSyntax::Deflated => work_dcm::DEFLATED_EXPLICIT_VR_LITTLE_ENDIAN,
+            OutputSyntax::Rle => RLE_LOSSLESS,
+            OutputSyntax::JpegLs => JPEG_LS_LOSSLESS,
+        }
+    }
+
+    /// Pixel Data хранится фрагментами сжатых кадров
+    fn is_encapsulated(&self) -> bool {
+        matches!(self, OutputSyntax::Rle | OutputSyntax::JpegLs)
+    }
+}
+
+/// Переводит объект в синтаксис передачи `syntax`: при необходимости распаковывает Pixel Data
+/// и сжимает его заново, затем обновляет Transfer Syntax UID и длину группы File Meta Information.
+/// Объект без Pixel Data при запросе RLE или JPEG-LS сохраняется в Explicit VR Little Endian
+pub fn transcode(obj: &mut DefaultDicomObject, syntax: OutputSyntax) -> Result<(), String> {
+    let current = obj.meta().transfer_syntax().to_string();
+    let pixel_data = obj.element(work_json::PIXEL_DATA).ok().map(|element| element.value().clone());
+    let target = match pixel_data {
+        None if syntax.is_encapsulated() => OutputSyntax::ExplicitLittle,
+        _ => syntax,
+    };
+    if current == target.uid() {
+        return Ok(());
+    }
+    let encapsulated = matches!(pixel_data, Some(Value::PixelSequence { .. }));
+    if encapsulated || (pixel_data.is_some() && target.is_encapsulated()) {
+        let pixels = work_pixels::decode(obj)?;
+        let value = if target.is_encapsulated() {
+            encapsulate(&pixels, target)?
+        } else {
+            native(&pixels)
+        };
+        let vr = if pixels.bits_allocated > 8 && !target.is_encapsulated() { VR::OW } else { VR::OB };
+        obj.put(DataElement::new(work_json::PIXEL_DATA, vr, value));
+        // Кодеки отдают компоненты пикселя подряд, а YBR после распаковки уже переведены в RGB
+        if pixels.samples_per_pixel > 1 {
+            obj.put(DataElement::new(Tag(0x0028, 0x0006), VR::US, PrimitiveValue::from(0_u16)));
+        }
+        obj.put(DataElement::new(Tag(0x0028, 0x0004), VR::CS, PrimitiveValue::from(pixels.photometric.as_str())));
+    }
+    let meta = obj.meta_mut();
+    meta.transfer_syntax = target.uid().to_string();
+    meta.update_information_group_length();
+    Ok(())
+}
+
+/// Несжатый Pixel Data: значения в Little Endian по Bits Allocated
+fn native(pixels: &Pixels) -> Value<dicom::object::InMemDicomObject, Vec<u8>> {
+    let bytes = pixels.frame_format().bytes_per_sample();
+    let mut data: Vec<u8> = pixels.values.iter()
+        .flat_map(|value| (*value as u32).to_le_bytes().into_iter().take(bytes))
+        .collect();
+    if data.len() % 2 == 1 {
+        data.push(0);
+    }
+    Value::Primitive(PrimitiveValue::U8(data.into()))
+}
+
+/// Инкапсулированный Pixel Data: кадр на фрагмент и Basic Offset Table
+fn encapsulate(pixels: &Pixels, syntax: OutputSyntax) -> Result<Value<dicom::object::InMemDicomObject, Vec<u8>>, String> {
+    let format = pixels.frame_format();
+    // Кодеки принимают беззнаковые значения: дополнительный код обрезается по Bits Stored
+    let mask = if format.bits_stored >= 32 { u32::MAX } else { (1u32 << format.bits_stored) - 1 };
+    let mut offset_table = Vec::with_capacity(pixels.frames);
+    let mut fragments = Vec::with_capacity(pixels.frames);
+    let mut position = 0u32;
+    for frame in pixels.values.chunks(format.samples().max(1)) {
+        let unsigned: Vec<i32> = frame.iter().map(|value| (*value as u32 & mask) as i32).collect();
+        let mut fragment = encode_frame(&unsigned, &format, syntax)?;
+        if fragment.len() % 2 == 1 {
+            fragment.push(0);
+        }
+        offset_table.push(position);
+        position += 8 + fragment.len() as u32;
+        fragments.push(fragment);
+    }
+    Ok(Value::PixelSequence { offset_table: offset_table.into(), fragments: fragments.into() })
+}
+
+#[cfg(feature = "codecs")]
+fn encode_frame(values: &[i32], format: &FrameFormat, syntax: OutputSyntax) -> Result<Vec<u8>, String> {
+    match syntax {
+        OutputSyntax::Rle => work_rle::encode(values, format),
+        OutputSyntax::JpegLs => work_jpegls::encode(values, format),
+        _ => Err(format!("{} is not a compressed transfer syntax", syntax.uid())),
+    }
+}
+
+#[cfg(not(feature = "codecs"))]
+fn encode_frame(_values: &[i32], _format: &FrameFormat, syntax: OutputSyntax) -> Result<Vec<u8>, String> {
+    Err(format!("compressing Pixel Data is not supported without the codecs feature (transfer syntax {})", syntax.uid()))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dicom::object::{FileMetaTableBuilder, InMemDicomObject};
+    use crate::work_pixels::tests::{synthetic_image, synthetic_rgb};
+
+    /// Несжатый объект с кадрами `values`; значения со знаком, если `signed`
+    fn image_obj(values: &[i32], format: &FrameFormat, frames: usize, signed: bool, photometric: &str) -> DefaultDicomObject {
+        let mut obj = InMemDicomObject::new_empty();
+        let us = |tag, value: usize| DataElement::new(tag, VR::US, PrimitiveValue::from(value as u16));
+        obj.put(us(Tag(0x0028, 0x0010), format.rows));
+        obj.put(us(Tag(0x0028, 0x0011), format.columns));
+        obj.put(us(Tag(0x0028, 0x0002), format.samples_per_pixel));
+        obj.put(us(Tag(0x0028, 0x0100), format.bits_allocated));
+        obj.put(us(Tag(0x0028, 0x0101), format.bits_stored));
+        obj.put(us(Tag(0x0028, 0x0103), signed as usize));
+        obj.put(DataElement::new(Tag(0x0028, 0x0008), VR::IS, PrimitiveValue::from(frames.to_string())));
+        obj.put(DataElement::new(Tag(0x0028, 0x0004), VR::CS, PrimitiveValue::from(photometric)));
+        obj.put(DataElement::new(Tag(0x0008, 0x0016), VR::UI, PrimitiveValue::from("1.2.840.10008.5.1.4.1.1.2")));
+        obj.put(DataElement::new(Tag(0x0008, 0x0018), VR::UI, PrimitiveValue::from("1.2.3.100.1.1")));
+        let pixels = Pixels {
+            rows: format.rows,
+            columns: format.columns,
+            samples_per_pixel: format.samples_per_pixel,
+            frames,
+            bits_allocated: format.bits_allocated,
+            bits_stored: format.bits_stored,
+            photometric: photometric.to_string(),
+            values: values.to_vec(),
+        };
+        obj.put(DataElement::new(work_json::PIXEL_DATA, VR::OW, native(&pixels)));
+        obj.with_meta(FileMetaTableBuilder::new()
+            .media_storage_sop_class_uid("1.2.840.10008.5.1.4.1.1.2")
+            .media_storage_sop_instance_uid("1.2.3.100.1.1")
+            .transfer_syntax(work_dcm::EXPLICIT_VR_LITTLE_ENDIAN))
+            .unwrap()
+    }
+
+    /// Длина группы File Meta Information, как она будет записана
+    fn written_group_length(obj: &DefaultDicomObject) -> u32 {
+        let mut meta = Vec::new();
+        obj.meta().write(&mut meta).unwrap();
+        u32::from_le_bytes([meta[8], meta[9], meta[10], meta[11]])
+    }
+
+    fn assert_meta(obj: &DefaultDicomObject, syntax: OutputSyntax) {
+        assert_eq!(obj.meta().transfer_syntax(), syntax.uid());
+        let mut meta = Vec::new();
+        obj.meta().write(&mut meta).unwrap();
+        assert_eq!(written_group_length(obj) as usize, meta.len() - 12);
+    }
+
+    #[test]
+    fn syntax_names_and_uids_are_parsed() {
+        assert_eq!("rle".parse::<OutputSyntax>().unwrap(), OutputSyntax::Rle);
+        assert_eq!("JPEG-LS".parse::<OutputSyntax>().unwrap(), OutputSyntax::JpegLs);
+        assert_eq!("1.2.840.10008.1.2".parse::<OutputSyntax>().unwrap(), OutputSyntax::ImplicitLittle);
+        assert!("jpeg".parse::<OutputSyntax>().is_err());
+    }
+
+    #[cfg(feature = "codecs")]
+    #[test]
+    fn signed_frames_survive_compression_and_back() {
+        let (image, format) = synthetic_image(16, 12);
+        // Два кадра со значениями от -2048 до 2047
+        let values: Vec<i32> = image.iter().chain(image.iter().rev()).map(|value| value - 2048).collect();
+        for syntax in [OutputSyntax::Rle, OutputSyntax::JpegLs] {
+            let mut obj = image_obj(&values, &format, 2, true, "MONOCHROME2");
+            transcode(&mut obj, syntax).unwrap();
+            assert_meta(&obj, syntax);
+            match obj.element(work_json::PIXEL_DATA).unwrap().value() {
+                Value::PixelSequence { offset_table, fragments } => {
+                    assert_eq!(fragments.len(), 2);
+                    assert_eq!(offset_table[1], 8 + fragments[0].len() as u32);
+                }
+                _ => panic!("Pixel Data is not encapsulated"),
+            }
+            assert_eq!(work_pixels::decode(&obj).unwrap().values, values);
+
+            transcode(&mut obj, OutputSyntax::ImplicitLittle).unwrap();
+            assert_meta(&obj, OutputSyntax::ImplicitLittle);
+            assert!(matches!(obj.element(work_json::PIXEL_DATA).unwrap().value(), Value::Primitive(_)));
+            assert_eq!(work_pixels::decode(&obj).unwrap().values, values);
+        }
+    }
+
+    #[cfg(feature = "codecs")]
+    #[test]
+    fn planar_rgb_becomes_interleaved() {
+        let (values, format) = synthetic_rgb();
+        let pixels = format.rows * format.columns;
+        let planes: Vec<i32> = (0..3).flat_map(|sample| values.iter().skip(sample).step_by(3).copied()).collect();
+        let mut obj = image_obj(&planes, &format, 1, false, "RGB");
+        obj.put(DataElement::new(Tag(0x0028, 0x0006), VR::US, PrimitiveValue::from(1_u16)));
+        assert_eq!(planes.len(), pixels * 3);
+        transcode(&mut obj, OutputSyntax::Rle).unwrap();
+        assert_eq!(obj.element_by_name("PlanarConfiguration").unwrap().to_int::<u16>().unwrap(), 0);
+        assert_eq!(work_pixels::decode(&obj).unwrap().values, values);
+    }
+
+    #[test]
+    fn deflated_file_is_written_and_read_back() {
+        let (values, format) = synthetic_image(8, 8);
+        let mut obj = image_obj(&values, &format, 1, false, "MONOCHROME2");
+        transcode(&mut obj, OutputSyntax::Deflated).unwrap();
+        assert_meta(&obj, OutputSyntax::Deflated);
+        let target = std::env::temp_dir().join(format!("dcm_finder_test_{}.deflated.dcm", std::process::id()));
+        work_dcm::save_dcm(&mut obj, &target.to_string_lossy().to_string(), None).unwrap();
+        let data = std::fs::read(&target).unwrap();
+        std::fs::remove_file(&target).unwrap();
+        // Набор данных сжат: в файле нет несжатого Pixel Data
+        assert!(data.len() < values.len());
+        let read = work_dcm::read_dcm_from_bytes(&data).unwrap();
+        assert_eq!(read.meta().transfer_syntax(), work_dcm::DEFLATED_EXPLICIT_VR_LITTLE_ENDIAN);
+        assert_eq!(work_pixels::decode(&read).unwrap().values, values);
+    }
+
+    #[test]
+    fn objects_without_pixel_data_stay_native() {
+        let (values, format) = synthetic_image(8, 8);
+        let mut obj = image_obj(&values, &format, 1, false, "MONOCHROME2");
+        obj.remove_element(work_json::PIXEL_DATA);
+        transcode(&mut obj, OutputSyntax::JpegLs).unwrap();
+        assert_meta(&obj, OutputSyntax::ExplicitLittle);
+    }
+}
@@ -0,0 +1,688 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path;
+use dicom::core::{Tag, VR};
+use dicom::core::header::Header;
+use dicom::core::dictionary::{DataDictionary, DictionaryEntry};
+use dicom::core::value::{PrimitiveValue, Value};
+use dicom::object::{DefaultDicomObject, InMemDicomObject, StandardDataDictionary};
+use indicatif::{ProgressBar, ProgressStyle};
+use rayon::prelude::*;
+use serde::Serialize;
+
+use crate::work_dcm;
+
+
+/// Серьезность проблемы: ошибка — нарушение стандарта, предупреждение — файл читается,
+/// но проверен не полностью или закодирован подозрительно
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl Severity {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+}
+
+/// Проблема, найденная в файле. `kind` — тип проблемы, по нему считается сводка
+#[derive(Debug, Clone, Serialize)]
+pub struct Issue {
+    pub severity: Severity,
+    pub kind: &'static str,
+    /// Тег атрибута `(gggg,eeee)`, если проблема относится к атрибуту
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    pub message: String,
+}
+
+impl Issue {
+    fn error(kind: &'static str, tag: Option<Tag>, message: String) -> Issue {
+        Issue { severity: Severity::Error, kind, tag: tag.map(|tag| tag.to_string()), message }
+    }
+
+    fn warning(kind: &'static str, tag: Option<Tag>, message: String) -> Issue {
+        Issue { severity: Severity::Warning, kind, tag: tag.map(|tag| tag.to_string()), message }
+    }
+}
+
+/// Результат проверки одного файла
+#[derive(Debug, Serialize)]
+pub struct FileReport {
+    pub path: String,
+    /// IOD, по требованиям которого проверен файл
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iod: Option<&'static str>,
+    pub issues: Vec<Issue>,
+}
+
+impl FileReport {
+    fn count(&self, severity: Severity) -> usize {
+        self.issues.iter().filter(|issue| issue.severity == severity).count()
+    }
+}
+
+/// Тип атрибута в модуле: 1 — обязателен и не пуст, 2 — обязателен, может быть пустым
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum AttributeType {
+    One,
+    Two,
+}
+
+/// Атрибут модуля IOD с типом и допустимой множественностью значений (VM: "1", "6", "1-n", "2-n")
+struct Requirement {
+    tag: Tag,
+    attribute_type: AttributeType,
+    vm: &'static str,
+}
+
+const fn one(group: u16, element: u16, vm: &'static str) -> Requirement {
+    Requirement { tag: Tag(group, element), attribute_type: AttributeType::One, vm }
+}
+
+const fn two(group: u16, element: u16, vm: &'static str) -> Requirement {
+    Requirement { tag: Tag(group, element), attribute_type: AttributeType::Two, vm }
+}
+
+/// Модуль IOD (PS3.3 C): только атрибуты типов 1 и 2
+struct Module {
+    name: &'static str,
+    attributes: &'static [Requirement],
+}
+
+const PATIENT: Module = Module { name: "Patient", attributes: &[
+    two(0x0010, 0x0010, "1"), two(0x0010, 0x0020, "1"), two(0x0010, 0x0030, "1"), two(0x0010, 0x0040, "1"),
+] };
+const GENERAL_STUDY: Module = Module { name: "General Study", attributes: &[
+    one(0x0020, 0x000D, "1"), two(0x0008, 0x0020, "1"), two(0x0008, 0x0030, "1"), two(0x0008, 0x0090, "1"),
+    two(0x0020, 0x0010, "1"), two(0x0008, 0x0050, "1"),
+] };
+const GENERAL_SERIES: Module = Module { name: "General Series", attributes: &[
+    one(0x0008, 0x0060, "1"), one(0x0020, 0x000E, "1"), two(0x0020, 0x0011, "1"),
+] };
+const FRAME_OF_REFERENCE: Module = Module { name: "Frame of Reference", attributes: &[
+    one(0x0020, 0x0052, "1"), two(0x0020, 0x1040, "1"),
+] };
+const GENERAL_EQUIPMENT: Module = Module { name: "General Equipment", attributes: &[
+    two(0x0008, 0x0070, "1"),
+] };
+const GENERAL_IMAGE: Module = Module { name: "General Image", attributes: &[
+    two(0x0020, 0x0013, "1"),
+] };
+const IMAGE_PLANE: Module = Module { name: "Image Plane", attributes: &[
+    one(0x0028, 0x0030, "2"), one(0x0020, 0x0037, "6"), one(0x0020, 0x0032, "3"), two(0x0018, 0x0050, "1"),
+] };
+const IMAGE_PIXEL: Module = Module { name: "Image Pixel", attributes: &[
+    one(0x0028, 0x0002, "1"), one(0x0028, 0x0004, "1"), one(0x0028, 0x0010, "1"), one(0x0028, 0x0011, "1"),
+    one(0x0028, 0x0100, "1"), one(0x0028, 0x0101, "1"), one(0x0028, 0x0102, "1"), one(0x0028, 0x0103, "1"),
+    one(0x7FE0, 0x0010, "1"),
+] };
+const SOP_COMMON: Module = Module { name: "SOP Common", attributes: &[
+    one(0x0008, 0x0016, "1"), one(0x0008, 0x0018, "1"),
+] };
+const CT_IMAGE: Module = Module { name: "CT Image", attributes: &[
+    one(0x0008, 0x0008, "2-n"), one(0x0028, 0x1052, "1"), one(0x0028, 0x1053, "1"),
+    two(0x0018, 0x0060, "1"), two(0x0020, 0x0012, "1"),
+] };
+const MR_IMAGE: Module = Module { name: "MR Image", attributes: &[
+    one(0x0008, 0x0008, "2-n"), one(0x0018, 0x0020, "1-n"), one(0x0018, 0x0021, "1-n"),
+    two(0x0018, 0x0022, "1-n"), two(0x0018, 0x0023, "1"), two(0x0018, 0x0081, "1"), two(0x0018, 0x0091, "1"),
+] };
+const CR_SERIES: Module = Module { name: "CR Series", attributes: &[
+    two(0x0018, 0x0015, "1"), two(0x0018, 0x5101, "1"),
+] };
+const DX_SERIES: Module = Module { name: "DX Series", attributes: &[
+    one(0x0008, 0x0068, "1"),
+] };
+const DX_ANATOMY_IMAGED: Module = Module { name: "DX Anatomy Imaged", attributes: &[
+    one(0x0020, 0x0062, "1"),
+] };
+const DX_IMAGE: Module = Module { name: "DX Image", attributes: &[
+    one(0x0008, 0x0008, "2-n"), one(0x0028, 0x1040, "1"), one(0x0028, 0x1041, "1"), one(0x0028, 0x1052, "1"),
+    one(0x0028, 0x1053, "1"), one(0x0028, 0x1054, "1"), one(0x0028, 0x2110, "1"), one(0x0028, 0x0301, "1"),
+] };
+const DX_DETECTOR: Module = Module { name: "DX Detector", attributes: &[
+    one(0x0018, 0x1164, "2"),
+] };
+const US_IMAGE: Module = Module { name: "US Image", attributes: &[
+    two(0x0008, 0x0008, "2-n"),
+] };
+const MULTI_FRAME: Module = Module { name: "Multi-frame", attributes: &[
+    one(0x0028, 0x0008, "1"), one(0x0028, 0x0009, "1-n"),
+] };
+const SC_EQUIPMENT: Module = Module { name: "SC Equipment", attributes: &[
+    one(0x0008, 0x0064, "1"),
+] };
+
+/// IOD (PS3.3 A): обязательные модули и перечисляемое значение Modality, если оно задано
+struct Iod {
+    sop_class_uid: &'static str,
+    name: &'static str,
+    modality: Option<&'static str>,
+    modules: &'static [&'static Module],
+}
+
+const IODS: &[Iod] = &[
+    Iod { sop_class_uid: "1.2.840.10008.5.1.4.1.1.2", name: "CT Image", modality: Some("CT"), modules: &[
+        &PATIENT, &GENERAL_STUDY, &GENERAL_SERIES, &FRAME_OF_REFERENCE, &GENERAL_EQUIPMENT, &GENERAL_IMAGE,
+        &IMAGE_PLANE, &IMAGE_PIXEL, &CT_IMAGE, &SOP_COMMON,
+    ] },
+    Iod { sop_class_uid: "1.2.840.10008.5.1.4.1.1.4", name: "MR Image", modality: Some("MR"), modules: &[
+        &PATIENT, &GENERAL_STUDY, &GENERAL_SERIES, &FRAME_OF_REFERENCE, &GENERAL_EQUIPMENT, &GENERAL_IMAGE,
+        &IMAGE_PLANE, &IMAGE_PIXEL, &MR_IMAGE, &SOP_COMMON,
+    ] },
+    Iod { sop_class_uid: "1.2.840.10008.5.1.4.1.1.1", name: "CR Image", modality: None, modules: &[
+        &PATIENT, &GENERAL_STUDY, &GENERAL_SERIES, &CR_SERIES, &GENERAL_EQUIPMENT, &GENERAL_IMAGE,
+        &IMAGE_PIXEL, &SOP_COMMON,
+    ] },
+    Iod { sop_class_uid: "1.2.840.10008.5.1.4.1.1.1.1", name: "DX Image For Presentation", modality: Some("DX"), modules: &[
+        &PATIENT, &GENERAL_STUDY, &GENERAL_SERIES, &DX_SERIES, &GENERAL_EQUIPMENT, &GENERAL_IMAGE, &IMAGE_PIXEL,
+        &DX_ANATOMY_IMAGED, &DX_IMAGE, &DX_DETECTOR, &SOP_COMMON,
+    ] },
+    Iod { sop_class_uid: "1.2.840.10008.5.1.4.1.1.1.1.1", name: "DX Image For Processing", modality: Some("DX"), modules: &[
+        &PATIENT, &GENERAL_STUDY, &GENERAL_SERIES, &DX_SERIES, &GENERAL_EQUIPMENT, &GENERAL_IMAGE, &IMAGE_PIXEL,
+        &DX_ANATOMY_IMAGED, &DX_IMAGE, &DX_DETECTOR, &SOP_COMMON,
+    ] },
+    Iod { sop_class_uid: "1.2.840.10008.5.1.4.1.1.6.1", name: "US Image", modality: Some("US"), modules: &[
+        &PATIENT, &GENERAL_STUDY, &GENERAL_SERIES, &GENERAL_EQUIPMENT, &GENERAL_IMAGE, &IMAGE_PIXEL,
+        &US_IMAGE, &SOP_COMMON,
+    ] },
+    Iod { sop_class_uid: "1.2.840.10008.5.1.4.1.1.3.1", name: "US Multi-frame Image", modality: Some("US"), modules: &[
+        &PATIENT, &GENERAL_STUDY, &GENERAL_SERIES, &GENERAL_EQUIPMENT, &GENERAL_IMAGE, &IMAGE_PIXEL,
+        &MULTI_FRAME, &US_IMAGE, &SOP_COMMON,
+    ] },
+    Iod { sop_class_uid: "1.2.840.10008.5.1.4.1.1.7", name: "Secondary Capture Image", modality: None, modules: &[
+        &PATIENT, &GENERAL_STUDY, &GENERAL_SERIES, &SC_EQUIPMENT, &GENERAL_IMAGE, &IMAGE_PIXEL, &SOP_COMMON,
+    ] },
+];
+
+/// Проверяет файлы и выводит проблемы по файлам и сводку по типам проблем.
+/// С `output` отчет также пишется в JSON (файлы с проблемами и сводка)
+pub fn validate(paths: &[String], output: Option<&path::Path>) {
+    let progress = ProgressBar::new(paths.len() as u64);
+    progress.set_style(ProgressStyle::default_bar()
+        .template("[{elapsed_precise}] {bar:40} validated {pos} of {len} files ({per_sec})"));
+    let reports: Vec<FileReport> = paths.par_iter()
+        .map(|path| {
+            let report = validate_file(path);
+            progress.inc(1);
+            report
+        })
+        .collect();
+    progress.finish_and_clear();
+
+    let mut by_kind: BTreeMap<(Severity, &str), usize> = BTreeMap::new();
+    for report in &reports {
+        if report.issues.is_empty() {
+            continue;
+        }
+        let iod = report.iod.map(|iod| format!(" ({})", iod)).unwrap_or_default();
+        println!("{}: {} errors, {} warnings{}", report.path, report.count(Severity::Error), report.count(Severity::Warning), iod);
+        for issue in &report.issues {
+            println!("  {:<8} {:<16} {:<12} {}", issue.severity.as_str(), issue.kind,
+                     issue.tag.as_deref().unwrap_or(""), issue.message);
+            *by_kind.entry((issue.severity, issue.kind)).or_insert(0) += 1;
+        }
+    }
+    let with_errors = reports.iter().filter(|report| report.count(Severity::Error) > 0).count();
+    let with_warnings = reports.iter().filter(|report| !report.issues.is_empty()).count() - with_errors;
+    println!("Validated files: {}, valid: {}, with errors: {}, with warnings only: {}",
+             reports.len(), reports.len() - with_errors - with_warnings, with_errors, with_warnings);
+    for ((severity, kind), count) in &by_kind {
+        println!("  {:<8} {:<16} {}", severity.as_str(), kind, count);
+    }
+
+    if let Some(output) = output {
+        let summary = Summary {
+            files: reports.len(),
+            with_errors,
+            with_warnings,
+            issues: by_kind.iter()
+                .map(|((severity, kind), count)| SummaryEntry { severity: *severity, kind, count: *count })
+                .collect(),
+        };
+        let files: Vec<&FileReport> = reports.iter().filter(|report| !report.issues.is_empty()).collect();
+        let report = serde_json::json!({ "summary": summary, "files": files });
+        serde_json::to_string_pretty(&report).map_err(|e| e.to_string())
+            .and_then(|text| fs::write(output, text).map_err(|e| e.to_string()))
+            .unwrap_or_else(|e| eprintln!("Error writing validation report {}: {}", output.display(), e));
+    }
+}
+
+#[derive(Serialize)]
+struct Summary<'a> {
+    files: usize,
+    with_errors: usize,
+    with_warnings: usize,
+    issues: Vec<SummaryEntry<'a>>,
+}
+
+#[derive(Serialize)]
+struct SummaryEntry<'a> {
+    severity: Severity,
+    kind: &'a str,
+    count: usize,
+}
+
+/// Проверяет один файл (путь из индекса, в том числе внутри архива)
+pub fn validate_file(path: &str) -> FileReport {
+    match work_dcm::read_indexed_dcm(path) {
+        Ok(obj) => {
+            let (iod, issues) = check_obj(&obj);
+            FileReport { path: path.to_string(), iod, issues }
+        }
+        Err(e) => FileReport {
+            path: path.to_string(),
+            iod: None,
+            issues: vec![Issue::error("unreadable", None, format!("the file cannot be read: {}", e))],
+        },
+    }
+}
+
+/// Проверяет объект: File Meta Information, VR и значения всех атрибутов (включая вложенные
+/// последовательности), требования IOD для известных SOP Class. Возвращает имя IOD и проблемы
+pub fn check_obj(obj: &DefaultDicomObject) -> (Option<&'static str>, Vec<Issue>) {
+    let mut issues = Vec::new();
+    if let Err(e) = work_dcm::check_file_meta(obj) {
+        issues.push(Issue::error("file-meta", None, e));
+    }
+    for (tag, problem) in work_dcm::file_meta_uid_problems(obj) {
+        issues.push(Issue::error("invalid-uid", Some(tag), problem));
+    }
+    check_elements(obj, &mut issues);
+
+    let sop_class_uid = text(obj, Tag(0x0008, 0x0016)).unwrap_or_default();
+    let iod = IODS.iter().find(|iod| iod.sop_class_uid == sop_class_uid);
+    match iod {
+        Some(iod) => check_iod(obj, iod, &mut issues),
+        None => issues.push(Issue::warning("unchecked-iod", Some(Tag(0x0008, 0x0016)),
+                                           format!("IOD requirements of SOP Class {} are not checked", sop_class_uid))),
+    }
+    issues.sort_by(|a, b| a.severity.cmp(&b.severity).then_with(|| a.tag.cmp(&b.tag)));
+    (iod.map(|iod| iod.name), issues)
+}
+
+fn check_iod(obj: &DefaultDicomObject, iod: &Iod, issues: &mut Vec<Issue>) {
+    for module in iod.modules {
+        for requirement in module.attributes {
+            let tag = requirement.tag;
+            let name = attribute_name(tag);
+            let element = match obj.element(tag) {
+                Ok(element) => element,
+                Err(_) => {
+                    let kind = match requirement.attribute_type {
+                        AttributeType::One => "missing-type1",
+                        AttributeType::Two => "missing-type2",
+                    };
+                    issues.push(Issue::error(kind, Some(tag), format!("{} is missing ({} module)", name, module.name)));
+                    continue;
+                }
+            };
+            let multiplicity = multiplicity(element.vr(), element.value());
+            if multiplicity == 0 {
+                if requirement.attribute_type == AttributeType::One {
+                    issues.push(Issue::error("empty-type1", Some(tag), format!("{} is empty ({} module)", name, module.name)));
+                }
+            } else if !vm_allows(requirement.vm, multiplicity) {
+                issues.push(Issue::error("wrong-vm", Some(tag),
+                                         format!("{} has {} values, VM {} expected", name, multiplicity, requirement.vm)));
+            }
+        }
+    }
+    // Planar Configuration обязателен (1C) для цветных изображений
+    let samples = obj.element(Tag(0x0028, 0x0002)).ok().and_then(|element| element.to_int::<u16>().ok());
+    if samples.unwrap_or(1) > 1 && obj.element(Tag(0x0028, 0x0006)).is_err() {
+        issues.push(Issue::error("missing-type1", Some(Tag(0x0028, 0x0006)),
+                                 "PlanarConfiguration is missing: Samples per Pixel is greater than 1 (Image Pixel module)".to_string()));
+    }
+    if let Some(expected) = iod.modality {
+        match text(obj, Tag(0x0008, 0x0060)) {
+            Some(modality) if modality != expected => issues.push(Issue::error("invalid-value", Some(Tag(0x0008, 0x0060)),
+                format!("Modality is {}, {} expected for {}", modality, expected, iod.name))),
+            _ => {}
+        }
+    }
+}
+
+/// Проверяет VR и значения атрибутов набора данных и его последовательностей
+fn check_elements(obj: &InMemDicomObject, issues: &mut Vec<Issue>) {
+    for element in obj {
+        let tag = element.tag();
+        // Длины групп устарели и пересчитываются при записи
+        if tag.element() == 0x0000 {
+            continue;
+        }
+        let vr = element.vr();
+        if tag.group() % 2 == 0 {
+            if let Some(entry) = StandardDataDictionary.by_tag(tag) {
+                if vr == VR::UN {
+                    issues.push(Issue::warning("vr-un", Some(tag), format!("{} is encoded as UN, {} expected", entry.alias(), entry.vr())));
+                } else if !vr_matches(vr, entry.vr()) {
+                    issues.push(Issue::error("wrong-vr", Some(tag), format!("{} has VR {}, {} expected", entry.alias(), vr, entry.vr())));
+                }
+            }
+        }
+        match element.value() {
+            Value::Sequence { items, .. } => {
+                for item in items {
+                    check_elements(item, issues);
+                }
+            }
+            Value::Primitive(value) => check_values(tag, vr, value, issues),
+            Value::PixelSequence { .. } => {}
+        }
+    }
+}
+
+/// VR в словаре dicom-rs для атрибутов с несколькими допустимыми VR — первый из них
+/// ("US or SS" — US, "OB or OW" — OB)
+fn vr_matches(vr: VR, expected: VR) -> bool {
+    vr == expected
+        || matches!((vr, expected), (VR::US | VR::SS | VR::OW, VR::US | VR::SS | VR::OW))
+        || matches!((vr, expected), (VR::OB | VR::OW, VR::OB | VR::OW))
+}
+
+/// Проверяет строковые значения по правилам VR (PS3.5 6.2)
+fn check_values(tag: Tag, vr: VR, value: &PrimitiveValue, issues: &mut Vec<Issue>) {
+    let values = match strings(vr, value) {
+        Some(values) => values,
+        None => return,
+    };
+    let name = attribute_name(tag);
+    for value in values {
+        let value = value.trim_end_matches(['\0', ' ']);
+        if value.is_empty() {
+            continue;
+        }
+        if vr == VR::UI {
+            if !work_dcm::is_valid_uid(value) {
+                let reason = if value.len() > 64 { "is longer than 64 characters" } else { "is not a valid UID" };
+                issues.push(Issue::error("invalid-uid", Some(tag), format!("{} '{}' {}", name, value, reason)));
+            }
+            continue;
+        }
+        if let Some(max) = max_length(vr) {
+            // У PN ограничена каждая группа компонентов (алфавитная, идеографическая, фонетическая)
+            let length = if vr == VR::PN { value.split('=').map(str::len).max().unwrap_or(0) } else { value.len() };
+            if length > max {
+                issues.push(Issue::error("value-too-long", Some(tag),
+                                         format!("{} value of {} characters exceeds {} for {}", name, length, max, vr)));
+                continue;
+            }
+        }
+        if !value_is_valid(vr, value) {
+            issues.push(Issue::error("invalid-value", Some(tag), format!("{} '{}' is not a valid {} value", name, value, vr)));
+        }
+    }
+}
+
+/// Наибольшая длина значения VR в байтах (PS3.5 таблица 6.2-1)
+fn max_length(vr: VR) -> Option<usize> {
+    match vr {
+        VR::AE | VR::CS | VR::DS | VR::SH => Some(16),
+        VR::AS => Some(4),
+        VR::DA => Some(8),
+        VR::DT => Some(26),
+        VR::IS => Some(12),
+        VR::LO | VR::PN => Some(64),
+        VR::TM => Some(14),
+        VR::ST => Some(1024),
+        VR::LT => Some(10240),
+        _ => None,
+    }
+}
+
+fn value_is_valid(vr: VR, value: &str) -> bool {
+    let digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    match vr {
+        VR::AE => !value.bytes().any(|b| b.is_ascii_control() || b == b'\\'),
+        VR::AS => value.len() == 4 && digits(&value[..3]) && matches!(&value[3..], "D" | "W" | "M" | "Y"),
+        VR::CS => value.bytes().all(|b| b.is_ascii_uppercase() || b.is_ascii_digit() || b == b' ' || b == b'_'),
+        VR::DA => is_valid_date(value),
+        VR::DT => is_valid_date_time(value),
+        VR::TM => is_valid_time(value),
+        VR::DS => {
+            let value = value.trim();
+            value.bytes().all(|b| b.is_ascii_digit() || matches!(b, b'+' | b'-' | b'.' | b'e' | b'E'))
+                && value.parse::<f64>().is_ok_and(f64::is_finite)
+        }
+        VR::IS => value.trim().parse::<i64>().is_ok_and(|number| i32::try_from(number).is_ok()),
+        _ => true,
+    }
+}
+
+/// DA: YYYYMMDD с существующим днем месяца
+fn is_valid_date(value: &str) -> bool {
+    if value.len() != 8 || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return false;
+    }
+    let year: u32 = value[..4].parse().unwrap_or(0);
+    let month: u32 = value[4..6].parse().unwrap_or(0);
+    let day: u32 = value[6..].parse().unwrap_or(0);
+    let leap = year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400));
+    let days = match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if leap => 29,
+        2 => 28,
+        _ => return false,
+    };
+    (1..=days).contains(&day)
+}
+
+/// TM: HH[MM[SS[.F{1,6}]]]
+fn is_valid_time(value: &str) -> bool {
+    let (whole, fraction) = match value.split_once('.') {
+        Some((whole, fraction)) => (whole, Some(fraction)),
+        None => (value, None),
+    };
+    if !matches!(whole.len(), 2 | 4 | 6) || !whole.bytes().all(|b| b.is_ascii_digit()) {
+        return false;
+    }
+    if let Some(fraction) = fraction {
+        if whole.len() != 6 || fraction.is_empty() || fraction.len() > 6 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+            return false;
+        }
+    }
+    let limits = [24, 60, 61];
+    whole.as_bytes().chunks(2).zip(limits)
+        .all(|(pair, limit)| ((pair[0] - b'0') as u32 * 10 + (pair[1] - b'0') as u32) < limit)
+}
+
+/// DT: YYYY[MM[DD[HH[MM[SS[.F{1,6}]]]]]][&ZZXX]
+fn is_valid_date_time(value: &str) -> bool {
+    let (value, offset) = match value.find(['+', '-']) {
+        Some(position) => (&value[..position], Some(&value[position + 1..])),
+        None => (value, None),
+    };
+    if let Some(offset) = offset {
+        if offset.len() != 4 || !offset.bytes().all(|b| b.is_ascii_digit()) {
+            return false;
+        }
+    }
+    let whole = value.split('.').next().unwrap_or_default();
+    if !matches!(whole.len(), 4 | 6 | 8 | 10 | 12 | 14) || !whole.bytes().all(|b| b.is_ascii_digit()) {
+        return false;
+    }
+    // Недостающие месяц и день дополняются единицами, время проверяется как TM
+    let date_length = whole.len().min(8);
+    let date = format!("{}{}", &whole[..date_length], &"0101"[..8 - date_length]);
+    is_valid_date(&date) && (value.len() == date_length || (whole.len() > 8 && is_valid_time(&value[8..])))
+}
+
+fn vm_allows(vm: &str, multiplicity: usize) -> bool {
+    match vm.split_once('-') {
+        Some((min, "n")) => multiplicity >= min.parse().unwrap_or(1),
+        Some((min, max)) => (min.parse().unwrap_or(1)..=max.parse().unwrap_or(usize::MAX)).contains(&multiplicity),
+        None => vm.parse() == Ok(multiplicity),
+    }
+}
+
+/// Строковые значения атрибута, разделенные по `\` (кроме текстовых VR с одним значением)
+fn strings(vr: VR, value: &PrimitiveValue) -> Option<Vec<&str>> {
+    let values: Vec<&str> = match value {
+        PrimitiveValue::Strs(values) => values.iter().map(|value| value.as_str()).collect(),
+        PrimitiveValue::Str(value) => vec![value.as_str()],
+        _ => return None,
+    };
+    if matches!(vr, VR::ST | VR::LT | VR::UT | VR::UR) {
+        return Some(values);
+    }
+    Some(values.into_iter().flat_map(|value| value.split('\\')).collect())
+}
+
+/// Число значений атрибута; пустое значение — 0. Двоичные значения (OB, OW, ...) считаются одним
+fn multiplicity(vr: VR, value: &Value<InMemDicomObject, Vec<u8>>) -> usize {
+    match value {
+        Value::Primitive(PrimitiveValue::Empty) => 0,
+        Value::Primitive(primitive) => match strings(vr, primitive) {
+            Some(values) if values.iter().all(|value| value.trim_end_matches(['\0', ' ']).is_empty()) => 0,
+            Some(values) => values.len(),
+            None if matches!(vr, VR::OB | VR::OW | VR::OF | VR::OD | VR::OL | VR::OV | VR::UN) => 1,
+            None => primitive.multiplicity() as usize,
+        },
+        Value::Sequence { items, .. } => items.len(),
+        Value::PixelSequence { .. } => 1,
+    }
+}
+
+fn attribute_name(tag: Tag) -> String {
+    work_dcm::keyword_by_tag(tag).map(|keyword| keyword.to_string()).unwrap_or_else(|| tag.to_string())
+}
+
+fn text(obj: &InMemDicomObject, tag: Tag) -> Option<String> {
+    let value = obj.element(tag).ok()?.to_str().ok()?;
+    Some(value.trim_end_matches(['\0', ' ']).to_string())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dicom::core::DataElement;
+    use dicom::object::FileMetaTableBuilder;
+
+    /// Минимальный CT, удовлетворяющий всем проверяемым требованиям IOD
+    fn valid_ct() -> DefaultDicomObject {
+        let mut obj = InMemDicomObject::new_empty();
+        let strings: &[(Tag, VR, &str)] = &[
+            (Tag(0x0008, 0x0016), VR::UI, "1.2.840.10008.5.1.4.1.1.2"),
+            (Tag(0x0008, 0x0018), VR::UI, "1.2.3.100.1.1"),
+            (Tag(0x0008, 0x0008), VR::CS, "ORIGINAL\\PRIMARY\\AXIAL"),
+            (Tag(0x0008, 0x0020), VR::DA, "20240229"),
+            (Tag(0x0008, 0x0030), VR::TM, "101530.25"),
+            (Tag(0x0008, 0x0050), VR::SH, ""),
+            (Tag(0x0008, 0x0060), VR::CS, "CT"),
+            (Tag(0x0008, 0x0070), VR::LO, "Vendor"),
+            (Tag(0x0008, 0x0090), VR::PN, ""),
+            (Tag(0x0010, 0x0010), VR::PN, "Doe^John"),
+            (Tag(0x0010, 0x0020), VR::LO, "PID1"),
+            (Tag(0x0010, 0x0030), VR::DA, ""),
+            (Tag(0x0010, 0x0040), VR::CS, "O"),
+            (Tag(0x0018, 0x0050), VR::DS, "1.25"),
+            (Tag(0x0018, 0x0060), VR::DS, "120"),
+            (Tag(0x0020, 0x000D), VR::UI, "1.2.3"),
+            (Tag(0x0020, 0x000E), VR::UI, "1.2.3.100"),
+            (Tag(0x0020, 0x0010), VR::SH, "1"),
+            (Tag(0x0020, 0x0011), VR::IS, "2"),
+            (Tag(0x0020, 0x0012), VR::IS, "1"),
+            (Tag(0x0020, 0x0013), VR::IS, "1"),
+            (Tag(0x0020, 0x0032), VR::DS, "-100\\-100\\50.5"),
+            (Tag(0x0020, 0x0037), VR::DS, "1\\0\\0\\0\\1\\0"),
+            (Tag(0x0020, 0x0052), VR::UI, "1.2.3.200"),
+            (Tag(0x0020, 0x1040), VR::LO, ""),
+            (Tag(0x0028, 0x0004), VR::CS, "MONOCHROME2"),
+            (Tag(0x0028, 0x0030), VR::DS, "0.5\\0.5"),
+            (Tag(0x0028, 0x1052), VR::DS, "-1024"),
+            (Tag(0x0028, 0x1053), VR::DS, "1"),
+        ];
+        for &(tag, vr, value) in strings {
+            obj.put(DataElement::new(tag, vr, PrimitiveValue::from(value)));
+        }
+        for (element, value) in [(0x0002, 1u16), (0x0010, 2), (0x0011, 2), (0x0100, 16), (0x0101, 12), (0x0102, 11), (0x0103, 1)] {
+            obj.put(DataElement::new(Tag(0x0028, element), VR::US, PrimitiveValue::from(value)));
+        }
+        obj.put(DataElement::new(Tag(0x7FE0, 0x0010), VR::OW, PrimitiveValue::U16([0u16; 4].into_iter().collect())));
+        obj.with_meta(FileMetaTableBuilder::new()
+            .media_storage_sop_class_uid("1.2.840.10008.5.1.4.1.1.2")
+            .media_storage_sop_instance_uid("1.2.3.100.1.1")
+            .transfer_syntax(work_dcm::EXPLICIT_VR_LITTLE_ENDIAN))
+            .unwrap()
+    }
+
+    fn kinds(obj: &DefaultDicomObject) -> Vec<&'static str> {
+        check_obj(obj).1.iter().map(|issue| issue.kind).collect()
+    }
+
+    #[test]
+    fn valid_ct_has_no_issues() {
+        let (iod, issues) = check_obj(&valid_ct());
+        assert_eq!(iod, Some("CT Image"));
+        assert!(issues.is_empty(), "{:?}", issues);
+    }
+
+    #[test]
+    fn module_requirements_are_checked() {
+        let mut obj = valid_ct();
+        obj.remove_element(Tag(0x0020, 0x000D));
+        obj.remove_element(Tag(0x0010, 0x0020));
+        obj.put(DataElement::new(Tag(0x0028, 0x1052), VR::DS, PrimitiveValue::from("")));
+        obj.put(DataElement::new(Tag(0x0020, 0x0037), VR::DS, PrimitiveValue::from("1\\0\\0")));
+        obj.put(DataElement::new(Tag(0x0008, 0x0060), VR::CS, PrimitiveValue::from("MR")));
+        let mut found = kinds(&obj);
+        found.sort();
+        assert_eq!(found, vec!["empty-type1", "invalid-value", "missing-type1", "missing-type2", "wrong-vm"]);
+    }
+
+    #[test]
+    fn values_follow_vr_rules() {
+        let mut obj = valid_ct();
+        obj.put(DataElement::new(Tag(0x0008, 0x0020), VR::DA, PrimitiveValue::from("20230229")));
+        obj.put(DataElement::new(Tag(0x0010, 0x0040), VR::CS, PrimitiveValue::from("male")));
+        obj.put(DataElement::new(Tag(0x0020, 0x0010), VR::SH, PrimitiveValue::from("STUDY-ID-LONGER-THAN-16")));
+        obj.put(DataElement::new(Tag(0x0020, 0x000E), VR::UI, PrimitiveValue::from(format!("1.2.{}", "3".repeat(70)))));
+        obj.put(DataElement::new(Tag(0x0018, 0x0060), VR::IS, PrimitiveValue::from("120")));
+        let issues = check_obj(&obj).1;
+        let find = |tag: &str| issues.iter().find(|issue| issue.tag.as_deref() == Some(tag)).map(|issue| issue.kind);
+        assert_eq!(find("(0008,0020)"), Some("invalid-value"));
+        assert_eq!(find("(0010,0040)"), Some("invalid-value"));
+        assert_eq!(find("(0020,0010)"), Some("value-too-long"));
+        assert_eq!(find("(0020,000E)"), Some("invalid-uid"));
+        assert_eq!(find("(0018,0060)"), Some("wrong-vr"));
+    }
+
+    #[test]
+    fn meta_and_unknown_sop_classes_are_reported() {
+        let mut obj = valid_ct();
+        obj.put(DataElement::new(Tag(0x0008, 0x0018), VR::UI, PrimitiveValue::from("1.2.3.100.1.2")));
+        assert_eq!(kinds(&obj), vec!["file-meta"]);
+
+        let mut obj = valid_ct();
+        obj.put(DataElement::new(Tag(0x0008, 0x0016), VR::UI, PrimitiveValue::from("1.2.840.10008.5.1.4.1.1.88.11")));
+        obj.meta_mut().media_storage_sop_class_uid = "1.2.840.10008.5.1.4.1.1.88.11".to_string();
+        let (iod, issues) = check_obj(&obj);
+        assert_eq!(iod, None);
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].severity, Severity::Warning);
+    }
+
+    #[test]
+    fn time_and_date_time_syntax() {
+        assert!(is_valid_time("23"));
+        assert!(is_valid_time("235960.123456"));
+        assert!(!is_valid_time("2460"));
+        assert!(!is_valid_time("1015.5"));
+        assert!(is_valid_date_time("2024"));
+        assert!(is_valid_date_time("20240229101530.5+0300"));
+        assert!(!is_valid_date_time("20241301"));
+        assert!(vm_allows("2-n", 3));
+        assert!(!vm_allows("2-n", 1));
+        assert!(vm_allows("6", 6));
+    }
+}
@@ -0,0 +1,422 @@
+use std::fs;
+use std::path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use dicom::object::DefaultDicomObject;
+use indicatif::{ProgressBar, ProgressStyle};
+use rayon::prelude::*;
+use serde_json::{Map, Value as JsonValue};
+
+use crate::work_db::SelectedSeries;
+use crate::work_dcm;
+use crate::work_geometry;
+use crate::work_geometry::SliceGeometry;
+use crate::work_nifti;
+use crate::work_nrrd;
+use crate::work_pixels;
+use crate::work_raw;
+
+
+/// Срезы, положения которых вдоль нормали отличаются меньше, считаются совпадающими, мм
+const POSITION_TOLERANCE: f64 = 1e-2;
+/// Допуск при сравнении направляющих косинусов ориентации
+const ORIENTATION_TOLERANCE: f64 = 1e-3;
+
+/// Атрибуты серии, которые пишутся в JSON рядом с объемом. Идентификаторы пациента,
+/// даты и UID сюда не входят
+const SIDECAR_KEYWORDS: &[&str] = &[
+    "Modality", "Manufacturer", "ManufacturerModelName", "MagneticFieldStrength",
+    "SeriesDescription", "ProtocolName", "BodyPartExamined", "ImageType", "ScanningSequence",
+    "SequenceVariant", "SliceThickness", "SpacingBetweenSlices", "RepetitionTime", "EchoTime",
+    "InversionTime", "FlipAngle", "EchoTrainLength", "KVP", "XRayTubeCurrent", "ExposureTime",
+    "ConvolutionKernel", "PatientPosition", "RescaleType",
+];
+
+/// Формат, в который преобразуются серии
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConvertFormat {
+    /// NIfTI-1 со сжатием gzip (`.nii.gz`)
+    Nifti,
+    /// NRRD с заголовком и данными в одном файле (`.nrrd`), данные сжаты gzip
+    Nrrd,
+    /// Несжатые значения (`.raw`) и заголовок в JSON (`.raw.json`)
+    Raw,
+}
+
+impl FromStr for ConvertFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "nifti" => Ok(ConvertFormat::Nifti),
+            "nrrd" => Ok(ConvertFormat::Nrrd),
+            "raw" => Ok(ConvertFormat::Raw),
+            _ => Err(format!("unknown convert format '{}' (expected nifti, nrrd or raw)", s)),
+        }
+    }
+}
+
+impl ConvertFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            ConvertFormat::Nifti => "nii.gz",
+            ConvertFormat::Nrrd => "nrrd",
+            ConvertFormat::Raw => "raw",
+        }
+    }
+}
+
+/// Тип значений вокселей, достаточный для значений объема без потерь
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SampleType {
+    Int16,
+    Int32,
+    Float32,
+}
+
+impl SampleType {
+    /// Размер значения в байтах
+    pub fn size(&self) -> usize {
+        match self {
+            SampleType::Int16 => 2,
+            SampleType::Int32 | SampleType::Float32 => 4,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SampleType::Int16 => "int16",
+            SampleType::Int32 => "int32",
+            SampleType::Float32 => "float32",
+        }
+    }
+}
+
+/// Объем серии. Индексы вокселя: столбец, строка, срез; координаты пациента в LPS (как в DICOM)
+#[derive(Debug, Clone)]
+pub struct Volume {
+    /// Размер: столбцы, строки, срезы
+    pub dims: [usize; 3],
+    /// Шаг между столбцами, строками и срезами, мм
+    pub spacing: [f64; 3],
+    /// Центр первого вокселя (Image Position первого среза)
+    pub origin: [f64; 3],
+    /// Единичные направления осей: косинус строки, косинус столбца, направление сдвига срезов.
+    /// При наклоне гентри направление срезов не перпендикулярно плоскости среза
+    pub directions: [[f64; 3]; 3],
+    /// Значения после Rescale Slope/Intercept, столбец меняется быстрее всего
+    pub voxels: Vec<f32>,
+    /// Пропущенные в серии срезы, заполненные минимальным значением объема
+    pub filled_slices: usize,
+    /// Проблемы геометрии серии (см. `work_geometry::SeriesGeometry::issues`)
+    pub issues: Vec<String>,
+    /// Обезличенные атрибуты первого среза для JSON рядом с объемом
+    pub metadata: Map<String, JsonValue>,
+}
+
+impl Volume {
+    /// Матрица 3x4 из индексов вокселя в координаты LPS, мм
+    pub fn affine(&self) -> [[f64; 4]; 3] {
+        let mut affine = [[0.0; 4]; 3];
+        for (row, line) in affine.iter_mut().enumerate() {
+            for (axis, value) in line.iter_mut().take(3).enumerate() {
+                *value = self.directions[axis][row] * self.spacing[axis];
+            }
+            line[3] = self.origin[row];
+        }
+        affine
+    }
+
+    /// Наименьший тип, в котором значения хранятся без потерь
+    pub fn sample_type(&self) -> SampleType {
+        let integer = self.voxels.iter().all(|value| value.fract() == 0.0);
+        let fits = |min: f32, max: f32| self.voxels.iter().all(|value| (min..=max).contains(value));
+        if integer && fits(i16::MIN as f32, i16::MAX as f32) {
+            SampleType::Int16
+        } else if integer && fits(i32::MIN as f32, i32::MAX as f32) {
+            SampleType::Int32
+        } else {
+            SampleType::Float32
+        }
+    }
+
+    /// Значения вокселей в типе `sample_type`, little endian
+    pub fn sample_bytes(&self, sample_type: SampleType) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.voxels.len() * sample_type.size());
+        for value in &self.voxels {
+            match sample_type {
+                SampleType::Int16 => bytes.extend_from_slice(&(*value as i16).to_le_bytes()),
+                SampleType::Int32 => bytes.extend_from_slice(&(*value as i32).to_le_bytes()),
+                SampleType::Float32 => bytes.extend_from_slice(&value.to_le_bytes()),
+            }
+        }
+        bytes
+    }
+
+    /// Описание объема в JSON: атрибуты серии, размер, шаг, проблемы геометрии и заполненные срезы
+    pub fn sidecar(&self) -> JsonValue {
+        let mut sidecar = self.metadata.clone();
+        sidecar.insert("Dimensions".to_string(), serde_json::json!(self.dims));
+        sidecar.insert("VoxelSize".to_string(), serde_json::json!(self.spacing));
+        if !self.issues.is_empty() {
+            sidecar.insert("GeometryIssues".to_string(), serde_json::json!(self.issues));
+        }
+        if self.filled_slices != 0 {
+            sidecar.insert("FilledSlices".to_string(), JsonValue::from(self.filled_slices));
+        }
+        sidecar.insert("ConversionSoftware".to_string(), JsonValue::from(env!("CARGO_PKG_NAME")));
+        sidecar.insert("ConversionSoftwareVersion".to_string(), JsonValue::from(env!("CARGO_PKG_VERSION")));
+        JsonValue::Object(sidecar)
+    }
+}
+
+/// Срез серии: геометрия, толщина и значения после Rescale
+struct Slice {
+    geometry: SliceGeometry,
+    thickness: Option<f64>,
+    values: Vec<f32>,
+}
+
+/// Собирает объем из файлов серии: срезы упорядочиваются по положению вдоль нормали,
+/// к значениям применяются Rescale Slope/Intercept каждого среза
+pub fn assemble(paths: &[String]) -> Result<Volume, String> {
+    let mut metadata = Map::new();
+    let mut slices: Vec<Slice> = Vec::with_capacity(paths.len());
+    for path in paths {
+        let obj = work_dcm::read_indexed_dcm(path).map_err(|e| format!("{}: {}", path, e))?;
+        if slices.is_empty() {
+            metadata = sidecar_metadata(&obj);
+        }
+        slices.push(read_slice(&obj).map_err(|e| format!("{}: {}", path, e))?);
+    }
+    volume_from_slices(slices, metadata)
+}
+
+fn read_slice(obj: &DefaultDicomObject) -> Result<Slice, String> {
+    let pixels = work_pixels::decode(obj)?;
+    if pixels.samples_per_pixel != 1 {
+        return Err(format!("{} samples per pixel, only grayscale images are supported", pixels.samples_per_pixel));
+    }
+    if pixels.frames != 1 {
+        return Err(format!("{} frames, only single-frame instances are supported", pixels.frames));
+    }
+    let slope = number(obj, "RescaleSlope").unwrap_or(1.0);
+    let intercept = number(obj, "RescaleIntercept").unwrap_or(0.0);
+    let geometry = SliceGeometry {
+        position: text(obj, "ImagePositionPatient").and_then(|value| work_geometry::parse_numbers(&value)),
+        orientation: text(obj, "ImageOrientationPatient").and_then(|value| work_geometry::parse_numbers(&value)),
+        pixel_spacing: text(obj, "PixelSpacing").and_then(|value| work_geometry::parse_numbers(&value)),
+        rows: Some(pixels.rows as u32),
+        columns: Some(pixels.columns as u32),
+    };
+    let values = pixels.values.iter().map(|&value| (value as f64 * slope + intercept) as f32).collect();
+    Ok(Slice { geometry, thickness: number(obj, "SliceThickness"), values })
+}
+
+fn volume_from_slices(slices: Vec<Slice>, metadata: Map<String, JsonValue>) -> Result<Volume, String> {
+    let first = slices.first().ok_or("no instances")?.geometry.clone();
+    let (Some(orientation), Some(pixel_spacing), Some(rows), Some(columns)) =
+        (first.orientation, first.pixel_spacing, first.rows, first.columns) else {
+        return Err("no Image Orientation (Patient) or Pixel Spacing".to_string());
+    };
+    for slice in &slices {
+        let geometry = &slice.geometry;
+        if geometry.rows != first.rows || geometry.columns != first.columns {
+            return Err("instances have different dimensions (index with --split-series dimensions)".to_string());
+        }
+        if !geometry.orientation.is_some_and(|value| value.iter().zip(orientation.iter())
+            .all(|(a, b)| (a - b).abs() <= ORIENTATION_TOLERANCE)) {
+            return Err("instances have different orientations (index with --split-series orientation)".to_string());
+        }
+        if geometry.position.is_none() {
+            return Err("no Image Position (Patient)".to_string());
+        }
+    }
+    let geometry = work_geometry::analyze(&slices.iter().map(|slice| slice.geometry.clone()).collect::<Vec<_>>());
+
+    let normal = work_geometry::normal(&orientation);
+    let thickness = slices[0].thickness.unwrap_or(1.0);
+    let mut ordered: Vec<(f64, Slice)> = slices.into_iter()
+        .map(|slice| (work_geometry::dot(&slice.geometry.position.unwrap_or_default(), &normal), slice))
+        .collect();
+    ordered.sort_by(|a, b| a.0.total_cmp(&b.0));
+    if ordered.windows(2).any(|pair| pair[1].0 - pair[0].0 < POSITION_TOLERANCE) {
+        return Err("several instances at the same position (index with --split-series)".to_string());
+    }
+
+    // Номер среза в объеме по медианному шагу: на месте пропущенных срезов остаются пустые.
+    // При нерегулярном шаге срезы просто идут подряд
+    let regular = !geometry.issues.iter().any(|issue| issue == "irregular_spacing");
+    let indices: Vec<usize> = match geometry.slice_spacing {
+        Some(spacing) if regular => ordered.iter()
+            .map(|(location, _)| ((location - ordered[0].0) / spacing).round() as usize)
+            .collect(),
+        _ => (0..ordered.len()).collect(),
+    };
+    let count = indices.last().map_or(1, |last| last + 1);
+
+    let origin = ordered[0].1.geometry.position.unwrap_or_default();
+    let last = ordered[ordered.len() - 1].1.geometry.position.unwrap_or_default();
+    let (slice_direction, slice_spacing) = if count > 1 {
+        let steps = (count - 1) as f64;
+        let step = [(last[0] - origin[0]) / steps, (last[1] - origin[1]) / steps, (last[2] - origin[2]) / steps];
+        let length = work_geometry::dot(&step, &step).sqrt();
+        (step.map(|value| value / length), length)
+    } else {
+        (normal, thickness)
+    };
+    let dims = [columns as usize, rows as usize, count];
+    let slice_size = dims[0] * dims[1];
+    let background = ordered.iter()
+        .flat_map(|(_, slice)| slice.values.iter().copied())
+        .fold(f32::INFINITY, f32::min);
+    let mut voxels = vec![background; slice_size * count];
+    for (index, (_, slice)) in indices.iter().zip(ordered) {
+        voxels[index * slice_size..(index + 1) * slice_size].copy_from_slice(&slice.values);
+    }
+    Ok(Volume {
+        dims,
+        spacing: [pixel_spacing[1], pixel_spacing[0], slice_spacing],
+        origin,
+        directions: [[orientation[0], orientation[1], orientation[2]],
+                     [orientation[3], orientation[4], orientation[5]],
+                     slice_direction],
+        voxels,
+        filled_slices: count - indices.len(),
+        issues: geometry.issues,
+        metadata,
+    })
+}
+
+/// Обезличенные атрибуты экземпляра для JSON рядом с объемом: числа и списки чисел пишутся числами
+fn sidecar_metadata(obj: &DefaultDicomObject) -> Map<String, JsonValue> {
+    let mut metadata = Map::new();
+    for keyword in SIDECAR_KEYWORDS {
+        let Some(value) = text(obj, keyword) else { continue };
+        let items: Vec<JsonValue> = value.split('\\')
+            .map(|item| {
+                let item = item.trim();
+                item.parse::<f64>().ok()
+                    .and_then(serde_json::Number::from_f64)
+                    .map(JsonValue::Number)
+                    .unwrap_or_else(|| JsonValue::from(item))
+            })
+            .collect();
+        let value = if items.len() == 1 { items.into_iter().next().unwrap_or_default() } else { JsonValue::Array(items) };
+        metadata.insert(keyword.to_string(), value);
+    }
+    metadata
+}
+
+fn text(obj: &DefaultDicomObject, keyword: &str) -> Option<String> {
+    obj.element_by_name(keyword).ok()
+        .and_then(|e| e.to_str().ok())
+        .map(|value| value.trim_end_matches(['\0', ' ']).to_string())
+        .filter(|value| !value.is_empty())
+}
+
+fn number(obj: &DefaultDicomObject, keyword: &str) -> Option<f64> {
+    text(obj, keyword)?.split('\\').next()?.trim().parse().ok()
+}
+
+/// Преобразует выбранные серии в объемы: `<output>/<study>/<series>.<ext>` для каждого формата и JSON рядом.
+/// Объем собирается один раз на серию. Серии, которые не удалось собрать в объем, пропускаются с сообщением
+pub fn convert(series: &[SelectedSeries], formats: &[ConvertFormat], output: &path::Path) {
+    let progress = ProgressBar::new(series.len() as u64);
+    progress.set_style(ProgressStyle::default_bar()
+        .template("[{elapsed_precise}] {bar:40} converted {pos} of {len} series ({per_sec})"));
+    let failed = AtomicUsize::new(0);
+    series.par_iter().for_each(|series| {
+        convert_series(series, formats, output).unwrap_or_else(|e| {
+            progress.println(format!("Error converting series {}: {}", series.series_uid, e));
+            failed.fetch_add(1, Ordering::Relaxed);
+        });
+        progress.inc(1);
+    });
+    progress.finish();
+    let failed = failed.into_inner();
+    println!("Converted series: {} to {}, failed: {}", series.len() - failed, output.display(), failed);
+}
+
+fn convert_series(series: &SelectedSeries, formats: &[ConvertFormat], output: &path::Path) -> Result<(), String> {
+    let volume = assemble(&series.paths)?;
+    let folder = output.join(&series.study_uid);
+    fs::create_dir_all(&folder).map_err(|e| e.to_string())?;
+    for format in formats {
+        let target = folder.join(format!("{}.{}", series.series_uid, format.extension()));
+        match format {
+            ConvertFormat::Nifti => work_nifti::write(&volume, &target),
+            ConvertFormat::Nrrd => work_nrrd::write(&volume, &target),
+            ConvertFormat::Raw => work_raw::write(&volume, &target),
+        }.map_err(|e| e.to_string())?;
+    }
+    let sidecar = serde_json::to_string_pretty(&volume.sidecar()).map_err(|e| e.to_string())?;
+    fs::write(folder.join(format!("{}.json", series.series_uid)), sidecar).map_err(|e| e.to_string())
+}
+
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Строки и столбцы синтетических срезов
+    const ROWS: u32 = 3;
+    const COLUMNS: u32 = 4;
+
+    fn synthetic_slice(y: f64, value: f32) -> Slice {
+        Slice {
+            geometry: SliceGeometry {
+                position: Some([-100.0, y, 50.0]),
+                // Корональная плоскость: строка идет вправо-влево (x), столбец — сверху вниз (-z)
+                orientation: Some([1.0, 0.0, 0.0, 0.0, 0.0, -1.0]),
+                pixel_spacing: Some([0.8, 0.5]),
+                rows: Some(ROWS),
+                columns: Some(COLUMNS),
+            },
+            thickness: Some(2.0),
+            values: vec![value; (ROWS * COLUMNS) as usize],
+        }
+    }
+
+    /// Синтетическая корональная серия из трех срезов с шагом 2.5 мм вдоль y, срезы перемешаны
+    pub(crate) fn synthetic_volume() -> Volume {
+        let slices = vec![synthetic_slice(15.0, 3.0), synthetic_slice(10.0, 1.0), synthetic_slice(12.5, 2.0)];
+        volume_from_slices(slices, Map::new()).unwrap()
+    }
+
+    #[test]
+    fn slices_are_stacked_along_the_normal() {
+        let volume = synthetic_volume();
+        assert_eq!(volume.dims, [4, 3, 3]);
+        assert_eq!(volume.spacing, [0.5, 0.8, 2.5]);
+        assert_eq!(volume.origin, [-100.0, 10.0, 50.0]);
+        assert_eq!(volume.directions, [[1.0, 0.0, 0.0], [0.0, 0.0, -1.0], [0.0, 1.0, 0.0]]);
+        assert_eq!(volume.voxels[0], 1.0);
+        assert_eq!(volume.voxels[12], 2.0);
+        assert_eq!(volume.voxels[24], 3.0);
+        assert_eq!(volume.filled_slices, 0);
+        assert_eq!(volume.sample_type(), SampleType::Int16);
+    }
+
+    #[test]
+    fn affine_maps_voxel_indices_to_lps() {
+        let affine = synthetic_volume().affine();
+        assert_eq!(affine, [
+            [0.5, 0.0, 0.0, -100.0],
+            [0.0, 0.0, 2.5, 10.0],
+            [0.0, -0.8, 0.0, 50.0],
+        ]);
+    }
+
+    #[test]
+    fn missing_slice_is_filled() {
+        let slices = vec![synthetic_slice(10.0, 1.0), synthetic_slice(12.5, 2.0),
+                          synthetic_slice(17.5, 4.0), synthetic_slice(20.0, 5.0)];
+        let volume = volume_from_slices(slices, Map::new()).unwrap();
+        assert_eq!(volume.dims[2], 5);
+        assert_eq!(volume.spacing[2], 2.5);
+        assert_eq!(volume.filled_slices, 1);
+        assert_eq!(volume.voxels[2 * 12], 1.0);
+        assert_eq!(volume.voxels[3 * 12], 4.0);
+    }
+}
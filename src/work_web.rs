@@ -0,0 +1,1002 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, Cursor, Read};
+use std::path;
+use std::str::FromStr;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use dicom::core::value::Value;
+use dicom::core::Tag;
+use dicom::object::DefaultDicomObject;
+use tiny_http::{Header, Method, Request, Response, ResponseBox, Server};
+
+use crate::dir_scan;
+use crate::work_archive;
+use crate::work_dcm;
+use crate::work_dimse;
+use crate::work_db;
+use crate::work_db::Dcm;
+use crate::work_json;
+use crate::work_pixels;
+
+
+/// Атрибуты, которые QIDO-RS возвращает по умолчанию для каждого уровня (PS3.18 10.6.3.3).
+/// Атрибуты, которых нет в индексе, возвращаются пустыми
+const STUDY_ATTRIBUTES: &[&str] = &[
+    "StudyDate", "StudyTime", "AccessionNumber", "ModalitiesInStudy", "ReferringPhysicianName",
+    "PatientName", "PatientID", "PatientBirthDate", "PatientSex", "StudyInstanceUID", "StudyID",
+    "StudyDescription", "NumberOfStudyRelatedSeries", "NumberOfStudyRelatedInstances",
+];
+const SERIES_ATTRIBUTES: &[&str] = &[
+    "Modality", "SeriesDescription", "SeriesNumber", "SeriesInstanceUID", "BodyPartExamined",
+    "ProtocolName", "NumberOfSeriesRelatedInstances",
+];
+const INSTANCE_ATTRIBUTES: &[&str] = &[
+    "SOPClassUID", "SOPInstanceUID", "InstanceNumber", "Rows", "Columns", "NumberOfFrames",
+];
+
+const RETRIEVE_URL: Tag = Tag(0x0008, 0x1190);
+const FAILED_SOP_SEQUENCE: Tag = Tag(0x0008, 0x1198);
+const REFERENCED_SOP_SEQUENCE: Tag = Tag(0x0008, 0x1199);
+const REFERENCED_SOP_CLASS_UID: Tag = Tag(0x0008, 0x1150);
+const REFERENCED_SOP_INSTANCE_UID: Tag = Tag(0x0008, 0x1155);
+const FAILURE_REASON: Tag = Tag(0x0008, 0x1197);
+/// Причины отказа STOW-RS (PS3.18 6.6.1.3.2)
+const FAILURE_PROCESSING: u16 = 0x0110;
+const FAILURE_OUT_OF_RESOURCES: u16 = 0xA700;
+const FAILURE_DATA_SET_MISMATCH: u16 = 0xA900;
+const DICOM_JSON: &str = "application/dicom+json";
+
+/// Параметры HTTP сервера DICOMweb
+pub struct ServeOptions {
+    /// TCP порт
+    pub port: u16,
+    /// Индекс, по которому выполняются запросы
+    pub db_path: path::PathBuf,
+    /// Количество потоков, обрабатывающих запросы
+    pub workers: usize,
+    /// Директория для экземпляров, загруженных по STOW-RS (без нее загрузка отключена)
+    pub save_in: Option<path::PathBuf>,
+    /// Деперсонализация экземпляров STOW-RS
+    pub profile: StowProfile,
+    /// Наибольший размер тела запроса STOW-RS, байт
+    pub max_upload: u64,
+}
+
+/// Профиль деперсонализации экземпляров STOW-RS: `basic` — как в `depersonalize`,
+/// `off` — экземпляры сохраняются без изменений
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StowProfile {
+    Basic,
+    Off,
+}
+
+impl FromStr for StowProfile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "basic" => Ok(StowProfile::Basic),
+            "off" => Ok(StowProfile::Off),
+            _ => Err(format!("unknown de-identification profile '{}', expected basic or off", s)),
+        }
+    }
+}
+
+/// Прием экземпляров по STOW-RS: куда сохранять и очередь записи в индекс
+#[derive(Clone)]
+struct Ingest {
+    save_in: path::PathBuf,
+    profile: StowProfile,
+    max_upload: u64,
+    meta_tx: mpsc::Sender<work_dcm::MetaDcm>,
+}
+
+/// Запускает HTTP сервер DICOMweb над индексом: поиск QIDO-RS (`/studies`, `/series`,
+/// `/instances` и вложенные ресурсы) в DICOM JSON и получение экземпляров, метаданных
+/// и кадров WADO-RS из проиндексированных файлов.
+/// Если задана `save_in`, принимает экземпляры по STOW-RS: деперсонализирует их по профилю `profile`,
+/// сохраняет в раскладке `depersonalize` и добавляет в индекс (индекс пишет один поток).
+/// Запросы с телом больше `max_upload` отклоняются с кодом 413
+pub fn serve(options: &ServeOptions) {
+    if !options.db_path.is_file() {
+        eprintln!("Index database not found: {}", options.db_path.display());
+        return;
+    }
+    let server = match Server::http(("0.0.0.0", options.port)) {
+        Ok(server) => Arc::new(server),
+        Err(e) => {
+            eprintln!("Error listening on port {}: {:?}", options.port, e);
+            return;
+        }
+    };
+    let ingest = options.save_in.as_ref().and_then(|save_in| {
+        let conn = match work_db::Connection::open_dcm_tables(&options.db_path) {
+            Ok(conn) => conn,
+            Err(e) => {
+                eprintln!("Error open data base [path: {}]: {:?}", options.db_path.display(), e);
+                return None;
+            }
+        };
+        let (meta_tx, meta_rx) = mpsc::channel::<work_dcm::MetaDcm>();
+        thread::spawn(move || dir_scan::index_received(&conn, meta_rx));
+        Some(Ingest { save_in: save_in.clone(), profile: options.profile, max_upload: options.max_upload, meta_tx })
+    });
+    println!("DICOMweb server on http://0.0.0.0:{}/ (index: {})", options.port, options.db_path.display());
+    let workers: Vec<_> = (0..options.workers.max(1))
+        .map(|_| {
+            let server = Arc::clone(&server);
+            let db_path = options.db_path.clone();
+            let ingest = ingest.clone();
+            thread::spawn(move || {
+                let conn = match work_db::Connection::open_dcm_tables(&db_path) {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        eprintln!("Error open data base [path: {}]: {:?}", db_path.display(), e);
+                        return;
+                    }
+                };
+                for request in server.incoming_requests() {
+                    handle_request(request, &conn, ingest.as_ref());
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().unwrap_or_default();
+    }
+}
+
+/// Ошибка запроса: код HTTP и описание
+type HttpError = (u16, String);
+
+fn handle_request(mut request: Request, conn: &work_db::Connection, ingest: Option<&Ingest>) {
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let segments: Vec<String> = path.split('/').filter(|s| !s.is_empty()).map(percent_decode).collect();
+    let segments: Vec<&str> = segments.iter().map(|s| s.as_str()).collect();
+    let params = parse_query(query);
+    let base_url = request.headers().iter()
+        .find(|header| header.field.equiv("Host"))
+        .map(|header| format!("http://{}", header.value.as_str()))
+        .unwrap_or_default();
+
+    let content_type = request.headers().iter()
+        .find(|header| header.field.equiv("Content-Type"))
+        .map(|header| header.value.as_str().to_string())
+        .unwrap_or_default();
+
+    let response = match request.method() {
+        Method::Get => route_get(&segments, &params, &base_url, conn),
+        Method::Post => match ingest {
+            Some(ingest) => read_body(&mut request, ingest.max_upload)
+                .and_then(|body| route_post(&segments, &content_type, &body, &base_url, ingest)),
+            None => Err((405, "STOW-RS is disabled: start serve with --save".to_string())),
+        },
+        Method::Options => Ok(Response::empty(204).boxed()),
+        _ => Err((405, "method not allowed".to_string())),
+    };
+    let response = response.unwrap_or_else(|(status, message)| {
+        if status >= 500 {
+            eprintln!("Error {} {}: {}", request.method(), url, message);
+        }
+        Response::from_string(message).with_status_code(status).boxed()
+    });
+    let response = response
+        .with_header(header("Access-Control-Allow-Origin", "*"))
+        .with_header(header("Access-Control-Allow-Headers", "*"))
+        .with_header(header("Access-Control-Allow-Methods", "GET, POST, OPTIONS"));
+    request.respond(response).unwrap_or_else(|e| {
+        eprintln!("Error sending response for {}: {:?}", url, e);
+    });
+}
+
+fn route_get(segments: &[&str], params: &[(String, String)], base_url: &str,
+             conn: &work_db::Connection) -> Result<ResponseBox, HttpError> {
+    use work_dcm::Level::{Instance, Series, Study};
+    match segments {
+        ["studies"] => qido(conn, Study, &[], params, base_url),
+        ["series"] => qido(conn, Series, &[], params, base_url),
+        ["instances"] => qido(conn, Instance, &[], params, base_url),
+        ["studies", study, "series"] => qido(conn, Series, &[("StudyInstanceUID", study)], params, base_url),
+        ["studies", study, "instances"] => qido(conn, Instance, &[("StudyInstanceUID", study)], params, base_url),
+        ["studies", study, "series", series, "instances"] => {
+            qido(conn, Instance, &[("StudyInstanceUID", study), ("SeriesInstanceUID", series)], params, base_url)
+        }
+        ["studies", study] => retrieve(conn, &[("StudyInstanceUID", study)]),
+        ["studies", study, "series", series] => {
+            retrieve(conn, &[("StudyInstanceUID", study), ("SeriesInstanceUID", series)])
+        }
+        ["studies", study, "series", series, "instances", instance] => {
+            retrieve(conn, &[("StudyInstanceUID", study), ("SeriesInstanceUID", series), ("SOPInstanceUID", instance)])
+        }
+        ["studies", study, "metadata"] => metadata(conn, &[("StudyInstanceUID", study)], base_url),
+        ["studies", study, "series", series, "metadata"] => {
+            metadata(conn, &[("StudyInstanceUID", study), ("SeriesInstanceUID", series)], base_url)
+        }
+        ["studies", study, "series", series, "instances", instance, "metadata"] => {
+            metadata(conn, &[("StudyInstanceUID", study), ("SeriesInstanceUID", series),
+                             ("SOPInstanceUID", instance)], base_url)
+        }
+        ["studies", study, "series", series, "instances", instance, "frames", numbers] => {
+            frames(conn, &[("StudyInstanceUID", study), ("SeriesInstanceUID", series),
+                           ("SOPInstanceUID", instance)], numbers)
+        }
+        ["studies", study, "series", series, "instances", instance, "bulkdata", tag] => {
+            bulk_data(conn, &[("StudyInstanceUID", study), ("SeriesInstanceUID", series),
+                              ("SOPInstanceUID", instance)], tag)
+        }
+        _ => Err((404, "not found".to_string())),
+    }
+}
+
+/// Читает тело запроса не больше `max_size` байт: по Content-Length, а без него — до превышения
+fn read_body(request: &mut Request, max_size: u64) -> Result<Vec<u8>, HttpError> {
+    let too_large = || (413, format!("request body exceeds {} bytes", max_size));
+    if request.body_length().is_some_and(|length| length as u64 > max_size) {
+        return Err(too_large());
+    }
+    let mut body = Vec::new();
+    request.as_reader().take(max_size.saturating_add(1)).read_to_end(&mut body)
+        .map_err(|e| (400, format!("error reading request body: {:?}", e)))?;
+    if body.len() as u64 > max_size {
+        return Err(too_large());
+    }
+    Ok(body)
+}
+
+fn route_post(segments: &[&str], content_type: &str, body: &[u8], base_url: &str,
+              ingest: &Ingest) -> Result<ResponseBox, HttpError> {
+    let study = match segments {
+        ["studies"] => None,
+        ["studies", study] => Some(*study),
+        _ => return Err((404, "not found".to_string())),
+    };
+    stow(content_type, body, study, base_url, ingest)
+}
+
+/// STOW-RS: принимает экземпляры из тела multipart/related; type="application/dicom".
+/// Каждый экземпляр деперсонализируется по профилю, сохраняется и добавляется в индекс; ответ содержит
+/// ссылки на сохраненные экземпляры и причины отказа для остальных.
+/// Код ответа: 200 — все сохранены, 202 — часть, 409 — ни одного
+fn stow(content_type: &str, body: &[u8], study: Option<&str>, base_url: &str,
+        ingest: &Ingest) -> Result<ResponseBox, HttpError> {
+    let (media_type, boundary, part_type) = parse_content_type(content_type);
+    if media_type != "multipart/related" || part_type.is_some_and(|t| t != "application/dicom") {
+        return Err((415, format!("unsupported media type '{}'", content_type)));
+    }
+    let boundary = boundary.ok_or_else(|| (400, "multipart boundary is missing".to_string()))?;
+    let parts = multipart_parts(body, &boundary);
+    if parts.is_empty() {
+        return Err((400, "no instances in the request".to_string()));
+    }
+    let mut stored: Vec<serde_json::Value> = Vec::new();
+    let mut failed: Vec<serde_json::Value> = Vec::new();
+    for (part_type, data) in parts {
+        let (sop_class_uid, sop_instance_uid, result) = if part_type.is_none_or(|t| t == "application/dicom") {
+            store_part(data, study, ingest)
+        } else {
+            (String::new(), String::new(), Err(FAILURE_PROCESSING))
+        };
+        let mut item = serde_json::Map::new();
+        item.insert(work_json::tag_key(REFERENCED_SOP_CLASS_UID),
+                    work_json::strings_to_json(work_json::vr_by_tag(REFERENCED_SOP_CLASS_UID), &[sop_class_uid]));
+        item.insert(work_json::tag_key(REFERENCED_SOP_INSTANCE_UID),
+                    work_json::strings_to_json(work_json::vr_by_tag(REFERENCED_SOP_INSTANCE_UID),
+                                               &[sop_instance_uid]));
+        match result {
+            Ok(url) => {
+                item.insert(work_json::tag_key(RETRIEVE_URL),
+                            work_json::strings_to_json(work_json::vr_by_tag(RETRIEVE_URL),
+                                                       &[format!("{}{}", base_url, url)]));
+                stored.push(serde_json::Value::Object(item));
+            }
+            Err(reason) => {
+                item.insert(work_json::tag_key(FAILURE_REASON),
+                            work_json::strings_to_json(work_json::vr_by_tag(FAILURE_REASON), &[reason.to_string()]));
+                failed.push(serde_json::Value::Object(item));
+            }
+        }
+    }
+    println!("STOW-RS: stored {}, failed {}", stored.len(), failed.len());
+    let status = match (stored.is_empty(), failed.is_empty()) {
+        (false, true) => 200,
+        (false, false) => 202,
+        _ => 409,
+    };
+    let mut result = serde_json::Map::new();
+    if let Some(study) = study {
+        result.insert(work_json::tag_key(RETRIEVE_URL),
+                      work_json::strings_to_json(work_json::vr_by_tag(RETRIEVE_URL),
+                                                 &[format!("{}/studies/{}", base_url, study)]));
+    }
+    if !failed.is_empty() {
+        result.insert(work_json::tag_key(FAILED_SOP_SEQUENCE), serde_json::json!({ "vr": "SQ", "Value": failed }));
+    }
+    if !stored.is_empty() {
+        result.insert(work_json::tag_key(REFERENCED_SOP_SEQUENCE), serde_json::json!({ "vr": "SQ", "Value": stored }));
+    }
+    Ok(json_response(&serde_json::Value::Object(result)).with_status_code(status))
+}
+
+/// Сохраняет один экземпляр STOW-RS. Возвращает SOP Class UID, SOP Instance UID
+/// и путь WADO-RS сохраненного экземпляра или причину отказа
+fn store_part(data: &[u8], study: Option<&str>, ingest: &Ingest) -> (String, String, Result<String, u16>) {
+    if !work_dcm::is_dicom_bytes(data) {
+        return (String::new(), String::new(), Err(work_dimse::STATUS_CANNOT_UNDERSTAND));
+    }
+    let mut dcm_obj = match work_dcm::read_dcm_from_bytes(data) {
+        Ok(dcm_obj) => dcm_obj,
+        Err(e) => {
+            eprintln!("STOW-RS: error reading instance: {:?}", e);
+            return (String::new(), String::new(), Err(work_dimse::STATUS_CANNOT_UNDERSTAND));
+        }
+    };
+    let sop_class_uid = uid(&dcm_obj, "SOPClassUID");
+    let sop_instance_uid = uid(&dcm_obj, "SOPInstanceUID");
+    let study_uid = uid(&dcm_obj, "StudyInstanceUID");
+    let series_uid = uid(&dcm_obj, "SeriesInstanceUID");
+    if sop_instance_uid.is_empty() || study.is_some_and(|study| study != study_uid) {
+        return (sop_class_uid, sop_instance_uid, Err(FAILURE_DATA_SET_MISMATCH));
+    }
+    if ingest.profile == StowProfile::Basic {
+        work_dcm::depersonalize_obj(&mut dcm_obj);
+    }
+    let meta_dcm = work_dcm::MetaDcm::from(&dcm_obj, "");
+    let new_path = dir_scan::create_new_path(&meta_dcm, &ingest.save_in);
+    if let Err(e) = work_dcm::save_dcm(&mut dcm_obj, &new_path, None) {
+        eprintln!("STOW-RS: error saving {}: {:?}", new_path, e);
+        return (sop_class_uid, sop_instance_uid, Err(FAILURE_OUT_OF_RESOURCES));
+    }
+    ingest.meta_tx.send(work_dcm::MetaDcm::from(&dcm_obj, &new_path)).unwrap_or_else(|e| {
+        eprintln!("STOW-RS: error indexing {}: {:?}", new_path, e);
+    });
+    let url = format!("/studies/{}/series/{}/instances/{}", study_uid, series_uid, sop_instance_uid);
+    (sop_class_uid, sop_instance_uid, Ok(url))
+}
+
+/// Разбирает Content-Type: медиатип, параметры `boundary` и `type`
+fn parse_content_type(content_type: &str) -> (String, Option<String>, Option<String>) {
+    let mut items = content_type.split(';');
+    let media_type = items.next().unwrap_or_default().trim().to_ascii_lowercase();
+    let mut boundary = None;
+    let mut part_type = None;
+    for item in items {
+        if let Some((name, value)) = item.split_once('=') {
+            let value = value.trim().trim_matches('"').to_string();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "boundary" => boundary = Some(value),
+                "type" => part_type = Some(value.to_ascii_lowercase()),
+                _ => {}
+            }
+        }
+    }
+    (media_type, boundary, part_type)
+}
+
+/// Части тела multipart: медиатип части (если задан) и ее содержимое
+fn multipart_parts<'a>(body: &'a [u8], boundary: &str) -> Vec<(Option<String>, &'a [u8])> {
+    let delimiter = format!("--{}", boundary);
+    let mut parts = Vec::new();
+    let mut rest = match find_bytes(body, delimiter.as_bytes()) {
+        Some(start) => &body[start + delimiter.len()..],
+        None => return parts,
+    };
+    // После разделителя идет `--` (конец) или перевод строки и заголовки следующей части
+    while !rest.starts_with(b"--") {
+        let end = find_bytes(rest, format!("\r\n{}", delimiter).as_bytes()).unwrap_or(rest.len());
+        let part = &rest[..end];
+        let part = part.strip_prefix(b"\r\n").unwrap_or(part);
+        // Часть без заголовков начинается с пустой строки
+        let (headers, content) = match part.strip_prefix(b"\r\n") {
+            Some(content) => (&part[..0], content),
+            None => match find_bytes(part, b"\r\n\r\n") {
+                Some(position) => (&part[..position], &part[position + 4..]),
+                None => (part, &part[part.len()..]),
+            },
+        };
+        let part_type = String::from_utf8_lossy(headers).lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("Content-Type"))
+            .map(|(_, value)| parse_content_type(value).0);
+        parts.push((part_type, content));
+        if end + 2 + delimiter.len() > rest.len() {
+            break;
+        }
+        rest = &rest[end + 2 + delimiter.len()..];
+    }
+    parts
+}
+
+fn find_bytes(data: &[u8], pattern: &[u8]) -> Option<usize> {
+    data.windows(pattern.len()).position(|window| window == pattern)
+}
+
+/// QIDO-RS: поиск сущностей уровня `level` с условиями из пути (`fixed`) и параметров запроса.
+/// Поддерживаются `includefield` (ключевое слово, тег или `all`), `limit` и `offset`
+fn qido(conn: &work_db::Connection, level: work_dcm::Level, fixed: &[(&str, &str)],
+        params: &[(String, String)], base_url: &str) -> Result<ResponseBox, HttpError> {
+    let mut keywords: Vec<String> = level_attributes(level).iter().map(|k| k.to_string()).collect();
+    let mut keys: Vec<work_db::QueryKey> = fixed.iter()
+        .map(|(keyword, value)| work_db::QueryKey { keyword: keyword.to_string(), value: value.to_string() })
+        .collect();
+    let mut limit = None;
+    let mut offset = 0;
+    for (name, value) in params {
+        match name.as_str() {
+            "limit" => limit = Some(value.parse().map_err(|_| (400, format!("invalid limit '{}'", value)))?),
+            "offset" => offset = value.parse().map_err(|_| (400, format!("invalid offset '{}'", value)))?,
+            "fuzzymatching" => {}
+            "includefield" => {
+                for field in value.split(',').filter(|field| !field.is_empty()) {
+                    if field == "all" {
+                        keywords.extend(work_db::query_keywords(level).iter().map(|k| k.to_string()));
+                    } else {
+                        keywords.push(resolve_keyword(field).ok_or_else(|| (400, format!("unknown attribute '{}'", field)))?);
+                    }
+                }
+            }
+            _ => {
+                let keyword = resolve_keyword(name).ok_or_else(|| (400, format!("unknown attribute '{}'", name)))?;
+                keywords.push(keyword.clone());
+                keys.push(work_db::QueryKey { keyword, value: value.clone() });
+            }
+        }
+    }
+    for keyword in &keywords {
+        if !keys.iter().any(|key| key.keyword == *keyword) {
+            keys.push(work_db::QueryKey { keyword: keyword.clone(), value: String::new() });
+        }
+    }
+    let entities = conn.query_entities(level, &keys, limit, offset).map_err(|e| (500, format!("{:?}", e)))?;
+    let result: Vec<serde_json::Value> = entities.iter()
+        .map(|entity| {
+            let mut attributes = serde_json::Map::new();
+            for key in &keys {
+                if let Some(tag) = work_dcm::tag_by_keyword(&key.keyword) {
+                    let value = entity.get(&key.keyword).cloned().unwrap_or_default();
+                    attributes.insert(work_json::tag_key(tag),
+                                      work_json::strings_to_json(work_json::vr_by_tag(tag), &[value]));
+                }
+            }
+            if let Some(url) = retrieve_url(level, entity, base_url) {
+                attributes.insert(work_json::tag_key(RETRIEVE_URL),
+                                  work_json::strings_to_json(work_json::vr_by_tag(RETRIEVE_URL), &[url]));
+            }
+            serde_json::Value::Object(attributes)
+        })
+        .collect();
+    Ok(json_response(&serde_json::Value::Array(result)))
+}
+
+/// Атрибуты по умолчанию уровня `level` вместе с уникальными ключами вышестоящих уровней
+fn level_attributes(level: work_dcm::Level) -> Vec<&'static str> {
+    match level {
+        work_dcm::Level::Patient | work_dcm::Level::Study => STUDY_ATTRIBUTES.to_vec(),
+        work_dcm::Level::Series => [SERIES_ATTRIBUTES, &["StudyInstanceUID"]].concat(),
+        work_dcm::Level::Instance => [INSTANCE_ATTRIBUTES, &["StudyInstanceUID", "SeriesInstanceUID"]].concat(),
+    }
+}
+
+fn retrieve_url(level: work_dcm::Level, entity: &std::collections::BTreeMap<String, String>,
+                base_url: &str) -> Option<String> {
+    let study = entity.get("StudyInstanceUID")?;
+    match level {
+        work_dcm::Level::Patient | work_dcm::Level::Study => Some(format!("{}/studies/{}", base_url, study)),
+        work_dcm::Level::Series => Some(format!("{}/studies/{}/series/{}", base_url, study,
+                                                entity.get("SeriesInstanceUID")?)),
+        work_dcm::Level::Instance => Some(format!("{}/studies/{}/series/{}/instances/{}", base_url, study,
+                                                  entity.get("SeriesInstanceUID")?, entity.get("SOPInstanceUID")?)),
+    }
+}
+
+/// Ключевое слово атрибута из параметра запроса: ключевое слово словаря или тег `ggggeeee`
+fn resolve_keyword(name: &str) -> Option<String> {
+    if name.len() == 8 && name.chars().all(|c| c.is_ascii_hexdigit()) {
+        let group = u16::from_str_radix(&name[..4], 16).ok()?;
+        let element = u16::from_str_radix(&name[4..], 16).ok()?;
+        return work_dcm::keyword_by_tag(Tag(group, element)).map(|keyword| keyword.to_string());
+    }
+    work_dcm::tag_by_keyword(name).map(|_| name.to_string())
+}
+
+/// Пути файлов экземпляров, заданных UID в пути запроса
+fn instance_paths(conn: &work_db::Connection, uids: &[(&str, &str)]) -> Result<Vec<String>, HttpError> {
+    let keys: Vec<work_db::QueryKey> = uids.iter()
+        .map(|(keyword, value)| work_db::QueryKey { keyword: keyword.to_string(), value: value.to_string() })
+        .collect();
+    let paths = conn.query_paths(work_dcm::Level::Instance, &keys).map_err(|e| (500, format!("{:?}", e)))?;
+    if paths.is_empty() {
+        return Err((404, "no matching instances".to_string()));
+    }
+    Ok(paths)
+}
+
+/// WADO-RS: экземпляры в виде multipart/related; type="application/dicom".
+/// Файлы передаются как есть (в своем синтаксисе передачи) и читаются по одному
+fn retrieve(conn: &work_db::Connection, uids: &[(&str, &str)]) -> Result<ResponseBox, HttpError> {
+    let paths = instance_paths(conn, uids)?;
+    let boundary = new_boundary();
+    let content_type = format!("multipart/related; type=\"application/dicom\"; boundary={}", boundary);
+    let reader = MultipartReader {
+        paths: paths.into_iter().collect(),
+        boundary,
+        current: Cursor::new(Vec::new()),
+        finished: false,
+    };
+    Ok(Response::empty(200)
+        .with_data(reader, None)
+        .with_header(header("Content-Type", &content_type))
+        .boxed())
+}
+
+/// WADO-RS: метаданные экземпляров в DICOM JSON, Pixel Data передается ссылкой на bulkdata
+fn metadata(conn: &work_db::Connection, uids: &[(&str, &str)], base_url: &str) -> Result<ResponseBox, HttpError> {
+    let paths = instance_paths(conn, uids)?;
+    let mut result: Vec<serde_json::Value> = Vec::with_capacity(paths.len());
+    for path in &paths {
+        let dcm_obj = read_instance(path)?;
+        let instance_url = format!("{}/studies/{}/series/{}/instances/{}", base_url,
+                                   uid(&dcm_obj, "StudyInstanceUID"), uid(&dcm_obj, "SeriesInstanceUID"),
+                                   uid(&dcm_obj, "SOPInstanceUID"));
+        let bulk_data_uri = |tag: Tag| Some(format!("{}/bulkdata/{}", instance_url, work_json::tag_key(tag)));
+        result.push(work_json::dataset_to_json(&dcm_obj, Some(&bulk_data_uri)));
+    }
+    Ok(json_response(&serde_json::Value::Array(result)))
+}
+
+/// WADO-RS: кадры экземпляра (номера через запятую, начиная с 1).
+/// Несжатые кадры передаются как application/octet-stream, сжатые — фрагментами
+/// с типом, соответствующим синтаксису передачи
+fn frames(conn: &work_db::Connection, uids: &[(&str, &str)], numbers: &str) -> Result<ResponseBox, HttpError> {
+    let paths = instance_paths(conn, uids)?;
+    let dcm_obj = read_instance(&paths[0])?;
+    let numbers: Vec<usize> = numbers.split(',')
+        .map(|n| n.trim().parse::<usize>().ok().filter(|n| *n > 0))
+        .collect::<Option<Vec<usize>>>()
+        .ok_or_else(|| (400, format!("invalid frame list '{}'", numbers)))?;
+    let transfer_syntax = dcm_obj.meta().transfer_syntax.trim_end_matches('\0').to_string();
+    let pixel_data = dcm_obj.element(work_json::PIXEL_DATA)
+        .map_err(|_| (404, "instance has no pixel data".to_string()))?;
+    let number_of_frames = get_number(&dcm_obj, "NumberOfFrames").unwrap_or(1).max(1);
+    let mut parts: Vec<Vec<u8>> = Vec::with_capacity(numbers.len());
+    let part_type = match pixel_data.value() {
+        Value::PixelSequence { offset_table, fragments } => {
+            let all_frames = work_pixels::split_frames(offset_table, fragments, number_of_frames)
+                .map_err(|e| (500, e))?;
+            for n in &numbers {
+                if *n > all_frames.len() {
+                    return Err((404, format!("frame {} out of range", n)));
+                }
+                parts.push(all_frames[n - 1].clone());
+            }
+            format!("{}; transfer-syntax={}", frame_media_type(&transfer_syntax), transfer_syntax)
+        }
+        Value::Primitive(value) => {
+            let bytes = value.to_bytes();
+            let frame_size = bytes.len() / number_of_frames;
+            for n in &numbers {
+                // Номер кадра задается в URL, границы кадра не должны переполняться
+                let frame = n.checked_sub(1)
+                    .filter(|_| *n <= number_of_frames && frame_size > 0)
+                    .and_then(|i| i.checked_mul(frame_size))
+                    .and_then(|start| bytes.get(start..start.checked_add(frame_size)?))
+                    .ok_or_else(|| (404, format!("frame {} out of range", n)))?;
+                parts.push(frame.to_vec());
+            }
+            format!("application/octet-stream; transfer-syntax={}", work_dcm::EXPLICIT_VR_LITTLE_ENDIAN)
+        }
+        Value::Sequence { .. } => return Err((500, "invalid pixel data".to_string())),
+    };
+    Ok(multipart_response(&parts, &part_type))
+}
+
+/// WADO-RS: значение бинарного атрибута по ссылке BulkDataURI из метаданных
+fn bulk_data(conn: &work_db::Connection, uids: &[(&str, &str)], tag: &str) -> Result<ResponseBox, HttpError> {
+    let tag = u32::from_str_radix(tag, 16).ok()
+        .filter(|_| tag.len() == 8)
+        .map(|tag| Tag((tag >> 16) as u16, tag as u16))
+        .ok_or_else(|| (400, format!("invalid tag '{}'", tag)))?;
+    let paths = instance_paths(conn, uids)?;
+    let dcm_obj = read_instance(&paths[0])?;
+    let element = dcm_obj.element(tag).map_err(|_| (404, "no such attribute".to_string()))?;
+    let bytes = match element.value() {
+        Value::PixelSequence { fragments, .. } => fragments.concat(),
+        Value::Primitive(value) => value.to_bytes().to_vec(),
+        Value::Sequence { .. } => return Err((400, "attribute is a sequence".to_string())),
+    };
+    Ok(multipart_response(&[bytes], "application/octet-stream"))
+}
+
+/// Медиатип сжатого кадра по синтаксису передачи (PS3.18 8.7.3.5)
+fn frame_media_type(transfer_syntax: &str) -> &'static str {
+    match transfer_syntax {
+        "1.2.840.10008.1.2.4.50" | "1.2.840.10008.1.2.4.51" | "1.2.840.10008.1.2.4.57"
+        | "1.2.840.10008.1.2.4.70" => "image/jpeg",
+        "1.2.840.10008.1.2.4.80" | "1.2.840.10008.1.2.4.81" => "image/jls",
+        "1.2.840.10008.1.2.4.90" | "1.2.840.10008.1.2.4.91" => "image/jp2",
+        "1.2.840.10008.1.2.5" => "image/dicom-rle",
+        _ => "application/octet-stream",
+    }
+}
+
+fn read_instance(path: &str) -> Result<DefaultDicomObject, HttpError> {
+    work_dcm::read_indexed_dcm(path).map_err(|e| (500, format!("error reading {}: {}", path, e)))
+}
+
+fn uid(obj: &DefaultDicomObject, keyword: &str) -> String {
+    obj.element_by_name(keyword).ok()
+        .and_then(|e| e.to_str().ok())
+        .map(|value| value.trim_end_matches(['\0', ' ']).to_string())
+        .unwrap_or_default()
+}
+
+fn get_number(obj: &DefaultDicomObject, keyword: &str) -> Option<usize> {
+    obj.element_by_name(keyword).ok()?.to_int::<usize>().ok()
+}
+
+/// Читает содержимое файла индекса (в том числе файла внутри архива)
+fn read_file_bytes(path: &str) -> io::Result<Vec<u8>> {
+    match work_archive::split_virtual_path(path) {
+        Some((archive, member)) => work_archive::read_member(archive, member),
+        None => fs::read(path),
+    }
+}
+
+/// Тело multipart/related, которое читает файлы по одному по мере отправки
+struct MultipartReader {
+    paths: VecDeque<String>,
+    boundary: String,
+    current: Cursor<Vec<u8>>,
+    finished: bool,
+}
+
+impl Read for MultipartReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.current.read(buf)?;
+            if read > 0 || self.finished {
+                return Ok(read);
+            }
+            let mut part = Vec::new();
+            match self.paths.pop_front() {
+                Some(path) => {
+                    let data = read_file_bytes(&path).map_err(|e| {
+                        eprintln!("Error reading {}: {:?}", path, e);
+                        e
+                    })?;
+                    part.extend_from_slice(format!("--{}\r\nContent-Type: application/dicom\r\n\r\n",
+                                                   self.boundary).as_bytes());
+                    part.extend_from_slice(&data);
+                    part.extend_from_slice(b"\r\n");
+                }
+                None => {
+                    part.extend_from_slice(format!("--{}--\r\n", self.boundary).as_bytes());
+                    self.finished = true;
+                }
+            }
+            self.current = Cursor::new(part);
+        }
+    }
+}
+
+fn multipart_response(parts: &[Vec<u8>], part_type: &str) -> ResponseBox {
+    let boundary = new_boundary();
+    let mut body = Vec::new();
+    for part in parts {
+        body.extend_from_slice(format!("--{}\r\nContent-Type: {}\r\n\r\n", boundary, part_type).as_bytes());
+        body.extend_from_slice(part);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+    let media_type = part_type.split(';').next().unwrap_or(part_type);
+    Response::from_data(body)
+        .with_header(header("Content-Type", &format!(
+            "multipart/related; type=\"{}\"; boundary={}", media_type, boundary)))
+        .boxed()
+}
+
+fn json_response(value: &serde_json::Value) -> ResponseBox {
+    Response::from_data(serde_json::to_vec(value).unwrap_or_default())
+        .with_header(header("Content-Type", DICOM_JSON))
+        .boxed()
+}
+
+fn new_boundary() -> String {
+    format!("dcm_finder_{:016x}", rand::random::<u64>())
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap()
+}
+
+/// Разбирает параметры запроса `a=1&b=2`; значения, заданные несколько раз, сохраняются все
+fn parse_query(query: &str) -> Vec<(String, String)> {
+    query.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(name), percent_decode(value))
+        })
+        .collect()
+}
+
+/// Декодирует `%XX` и `+` в компоненте URL
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                match std::str::from_utf8(&bytes[i + 1..i + 3]).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 3;
+                        continue;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            b'+' => decoded.push(b' '),
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::TcpStream;
+    use dicom::core::{DataElement, PrimitiveValue, VR};
+    use dicom::object::{FileMetaTableBuilder, InMemDicomObject};
+
+    const JPEG_BASELINE: &str = "1.2.840.10008.1.2.4.50";
+
+    /// Экземпляр КТ из двух кадров 2x2: несжатый или из фрагментов JPEG без Basic Offset Table
+    fn instance(patient: &str, uids: [&str; 3], encapsulated: bool) -> DefaultDicomObject {
+        let mut obj = InMemDicomObject::new_empty();
+        let us = |tag, value: u16| DataElement::new(tag, VR::US, PrimitiveValue::from(value));
+        obj.put(us(Tag(0x0028, 0x0010), 2));
+        obj.put(us(Tag(0x0028, 0x0011), 2));
+        obj.put(us(Tag(0x0028, 0x0100), 8));
+        obj.put(DataElement::new(Tag(0x0028, 0x0008), VR::IS, PrimitiveValue::from("2")));
+        obj.put(DataElement::new(Tag(0x0010, 0x0010), VR::PN, PrimitiveValue::from(patient)));
+        obj.put(DataElement::new(Tag(0x0010, 0x0020), VR::LO, PrimitiveValue::from(patient)));
+        obj.put(DataElement::new(Tag(0x0008, 0x0060), VR::CS, PrimitiveValue::from("CT")));
+        obj.put(DataElement::new(Tag(0x0020, 0x000D), VR::UI, PrimitiveValue::from(uids[0])));
+        obj.put(DataElement::new(Tag(0x0020, 0x000E), VR::UI, PrimitiveValue::from(uids[1])));
+        obj.put(DataElement::new(Tag(0x0008, 0x0016), VR::UI, PrimitiveValue::from("1.2.840.10008.5.1.4.1.1.2")));
+        obj.put(DataElement::new(Tag(0x0008, 0x0018), VR::UI, PrimitiveValue::from(uids[2])));
+        let transfer_syntax = if encapsulated {
+            let fragments = vec![
+                vec![0xFF, 0xD8, 0xFF, 0xDB, 1, 2], vec![3, 3, 0xFF, 0xD9],
+                vec![0xFF, 0xD8, 0xFF, 0xDB, 4, 5], vec![6, 6, 0xFF, 0xD9],
+            ];
+            obj.put(DataElement::new(work_json::PIXEL_DATA, VR::OB,
+                                     Value::PixelSequence { offset_table: Vec::new().into(), fragments: fragments.into() }));
+            JPEG_BASELINE
+        } else {
+            obj.put(DataElement::new(work_json::PIXEL_DATA, VR::OB, PrimitiveValue::from(vec![1u8, 2, 3, 4, 5, 6, 7, 8])));
+            work_dcm::EXPLICIT_VR_LITTLE_ENDIAN
+        };
+        obj.with_meta(FileMetaTableBuilder::new()
+            .media_storage_sop_class_uid("1.2.840.10008.5.1.4.1.1.2")
+            .media_storage_sop_instance_uid(uids[2])
+            .transfer_syntax(transfer_syntax))
+            .unwrap()
+    }
+
+    /// Индекс в файле с двумя исследованиями: несжатый экземпляр у P1 и сжатый у P2
+    fn index(name: &str) -> path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("dcm_finder_test_{}_web_{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let db_path = dir.join("index.db");
+        let conn = work_db::Connection::open_dcm_tables(&db_path).unwrap();
+        for (patient, uids, encapsulated) in [("P1", ["1.2.1", "1.2.1.1", "1.2.1.1.1"], false),
+                                              ("P2", ["1.2.2", "1.2.2.1", "1.2.2.1.1"], true)] {
+            let obj = instance(patient, uids, encapsulated);
+            let path = dir.join(format!("{}.dcm", uids[2])).display().to_string();
+            obj.write_to_file(&path).unwrap();
+            conn.insert_dcm(&work_dcm::MetaDcm::from(&obj, &path));
+        }
+        db_path
+    }
+
+    /// Выполняет один HTTP запрос к серверу на свободном порту: код ответа, Content-Type и тело
+    fn request(db_path: &path::Path, ingest: Option<Ingest>, head: &str, body: &[u8]) -> (u16, String, Vec<u8>) {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let address = server.server_addr().to_ip().unwrap();
+        let conn = work_db::Connection::open_dcm_tables(db_path).unwrap();
+        let handler = thread::spawn(move || handle_request(server.recv().unwrap(), &conn, ingest.as_ref()));
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(format!("{}\r\nHost: test\r\nContent-Length: {}\r\n\r\n", head, body.len()).as_bytes()).unwrap();
+        stream.write_all(body).unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        handler.join().unwrap();
+        let end = find_bytes(&response, b"\r\n\r\n").unwrap();
+        let headers = String::from_utf8_lossy(&response[..end]).to_string();
+        let status = headers.split(' ').nth(1).unwrap().parse().unwrap();
+        let content_type = headers.lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.eq_ignore_ascii_case("Content-Type"))
+            .map(|(_, value)| value.trim().to_string())
+            .unwrap_or_default();
+        (status, content_type, response[end + 4..].to_vec())
+    }
+
+    fn get_json(db_path: &path::Path, url: &str) -> Vec<serde_json::Value> {
+        let (status, content_type, body) = request(db_path, None, &format!("GET {} HTTP/1.0", url), &[]);
+        assert_eq!((status, content_type.as_str()), (200, DICOM_JSON), "{}", url);
+        serde_json::from_slice::<serde_json::Value>(&body).unwrap().as_array().unwrap().clone()
+    }
+
+    fn value(item: &serde_json::Value, tag: &str) -> String {
+        item[tag]["Value"][0].as_str().unwrap_or_default().to_string()
+    }
+
+    #[test]
+    fn qido_searches_the_index() {
+        let db_path = index("qido");
+        let studies = get_json(&db_path, "/studies");
+        assert_eq!(studies.iter().map(|study| value(study, "0020000D")).collect::<Vec<_>>(), ["1.2.1", "1.2.2"]);
+        assert_eq!(value(&studies[0], "00081190"), "http://test/studies/1.2.1");
+        let studies = get_json(&db_path, "/studies?PatientID=P2");
+        assert_eq!(studies.len(), 1);
+        assert_eq!(value(&studies[0], "00100020"), "P2");
+        let studies = get_json(&db_path, "/studies?limit=1&offset=1");
+        assert_eq!(studies.iter().map(|study| value(study, "0020000D")).collect::<Vec<_>>(), ["1.2.2"]);
+        let series = get_json(&db_path, "/studies/1.2.1/series?includefield=00080060");
+        assert_eq!((value(&series[0], "0020000E"), value(&series[0], "00080060")), ("1.2.1.1".to_string(), "CT".to_string()));
+        let instances = get_json(&db_path, "/studies/1.2.2/series/1.2.2.1/instances");
+        assert_eq!(value(&instances[0], "00081190"), "http://test/studies/1.2.2/series/1.2.2.1/instances/1.2.2.1.1");
+        assert_eq!(request(&db_path, None, "GET /studies?Unknown=1 HTTP/1.0", &[]).0, 400);
+        assert_eq!(request(&db_path, None, "GET /studies?limit=x HTTP/1.0", &[]).0, 400);
+        assert_eq!(request(&db_path, None, "GET /patients HTTP/1.0", &[]).0, 404);
+        fs::remove_dir_all(db_path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn wado_metadata_refers_to_bulk_data() {
+        let db_path = index("metadata");
+        let instances = get_json(&db_path, "/studies/1.2.2/metadata");
+        assert_eq!(instances.len(), 1);
+        assert_eq!(value(&instances[0], "00080018"), "1.2.2.1.1");
+        assert_eq!(instances[0]["7FE00010"]["BulkDataURI"],
+                   "http://test/studies/1.2.2/series/1.2.2.1/instances/1.2.2.1.1/bulkdata/7FE00010");
+        assert_eq!(request(&db_path, None, "GET /studies/9.9/metadata HTTP/1.0", &[]).0, 404);
+        fs::remove_dir_all(db_path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn wado_frames_are_split() {
+        let db_path = index("frames");
+        let frames = |url: &str| {
+            let (status, content_type, body) = request(&db_path, None, &format!("GET {} HTTP/1.0", url), &[]);
+            assert_eq!(status, 200, "{}", url);
+            let (media_type, boundary, part_type) = parse_content_type(&content_type);
+            assert_eq!(media_type, "multipart/related");
+            let parts: Vec<Vec<u8>> = multipart_parts(&body, &boundary.unwrap()).into_iter()
+                .map(|(_, data)| data.to_vec())
+                .collect();
+            (part_type.unwrap(), parts)
+        };
+        let (part_type, parts) = frames("/studies/1.2.1/series/1.2.1.1/instances/1.2.1.1.1/frames/2,1");
+        assert_eq!(part_type, "application/octet-stream");
+        assert_eq!(parts, vec![vec![5, 6, 7, 8], vec![1, 2, 3, 4]]);
+        for n in ["3", "2,3", &usize::MAX.to_string(), &(usize::MAX / 4 + 2).to_string()] {
+            let url = format!("/studies/1.2.1/series/1.2.1.1/instances/1.2.1.1.1/frames/{}", n);
+            assert_eq!(request(&db_path, None, &format!("GET {} HTTP/1.0", url), &[]).0, 404, "{}", url);
+        }
+        // Кадры из нескольких фрагментов без Basic Offset Table собираются по маркерам SOI
+        let (part_type, parts) = frames("/studies/1.2.2/series/1.2.2.1/instances/1.2.2.1.1/frames/2");
+        assert_eq!(part_type, "image/jpeg");
+        assert_eq!(parts, vec![vec![0xFF, 0xD8, 0xFF, 0xDB, 4, 5, 6, 6, 0xFF, 0xD9]]);
+        let url = "/studies/1.2.2/series/1.2.2.1/instances/1.2.2.1.1/frames/3";
+        assert_eq!(request(&db_path, None, &format!("GET {} HTTP/1.0", url), &[]).0, 404);
+        let url = "/studies/1.2.2/series/1.2.2.1/instances/1.2.2.1.1/frames/0";
+        assert_eq!(request(&db_path, None, &format!("GET {} HTTP/1.0", url), &[]).0, 400);
+        fs::remove_dir_all(db_path.parent().unwrap()).unwrap();
+    }
+
+    /// Прием STOW-RS в `dir/stored`: метаданные сохраненных экземпляров остаются в очереди
+    fn ingest(dir: &path::Path, profile: StowProfile, max_upload: u64) -> (Ingest, mpsc::Receiver<work_dcm::MetaDcm>) {
+        let (meta_tx, meta_rx) = mpsc::channel();
+        (Ingest { save_in: dir.join("stored"), profile, max_upload, meta_tx }, meta_rx)
+    }
+
+    fn multipart(parts: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (part_type, data) in parts {
+            body.extend_from_slice(format!("--stow\r\nContent-Type: {}\r\n\r\n", part_type).as_bytes());
+            body.extend_from_slice(data);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(b"--stow--\r\n");
+        body
+    }
+
+    fn dicom_bytes(obj: &DefaultDicomObject) -> Vec<u8> {
+        let mut data = Vec::new();
+        obj.write_all(&mut data).unwrap();
+        data
+    }
+
+    fn post(db_path: &path::Path, ingest: Ingest, url: &str, body: &[u8]) -> (u16, serde_json::Value) {
+        let head = format!("POST {} HTTP/1.0\r\nContent-Type: multipart/related; type=\"application/dicom\"; boundary=stow", url);
+        let (status, _, body) = request(db_path, Some(ingest), &head, body);
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    #[test]
+    fn stow_stores_instances_and_reports_failures() {
+        let db_path = index("stow");
+        let dir = db_path.parent().unwrap();
+        let instance = dicom_bytes(&instance("Patient^Upload", ["1.2.9", "1.2.9.1", "1.2.9.1.1"], false));
+
+        let (accepted, meta_rx) = ingest(dir, StowProfile::Basic, 1 << 20);
+        let (status, response) = post(&db_path, accepted, "/studies", &multipart(&[("application/dicom", instance.clone())]));
+        assert_eq!(status, 200);
+        assert_eq!(response["00081199"]["Value"][0]["00081190"]["Value"][0],
+                   "http://test/studies/1.2.9/series/1.2.9.1/instances/1.2.9.1.1");
+        assert_eq!(meta_rx.try_iter().count(), 1);
+        let stored = dir_scan::find_dicom_files(&dir.join("stored"));
+        assert_eq!(stored.len(), 1);
+        let saved = work_dcm::read_indexed_dcm(&stored[0]).unwrap();
+        assert_ne!(uid(&saved, "PatientName"), "Patient^Upload");
+
+        // Часть не DICOM не сохраняется, остальные сохраняются: 202
+        let (accepted, meta_rx) = ingest(dir, StowProfile::Off, 1 << 20);
+        let body = multipart(&[("application/dicom", instance.clone()), ("application/dicom", b"not dicom".to_vec())]);
+        let (status, response) = post(&db_path, accepted, "/studies", &body);
+        assert_eq!(status, 202);
+        assert_eq!(response["00081198"]["Value"][0]["00081197"]["Value"][0],
+                   work_dimse::STATUS_CANNOT_UNDERSTAND);
+        assert_eq!(meta_rx.try_iter().count(), 1);
+
+        // Экземпляр другого исследования: 409 и ничего не сохранено
+        let (accepted, meta_rx) = ingest(dir, StowProfile::Basic, 1 << 20);
+        let (status, response) = post(&db_path, accepted, "/studies/1.2.8", &multipart(&[("application/dicom", instance.clone())]));
+        assert_eq!(status, 409);
+        assert_eq!(response["00081198"]["Value"][0]["00081197"]["Value"][0], FAILURE_DATA_SET_MISMATCH);
+        assert_eq!(meta_rx.try_iter().count(), 0);
+
+        let (limited, meta_rx) = ingest(dir, StowProfile::Basic, 256);
+        let (status, _) = post(&db_path, limited, "/studies", &multipart(&[("application/dicom", instance)]));
+        assert_eq!(status, 413);
+        assert_eq!(meta_rx.try_iter().count(), 0);
+        assert_eq!(request(&db_path, None, "POST /studies HTTP/1.0", &[]).0, 405);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn stow_keeps_files_inside_the_save_directory() {
+        let db_path = index("stow_paths");
+        let dir = db_path.parent().unwrap();
+        let escaped = dir.join("escaped");
+        let uids = ["/tmp/study", "..", "1.2.9.1.2"];
+        let instance = dicom_bytes(&instance("../../escaped", uids, false));
+        let (accepted, meta_rx) = ingest(&dir.join("stored"), StowProfile::Off, 1 << 20);
+        let (status, _) = post(&db_path, accepted, "/studies", &multipart(&[("application/dicom", instance)]));
+        assert_eq!(status, 200);
+        let saved = meta_rx.try_iter().map(|meta| path::PathBuf::from(meta.get_path_ref())).collect::<Vec<_>>();
+        assert_eq!(saved.len(), 1);
+        // Идентификаторы становятся одним компонентом пути каждый
+        let stored = dir.join("stored").join("stored");
+        assert_eq!(saved[0].parent().unwrap(), stored.join(".._.._escaped").join("_tmp_study").join("__"));
+        assert!(!escaped.exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn profiles_are_parsed() {
+        assert_eq!("Basic".parse::<StowProfile>(), Ok(StowProfile::Basic));
+        assert_eq!("off".parse::<StowProfile>(), Ok(StowProfile::Off));
+        assert!("full".parse::<StowProfile>().is_err());
+    }
+}
@@ -0,0 +1,144 @@
+use dicom::core::Tag;
+use serde_json::Map;
+
+use crate::work_dcm;
+
+
+/// Компоненты группы имени PN в порядке PS3.5 6.2
+const NAME_COMPONENTS: &[&str] = &["FamilyName", "GivenName", "MiddleName", "NamePrefix", "NameSuffix"];
+
+/// Преобразует набор данных в DICOM JSON (PS3.18 F.2) в Native DICOM Model XML (PS3.19 A.1).
+/// Модели изоморфны, поэтому XML строится по уже закодированному JSON
+pub fn json_to_xml(dataset: &serde_json::Value) -> String {
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<NativeDicomModel xml:space=\"preserve\">\n");
+    if let serde_json::Value::Object(attributes) = dataset {
+        write_attributes(&mut xml, attributes, 1);
+    }
+    xml.push_str("</NativeDicomModel>\n");
+    xml
+}
+
+fn write_attributes(xml: &mut String, attributes: &Map<String, serde_json::Value>, depth: usize) {
+    for (key, attribute) in attributes {
+        let tag = match parse_tag(key) {
+            Some(tag) => tag,
+            None => continue,
+        };
+        let vr = attribute.get("vr").and_then(|vr| vr.as_str()).unwrap_or("UN");
+        let indent = "  ".repeat(depth);
+        xml.push_str(&format!("{}<DicomAttribute tag=\"{}\" vr=\"{}\"", indent, key, vr));
+        if let Some(keyword) = work_dcm::keyword_by_tag(tag).filter(|_| tag.group().is_multiple_of(2)) {
+            xml.push_str(&format!(" keyword=\"{}\"", keyword));
+        }
+        if let Some(creator) = private_creator(attributes, tag) {
+            xml.push_str(&format!(" privateCreator=\"{}\"", escape(&creator)));
+        }
+        xml.push_str(">\n");
+        let values = attribute.get("Value").and_then(|values| values.as_array());
+        for (i, value) in values.into_iter().flatten().enumerate() {
+            let number = i + 1;
+            match (vr, value) {
+                ("SQ", serde_json::Value::Object(item)) => {
+                    xml.push_str(&format!("{}  <Item number=\"{}\">\n", indent, number));
+                    write_attributes(xml, item, depth + 2);
+                    xml.push_str(&format!("{}  </Item>\n", indent));
+                }
+                ("PN", serde_json::Value::Object(name)) => {
+                    xml.push_str(&format!("{}  <PersonName number=\"{}\">\n", indent, number));
+                    for group in ["Alphabetic", "Ideographic", "Phonetic"] {
+                        if let Some(group_value) = name.get(group).and_then(|value| value.as_str()) {
+                            xml.push_str(&format!("{}    <{}>", indent, group));
+                            for (component, value) in NAME_COMPONENTS.iter().zip(group_value.split('^')) {
+                                if !value.is_empty() {
+                                    xml.push_str(&format!("<{0}>{1}</{0}>", component, escape(value)));
+                                }
+                            }
+                            xml.push_str(&format!("</{}>\n", group));
+                        }
+                    }
+                    xml.push_str(&format!("{}  </PersonName>\n", indent));
+                }
+                (_, serde_json::Value::Null) => {
+                    xml.push_str(&format!("{}  <Value number=\"{}\"/>\n", indent, number));
+                }
+                (_, serde_json::Value::String(text)) => {
+                    xml.push_str(&format!("{}  <Value number=\"{}\">{}</Value>\n", indent, number, escape(text)));
+                }
+                (_, other) => {
+                    xml.push_str(&format!("{}  <Value number=\"{}\">{}</Value>\n", indent, number, other));
+                }
+            }
+        }
+        if let Some(uri) = attribute.get("BulkDataURI").and_then(|uri| uri.as_str()) {
+            xml.push_str(&format!("{}  <BulkData uri=\"{}\"/>\n", indent, escape(uri)));
+        }
+        if let Some(data) = attribute.get("InlineBinary").and_then(|data| data.as_str()) {
+            xml.push_str(&format!("{}  <InlineBinary>{}</InlineBinary>\n", indent, data));
+        }
+        xml.push_str(&format!("{}</DicomAttribute>\n", indent));
+    }
+}
+
+fn parse_tag(key: &str) -> Option<Tag> {
+    let tag = u32::from_str_radix(key, 16).ok().filter(|_| key.len() == 8)?;
+    Some(Tag((tag >> 16) as u16, tag as u16))
+}
+
+/// Private Creator частного атрибута: значение элемента (gggg,00xx) того же набора,
+/// где xx — старший байт номера элемента
+fn private_creator(attributes: &Map<String, serde_json::Value>, tag: Tag) -> Option<String> {
+    if tag.group().is_multiple_of(2) || tag.element() < 0x1000 {
+        return None;
+    }
+    let creator_key = format!("{:04X}00{:02X}", tag.group(), tag.element() >> 8);
+    attributes.get(&creator_key)?
+        .get("Value")?
+        .get(0)?
+        .as_str()
+        .map(|creator| creator.to_string())
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::work_json;
+
+    #[test]
+    fn native_model_follows_the_json() {
+        let uri = |tag: Tag| if tag == work_json::PIXEL_DATA { Some("file:///data/a b.dcm#offset=1&length=2".to_string()) } else { None };
+        let xml = json_to_xml(&work_json::dataset_to_json(&work_json::tests::sample_dataset(), Some(&uri)));
+        let lines: Vec<&str> = xml.lines().collect();
+        assert_eq!(lines[0], "<?xml version=\"1.0\" encoding=\"UTF-8\"?>");
+        assert_eq!(lines[1], "<NativeDicomModel xml:space=\"preserve\">");
+        assert_eq!(lines[lines.len() - 1], "</NativeDicomModel>");
+        for expected in [
+            "  <DicomAttribute tag=\"00080060\" vr=\"CS\" keyword=\"Modality\">",
+            "    <Value number=\"1\">CT</Value>",
+            "    <Value number=\"1\">Head &lt;contrast&gt; &amp; &quot;neck&quot;</Value>",
+            "    <Value number=\"2\">0.75</Value>",
+            "    <PersonName number=\"1\">",
+            "      <Alphabetic><FamilyName>Doe</FamilyName><GivenName>John</GivenName></Alphabetic>",
+            "      <Ideographic><FamilyName>Иванов</FamilyName><GivenName>Иван</GivenName></Ideographic>",
+            "  <DicomAttribute tag=\"00080050\" vr=\"SH\" keyword=\"AccessionNumber\">",
+            "    <Item number=\"1\">",
+            "      <DicomAttribute tag=\"00080100\" vr=\"SH\" keyword=\"CodeValue\">",
+            "  <DicomAttribute tag=\"00091001\" vr=\"UN\" privateCreator=\"ACME 1.0\">",
+            "    <InlineBinary>AQIDBA==</InlineBinary>",
+            "    <BulkData uri=\"file:///data/a b.dcm#offset=1&amp;length=2\"/>",
+        ] {
+            assert!(lines.contains(&expected), "{}\n{}", expected, xml);
+        }
+        // Частные атрибуты не получают ключевое слово, атрибут без значения - пустой элемент
+        assert!(lines.contains(&"  <DicomAttribute tag=\"00090010\" vr=\"LO\">"));
+        let empty = lines.iter().position(|line| line.contains("tag=\"00080050\"")).unwrap();
+        assert_eq!(lines[empty + 1], "  </DicomAttribute>");
+        assert_eq!(xml.matches("<DicomAttribute").count(), xml.matches("</DicomAttribute>").count());
+    }
+}
@@ -0,0 +1,92 @@
+/* Кодовые потоки JPEG 2000 для тестов work_j2k, сжатые OpenJPEG 2.5.3:
+   gcc gen_j2k.c -lopenjp2 -o gen_j2k && mkdir -p out && ./gen_j2k */
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include "openjpeg.h"
+
+/* те же кадры, что work_pixels::tests::synthetic_image и synthetic_rgb */
+static void gray(int bits, int *v) {
+    long max = (1L << bits) - 1;
+    for (int y = 0; y < 30; y++) for (int x = 0; x < 40; x++) {
+        long dx = x - 20, dy = y - 15;
+        long val = (dx*dx + dy*dy < 144) ? (max * (x*3 + y*5) / 140) : 0;
+        if (val > max) val = max;
+        v[y*40 + x] = (int)val;
+    }
+    v[15*40 + 31] = (int)max;
+}
+static void rgb(int *v) {
+    for (int y = 0; y < 16; y++) for (int x = 0; x < 24; x++) {
+        v[(y*24+x)*3] = x*10; v[(y*24+x)*3+1] = y*15; v[(y*24+x)*3+2] = ((x/8 + y/8) % 2 == 0) ? 200 : 50;
+    }
+}
+
+static opj_image_t *make(int w, int h, int n, int prec, int sgnd, const int *values) {
+    opj_image_cmptparm_t p[3];
+    memset(p, 0, sizeof p);
+    for (int c = 0; c < n; c++) { p[c].dx = p[c].dy = 1; p[c].w = w; p[c].h = h; p[c].prec = prec; p[c].sgnd = sgnd; }
+    opj_image_t *img = opj_image_create(n, p, n == 3 ? OPJ_CLRSPC_SRGB : OPJ_CLRSPC_GRAY);
+    img->x0 = 0; img->y0 = 0; img->x1 = w; img->y1 = h;
+    for (int c = 0; c < n; c++) for (int i = 0; i < w*h; i++) img->comps[c].data[i] = values[i*n + c];
+    return img;
+}
+
+static void write_codestream(const char *path, opj_image_t *img, opj_cparameters_t *params) {
+    opj_codec_t *codec = opj_create_compress(OPJ_CODEC_J2K);
+    if (!opj_setup_encoder(codec, params, img)) { fprintf(stderr, "setup %s\n", path); exit(1); }
+    opj_stream_t *s = opj_stream_create_default_file_stream(path, OPJ_FALSE);
+    if (!opj_start_compress(codec, img, s) || !opj_encode(codec, s) || !opj_end_compress(codec, s)) { fprintf(stderr, "encode %s\n", path); exit(1); }
+    opj_stream_destroy(s); opj_destroy_codec(codec);
+}
+
+static void decode_raw(const char *path, const char *raw) {
+    opj_codec_t *codec = opj_create_decompress(OPJ_CODEC_J2K);
+    opj_dparameters_t d; opj_set_default_decoder_parameters(&d); opj_setup_decoder(codec, &d);
+    opj_stream_t *s = opj_stream_create_default_file_stream(path, OPJ_TRUE);
+    opj_image_t *img = NULL;
+    if (!opj_read_header(s, codec, &img) || !opj_decode(codec, s, img)) { fprintf(stderr, "decode %s\n", path); exit(1); }
+    FILE *f = fopen(raw, "wb");
+    int n = img->numcomps, count = img->comps[0].w * img->comps[0].h;
+    for (int i = 0; i < count; i++) for (int c = 0; c < n; c++) { unsigned char b = (unsigned char)img->comps[c].data[i]; fwrite(&b, 1, 1, f); }
+    fclose(f);
+    opj_image_destroy(img); opj_stream_destroy(s); opj_destroy_codec(codec);
+}
+
+int main(void) {
+    int v[40*30], c[24*16*3];
+    opj_cparameters_t p;
+
+    /* 12 бит без знака, без потерь, 5 уровней разрешения, один слой */
+    gray(12, v);
+    opj_set_default_encoder_parameters(&p);
+    p.numresolution = 5; p.tcp_numlayers = 1; p.tcp_rates[0] = 0; p.cp_disto_alloc = 1;
+    write_codestream("out/gray12_lossless.j2k", make(40, 30, 1, 12, 0, v), &p);
+
+    /* 12 бит со знаком, без потерь, блоки 16x16, маркеры SOP и EPH, порядок RLCP */
+    gray(12, v);
+    for (int i = 0; i < 40*30; i++) v[i] -= 2048;
+    opj_set_default_encoder_parameters(&p);
+    p.numresolution = 4; p.cblockw_init = 16; p.cblockh_init = 16; p.csty |= 0x02 | 0x04;
+    p.prog_order = OPJ_RLCP; p.tcp_numlayers = 1; p.tcp_rates[0] = 0; p.cp_disto_alloc = 1;
+    write_codestream("out/gray12_signed_sop_eph.j2k", make(40, 30, 1, 12, 1, v), &p);
+
+    /* RGB 8 бит без потерь с MCT: тайлы 16x16, 3 слоя, участки 8x8, порядок RPCL */
+    rgb(c);
+    opj_set_default_encoder_parameters(&p);
+    p.numresolution = 3; p.cblockw_init = 8; p.cblockh_init = 8; p.tcp_mct = 1;
+    p.tile_size_on = OPJ_TRUE; p.cp_tdx = 16; p.cp_tdy = 16;
+    p.prog_order = OPJ_RPCL; p.tcp_numlayers = 3; p.tcp_rates[0] = 20; p.tcp_rates[1] = 5; p.tcp_rates[2] = 0; p.cp_disto_alloc = 1;
+    p.csty |= 0x01; p.res_spec = 3;
+    for (int r = 0; r < 3; r++) { p.prcw_init[r] = 8 << r; p.prch_init[r] = 8 << r; }
+    write_codestream("out/rgb8_tiles_layers_rpcl.j2k", make(24, 16, 3, 8, 0, c), &p);
+
+    /* 8 бит с потерями (9/7), сжатие в 4 раза */
+    gray(8, v);
+    opj_set_default_encoder_parameters(&p);
+    p.numresolution = 4; p.irreversible = 1; p.tcp_numlayers = 1; p.tcp_rates[0] = 4; p.cp_disto_alloc = 1;
+    write_codestream("out/gray8_lossy.j2k", make(40, 30, 1, 8, 0, v), &p);
+    /* значения, распакованные OpenJPEG */
+    decode_raw("out/gray8_lossy.j2k", "out/gray8_lossy.raw");
+    return 0;
+}
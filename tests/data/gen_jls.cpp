@@ -0,0 +1,67 @@
+// Кадры JPEG-LS для тестов work_jpegls, сжатые CharLS 2.4.2:
+// g++ -std=c++17 gen_jls.cpp -lcharls -o gen_jls && mkdir -p out && ./gen_jls
+#include <charls/charls.h>
+#include <cstdio>
+#include <vector>
+#include <cstdint>
+
+// тот же кадр, что work_pixels::tests::synthetic_image
+static std::vector<uint16_t> gray(int bits) {
+    std::vector<uint16_t> v(40 * 30);
+    long max = (1L << bits) - 1;
+    for (int y = 0; y < 30; y++) for (int x = 0; x < 40; x++) {
+        long dx = x - 20, dy = y - 15;
+        long val = (dx*dx + dy*dy < 144) ? (max * (x*3 + y*5) / 140) : 0;
+        if (val > max) val = max;
+        v[y*40 + x] = (uint16_t)val;
+    }
+    v[15*40 + 31] = (uint16_t)max;
+    return v;
+}
+
+static void save(const char *path, const std::vector<uint8_t> &data) {
+    FILE *f = fopen(path, "wb"); fwrite(data.data(), 1, data.size(), f); fclose(f);
+}
+
+static std::vector<uint8_t> encode(const void *src, size_t size, int w, int h, int bits, int n,
+                                   charls::interleave_mode mode, int near, const charls::jpegls_pc_parameters *preset) {
+    charls::jpegls_encoder e;
+    e.frame_info({(uint32_t)w, (uint32_t)h, bits, n}).interleave_mode(mode).near_lossless(near);
+    if (preset) e.preset_coding_parameters(*preset);
+    std::vector<uint8_t> out(e.estimated_destination_size());
+    e.destination(out);
+    out.resize(e.encode(src, size));
+    return out;
+}
+
+int main() {
+    auto g8 = gray(8);
+    std::vector<uint8_t> b8(g8.begin(), g8.end());
+    save("out/gray8_lossless.jls", encode(b8.data(), b8.size(), 40, 30, 8, 1, charls::interleave_mode::none, 0, nullptr));
+
+    auto g12 = gray(12);
+    save("out/gray12_lossless.jls", encode(g12.data(), g12.size() * 2, 40, 30, 12, 1, charls::interleave_mode::none, 0, nullptr));
+
+    auto near = encode(g12.data(), g12.size() * 2, 40, 30, 12, 1, charls::interleave_mode::none, 3, nullptr);
+    save("out/gray12_near3.jls", near);
+    std::vector<uint16_t> decoded(40 * 30);
+    charls::jpegls_decoder::decode(near, decoded);
+    std::vector<uint8_t> raw;
+    for (auto value : decoded) { raw.push_back(value & 0xFF); raw.push_back(value >> 8); }
+    // значения, распакованные CharLS
+    save("out/gray12_near3.raw", raw);
+
+    charls::jpegls_pc_parameters preset{4095, 18, 27, 60, 32};
+    save("out/gray12_preset.jls", encode(g12.data(), g12.size() * 2, 40, 30, 12, 1, charls::interleave_mode::none, 0, &preset));
+
+    std::vector<uint8_t> rgb(24 * 16 * 3), planar(24 * 16 * 3);
+    for (int y = 0; y < 16; y++) for (int x = 0; x < 24; x++) {
+        int i = (y * 24 + x) * 3;
+        rgb[i] = x * 10; rgb[i + 1] = y * 15; rgb[i + 2] = ((x / 8 + y / 8) % 2 == 0) ? 200 : 50;
+        for (int c = 0; c < 3; c++) planar[c * 24 * 16 + y * 24 + x] = rgb[i + c];
+    }
+    save("out/rgb8_none.jls", encode(planar.data(), planar.size(), 24, 16, 8, 3, charls::interleave_mode::none, 0, nullptr));
+    save("out/rgb8_line.jls", encode(rgb.data(), rgb.size(), 24, 16, 8, 3, charls::interleave_mode::line, 0, nullptr));
+    save("out/rgb8_sample.jls", encode(rgb.data(), rgb.size(), 24, 16, 8, 3, charls::interleave_mode::sample, 0, nullptr));
+    return 0;
+}